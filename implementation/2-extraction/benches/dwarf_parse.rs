@@ -0,0 +1,70 @@
+//! Compare parsing all DWARF functions of a binary against parsing only those at given offsets,
+//! which skips compilation units without any of the offsets (see `DwarfBinary::parse()`).
+//! The unit test `test_lazy_parse_same_functions` checks the number of visited units and entries,
+//! this measures the actual time.
+
+use std::collections::{BTreeSet, HashMap};
+use std::rc::Rc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use gimli::constants::*;
+use gimli::write::{self, Address, AttributeValue, EndianVec, Sections};
+use gimli::{Encoding, Format, LittleEndian};
+
+use extraction::DwarfBinary;
+
+/// Many compilation units with many functions each, of which only a few are in the binary.
+fn dwarf_sections() -> HashMap<&'static str, Rc<[u8]>> {
+    let encoding = Encoding { format: Format::Dwarf32, version: 4, address_size: 4 };
+    let mut dwarf = write::Dwarf::new();
+    for i in 0..500 {
+        let unit_begin = i * 0x1000;
+        let unit = dwarf.units.add(write::Unit::new(encoding, write::LineProgram::none()));
+        let unit = dwarf.units.get_mut(unit);
+        let root = unit.root();
+        unit.get_mut(root).set(DW_AT_low_pc, AttributeValue::Address(Address::Constant(unit_begin)));
+        unit.get_mut(root).set(DW_AT_high_pc, AttributeValue::Udata(0x1000));
+
+        let int = unit.add(root, DW_TAG_base_type);
+        unit.get_mut(int).set(DW_AT_name, AttributeValue::String(b"int".to_vec()));
+        unit.get_mut(int).set(DW_AT_encoding, AttributeValue::Encoding(DW_ATE_signed));
+        unit.get_mut(int).set(DW_AT_byte_size, AttributeValue::Udata(4));
+
+        for j in 0..50 {
+            let function = unit.add(root, DW_TAG_subprogram);
+            unit.get_mut(function).set(DW_AT_name, AttributeValue::String(format!("f{}_{}", i, j).into_bytes()));
+            unit.get_mut(function).set(DW_AT_low_pc, AttributeValue::Address(Address::Constant(unit_begin + j * 0x10)));
+            for name in &["a", "b"] {
+                let param = unit.add(function, DW_TAG_formal_parameter);
+                unit.get_mut(param).set(DW_AT_name, AttributeValue::String(name.as_bytes().to_vec()));
+                unit.get_mut(param).set(DW_AT_type, AttributeValue::UnitRef(int));
+            }
+        }
+    }
+
+    let mut sections = Sections::new(EndianVec::new(LittleEndian));
+    dwarf.write(&mut sections).unwrap();
+    let mut result = HashMap::new();
+    sections
+        .for_each(|id, data| -> Result<(), ()> {
+            result.insert(id.name(), Rc::from(data.slice()));
+            Ok(())
+        })
+        .unwrap();
+    result
+}
+
+fn bench_lazy_parse(c: &mut Criterion) {
+    let sections = dwarf_sections();
+    let relative_offsets: BTreeSet<usize> = vec![0x10, 0x20, 0x5000, 0x7000, 0x7ff0, 0x1f3010, 0x123456]
+        .into_iter()
+        .collect();
+
+    let mut group = c.benchmark_group("dwarf_parse");
+    group.bench_function("eager", |b| b.iter(|| DwarfBinary::parse(&sections, None, None, false).unwrap()));
+    group.bench_function("lazy", |b| b.iter(|| DwarfBinary::parse(&sections, Some(&relative_offsets), None, false).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, bench_lazy_parse);
+criterion_main!(benches);
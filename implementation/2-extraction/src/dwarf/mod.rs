@@ -1,2 +1,4 @@
 pub mod parse;
 pub mod util;
+#[cfg(test)]
+pub mod test_util;
@@ -1,9 +1,13 @@
-use std::collections::{HashMap, HashSet};
-use std::convert::Infallible;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::Hash;
+use std::io::Read;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
-use gimli::{AttributeValue, EndianRcSlice, LittleEndian, Reader, SectionId};
+use anyhow::Context;
+use flate2::read::ZlibDecoder;
+use gimli::{AttributeValue, DebuggingInformationEntry, DwLang, Dwarf, EndianRcSlice, LittleEndian, Reader, SectionId, Unit};
 use gimli::constants::*;
 use itertools::Itertools;
 
@@ -11,56 +15,124 @@ use crate::dwarf::util::DwarfEntry;
 
 #[derive(Debug)]
 pub struct DwarfBinary<R: Reader> {
-    pub relative_offsets_to_function_entries: HashMap<usize, DwarfFunction<R>>
+    pub relative_offsets_to_function_entries: HashMap<usize, DwarfFunction<R>>,
+    /// Only for relocatable object files, instead of the above, see `DwarfBinary::parse()`.
+    pub names_to_function_entries: HashMap<Arc<str>, DwarfFunction<R>>,
 }
 
-impl DwarfBinary<EndianRcSlice<LittleEndian>> {
-    pub fn parse(sections: &HashMap<&str, Rc<[u8]>>) -> anyhow::Result<Self> {
-        let dwarf = {
-            // Identify DWARF sections by their custom section name.
-            let loader = |dwarf_section: SectionId| -> Result<_, Infallible> {
-                let data = sections.get(dwarf_section.name())
-                    .cloned()
-                    .unwrap_or(Rc::from([]));
-
-                Ok(EndianRcSlice::new(data, LittleEndian))
-            };
+/// Counters for diagnosing why a corpus yields few samples, accumulated over all parsed binaries.
+/// Since compilation units and functions can be skipped, see `DwarfBinary::parse()`, the counts
+/// are of entries actually visited, not of all entries in the binaries.
+#[derive(Debug, Default)]
+pub struct DwarfStats {
+    pub entries: AtomicU64,
+    pub compilation_units: AtomicU64,
+    pub subprograms: AtomicU64,
+    /// Subprograms with a code location, see `function_start()`.
+    pub subprograms_with_location: AtomicU64,
+    /// The following are incremented by the caller, when matching up DWARF with WebAssembly
+    /// functions: All WebAssembly functions (with a body), which are either matched to a DWARF
+    /// function at their offset or not.
+    pub wasm_functions: AtomicU64,
+    pub functions_matched: AtomicU64,
+    pub functions_unmatched: AtomicU64,
+    /// Matched functions that were skipped, because their WebAssembly and DWARF parameters could
+    /// not be aligned, see `align_params()` in samples/extract.rs.
+    pub functions_param_mismatch: AtomicU64,
+}
 
-            // We don't have a supplementary object file.
-            let sup_loader = |_| Ok(EndianRcSlice::new(Rc::from([]), LittleEndian));
+impl DwarfStats {
+    /// Add all counts to `total`, e.g., from the stats of a single binary to those of all binaries.
+    pub fn add_to(&self, total: &DwarfStats) {
+        let add = |count: &AtomicU64, total: &AtomicU64| { total.fetch_add(count.load(Ordering::SeqCst), Ordering::SeqCst); };
+        add(&self.entries, &total.entries);
+        add(&self.compilation_units, &total.compilation_units);
+        add(&self.subprograms, &total.subprograms);
+        add(&self.subprograms_with_location, &total.subprograms_with_location);
+        add(&self.wasm_functions, &total.wasm_functions);
+        add(&self.functions_matched, &total.functions_matched);
+        add(&self.functions_unmatched, &total.functions_unmatched);
+        add(&self.functions_param_mismatch, &total.functions_param_mismatch);
+    }
+}
+
+impl DwarfBinary<EndianRcSlice<LittleEndian>> {
+    /// If `relative_offsets` is given (e.g., of all WebAssembly function bodies, relative to the
+    /// code section), only the functions at those offsets are parsed. Compilation units whose
+    /// address ranges contain none of the offsets are then skipped without walking their entries,
+    /// which avoids most of the work for binaries with many compilation units.
+    /// If `relocatable`, the functions are keyed by their linkage name (or name, e.g., for C)
+    /// instead of their offset, because in relocatable object files, relocations are not applied
+    /// yet and many functions share offset 0x0. Then, `relative_offsets` should be None.
+    pub fn parse(sections: &HashMap<&str, Rc<[u8]>>, relative_offsets: Option<&BTreeSet<usize>>, stats: Option<&DwarfStats>, relocatable: bool) -> anyhow::Result<Self> {
+        let dwarf = Rc::new(load_dwarf(sections)?);
 
-            gimli::Dwarf::load(loader, sup_loader)
-        }?;
-        let dwarf = Rc::new(dwarf);
+        // Count locally and add to the (shared) stats only once, to avoid contention between threads.
+        let mut entries_count = 0;
+        let mut compilation_units_count = 0;
+        let mut subprograms_count = 0;
+        let mut subprograms_with_location_count = 0;
 
         let mut relative_offsets_to_function_entries: HashMap<usize, DwarfFunction<EndianRcSlice<LittleEndian>>> =  HashMap::new();
         let mut relative_offsets_with_inconsistent_entries = HashSet::new();
+        let mut names_to_function_entries = HashMap::new();
+        let mut names_with_inconsistent_entries = HashSet::new();
 
         // Iterate over all compilation units.
         let mut units_iter = dwarf.units();
         while let Some(unit_header) = units_iter.next()? {
             let unit = dwarf.unit(unit_header)?;
+            if let Some(relative_offsets) = relative_offsets {
+                if !unit_may_contain_any(&dwarf, &unit, relative_offsets)? {
+                    continue;
+                }
+            }
+            compilation_units_count += 1;
             let unit_name = match &unit.name {
-                Some(name) => Some(Arc::from(name.to_string()?)),
+                // See `DwarfEntry::attr_str()` for why this is lossy.
+                Some(name) => Some(Arc::from(name.to_string_lossy()?)),
                 None => None,
             };
             let unit = Rc::new(unit);
 
+            // The compiler (name and version) that produced this compilation unit and its source
+            // language, both on its root entry.
+            let root = unit.entries().next_dfs()?.map(|(_, root)| DwarfEntry::from(&dwarf, &unit, root));
+            let (producer, language) = match &root {
+                Some(root) => {
+                    let producer = root.attr_str(DW_AT_producer)?.map(Arc::from);
+                    let language = match root.attr(DW_AT_language)? {
+                        Some(AttributeValue::Language(language)) => language_name(language),
+                        _ => None,
+                    };
+                    (producer, language)
+                }
+                None => (None, None),
+            };
+
             // Iterate over all DWARF tags in depth-first order.
             let mut entries = unit.entries();
             while let Some((_delta_depth, entry)) = entries.next_dfs()? {
+                entries_count += 1;
 
                 // Look for all functions in this compilation unit.
                 if entry.tag() == DW_TAG_subprogram {
+                    subprograms_count += 1;
                 
                     // Save only those with a location (i.e., that can be potentially mapped to WebAssembly).
-                    let location = entry.attr_value(DW_AT_low_pc)?;
-                    match location {
+                    match function_start(&dwarf, &unit, entry)? {
                         None => {}
-                        Some(AttributeValue::Addr(relative_offset)) => {
+                        Some(relative_offset) => {
+                            subprograms_with_location_count += 1;
                             let relative_offset = relative_offset as usize;
-                            let entry = DwarfEntry::from(&dwarf, &unit, entry);
-                            let function = DwarfFunction::from(unit_name.clone(), &entry)?;
+                            if let Some(relative_offsets) = relative_offsets {
+                                if !relative_offsets.contains(&relative_offset) {
+                                    continue;
+                                }
+                            }
+                            // Share the parents of the unit among all its entries, see `DwarfEntry::ancestors()`.
+                            let entry = root.as_ref().expect("the unit contains at least its root entry").same_unit_entry(entry);
+                            let function = DwarfFunction::from(unit_name.clone(), producer.clone(), language, &entry)?;
 
                             // In some binaries (e.g., scummvm.wasm) there are multiple DWARF 
                             // entries for the same WebAssembly function (by relative_offset).
@@ -72,26 +144,26 @@ impl DwarfBinary<EndianRcSlice<LittleEndian>> {
                             // where we suspect the compiler just duplicated/copied DWARF info
                             // for whatever reason (example: scummvm.wasm, multiple entries with 
                             // DW_AT_low_pc = 0x01002d1f, but all containing essentially the same info).
-                            if let Some(previous) = relative_offsets_to_function_entries.get(&relative_offset) {
-                                // Ensure that the previous one and this entry are consistent (contain
-                                // the same debug information), at least on a superficial level.
-                                let same_name = previous.name == function.name;
-                                let same_param_count = previous.params.len() == function.params.len();
-                                let same_return_count = previous.return_type.iter().count() == function.return_type.iter().count();
-                                if !(same_name && same_param_count && same_return_count) {
-                                    relative_offsets_with_inconsistent_entries.insert(relative_offset);
+                            if relocatable {
+                                if let Some(name) = linkage_name(&entry)?.map(Arc::from).or_else(|| function.name.clone()) {
+                                    insert_consistent(&mut names_to_function_entries, &mut names_with_inconsistent_entries, name, function);
                                 }
                             } else {
-                                relative_offsets_to_function_entries.insert(relative_offset, function);
+                                insert_consistent(&mut relative_offsets_to_function_entries, &mut relative_offsets_with_inconsistent_entries, relative_offset, function);
                             }
                         }
-
-                        Some(attr_value) => unimplemented!("unknown attribute value for DW_AT_low_pc: {:?}", attr_value)
                     }
                 }
             }
         }
 
+        if let Some(stats) = stats {
+            stats.entries.fetch_add(entries_count, Ordering::Relaxed);
+            stats.compilation_units.fetch_add(compilation_units_count, Ordering::Relaxed);
+            stats.subprograms.fetch_add(subprograms_count, Ordering::Relaxed);
+            stats.subprograms_with_location.fetch_add(subprograms_with_location_count, Ordering::Relaxed);
+        }
+
         for relative_offset in relative_offsets_with_inconsistent_entries {
             relative_offsets_to_function_entries.remove(&relative_offset);
             
@@ -102,26 +174,216 @@ impl DwarfBinary<EndianRcSlice<LittleEndian>> {
                 anyhow::bail!("duplicate, inconsistent DWARF entries for function at relative offset 0x{:x}", relative_offset);
             }
         }
+        // E.g., static functions of the same name in different compilation units, which cannot be
+        // told apart by name.
+        for name in names_with_inconsistent_entries {
+            names_to_function_entries.remove(&name);
+        }
+
+        Ok(DwarfBinary { relative_offsets_to_function_entries, names_to_function_entries })
+    }
+}
+
+/// Keep only the first of multiple DWARF functions for the same key, but remember the key if
+/// they are inconsistent (i.e., do not contain the same debug information, at least on a
+/// superficial level), see `DwarfBinary::parse()`.
+fn insert_consistent<K: Hash + Eq, R: Reader>(functions: &mut HashMap<K, DwarfFunction<R>>, inconsistent_keys: &mut HashSet<K>, key: K, function: DwarfFunction<R>) {
+    if let Some(previous) = functions.get(&key) {
+        let same_name = previous.name == function.name;
+        let same_param_count = previous.params.len() == function.params.len();
+        let same_return_count = previous.return_type.iter().count() == function.return_type.iter().count();
+        if !(same_name && same_param_count && same_return_count) {
+            inconsistent_keys.insert(key);
+        }
+    } else {
+        functions.insert(key, function);
+    }
+}
+
+/// The (mangled) symbol name of a function, which for C++ is only on its declaration, e.g., the
+/// one inside the class, or on the abstract origin of concrete instances.
+fn linkage_name<R: Reader>(entry: &DwarfEntry<R>) -> gimli::Result<Option<Box<str>>> {
+    // Valid DWARF needs at most a few steps (e.g., concrete instance -> abstract origin ->
+    // declaration), but malformed DWARF can contain cycles of references.
+    const MAX_REFERENCES: usize = 8;
+
+    let mut entry = entry.clone();
+    for _ in 0..=MAX_REFERENCES {
+        if let Some(name) = entry.attr_str(DW_AT_linkage_name)? {
+            return Ok(Some(name));
+        }
+        entry = match entry.attr_entry(DW_AT_specification)? {
+            Some(declaration) => declaration,
+            None => match entry.attr_entry(DW_AT_abstract_origin)? {
+                Some(origin) => origin,
+                None => return Ok(None),
+            },
+        };
+    }
+    Ok(None)
+}
+
+/// Start of the code of a function, relative to the code section, or None if the function has no
+/// (usable) location, e.g., because it is only a declaration or was inlined everywhere.
+/// Most producers give the start as a DW_AT_low_pc address, but it can also be an index into
+/// .debug_addr (DW_FORM_addrx in DWARF 5), or a constant. Functions whose code is split into
+/// multiple parts (DW_AT_ranges instead of DW_AT_low_pc) start at their lowest address.
+fn function_start<R: Reader>(dwarf: &Dwarf<R>, unit: &Unit<R>, entry: &DebuggingInformationEntry<R>) -> gimli::Result<Option<u64>> {
+    match entry.attr_value(DW_AT_low_pc)? {
+        Some(AttributeValue::Addr(address)) => Ok(Some(address)),
+        Some(AttributeValue::DebugAddrIndex(index)) => Ok(Some(dwarf.address(unit, index)?)),
+        Some(value) => Ok(value.udata_value()),
+        None if entry.attr_value(DW_AT_ranges)?.is_some() => {
+            let mut start = None;
+            let mut ranges = dwarf.die_ranges(unit, entry)?;
+            while let Some(range) = ranges.next()? {
+                if range.begin < range.end {
+                    start = Some(start.map_or(range.begin, |start: u64| start.min(range.begin)));
+                }
+            }
+            Ok(start)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Whether the address ranges of a compilation unit contain any of the given offsets. Units
+/// without (usable) address ranges on their root entry could contain any function.
+fn unit_may_contain_any<R: Reader>(dwarf: &Dwarf<R>, unit: &Unit<R>, offsets: &BTreeSet<usize>) -> gimli::Result<bool> {
+    let mut entries = unit.entries();
+    let root = match entries.next_dfs()? {
+        Some((_, root)) => root,
+        None => return Ok(false),
+    };
+    if root.attr_value(DW_AT_low_pc)?.is_none() && root.attr_value(DW_AT_ranges)?.is_none() {
+        return Ok(true);
+    }
+
+    let mut has_ranges = false;
+    let mut ranges = dwarf.die_ranges(unit, root)?;
+    while let Some(range) = ranges.next()? {
+        has_ranges = true;
+        if range.begin < range.end && offsets.range(range.begin as usize..range.end as usize).next().is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(!has_ranges)
+}
+
+/// Load the DWARF sections from the custom sections of a WebAssembly binary.
+pub fn load_dwarf(sections: &HashMap<&str, Rc<[u8]>>) -> anyhow::Result<Dwarf<EndianRcSlice<LittleEndian>>> {
+    // Identify DWARF sections by their custom section name.
+    let loader = |dwarf_section: SectionId| -> anyhow::Result<_> {
+        let name = dwarf_section.name();
+        let data = match sections.get(name) {
+            Some(data) => decompress_section(name, data)?,
+            // Compressed sections can also be named .zdebug_* instead of .debug_* (old GNU style).
+            None => match sections.get(format!(".z{}", &name[1..]).as_str()) {
+                Some(data) => decompress_section(name, data)?,
+                None => Rc::from([]),
+            }
+        };
+
+        Ok(EndianRcSlice::new(data, LittleEndian))
+    };
+
+    // We don't have a supplementary object file.
+    let sup_loader = |_| Ok(EndianRcSlice::new(Rc::from([]), LittleEndian));
+
+    Dwarf::load(loader, sup_loader)
+}
+
+/// Inflate a zlib-compressed DWARF section, as emitted by GCC/LLVM with -gz=zlib-gnu: The section
+/// data starts with "ZLIB", followed by the uncompressed size (8 bytes, big-endian), followed by
+/// the zlib stream. Uncompressed sections are returned as is.
+fn decompress_section(name: &str, data: &Rc<[u8]>) -> anyhow::Result<Rc<[u8]>> {
+    const ZLIB_HEADER: &[u8] = b"ZLIB";
+    const HEADER_SIZE: usize = ZLIB_HEADER.len() + 8;
+
+    if !data.starts_with(ZLIB_HEADER) || data.len() < HEADER_SIZE {
+        return Ok(Rc::clone(data));
+    }
+
+    let mut size = [0; 8];
+    size.copy_from_slice(&data[ZLIB_HEADER.len()..HEADER_SIZE]);
+    let size = u64::from_be_bytes(size);
+
+    let mut decompressed = Vec::with_capacity(size as usize);
+    ZlibDecoder::new(&data[HEADER_SIZE..]).read_to_end(&mut decompressed)
+        .with_context(|| format!("could not decompress DWARF section {}", name))?;
+    if decompressed.len() as u64 != size {
+        anyhow::bail!("decompressed DWARF section {} has {} bytes, but header says {}", name, decompressed.len(), size);
+    }
+    Ok(Rc::from(decompressed))
+}
+
+/// Readable name of the source language of a compilation unit (DW_AT_language), e.g., "cpp14" for
+/// DW_LANG_C_plus_plus_14. Less common languages keep the name of their DWARF constant.
+pub fn language_name(language: DwLang) -> Option<&'static str> {
+    #[allow(non_upper_case_globals)]
+    let name = match language {
+        DW_LANG_C => "c",
+        DW_LANG_C89 => "c89",
+        DW_LANG_C99 => "c99",
+        DW_LANG_C11 => "c11",
+        DW_LANG_C_plus_plus => "cpp",
+        DW_LANG_C_plus_plus_03 => "cpp03",
+        DW_LANG_C_plus_plus_11 => "cpp11",
+        DW_LANG_C_plus_plus_14 => "cpp14",
+        DW_LANG_ObjC => "objc",
+        DW_LANG_ObjC_plus_plus => "objcpp",
+        DW_LANG_Rust => "rust",
+        DW_LANG_Go => "go",
+        DW_LANG_Swift => "swift",
+        DW_LANG_D => "d",
+        DW_LANG_Mips_Assembler => "assembler",
+        _ => return language.static_string(),
+    };
+    Some(name)
+}
 
-        Ok(DwarfBinary { relative_offsets_to_function_entries })
+/// Whether `language` (see `language_name()`) is in `languages`, where a language without version
+/// also matches all its versions, e.g., "cpp" matches "cpp14". An empty list matches all languages.
+pub fn language_in(language: Option<&str>, languages: &[String]) -> bool {
+    if languages.is_empty() {
+        return true;
     }
+    let language = match language {
+        Some(language) => language,
+        None => return false,
+    };
+    languages.iter().any(|filter| match language.strip_prefix(filter.as_str()) {
+        Some(version) => version.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    })
 }
 
 #[derive(Debug, Clone)]
 pub struct DwarfFunction<R: Reader> {
     pub compilation_unit_name: Option<Arc<str>>,
+    /// DW_AT_producer of the compilation unit, e.g., "clang version 14.0.0".
+    pub producer: Option<Arc<str>>,
+    /// DW_AT_language of the compilation unit, see `language_name()`.
+    pub language: Option<&'static str>,
     // Make it directly a shared pointer (instead of String or Box<str>), because the function name
     // will be shared across all parameter/return type samples from this function and that saves
     // one copy.
     pub name: Option<Arc<str>>,
+    /// Source location of the function, see `DwarfEntry::decl_file()`.
+    pub decl_file: Option<Arc<str>>,
+    pub decl_line: Option<u64>,
 
     pub params: Vec<DwarfEntry<R>>,
     pub return_type: Option<DwarfEntry<R>>,
+    /// Local variables (DW_TAG_variable), including those in nested lexical blocks.
+    pub variables: Vec<DwarfEntry<R>>,
 }
 
 impl<R: Reader> DwarfFunction<R> {
     pub fn from(
         compilation_unit_name: Option<Arc<str>>,
+        producer: Option<Arc<str>>,
+        language: Option<&'static str>,
         function_entry: &DwarfEntry<R>
     ) -> anyhow::Result<Self> {
         // If the function has an DW_AT_abstract_origin attribute (e.g., it is the inlined version
@@ -130,16 +392,283 @@ impl<R: Reader> DwarfFunction<R> {
         // For parameters, I have similar handling to this in `Type::parse()`, but for return types
         // this didn't work, because the DW_AT_type attribute is just missing on the non-abstract function.
         if let Some(abstract_origin) = function_entry.attr_entry(DW_AT_abstract_origin)? {
-            return Self::from(compilation_unit_name, &abstract_origin);
+            let mut function = Self::from(compilation_unit_name, producer, language, &abstract_origin)?;
+            // Only the concrete variables have a location, their names and types are looked up
+            // via their own DW_AT_abstract_origin.
+            function.variables = variables(function_entry)?;
+            return Ok(function);
         }
 
         let name = function_entry.attr_str(DW_AT_name)?.map(Arc::from);
+        let decl_file = function_entry.decl_file()?.map(Arc::from);
+        let decl_line = function_entry.decl_line()?;
 
         let params = function_entry.children()?
             .filter_ok(|entry| entry.tag == DW_TAG_formal_parameter)
             .try_collect()?;
         let return_type = function_entry.attr_entry(DW_AT_type)?;
-        
-        Ok(DwarfFunction { compilation_unit_name, name, params, return_type })
+        let variables = variables(function_entry)?;
+
+        let mut function = DwarfFunction { compilation_unit_name, producer, language, name, decl_file, decl_line, params, return_type, variables };
+
+        // Out-of-line definitions of C++ member functions (e.g., `int C::get(int x) { ... }`)
+        // refer to their declaration inside the class via DW_AT_specification. The definition
+        // (which has the DW_AT_low_pc) often lacks the name, return type, and sometimes also the
+        // parameters, so take whatever is missing from the declaration.
+        if let Some(declaration) = function_entry.attr_entry(DW_AT_specification)? {
+            let declaration = Self::from(function.compilation_unit_name.clone(), function.producer.clone(), language, &declaration)?;
+            function.name = function.name.or(declaration.name);
+            function.decl_file = function.decl_file.or(declaration.decl_file);
+            function.decl_line = function.decl_line.or(declaration.decl_line);
+            if function.params.is_empty() {
+                function.params = declaration.params;
+            }
+            function.return_type = function.return_type.or(declaration.return_type);
+        }
+
+        Ok(function)
+    }
+}
+
+/// All DW_TAG_variable entries in the scope of `entry`, i.e., its children and those of nested
+/// lexical blocks (but not of nested functions, e.g., lambdas).
+fn variables<R: Reader>(entry: &DwarfEntry<R>) -> gimli::Result<Vec<DwarfEntry<R>>> {
+    let mut variables = Vec::new();
+    for child in entry.children()? {
+        let child = child?;
+        #[allow(non_upper_case_globals)]
+        match child.tag {
+            DW_TAG_variable => variables.push(child),
+            DW_TAG_lexical_block => variables.extend(self::variables(&child)?),
+            _ => {}
+        }
+    }
+    Ok(variables)
+}
+
+#[cfg(test)]
+mod tests {
+    use gimli::write::{Address, AttributeValue, Range, RangeList, Reference};
+
+    use super::*;
+    use crate::dwarf::test_util::*;
+    use crate::samples::types::Type;
+    use crate::samples::types::parse::ParseOptions;
+
+    #[test]
+    fn test_lazy_parse_same_functions() {
+        // Many compilation units with many functions each, of which only a few are in the binary.
+        let sections = dwarf_sections_with_units(500, |i, unit| {
+            let unit_begin = i as u64 * 0x1000;
+            let root = unit.root();
+            // The last unit has no address range, so it must be always walked.
+            if i < 499 {
+                unit.get_mut(root).set(DW_AT_low_pc, AttributeValue::Address(Address::Constant(unit_begin)));
+                unit.get_mut(root).set(DW_AT_high_pc, AttributeValue::Udata(0x1000));
+            }
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            for j in 0..50 {
+                let function = add_subprogram(unit, Some(&format!("f{}_{}", i, j)), unit_begin + j * 0x10);
+                add_typed(unit, function, DW_TAG_formal_parameter, Some("a"), int);
+                add_typed(unit, function, DW_TAG_formal_parameter, Some("b"), int);
+            }
+        });
+        let relative_offsets: BTreeSet<usize> = vec![0x10, 0x20, 0x5000, 0x7000, 0x7ff0, 0x1f3010, 0x123456]
+            .into_iter()
+            .collect();
+
+        let function_names = |dwarf: &DwarfBinary<_>| -> BTreeSet<_> {
+            dwarf.relative_offsets_to_function_entries.iter()
+                .filter(|(offset, _)| relative_offsets.contains(offset))
+                .map(|(offset, function)| (*offset, function.name.clone()))
+                .collect()
+        };
+
+        let parse = |relative_offsets: Option<&BTreeSet<usize>>| {
+            let stats = DwarfStats::default();
+            let dwarf = DwarfBinary::parse(&sections, relative_offsets, Some(&stats), false).unwrap();
+            (function_names(&dwarf), stats)
+        };
+
+        let (functions_eager, stats_eager) = parse(None);
+        let (functions_lazy, stats_lazy) = parse(Some(&relative_offsets));
+
+        assert_eq!(functions_lazy, functions_eager);
+        assert_eq!(functions_lazy.len(), 5);
+        assert!(functions_lazy.contains(&(0x1f3010, Some(Arc::from("f499_1")))));
+
+        // Each unit has 152 entries: the root, the base type, and 50 functions with 2 parameters.
+        assert_eq!(stats_eager.compilation_units.into_inner(), 500);
+        assert_eq!(stats_eager.entries.into_inner(), 500 * 152);
+        // Only the units containing an offset (0, 5, 7, and 0x123) and the one without range (499).
+        assert_eq!(stats_lazy.compilation_units.into_inner(), 5);
+        assert_eq!(stats_lazy.entries.into_inner(), 5 * 152);
+    }
+
+    #[test]
+    fn test_non_utf8_names() {
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            unit.get_mut(root).set(DW_AT_name, AttributeValue::String(b"caf\xe9.c".to_vec()));
+            let function = add_subprogram(unit, None, 0x10);
+            unit.get_mut(function).set(DW_AT_name, AttributeValue::String(b"f\xff".to_vec()));
+        });
+
+        let dwarf = DwarfBinary::parse(&sections, None, None, false).unwrap();
+        let function = &dwarf.relative_offsets_to_function_entries[&0x10];
+        assert_eq!(function.compilation_unit_name.as_deref(), Some("caf\u{fffd}.c"));
+        assert_eq!(function.name.as_deref(), Some("f\u{fffd}"));
+    }
+
+    #[test]
+    fn test_stats() {
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let function = add_subprogram(unit, Some("f"), 0x10);
+            add_typed(unit, function, DW_TAG_formal_parameter, Some("a"), int);
+            add_subprogram(unit, Some("g"), 0x20);
+            // Declaration without a location.
+            let root = unit.root();
+            add(unit, root, DW_TAG_subprogram, Some("h"));
+        });
+
+        let stats = DwarfStats::default();
+        DwarfBinary::parse(&sections, None, Some(&stats), false).unwrap();
+        DwarfBinary::parse(&sections, None, Some(&stats), false).unwrap();
+        // Root, base type, three subprograms, and one parameter, for each of the two parses.
+        assert_eq!(stats.entries.into_inner(), 12);
+        assert_eq!(stats.compilation_units.into_inner(), 2);
+        assert_eq!(stats.subprograms.into_inner(), 6);
+        assert_eq!(stats.subprograms_with_location.into_inner(), 4);
+    }
+
+    #[test]
+    fn test_specification() {
+        // class C { int get(int); int set(int); }; int C::get(int) { ... } int C::set(int x) { ... }
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let class = add(unit, root, DW_TAG_class_type, Some("C"));
+            let class_ptr = add_typed(unit, root, DW_TAG_pointer_type, None, class);
+
+            let mut declarations = Vec::new();
+            for name in &["get", "set"] {
+                let declaration = add_typed(unit, class, DW_TAG_subprogram, Some(name), int);
+                unit.get_mut(declaration).set(DW_AT_declaration, AttributeValue::Flag(true));
+                let this = add_typed(unit, declaration, DW_TAG_formal_parameter, None, class_ptr);
+                unit.get_mut(this).set(DW_AT_artificial, AttributeValue::Flag(true));
+                add_typed(unit, declaration, DW_TAG_formal_parameter, None, int);
+                declarations.push(declaration);
+            }
+
+            // Definition without parameters of its own...
+            let get = add_subprogram(unit, None, 0x10);
+            unit.get_mut(get).set(DW_AT_specification, AttributeValue::UnitRef(declarations[0]));
+            // ...and with (named) parameters.
+            let set = add_subprogram(unit, None, 0x20);
+            unit.get_mut(set).set(DW_AT_specification, AttributeValue::UnitRef(declarations[1]));
+            add_typed(unit, set, DW_TAG_formal_parameter, Some("this"), class_ptr);
+            add_typed(unit, set, DW_TAG_formal_parameter, Some("x"), int);
+        });
+
+        let dwarf = DwarfBinary::parse(&sections, None, None, false).unwrap();
+        let options = ParseOptions::default();
+        let param_types = |function: &DwarfFunction<_>| function.params.iter()
+            .map(|param| Type::parse_param(param, &options).unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        let get = &dwarf.relative_offsets_to_function_entries[&0x10];
+        assert_eq!(get.name.as_deref(), Some("get"));
+        assert_eq!(param_types(get), [r#"pointer name "C" class"#, "primitive int32_t"]);
+        assert_eq!(Type::parse_type(get.return_type.as_ref().unwrap(), &options).unwrap().to_string(), "primitive int32_t");
+
+        let set = &dwarf.relative_offsets_to_function_entries[&0x20];
+        assert_eq!(set.name.as_deref(), Some("set"));
+        assert_eq!(param_types(set), [r#"pointer name "C" class"#, "primitive int32_t"]);
+        assert_eq!(set.params[1].attr_str(DW_AT_name).unwrap().as_deref(), Some("x"));
+        assert!(set.return_type.is_some());
+    }
+
+    #[test]
+    fn test_specification_in_other_unit() {
+        // The declaration `int get(int)` is in the first unit, the definition in the second one
+        // refers to it via DW_FORM_ref_addr, as in the output of LTO.
+        let sections = dwarf_sections_with_unit_table(2, |units, ids| {
+            let unit = units.get_mut(ids[0]);
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let root = unit.root();
+            let declaration = add_typed(unit, root, DW_TAG_subprogram, Some("get"), int);
+            unit.get_mut(declaration).set(DW_AT_declaration, AttributeValue::Flag(true));
+            add_typed(unit, declaration, DW_TAG_formal_parameter, None, int);
+
+            let unit = units.get_mut(ids[1]);
+            let get = add_subprogram(unit, None, 0x10);
+            unit.get_mut(get).set(DW_AT_specification, AttributeValue::DebugInfoRef(Reference::Entry(ids[0], declaration)));
+        });
+
+        let dwarf = DwarfBinary::parse(&sections, None, None, false).unwrap();
+        let get = &dwarf.relative_offsets_to_function_entries[&0x10];
+        assert_eq!(get.name.as_deref(), Some("get"));
+        assert_eq!(get.params.len(), 1);
+        assert_eq!(Type::parse_param(&get.params[0], &ParseOptions::default()).unwrap().to_string(), "primitive int32_t");
+
+        // Unsupported forms of references are an error (instead of a panic).
+        let sections = dwarf_sections(|unit| {
+            let get = add_subprogram(unit, None, 0x10);
+            unit.get_mut(get).set(DW_AT_specification, AttributeValue::Udata(0x1234));
+        });
+        assert!(DwarfBinary::parse(&sections, None, None, false).is_err());
+    }
+
+    #[test]
+    fn test_linkage_name() {
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            // Concrete instance -> abstract origin -> declaration with the linkage name.
+            let declaration = add(unit, root, DW_TAG_subprogram, Some("declaration"));
+            unit.get_mut(declaration).set(DW_AT_linkage_name, AttributeValue::String(b"_Z1fv".to_vec()));
+            let origin = add(unit, root, DW_TAG_subprogram, Some("origin"));
+            unit.get_mut(origin).set(DW_AT_specification, AttributeValue::UnitRef(declaration));
+            let instance = add(unit, root, DW_TAG_subprogram, Some("instance"));
+            unit.get_mut(instance).set(DW_AT_abstract_origin, AttributeValue::UnitRef(origin));
+
+            // Malformed: the two entries refer to each other.
+            let cycle_a = add(unit, root, DW_TAG_subprogram, Some("cycle_a"));
+            let cycle_b = add(unit, root, DW_TAG_subprogram, Some("cycle_b"));
+            unit.get_mut(cycle_a).set(DW_AT_specification, AttributeValue::UnitRef(cycle_b));
+            unit.get_mut(cycle_b).set(DW_AT_abstract_origin, AttributeValue::UnitRef(cycle_a));
+        });
+
+        assert_eq!(linkage_name(&entry_by_name(&sections, "instance")).unwrap().as_deref(), Some("_Z1fv"));
+        assert_eq!(linkage_name(&entry_by_name(&sections, "cycle_a")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_location_forms() {
+        let sections = dwarf_sections(|unit| {
+            add_subprogram(unit, Some("address"), 0x10);
+
+            // Start as a constant (offset) instead of an address, and end as a length.
+            let root = unit.root();
+            let constant = add(unit, root, DW_TAG_subprogram, Some("constant"));
+            unit.get_mut(constant).set(DW_AT_low_pc, AttributeValue::Udata(0x20));
+            unit.get_mut(constant).set(DW_AT_high_pc, AttributeValue::Udata(0x8));
+
+            // Non-contiguous code, with the lowest range not listed first.
+            let ranges = unit.ranges.add(RangeList(vec![
+                Range::StartLength { begin: Address::Constant(0x40), length: 0x8 },
+                Range::StartLength { begin: Address::Constant(0x30), length: 0x8 },
+            ]));
+            let split = add(unit, root, DW_TAG_subprogram, Some("split"));
+            unit.get_mut(split).set(DW_AT_ranges, AttributeValue::RangeListRef(ranges));
+        });
+
+        let stats = DwarfStats::default();
+        let dwarf = DwarfBinary::parse(&sections, None, Some(&stats), false).unwrap();
+        let functions: BTreeSet<_> = dwarf.relative_offsets_to_function_entries.iter()
+            .map(|(offset, function)| (*offset, function.name.as_deref().unwrap().to_string()))
+            .collect();
+        assert_eq!(functions, vec![(0x10, "address".to_string()), (0x20, "constant".to_string()), (0x30, "split".to_string())].into_iter().collect());
+        assert_eq!(stats.subprograms_with_location.into_inner(), 3);
     }
 }
@@ -0,0 +1,126 @@
+//! Helpers for building small, synthetic DWARF debug information in unit tests, since we cannot
+//! easily compile C/C++ test programs to WebAssembly as part of the tests.
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use gimli::constants::*;
+use gimli::write::{self, Address, AttributeValue, DwarfUnit, EndianVec, Sections, UnitEntryId};
+use gimli::{DwAte, DwTag, EndianRcSlice, Encoding, Format, LittleEndian};
+
+use crate::dwarf::parse::load_dwarf;
+use crate::dwarf::util::DwarfEntry;
+use crate::wasm::test_util::ModuleBuilder;
+
+/// Build the DWARF sections (keyed by custom section name, as they would appear in a Wasm binary)
+/// of a single compilation unit, whose entries are added by `build` to the given unit.
+pub fn dwarf_sections(build: impl FnOnce(&mut write::Unit)) -> HashMap<&'static str, Rc<[u8]>> {
+    let mut dwarf = DwarfUnit::new(ENCODING);
+    build(&mut dwarf.unit);
+
+    let mut sections = Sections::new(EndianVec::new(LittleEndian));
+    dwarf.write(&mut sections).unwrap();
+    sections_by_name(sections)
+}
+
+/// Like `dwarf_sections()`, but with `unit_count` compilation units, each built by `build` (which
+/// also gets the index of the unit).
+pub fn dwarf_sections_with_units(unit_count: usize, mut build: impl FnMut(usize, &mut write::Unit)) -> HashMap<&'static str, Rc<[u8]>> {
+    dwarf_sections_with_unit_table(unit_count, |units, ids| {
+        for (i, &id) in ids.iter().enumerate() {
+            build(i, units.get_mut(id));
+        }
+    })
+}
+
+/// Like `dwarf_sections_with_units()`, but `build` gets all units at once (and their ids), e.g.,
+/// for references from one unit into another (`AttributeValue::DebugInfoRef`, i.e., DW_FORM_ref_addr).
+pub fn dwarf_sections_with_unit_table(unit_count: usize, build: impl FnOnce(&mut write::UnitTable, &[write::UnitId])) -> HashMap<&'static str, Rc<[u8]>> {
+    let mut dwarf = write::Dwarf::new();
+    let ids: Vec<_> = (0..unit_count)
+        .map(|_| dwarf.units.add(write::Unit::new(ENCODING, write::LineProgram::none())))
+        .collect();
+    build(&mut dwarf.units, &ids);
+
+    let mut sections = Sections::new(EndianVec::new(LittleEndian));
+    dwarf.write(&mut sections).unwrap();
+    sections_by_name(sections)
+}
+
+const ENCODING: Encoding = Encoding { format: Format::Dwarf32, version: 4, address_size: 4 };
+
+fn sections_by_name(sections: Sections<EndianVec<LittleEndian>>) -> HashMap<&'static str, Rc<[u8]>> {
+    let mut result = HashMap::new();
+    sections
+        .for_each(|id, data| -> Result<(), ()> {
+            result.insert(id.name(), Rc::from(data.slice()));
+            Ok(())
+        })
+        .unwrap();
+    result
+}
+
+/// Find the first entry (in depth-first order) with the given `DW_AT_name`.
+pub fn entry_by_name(sections: &HashMap<&str, Rc<[u8]>>, name: &str) -> DwarfEntry<EndianRcSlice<LittleEndian>> {
+    let dwarf = Rc::new(load_dwarf(sections).unwrap());
+    let mut units = dwarf.units();
+    while let Some(unit_header) = units.next().unwrap() {
+        let unit = Rc::new(dwarf.unit(unit_header).unwrap());
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs().unwrap() {
+            let entry = DwarfEntry::from(&dwarf, &unit, entry);
+            if entry.attr_str(DW_AT_name).unwrap().as_deref() == Some(name) {
+                return entry;
+            }
+        }
+    }
+    panic!("no DWARF entry with name '{}'", name)
+}
+
+/// Add a child entry with `tag` (and optionally a name) to `parent`.
+pub fn add(unit: &mut write::Unit, parent: UnitEntryId, tag: DwTag, name: Option<&str>) -> UnitEntryId {
+    let id = unit.add(parent, tag);
+    if let Some(name) = name {
+        unit.get_mut(id).set(DW_AT_name, AttributeValue::String(name.as_bytes().to_vec()));
+    }
+    id
+}
+
+/// Add a child entry with `tag` (and optionally a name) to `parent`, whose `DW_AT_type` is `type_`.
+pub fn add_typed(
+    unit: &mut write::Unit,
+    parent: UnitEntryId,
+    tag: DwTag,
+    name: Option<&str>,
+    type_: UnitEntryId,
+) -> UnitEntryId {
+    let id = add(unit, parent, tag, name);
+    unit.get_mut(id).set(DW_AT_type, AttributeValue::UnitRef(type_));
+    id
+}
+
+/// Add a base (=primitive) type entry to the root of the unit.
+pub fn add_base_type(unit: &mut write::Unit, name: &str, encoding: DwAte, byte_size: u64) -> UnitEntryId {
+    let root = unit.root();
+    let id = add(unit, root, DW_TAG_base_type, Some(name));
+    unit.get_mut(id).set(DW_AT_encoding, AttributeValue::Encoding(encoding));
+    unit.get_mut(id).set(DW_AT_byte_size, AttributeValue::Udata(byte_size));
+    id
+}
+
+/// Add a function entry at the given offset in the code section (see `ModuleBuilder::code_offsets()`)
+/// to the root of the unit.
+pub fn add_subprogram(unit: &mut write::Unit, name: Option<&str>, low_pc: u64) -> UnitEntryId {
+    let root = unit.root();
+    let id = add(unit, root, DW_TAG_subprogram, name);
+    unit.get_mut(id).set(DW_AT_low_pc, AttributeValue::Address(Address::Constant(low_pc)));
+    id
+}
+
+/// Add all (non-empty) DWARF sections as custom sections to the Wasm module.
+pub fn add_dwarf_sections(module: &mut ModuleBuilder, sections: &HashMap<&str, Rc<[u8]>>) {
+    let mut sections: Vec<_> = sections.iter().filter(|(_, data)| !data.is_empty()).collect();
+    sections.sort();
+    for (name, data) in sections {
+        module.custom_section(name, data);
+    }
+}
@@ -1,7 +1,11 @@
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
 use std::rc::Rc;
 
-use gimli::{AttributeValue, DebuggingInformationEntry, DwAt, DwTag, Dwarf, Reader, ReaderOffset, Unit, UnitOffset};
+use gimli::{AttributeValue, DebuggingInformationEntry, DwAt, DwTag, Dwarf, Expression, Reader, ReaderOffset, Unit, UnitOffset, UnitSectionOffset};
+use gimli::constants::{DW_AT_decl_file, DW_AT_decl_line, DW_AT_location};
 
 // My own convenience wrapper around gimli::DebuggingInformationEntry, which has two problems: 
 // 1. It has complicated lifetimes because it borrows from Unit and Abbreviations.
@@ -22,8 +26,15 @@ pub struct DwarfEntry<R: Reader> {
 
     // Store the tag in here, so that inspecting it doesn't cause a re-parse of the DIE.
     pub tag: DwTag,
+
+    // Parent of each entry in the unit, computed on the first call to ancestors(), since gimli
+    // entries have no link to their parent. Shared by all entries of the unit that are derived from
+    // each other (see same_unit_entry()), such that the unit is traversed only once.
+    parents: Rc<OnceCell<ParentOffsets<R::Offset>>>,
 }
 
+type ParentOffsets<O> = HashMap<UnitOffset<O>, UnitOffset<O>>;
+
 // Do not write dwarf and unit fields with all their (gimli-specific) data to debug output, since
 // that easily overwhelms the console (and is also quite useless for my debugging).
 // One easy way to look at the original DWARF entry given this debug output is:
@@ -38,6 +49,37 @@ impl<R: Reader> fmt::Debug for DwarfEntry<R> {
 }
 
 impl<R: Reader> DwarfEntry<R> {
+    /// Wrap a gimli entry together with its unit and the DWARF sections it comes from, e.g., to
+    /// convert the type of a parameter entry to our type language:
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use std::rc::Rc;
+    ///
+    /// use extraction::{DwarfEntry, Type};
+    /// use extraction::dwarf::parse::load_dwarf;
+    /// use extraction::samples::types::parse::ParseOptions;
+    ///
+    /// /// Types of all parameters in the given DWARF sections (keyed by section name, e.g., ".debug_info").
+    /// fn param_types(sections: &HashMap<&str, Rc<[u8]>>) -> anyhow::Result<Vec<Type>> {
+    ///     let dwarf = Rc::new(load_dwarf(sections)?);
+    ///     let mut types = Vec::new();
+    ///     let mut units = dwarf.units();
+    ///     while let Some(unit_header) = units.next()? {
+    ///         let unit = Rc::new(dwarf.unit(unit_header)?);
+    ///         let mut entries = unit.entries();
+    ///         while let Some((_, entry)) = entries.next_dfs()? {
+    ///             if entry.tag() == gimli::DW_TAG_formal_parameter {
+    ///                 let entry = DwarfEntry::from(&dwarf, &unit, entry);
+    ///                 types.push(Type::parse_param(&entry, &ParseOptions::default())?);
+    ///             }
+    ///         }
+    ///     }
+    ///     Ok(types)
+    /// }
+    ///
+    /// assert!(param_types(&HashMap::new()).unwrap().is_empty());
+    /// ```
     pub fn from(
         dwarf: &Rc<Dwarf<R>>,
         unit: &Rc<Unit<R>>,
@@ -48,6 +90,19 @@ impl<R: Reader> DwarfEntry<R> {
             unit: Rc::clone(unit),
             entry_offset: entry.offset(),
             tag: entry.tag(),
+            parents: Rc::new(OnceCell::new()),
+        }
+    }
+
+    /// Another entry of the same unit as this one. Unlike `from()`, this shares the parents of
+    /// the unit, so prefer it when creating many entries of a unit, see `ancestors()`.
+    pub fn same_unit_entry(&self, entry: &DebuggingInformationEntry<R>) -> Self {
+        Self {
+            dwarf: Rc::clone(&self.dwarf),
+            unit: Rc::clone(&self.unit),
+            entry_offset: entry.offset(),
+            tag: entry.tag(),
+            parents: Rc::clone(&self.parents),
         }
     }
 
@@ -56,30 +111,92 @@ impl<R: Reader> DwarfEntry<R> {
             .expect("since we constructed it from a valid entry, the unit should contain an entry at the offset")
     }
 
+    /// Offset of this entry inside its compilation unit, e.g., to identify already visited entries.
+    pub fn offset(&self) -> UnitOffset<R::Offset> {
+        self.entry_offset
+    }
+
     /// Read a generic attribute with `name`.
     pub fn attr(&self, name: DwAt) -> gimli::Result<Option<AttributeValue<R>>> {
         self.entry().attr_value(name)
     }
 
     /// Read an attribute with `name` that resolves to another debugging information entry.
+    /// Besides references inside the unit, this also follows references into other units of
+    /// .debug_info (DW_FORM_ref_addr, e.g., in the output of LTO or linked C++ programs). Other
+    /// forms (e.g., into .debug_types or supplementary object files) are an error.
     pub fn attr_entry(&self, name: DwAt) -> gimli::Result<Option<Self>> {
         match self.attr(name)? {
             Some(AttributeValue::UnitRef(offset)) => {
                 let entry = self.unit.entry(offset)?;
-                let entry = Self::from(&self.dwarf, &self.unit, &entry);
-                Ok(Some(entry))
+                Ok(Some(self.same_unit_entry(&entry)))
+            }
+            Some(AttributeValue::DebugInfoRef(offset)) => {
+                if let Some(offset) = UnitSectionOffset::DebugInfoOffset(offset).to_unit_offset(&self.unit) {
+                    let entry = self.unit.entry(offset)?;
+                    return Ok(Some(self.same_unit_entry(&entry)));
+                }
+                // Find the unit that contains the offset, which needs to be parsed (again).
+                let mut units = self.dwarf.units();
+                while let Some(header) = units.next()? {
+                    if let Some(offset) = offset.to_unit_offset(&header) {
+                        let unit = Rc::new(self.dwarf.unit(header)?);
+                        let entry = unit.entry(offset)?;
+                        return Ok(Some(Self::from(&self.dwarf, &unit, &entry)));
+                    }
+                }
+                Err(gimli::Error::NoEntryAtGivenOffset)
             }
-            Some(attr_value) => unimplemented!("unknown DWARF attribute value: {:?}", attr_value),
+            Some(_) => Err(gimli::Error::UnsupportedAttributeForm),
             None => Ok(None)
         }
     }
 
+    /// Source file where this entry is declared (DW_AT_decl_file), resolved against the file table
+    /// of the line program of its unit, i.e., including the directory of the file.
+    pub fn decl_file(&self) -> gimli::Result<Option<Box<str>>> {
+        let file_idx = match self.attr(DW_AT_decl_file)? {
+            Some(AttributeValue::FileIndex(file_idx)) => file_idx,
+            _ => return Ok(None),
+        };
+        let header = match &self.unit.line_program {
+            Some(line_program) => line_program.header(),
+            None => return Ok(None),
+        };
+        let file = match header.file(file_idx) {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        // See `attr_str()` for why this is lossy.
+        let name = self.dwarf.attr_string(&self.unit, file.path_name())?.to_string_lossy()?.into_owned();
+        let path = match file.directory(header) {
+            Some(directory) if !name.starts_with('/') => {
+                let directory = self.dwarf.attr_string(&self.unit, directory)?.to_string_lossy()?.into_owned();
+                if directory.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", directory.trim_end_matches('/'), name)
+                }
+            }
+            _ => name,
+        };
+        Ok(Some(path.into()))
+    }
+
+    /// Source line where this entry is declared (DW_AT_decl_line).
+    pub fn decl_line(&self) -> gimli::Result<Option<u64>> {
+        Ok(self.attr(DW_AT_decl_line)?.and_then(|line| line.udata_value()))
+    }
+
     /// Read a string attribute with `name`.
     pub fn attr_str(&self, name: DwAt) -> gimli::Result<Option<Box<str>>> {
         match self.attr(name)? {
             Some(attr_value) => {
                 let data = self.dwarf.attr_string(&self.unit, attr_value)?;
-                let str = data.to_string()?;
+                // Not all strings in DWARF are valid UTF-8 (e.g., names from Latin-1 encoded source
+                // files), but since we only output them, replace invalid parts instead of failing.
+                let str = data.to_string_lossy()?;
                 // TODO I really would have like to avoid the allocation (Box<str>), but gimli's API
                 // is really cumbersome for two reasons:
                 // 1. R::to_string() gives you a Cow<str> instead of a plain &str, even though all
@@ -114,39 +231,202 @@ impl<R: Reader> DwarfEntry<R> {
         }))
     }
     
+    /// Index of the WebAssembly local that holds this entry (e.g., a formal parameter), if its
+    /// DW_AT_location is a DW_OP_WASM_location of a local. For location lists, all entries must
+    /// agree on the same local. Other locations (e.g., in the linear memory stack frame with
+    /// DW_OP_fbreg, as is common for unoptimized code) give None.
+    pub fn wasm_local_idx(&self) -> gimli::Result<Option<u32>> {
+        let attr_value = match self.attr(DW_AT_location)? {
+            Some(attr_value) => attr_value,
+            None => return Ok(None)
+        };
+
+        if let AttributeValue::Exprloc(expr) = attr_value {
+            return wasm_local_idx(expr);
+        }
+
+        let mut local_idx = None;
+        if let Some(mut locations) = self.dwarf.attr_locations(&self.unit, attr_value)? {
+            while let Some(location) = locations.next()? {
+                match (local_idx, wasm_local_idx(location.data)?) {
+                    (_, None) => return Ok(None),
+                    (Some(previous), Some(current)) if previous != current => return Ok(None),
+                    (_, current) => local_idx = current,
+                }
+            }
+        }
+        Ok(local_idx)
+    }
+
+    /// All entries enclosing this one, from the root (the compilation unit) down to the direct
+    /// parent, e.g., the namespaces of a class.
+    /// Since gimli entries have no link to their parent, the first call walks the whole unit to
+    /// find the parents of all its entries, which later calls (for entries of the same unit, see
+    /// `same_unit_entry()`) reuse.
+    pub fn ancestors(&self) -> gimli::Result<Vec<Self>> {
+        let parents = match self.parents.get() {
+            Some(parents) => parents,
+            None => {
+                let parents = self.parent_offsets()?;
+                self.parents.get_or_init(|| parents)
+            }
+        };
+
+        let mut ancestors = Vec::new();
+        let mut offset = self.entry_offset;
+        while let Some(&parent) = parents.get(&offset) {
+            ancestors.push(self.same_unit_entry(&self.unit.entry(parent)?));
+            offset = parent;
+        }
+        ancestors.reverse();
+        Ok(ancestors)
+    }
+
+    fn parent_offsets(&self) -> gimli::Result<ParentOffsets<R::Offset>> {
+        let mut parents = HashMap::new();
+        // Path from the root to the current entry of the depth-first traversal.
+        let mut path = Vec::new();
+        let mut depth = 0;
+        let mut cursor = self.unit.entries();
+        while let Some((delta_depth, entry)) = cursor.next_dfs()? {
+            depth += delta_depth;
+            path.truncate(depth as usize);
+            if let Some(&parent) = path.last() {
+                parents.insert(entry.offset(), parent);
+            }
+            path.push(entry.offset());
+        }
+        Ok(parents)
+    }
+
     /// Iterator over the direct children of this entry.
     pub fn children(&self) -> gimli::Result<ChildIter<R>> {
         let mut cursor = self.unit.entries_at_offset(self.entry_offset)?;
         // We need one invocation of next_dfs() to actually move the cursor to the given offset.
         cursor.next_dfs()?;
+        // Without children, one further DFS step would move to the next sibling instead.
+        let has_children = match cursor.current() {
+            Some(entry) => entry.has_children(),
+            None => false,
+        };
         // Do one further step DFS to go to the first child.
         cursor.next_dfs()?;
         Ok(ChildIter { 
-            dwarf: Rc::clone(&self.dwarf),
-            unit: Rc::clone(&self.unit), 
-            cursor
+            parent: self.clone(),
+            cursor,
+            has_children,
         })
     }
     
 }
 
+/// Operation DW_OP_WASM_location (LLVM extension, not yet in gimli), followed by the kind of location
+/// and its index. Kind 0 is a local, the others are globals and the operand stack.
+const DW_OP_WASM_LOCATION: u8 = 0xed;
+const WASM_LOCATION_LOCAL: u64 = 0;
+
+fn wasm_local_idx<R: Reader>(expr: Expression<R>) -> gimli::Result<Option<u32>> {
+    let mut bytes = expr.0;
+    if bytes.is_empty() || bytes.read_u8()? != DW_OP_WASM_LOCATION {
+        return Ok(None);
+    }
+    let kind = bytes.read_uleb128()?;
+    let idx = bytes.read_uleb128()?;
+    // The location must be the local itself, not, e.g., a pointer in it (followed by DW_OP_deref).
+    if kind != WASM_LOCATION_LOCAL || !bytes.is_empty() {
+        return Ok(None);
+    }
+    Ok(u32::try_from(idx).ok())
+}
+
 pub struct ChildIter<'abbrev, 'unit, R: Reader> {
-    dwarf: Rc<Dwarf<R>>,
-    unit: Rc<Unit<R>>,
-    cursor: gimli::read::EntriesCursor<'abbrev, 'unit, R>
+    parent: DwarfEntry<R>,
+    cursor: gimli::read::EntriesCursor<'abbrev, 'unit, R>,
+    has_children: bool,
 }
 
 impl<'abbrev, 'unit, R: Reader> Iterator for ChildIter<'abbrev, 'unit, R> {
     type Item = gimli::Result<DwarfEntry<R>>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if !self.has_children {
+            return None;
+        }
+
         // This is a bit weird: Since the cursor from gimli caches the "current" (really the last)
         // parsed entry, we already have it available in the beginning, and THEN try to advance the
         // iterator with cursor.next_sibling(). If the next_sibling call fails, give back the error,
         // if it could advance, give back the current (converted) entry.
         let entry = self.cursor.current()
-            .map(|entry| 
-                DwarfEntry::from(&self.dwarf, &self.unit, entry));
+            .map(|entry| self.parent.same_unit_entry(entry));
         self.cursor.next_sibling().map(|_next| entry).transpose()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use gimli::constants::*;
+
+    use super::*;
+    use crate::dwarf::parse::load_dwarf;
+    use crate::dwarf::test_util::*;
+    use crate::samples::types::parse::ParseOptions;
+    use crate::samples::types::Type;
+
+    #[test]
+    fn test_param_type_from_known_debug_info() {
+        // Single compilation unit with the entries `int` (base type) and `x` (parameter of type int),
+        // as written by gimli::write.
+        let mut sections: HashMap<&str, Rc<[u8]>> = HashMap::new();
+        sections.insert(".debug_abbrev", Rc::from(&[
+            0x01, 0x11, 0x01, 0x00, 0x00, 0x02, 0x24, 0x00, 0x03, 0x08, 0x3e, 0x0f, 0x0b, 0x0f, 0x00, 0x00,
+            0x03, 0x05, 0x00, 0x03, 0x08, 0x49, 0x13, 0x00, 0x00, 0x00,
+        ][..]));
+        sections.insert(".debug_info", Rc::from(&[
+            0x17, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x01, 0x02, 0x69, 0x6e, 0x74,
+            0x00, 0x05, 0x04, 0x03, 0x78, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x00,
+        ][..]));
+
+        let dwarf = Rc::new(load_dwarf(&sections).unwrap());
+        let unit_header = dwarf.units().next().unwrap().unwrap();
+        let unit = Rc::new(dwarf.unit(unit_header).unwrap());
+        let mut entries = unit.entries();
+        let mut types = Vec::new();
+        while let Some((_, entry)) = entries.next_dfs().unwrap() {
+            if entry.tag() == DW_TAG_formal_parameter {
+                let entry = DwarfEntry::from(&dwarf, &unit, entry);
+                assert_eq!(entry.attr_str(DW_AT_name).unwrap().as_deref(), Some("x"));
+                types.push(Type::parse_param(&entry, &ParseOptions::default()).unwrap().to_string());
+            }
+        }
+        assert_eq!(types, vec!["primitive int32_t"]);
+    }
+
+    #[test]
+    fn test_ancestors_share_parents() {
+        // namespace ns { struct Outer { struct Inner { int i; }; }; }
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let ns = add(unit, root, DW_TAG_namespace, Some("ns"));
+            let outer = add(unit, ns, DW_TAG_structure_type, Some("Outer"));
+            let inner = add(unit, outer, DW_TAG_structure_type, Some("Inner"));
+            add(unit, inner, DW_TAG_member, Some("i"));
+        });
+        let names = |entries: &[DwarfEntry<_>]| entries.iter()
+            .map(|entry| entry.attr_str(DW_AT_name).unwrap())
+            .collect::<Vec<_>>();
+
+        let inner = entry_by_name(&sections, "Inner");
+        let ancestors = inner.ancestors().unwrap();
+        assert_eq!(names(&ancestors), vec![None, Some("ns".into()), Some("Outer".into())]);
+        assert!(inner.parents.get().is_some());
+
+        // Entries reached from this one reuse its parents instead of walking the unit again.
+        let member = inner.children().unwrap().next().unwrap().unwrap();
+        assert!(Rc::ptr_eq(&member.parents, &inner.parents));
+        assert!(Rc::ptr_eq(&ancestors[1].parents, &inner.parents));
+        assert_eq!(names(&member.ancestors().unwrap()), vec![None, Some("ns".into()), Some("Outer".into()), Some("Inner".into())]);
+        assert!(ancestors[0].ancestors().unwrap().is_empty());
+    }
+}
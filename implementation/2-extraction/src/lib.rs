@@ -0,0 +1,33 @@
+//! Extract training data (WebAssembly functions and type information) from WebAssembly binaries.
+//!
+//! The command-line application in `main.rs` is built on top of this library, but the extraction
+//! can also be used directly, e.g., on binaries that are generated in memory (see also
+//! `render_samples_from_bytes()` for samples that went through the whole pipeline of the
+//! application, including the WebAssembly representation and type simplifications):
+//!
+//! ```
+//! use extraction::extract_samples_from_bytes;
+//!
+//! // A module with a single empty function `(func)`, but no DWARF debug info.
+//! let bytes = b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x03\x02\x01\0\x0a\x04\x01\x02\0\x0b";
+//!
+//! // Without debug info, there are no types to extract.
+//! let samples = extract_samples_from_bytes(bytes, false).unwrap();
+//! assert!(samples.is_empty());
+//! ```
+
+#![allow(unused_imports)]
+
+pub mod dwarf;
+pub mod options;
+pub mod samples;
+pub mod util;
+pub mod wasm;
+
+pub use crate::dwarf::parse::DwarfBinary;
+pub use crate::dwarf::util::DwarfEntry;
+pub use crate::samples::extract::{extract_samples, extract_samples_from_bytes};
+pub use crate::samples::pipeline::{render_samples_from_bytes, render_samples_from_dir, PipelineOptions, PipelineStats};
+pub use crate::samples::sample::WasmTypeSample;
+pub use crate::samples::types::{Type, TypeToken};
+pub use crate::samples::wasm_repr::WasmRepr;
@@ -5,6 +5,7 @@ use std::convert::TryInto;
 use std::fmt::Display;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::hash::Hash;
 use std::io::Write;
 
@@ -18,37 +19,44 @@ use rand::prelude::*;
 use rayon::iter::ParallelIterator;
 use rayon::prelude::*;
 use sha2::{Digest, Sha256};
-use simplelog::{Color, CombinedLogger, LevelPadding, TermLogger, TerminalMode, WriteLogger};
+use simplelog::{Color, CombinedLogger, LevelPadding, WriteLogger};
 use util::human_gnu_format;
 use util::into_seq_iter::IntoSeqIter;
+use util::manifest::Manifest;
 
-mod options;
-mod samples;
-mod util;
-mod wasm;
-use wasm::magic_bytes::is_wasm_by_magic_bytes;
-use wasm::binary_stats::WasmBinaryStats;
+use extraction::{dwarf, options, samples, util, wasm};
+use wasm::archive::{is_archive, wasm_members};
+use wasm::magic_bytes::{wasm_file_kind, WasmFileKind};
+use wasm::binary_stats::{dedup_binaries, WasmBinaryStats};
 
-use crate::options::Typedefs;
+use crate::dwarf::parse::DwarfStats;
+use crate::options::{DedupMode, TypeNotation, Typedefs};
 use crate::samples::sample::{WasmTypeSample, ParamOrReturn};
+use crate::samples::balance::MaxPerType;
+use crate::samples::baseline::FrequencyBaseline;
+use crate::samples::complexity::TypeComplexityStats;
+use crate::samples::split::TrainDevTest;
 use util::frequencies::Frequencies;
 use util::handle_errors::HandleErrorsIterExt;
 use wasmparser::Operator;
 use crate::samples::extract::extract_samples;
-use crate::samples::types::{Type, TypeToken};
+use crate::samples::pipeline::{process_binary, BinaryYields, NondeterminismStats, PipelineStats};
+use crate::samples::types::{PrimitiveTypeCounts, Type, TypeToken};
 use crate::samples::wasm_repr::WasmRepr;
 use crate::util::cmultimap::CMultiMap;
+use crate::util::progress_bar_log::ProgressBarLog;
 use crate::util::file_error::ResultWithFile;
 use crate::util::handle_errors::HandleErrorsParIterExt;
+use crate::util::vocab::Vocabulary;
 use crate::wasm::fmt::type_str;
-use crate::wasm::parse::WasmBody;
+use crate::wasm::parse::{uses_local, WasmBody};
 use util::ParallelProgressBar;
 use util::percent::Percent;
-mod dwarf;
 
 fn main() -> anyhow::Result<()> {
     let options: Options = Options::parse();
-    let wasm_repr = options.wasm_repr()?;
+    // Early, such that invalid combinations of options are reported before the first pass.
+    let mut pipeline_options = options.pipeline_options()?;
 
     // Print debug messages if verbose option is given.
     let log_level = if options.verbose { LevelFilter::Debug } else { LevelFilter::Info };
@@ -62,14 +70,14 @@ fn main() -> anyhow::Result<()> {
         .set_level_padding(LevelPadding::Right)
         .build();
     
-    // Log additionally to a file, if that option is given.
+    // Log to the terminal above the progress bars, and additionally to a file, if that option is given.
     if let Some(log_file) = options.create_log_file() {
         CombinedLogger::init(vec![
-            TermLogger::new(log_level, log_config.clone(), TerminalMode::Stdout), 
+            ProgressBarLog::stdout(log_level),
             WriteLogger::new(log_level, log_config, log_file?)
         ])
     } else {
-        TermLogger::init(log_level, log_config, TerminalMode::Stdout)
+        CombinedLogger::init(vec![ProgressBarLog::stdout(log_level)])
     }?;
 
     log::debug!("built on {}", env!("BUILD_TIMESTAMP"));
@@ -78,6 +86,11 @@ fn main() -> anyhow::Result<()> {
 
     log::debug!("{} threads\n", rayon::current_num_threads());
 
+    if let Some(path) = &options.emit_type_grammar {
+        std::fs::write(path, samples::types::grammar::type_grammar_ebnf())?;
+        log::info!("type grammar written to {}", path.display());
+    }
+
     // Collect input files and all recursive files in input directories.
     let mut files: Vec<PathBuf> = options.input_files()
         .handle_errors(log_walkdir_error)
@@ -91,9 +104,9 @@ fn main() -> anyhow::Result<()> {
     // Pass 1, over input files: Statistics for all binaries, compute signatures.
 
     let wasm_binaries_count = AtomicU64::new(0);
+    let wasm_components_count = AtomicU64::new(0);
+    let wasm_archive_members_count = AtomicU64::new(0);
 
-    let mut errors_magic_bytes = Vec::new();
-    let mut errors_stats = Vec::new();
 
     let wasm_binaries_stats: Vec<(PathBuf, WasmBinaryStats)> = files
         .into_par_iter()
@@ -101,35 +114,43 @@ fn main() -> anyhow::Result<()> {
         // Show nice progress bar while reading in all files.
         .progress_bar()
 
-        // Keep only Wasm binaries for further processesing.
-        .filter_map(|file| 
-            match is_wasm_by_magic_bytes(&file) {
-                Ok(false) => None,
-                Ok(true) => Some(Ok(file)),
-                Err(err) => Some(Err(err)),
+        // Keep only Wasm binaries for further processesing, and expand static archives (of
+        // object files) into their members, which are then processed like regular binaries.
+        .flat_map_iter(|file| 
+            match wasm_file_kind(&file, options.accept_wat) {
+                Ok(WasmFileKind::Other) => {
+                    match is_archive(&file).and_then(|is_archive| if is_archive { wasm_members(&file) } else { Ok(Vec::new()) }) {
+                        Ok(members) => {
+                            wasm_archive_members_count.fetch_add(members.len() as u64, Ordering::SeqCst);
+                            members.into_iter().map(Ok).collect()
+                        }
+                        Err(err) => vec![Err(err).with_file(&file)],
+                    }
+                }
+                Ok(WasmFileKind::Component) => {
+                    log::warn!("{}: skipping WebAssembly component, component model not supported", file.display());
+                    wasm_components_count.fetch_add(1, Ordering::SeqCst);
+                    Vec::new()
+                }
+                Ok(WasmFileKind::Module) => vec![Ok(file)],
+                Err(err) => vec![Err(err)],
             }
         )
 
-        // Collect errors into temporary vec and print after progress bar has finished, 
-        // otherwise the console is cluttered with partial progress bar output.
-        .collect_errors(&mut errors_magic_bytes)
+        // Log errors right away, the logger prints them above the progress bar.
+        .handle_errors(|err| log::error!("{}: could not check for Wasm magic bytes, {}", err.file.display(), err.error))
 
         .inspect(|_| { wasm_binaries_count.fetch_add(1, Ordering::SeqCst); })
 
-        .map(|file| WasmBinaryStats::from_file(&file).map(|stats| (file, stats)))
+        .map(|file| WasmBinaryStats::from_file(&file, options.mmap_input, options.signature_mode).map(|stats| (file, stats)))
 
-        .collect_errors(&mut errors_stats)
+        .handle_errors(|err| log::error!("{}: could not parse Wasm binary, {}", err.file.display(), err.error))
 
         .collect();
 
-    for err in errors_magic_bytes {
-        log::error!("{}: could not check for Wasm magic bytes, {}", err.file.display(), err.error);
-    }
     log_number_human_aligned(wasm_binaries_count.into_inner(), "total Wasm binaries (by magic bytes)");
-
-    for err in errors_stats {
-        log::error!("{}: could not parse Wasm binary, {}", err.file.display(), err.error);
-    }
+    log_number_human_aligned(wasm_archive_members_count.into_inner(), "of which are members of static archives (.a)");
+    log_number_human_aligned(wasm_components_count.into_inner(), "Wasm components skipped (component model not supported)");
     log_number_human_aligned(wasm_binaries_stats.len(), "total Wasm binaries (successfully parsed)\n");
 
     log::info!("stats on all (non-unique) parsed Wasm binaries:");
@@ -158,34 +179,31 @@ fn main() -> anyhow::Result<()> {
     // Pass 2, over statistics of successfully parsed Wasm binaries: 
     // Remove duplicate binaries and report stats on the removed ones.
     
-    // First sort (in parallel) including by filename to make the file order deterministic, 
-    // and then remove consecutive duplicates.
+    let dedup_desc = match options.dedup_mode {
+        DedupMode::Signature => format!("{} {}", options.dedup_mode.description(), options.signature_mode.description()),
+        _ => options.dedup_mode.description().to_string(),
+    };
+    let wasm_binaries_count = wasm_binaries_stats.len();
     let mut wasm_binaries_stats = wasm_binaries_stats;
-    wasm_binaries_stats.par_sort_unstable_by_key(|(path, stats)| (stats.binary_signature.clone(), path.clone()));
-    
-    // TODO deduplicate only when option is set, otherwise take files by SHA256.
-    let mut wasm_binaries_unique_signature = 
-        wasm_binaries_stats.iter()
-        .dedup_by_with_count(|a, b| a.1.binary_signature == b.1.binary_signature)
-        .collect_vec();
-    log_number_human_aligned(wasm_binaries_unique_signature.len(), "unique Wasm binaries (by function signatures)\n");
+    let mut wasm_binaries_unique = dedup_binaries(&mut wasm_binaries_stats, options.dedup_mode);
+    log_number_human_aligned(wasm_binaries_unique.len(), &format!("unique Wasm binaries ({})\n", dedup_desc));
 
-    log::info!("most duplicated Wasm binaries (by function signatures):");
+    log::info!("most duplicated Wasm binaries ({}):", dedup_desc);
 
-    wasm_binaries_unique_signature.par_sort_unstable_by_key(|(dup_count, _)| Reverse(*dup_count));
-    for (dup_count, (file, stats)) in wasm_binaries_unique_signature.iter().take(options.stats_max) {
+    wasm_binaries_unique.par_sort_unstable_by_key(|(dup_count, _)| Reverse(*dup_count));
+    for (dup_count, (file, stats)) in wasm_binaries_unique.iter().take(options.stats_max) {
         if *dup_count > 1 {
             log::info!("{:6} x [example] {}", dup_count, file.display());
             log_number_human_aligned(stats.instruction_count, "total instructions");
             log_number_human_aligned(stats.function_bodies_count, "total function bodies");
         }
     }
-    let duplication_factor = Percent::from_counts(wasm_binaries_stats.len() - wasm_binaries_unique_signature.len(), wasm_binaries_stats.len());
-    log::info!("duplication factor: {}\n", duplication_factor);
+    let duplication_factor = Percent::from_counts(wasm_binaries_count - wasm_binaries_unique.len(), wasm_binaries_count);
+    log::info!("duplication factor ({}): {}\n", dedup_desc, duplication_factor);
 
-    log::info!("stats on unique Wasm binaries (by function signatures):");
+    log::info!("stats on unique Wasm binaries ({}):", dedup_desc);
 
-    let stats_unique = wasm_binaries_unique_signature
+    let stats_unique = wasm_binaries_unique
         .par_iter()
         .map(|(_, (_, stats))| 
             (stats.file_size, stats.instruction_count, stats.function_bodies_count, stats.function_bodies_bytes)
@@ -200,239 +218,129 @@ fn main() -> anyhow::Result<()> {
     log_filesize_human_aligned(stats_unique.3, "total function bodies\n");
     
 
-    // Pass 3, over unique Wasm binaries: extract samples.
+    // Skip binaries from previous runs, if a manifest of them is given.
+    let mut manifest = options.skip_processed.as_ref().map(Manifest::load).transpose()?;
+    let append_samples = matches!(&manifest, Some(manifest) if manifest.existed());
+    if let Some(manifest) = &manifest {
+        let count_before = wasm_binaries_unique.len();
+        wasm_binaries_unique.retain(|(_, (_, stats))| !manifest.contains(&stats.file_sha256));
+        log_number_human_aligned(count_before - wasm_binaries_unique.len(), "Wasm binaries skipped because they were already processed\n");
+    }
+    let processed_sha256s = wasm_binaries_unique.iter()
+        .map(|(_, (_, stats))| &stats.file_sha256[..])
+        .collect_vec();
 
-    let rng = options.rng_with_seed();
-    let wasm_add_raw_type = options.wasm_add_raw_type;   
-    let (repr_desc, repr_fn): (String, Box<dyn Fn(&WasmTypeSample<WasmBody, Type>) -> anyhow::Result<WasmRepr> + Sync>) = match wasm_repr {
-        options::WasmRepr::Hash => (
-            "hash of full body bytes".to_string(),
-            Box::new(|sample| Ok(WasmRepr::new_hash(sample, wasm_add_raw_type)))
-        ),
-        options::WasmRepr::Full => (
-            "full body (but abstracted <param>)".to_string(),
-            Box::new(|sample| WasmRepr::new_full(sample, wasm_add_raw_type))
-        ),
-        options::WasmRepr::Subrange(size) => (
-            format!("(single) subrange with size {}", size),
-            Box::new(move |sample| WasmRepr::new_subrange(sample, size, wasm_add_raw_type))
-        ),
-        options::WasmRepr::Windows(size) => (
-            format!("(multiple) windows with size {}", size),
-            Box::new(move |sample| WasmRepr::new_windows(sample, size, wasm_add_raw_type, &mut rng.clone()))
-        ),
-    };
-    log::info!("input Wasm representation: {}\n", repr_desc);
 
-    log::info!("extracting samples from binaries...");
+    // Pass 3, over unique Wasm binaries: extract samples.
 
-    let mut errors_extraction_files = Vec::new();
-    let mut errors_extraction_samples = Vec::new();
+    log::info!("input Wasm representation: {}\n", pipeline_options.wasm_repr_description());
 
-    let samples_removed_unused_param = AtomicU64::new(0);
-    let samples_removed_unknown_type = AtomicU64::new(0);
+    log::info!("extracting samples from binaries...");
 
-    let name_stats = CMultiMap::new();
     let name_stats_file = options.name_stats_file().transpose()?;
-    let keep_name_list = options.keep_name_list().transpose()?;
+    let binary_yields_file = options.binary_yields_file().transpose()?;
+    let pipeline_stats = PipelineStats {
+        primitive_counts: options.stats_primitives.then(PrimitiveTypeCounts::default),
+        name_stats: name_stats_file.is_some().then(CMultiMap::new),
+        nondeterminism: options.stats_nondeterminism.then(NondeterminismStats::default),
+        binary_yields: binary_yields_file.is_some().then(BinaryYields::default),
+        ..PipelineStats::default()
+    };
+    let mut keep_name_list = options.keep_name_list().transpose()?;
+
+    // Optional additional pass over all binaries, to determine which names are common enough to
+    // be kept. Runs the same pipeline as the extraction below (with all its filters), such that
+    // names are counted over the same samples, but with separate statistics.
+    if let (None, Some(min_binaries)) = (&keep_name_list, options.type_keep_names_min_binaries) {
+        log::info!("collecting typedef and nominal names of all binaries...");
+        let name_pass_stats = PipelineStats {
+            name_stats: Some(CMultiMap::new()),
+            ..PipelineStats::default()
+        };
+        wasm_binaries_unique
+            .par_iter()
+            .progress_bar()
+            .for_each(|(_count, (path, stats))| {
+                if let Err(err) = process_binary(path, Some(&stats.file_sha256), None, &pipeline_options, &name_pass_stats) {
+                    log::warn!("{}: skipped when collecting names for --type-keep-names-min-binaries, {}", path.display(), err);
+                }
+            });
+        let keep = name_pass_stats.name_stats.expect("name statistics were enabled above").keys_with_min_distinct_values(min_binaries);
+        log_number_human_aligned(keep.len(), &format!("typedef and nominal names in at least {} binaries (--type-keep-names-min-binaries)\n", min_binaries));
+        keep_name_list = Some(keep);
+    }
+    pipeline_options.keep_name_list = keep_name_list;
+    let dataset_split = options.dataset_split();
+    let dedup_eval_samples = options.dedup_eval_samples()?;
+    let baseline = options.baseline()?.then(FrequencyBaseline::default);
 
-    let dataset_samples = wasm_binaries_unique_signature
+    let dataset_samples = wasm_binaries_unique
         .into_par_iter()
         .progress_bar()
 
         // Parallel over binaries.
-        .map(|(_count, (path, _stats))|  {
-            
-            // Makeshift try-block so that we can use ? inside.
-            let result = (|| -> anyhow::Result<_> {
-
-                let samples = 
-                    // Parse WebAssembly binary and DWARF sections.
-                    extract_samples(&path)?
-                
-                    // Filter out samples where the parameter is never used anywhere in the WebAssembly function.
-                    .filter_ok(|sample| {
-                        if !options.wasm_filter_unused_param {
-                            return true;
-                        }
-
-                        match sample.param_or_return {
-                            ParamOrReturn::Param { idx, .. } => {
-                                let is_used = sample.wasm_body
-                                    .instructions()
-                                    .map(|instrs|
-                                        instrs
-                                            .filter_map(Result::ok)
-                                            .any(|i| match i {
-                                                Operator::LocalGet { local_index } 
-                                                | Operator::LocalSet { local_index } 
-                                                | Operator::LocalTee { local_index } if local_index == idx => true,
-                                                _ => false
-                                            })
-                                    )
-                                    .unwrap_or(false);
-
-                                if !is_used {
-                                    samples_removed_unused_param.fetch_add(1, Ordering::SeqCst);
-                                }
-                                is_used
-                            }
-                            ParamOrReturn::Return => true,
-                        }
-                    })
-
-                    // Convert to own type language.
-                    .map(|sample| -> anyhow::Result<_> {
-                        let sample = sample?;
-                        let ty = match sample.param_or_return {
-                            ParamOrReturn::Param { .. } => Type::parse_param(&sample.type_),
-                            ParamOrReturn::Return => Type::parse_type(&sample.type_),
-                        }?;
-                        Ok(sample.with_type(ty))
-                    })
-
-                    // // Statistics: non-determinism with full WebAssembly body.
-                    // .map_ok(|sample| {
-                    //     type_map_wasm_full.insert(WasmRepr::new_hash_str(&sample, true), &sample.type_);
-                    //     sample
-                    // })
-
-                    // Convert to WebAssembly input representation.
-                    .map(|sample| -> anyhow::Result<_> {
-                        let sample = sample?;
-                        let wasm_repr = repr_fn(&sample)?;
-
-                        // // Statistics: non-determinism with our WebAssembly representation.
-                        // type_map_wasm_repr.insert(wasm_repr.clone(), &sample.type_);
-
-                        Ok(sample.with_wasm_body(wasm_repr))
-                    })
-
-                    // Attach file to error for better error reporting.
-                    .map(|result| result.with_file(path.clone()))
-
-                    // Filter out samples where the type is just Unknown.
-                    .filter_ok(|sample| {
-                        if !options.type_filter_unknown {
-                            return true;
-                        }
-
-                        let is_unknown = sample.type_ == Type(vec![TypeToken::Unknown]);
-
-                        if is_unknown {
-                            samples_removed_unknown_type.fetch_add(1, Ordering::SeqCst);
-                        }
-                        !is_unknown
-                    })
-
-                    // Simplify types, if options given.
-                    .map_ok(|mut sample| {
-                        
-                        // Collect statistics about names before removing or otherwise simplifying them.
-                        if name_stats_file.is_some() {
-                            for t in &sample.type_.0 {
-                                match t {
-                                    TypeToken::Typedef(name) | TypeToken::Nominal(name) => {
-                                        name_stats.insert(name.clone(), &sample.file);
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        }
-
-                        // Keep either no name at all, or only those in the given list.
-                        if options.type_remove_names {
-                            sample.type_.0.retain(|t| match t {
-                                TypeToken::Typedef(_) | TypeToken::Nominal(_) => false,
-                                _ => true
-                            });
-                        } else if let Some(keep) = &keep_name_list {
-                            sample.type_.0.retain(|t| match t {
-                                TypeToken::Typedef(name) | TypeToken::Nominal(name) => keep.contains(&name),
-                                _ => true
-                            });
-                        }
-
-                        match options.type_typedefs {
-                            Typedefs::Keep => {}
-                            Typedefs::ToNominal => {
-                                for t in &mut sample.type_.0 {
-                                    if let TypeToken::Typedef(name) = t {
-                                        *t = TypeToken::Nominal(name.clone());
-                                    }
-                                }
-                            }
-                            Typedefs::Remove => {
-                                sample.type_.0.retain(|t| if let TypeToken::Typedef(_) = t { false } else { true });
-                            }
-                        }
-
-                        if options.type_name_flatten_outermost {
-                            let mut outermost_name = false;
-                            let mut new_type = Vec::with_capacity(sample.type_.0.len());
-                            for t in sample.type_.0 {
-                                new_type.push(match t {
-                                    TypeToken::Typedef(_) | TypeToken::Nominal(_) if !outermost_name => {
-                                        outermost_name = true;
-                                        t
-                                    },
-                                    TypeToken::Typedef(_) | TypeToken::Nominal(_) => {
-                                        continue
-                                    }
-                                    t => t
-                                });
-                            }
-                            sample.type_.0 = new_type;
-                        }
-
-                        if options.type_remove_const {
-                            sample.type_.0.retain(|t| t != &TypeToken::Const);
-                        }
-
-                        if options.type_class_to_struct {
-                            for t in &mut sample.type_.0 {
-                                if t == &TypeToken::Class {
-                                    *t = TypeToken::Struct;
-                                }
-                            }
-                        }
-                        
-                        sample
-                    })
-
-                    // Collect samples into Vec, for further parallel processing.
-                    // (We cannot return the iterator directly here, because it contains ref-counted
-                    // slices of the input files, which are not Send, which makes the iterator not Send, 
-                    // and can thus it cannot be processed by rayon in parallel.
-                    // An alternative would be to change all Rc -> Arc and remove collect_vec() below,
-                    // but I am not sure which is more expensive: every ref-count being atomic when
-                    // parsing the input vs. one single allocation more per binary. I strongly suspect
-                    // the Arc'ing is more expensive. So that is why collect_vec().)
-                    .collect_vec();
-                Ok(samples)
-            })();
-
-            // Attach file to error for better reporting.
-            result.with_file(path.clone())
+        .map(|(_count, (path, stats))| {
+            // All samples of one binary end up in the same subset, see DatasetSplit::assign().
+            let subset = dataset_split.map(|split| split.assign(&stats.file_sha256));
+            process_binary(&path, Some(&stats.file_sha256), subset, &pipeline_options, &pipeline_stats)
+                // Attach file to error for better reporting.
+                .with_file(path.clone())
         })
 
-        .collect_errors(&mut errors_extraction_files)
+        // Log errors right away (above the progress bar), instead of only after all samples are written.
+        .handle_errors(|err| log::warn!("{}: could not process binary, {}", err.file.display(), err.error))
         
         .flatten_iter()
 
-        .collect_errors(&mut errors_extraction_samples);
+        .handle_errors(|err| log::warn!("{}: could not convert sample, {}", err.file.display(), err.error));
+
+    // Deduplication needs to see all dev/test samples before writing any train sample, so
+    // (unfortunately) all samples need to be collected into memory first.
+    let mut samples_removed_eval_duplicate = 0;
+    let dataset_samples = if dedup_eval_samples {
+        let mut samples: Vec<_> = dataset_samples.collect();
+        samples_removed_eval_duplicate = samples::split::dedup_eval_samples(&mut samples);
+        rayon::iter::Either::Left(samples.into_par_iter())
+    } else {
+        rayon::iter::Either::Right(dataset_samples)
+    };
+
+    // Subsampling also needs to see all samples, but keeps only (at most) the subsample in memory.
+    let mut samples_removed_subsample = 0;
+    let dataset_samples = if let Some(max_samples) = options.max_samples {
+        let ratios = dataset_split.map(|split| split.ratios);
+        let (samples, removed) = samples::split::subsample(dataset_samples.into_seq_iter(), max_samples, ratios, options.rand_seed);
+        samples_removed_subsample = removed;
+        rayon::iter::Either::Left(samples.into_par_iter())
+    } else {
+        rayon::iter::Either::Right(dataset_samples)
+    };
 
+    // Cap the number of samples per type, after all simplifications of the types above.
+    let max_per_type = options.max_per_type.map(MaxPerType::new);
+    let samples_removed_max_per_type = AtomicU64::new(0);
+    let dataset_samples = dataset_samples.filter(|sample| {
+        let keep = match &max_per_type {
+            Some(max_per_type) => max_per_type.admit(&sample.type_),
+            None => true,
+        };
+        if !keep {
+            samples_removed_max_per_type.fetch_add(1, Ordering::SeqCst);
+        }
+        keep
+    });
 
     // Collect statistics on the samples (input/output tokens, unusual types).
 
     let types = CHashMap::new();
+    let type_complexity = options.stats_type_complexity.then(TypeComplexityStats::default);
+    let producers = CHashMap::new();
+    let languages = CHashMap::new();
     let param_samples = AtomicU64::new(0);
     let return_samples = AtomicU64::new(0);
-
-    // // Baseline mode: take most common output DWARF type for each input raw WebAssembly type.
-    // // "Model" (i.e. mapping) extracted only on training data.
-    // let baseline_model_train_params = CMultiMap::new();
-    // let baseline_model_train_return = CMultiMap::new();
+    let local_samples = AtomicU64::new(0);
+    let wasm_vocab = Vocabulary::new();
+    let type_vocab = Vocabulary::new();
 
     // // "Perfect model": For each sample from the dev-set, just store the correct output for each input.
     // // However, due to non-determinism (same input, different outputs), there can be multiple
@@ -448,22 +356,43 @@ fn main() -> anyhow::Result<()> {
                 || 1,
                 |count| *count += 1
             );
+            if let Some(type_complexity) = &type_complexity {
+                type_complexity.add(sample);
+            }
+
+            // Distribution of compilers (with version), to compare toolchains later.
+            producers.upsert(
+                sample.producer.clone().unwrap_or_else(|| Arc::from("<unknown>")),
+                || 1,
+                |count| *count += 1
+            );
+
+            languages.upsert(
+                sample.language.unwrap_or("<unknown>"),
+                || 1,
+                |count| *count += 1
+            );
 
             match sample.param_or_return {
                 ParamOrReturn::Param { .. } => param_samples.fetch_add(1, Ordering::SeqCst),
-                ParamOrReturn::Return => return_samples.fetch_add(1, Ordering::SeqCst),
+                ParamOrReturn::Return { .. } => return_samples.fetch_add(1, Ordering::SeqCst),
+                ParamOrReturn::Local { .. } => local_samples.fetch_add(1, Ordering::SeqCst),
             };
 
-            // // Simple Wasm type -> DWARF type, frequency-based baseline:
-            // // "Build" model only on training data
-            // if let TrainDevTest::Train = sample.aux {
-            //     // Convert type first to string for printing it easily later.
-            //     let type_str = type_str(sample.wasm_type);
-            //     match sample.param_or_return {
-            //         ParamOrReturn::Param { .. } => baseline_model_train_params.insert(type_str, &sample.type_),
-            //         ParamOrReturn::Return => baseline_model_train_return.insert(type_str, &sample.type_)
-            //     };
-            // }
+            // Build the vocabulary only on the training data (or all data if not split).
+            if options.write_vocab() && matches!(sample.aux, None | Some(TrainDevTest::Train)) {
+                for token in sample.wasm_body.to_string().split_whitespace() {
+                    wasm_vocab.add(token);
+                }
+                for token in &sample.type_.0 {
+                    type_vocab.add(&token.to_string());
+                }
+            }
+
+            // Simple Wasm type -> type, frequency-based baseline.
+            if let Some(baseline) = &baseline {
+                baseline.add(sample);
+            }
 
             // // "Perfect" model, if it could see all test data before.
             // if let TrainDevTest::Dev = sample.aux {
@@ -472,50 +401,116 @@ fn main() -> anyhow::Result<()> {
             //     let input_hash = format!("{:x}", Sha256::digest(input_line.as_bytes()));
             //     match sample.param_or_return {
             //         ParamOrReturn::Param { .. } => perfect_model_dev_params.insert(input_hash, &sample.type_),
-            //         ParamOrReturn::Return => perfect_model_dev_return.insert(input_hash, &sample.type_)
+            //         ParamOrReturn::Return { .. } => perfect_model_dev_return.insert(input_hash, &sample.type_)
             //     };
             // }
         });
 
-    // Sequentially write output dataset for OpenNMT into text files.
-    let mut sample_writer = options.sample_writer()?;
-
-    for sample in dataset_samples.into_seq_iter() {
-        sample_writer.write(&sample)?;
+    // Write output dataset for OpenNMT into text files, either in parallel to shards or sequentially.
+    // Sorting for a deterministic order needs all samples in memory, and is written sequentially.
+    if options.deterministic_output && options.output_shards.is_some() {
+        log::warn!("--output-shards has no effect with --deterministic-output, writing sequentially...");
     }
-    for err in errors_extraction_files.into_iter().sorted() {
-        log::warn!("{}: could not extract samples, {}", err.file.display(), err.error);
+    let bytes_written = if let (Some(n_shards), false) = (options.output_shards, options.deterministic_output) {
+        match options.sample_writer_sharded(n_shards, append_samples).transpose()? {
+            Some(sample_writer) => {
+                dataset_samples.try_for_each(|sample| sample_writer.write(&sample))?;
+                sample_writer.merge()?
+            }
+            // With --count-only, still run the whole pipeline for the statistics.
+            None => { dataset_samples.for_each(drop); 0 }
+        }
+    } else {
+        match options.sample_writer(append_samples).transpose()? {
+            Some(mut sample_writer) => {
+                if options.deterministic_output {
+                    sample_writer.write_sorted(dataset_samples)?;
+                } else {
+                    for sample in dataset_samples.into_seq_iter() {
+                        sample_writer.write(&sample)?;
+                    }
+                }
+                sample_writer.bytes_written()?
+            }
+            None => { dataset_samples.for_each(drop); 0 }
+        }
+    };
+
+    // Only now that all samples are written, record the binaries as processed, see `Manifest::append()`.
+    if let Some(manifest) = &mut manifest {
+        if !options.count_only {
+            manifest.append(processed_sha256s)?;
+        }
     }
-    for err in errors_extraction_samples.into_iter().sorted() {
-        log::warn!("{}: could not extract samples, {}", err.file.display(), err.error);
+
+    let PipelineStats {
+        samples_removed_return_alignment,
+        samples_removed_language,
+        samples_removed_unused_param,
+        samples_removed_unknown_type,
+        samples_removed_max_per_binary,
+        samples_truncated,
+        types_pointer_depth_capped,
+        class_tokens,
+        struct_tokens,
+        dwarf_stats,
+        local_counts,
+        window_counts,
+        primitive_counts,
+        name_stats,
+        nondeterminism,
+        binary_yields,
+    } = pipeline_stats;
+    let function_name_filter = pipeline_options.function_name_filter;
+
+    // Explains, e.g., why a corpus yields few samples: no subprograms with locations, no matches etc.
+    log_number_human_aligned(dwarf_stats.entries.into_inner(), "DWARF entries visited");
+    log_number_human_aligned(dwarf_stats.compilation_units.into_inner(), "DWARF compilation units visited");
+    log_number_human_aligned(dwarf_stats.subprograms.into_inner(), "DWARF functions visited");
+    log_number_human_aligned(dwarf_stats.subprograms_with_location.into_inner(), "DWARF functions with offset");
+    log_number_human_aligned(dwarf_stats.wasm_functions.into_inner(), "WebAssembly functions");
+    log_number_human_aligned(dwarf_stats.functions_matched.into_inner(), "WebAssembly functions matched to DWARF function");
+    log_number_human_aligned(dwarf_stats.functions_unmatched.into_inner(), "WebAssembly functions without matching DWARF function");
+    log_number_human_aligned(dwarf_stats.functions_param_mismatch.into_inner(), "matched functions removed because WebAssembly and DWARF parameters do not align\n");
+    if let Some(filter) = function_name_filter {
+        log_number_human_aligned(filter.functions_excluded.into_inner(), "functions removed by --function-name-include/--function-name-exclude\n");
     }
 
+    log_number_human_aligned(samples_removed_return_alignment.into_inner(), "samples removed because WebAssembly and DWARF disagree on return value");
+    log_number_human_aligned(samples_removed_language.into_inner(), "samples removed because of their source language");
     log_number_human_aligned(samples_removed_unused_param.into_inner(), "samples removed because parameter was never used in WebAssembly function body");
-    log_number_human_aligned(samples_removed_unknown_type.into_inner(), "samples removed where DWARF type was unknown\n");
+    log_number_human_aligned(samples_removed_unknown_type.into_inner(), "samples removed where DWARF type was unknown");
+    log_number_human_aligned(samples_removed_max_per_binary.into_inner(), "samples removed because there were already --max-samples-per-binary samples of their binary");
+    log_number_human_aligned(samples_removed_subsample, "samples removed by subsampling to --max-samples");
+    log_number_human_aligned(samples_removed_max_per_type.into_inner(), "samples removed because there were already --max-per-type samples of their type");
+    log_number_human_aligned(samples_removed_eval_duplicate, "samples removed from train set because they also appear in dev or test set");
+    log_number_human_aligned(samples_truncated.into_inner(), "samples truncated to --wasm-max-tokens");
+    log_number_human_aligned(types_pointer_depth_capped.into_inner(), "sample types with pointers collapsed to --type-max-pointer-depth");
+    log_number_human_aligned(class_tokens.into_inner(), "class tokens in sample types (before --type-class-to-struct/--type-struct-to-class)");
+    log_number_human_aligned(struct_tokens.into_inner(), "struct tokens in sample types (before --type-class-to-struct/--type-struct-to-class)\n");
 
     log::info!("samples total:");
     log_number_human_aligned(param_samples.into_inner(), "parameters");
     log_number_human_aligned(return_samples.into_inner(), "return values");
+    if options.include_locals {
+        log_number_human_aligned(local_samples.into_inner(), "local variables");
+    }
 
-    log_filesize_human_aligned(sample_writer.bytes_written()?, "total bytes sample files written\n");
-
-    // options.write_mapping_model(baseline_model_train_params, "param", "baseline-model-train")?;
-    // options.write_mapping_model(baseline_model_train_return, "return", "baseline-model-train")?;
+    log_filesize_human_aligned(bytes_written, "total bytes sample files written\n");
 
     // options.write_mapping_model(perfect_model_dev_params, "param", "perfect-model-dev")?;
     // options.write_mapping_model(perfect_model_dev_return, "return", "perfect-model-dev")?;
 
-    // log_distribution(
-    //     type_map_wasm_full.into_iter()
-    //         .map(|(wasm, types)| (wasm, types.len() as u64)),
-    //     "task inherent non-determinism, full Wasm instructions -> number of types",
-    //     Some(options.stats_max));
-
-    // log_distribution(
-    //     type_map_wasm_repr.into_iter()
-    //         .map(|(wasm, types)| (wasm, types.len() as u64)),
-    //     "non-determinism with Wasm representation -> number of types",
-    //     Some(options.stats_max));
+    if let Some(nondeterminism) = nondeterminism {
+        log_distribution(
+            nondeterminism.raw_hash.distinct_values_distribution(),
+            "task inherent non-determinism, hash of raw Wasm function body -> number of distinct types",
+            Some(options.stats_max));
+        log_distribution(
+            nondeterminism.wasm_repr.distinct_values_distribution(),
+            "non-determinism with Wasm representation -> number of distinct types",
+            Some(options.stats_max));
+    }
     
     // log_distribution(
     //     frequency_baseline_wasm_raw_to_type.into_iter()
@@ -523,70 +518,75 @@ fn main() -> anyhow::Result<()> {
     //     "types", Some(options.stats_max));
     
     log_distribution(types, "types", Some(options.stats_max));
+    if let Some(type_complexity) = type_complexity {
+        log_distribution(type_complexity.lengths.clone(), "type lengths (in tokens)", Some(options.stats_max));
+        log::info!("most complex {} types (by number of tokens):", options.stats_max);
+        for (type_, example) in type_complexity.most_complex(options.stats_max) {
+            log::info!("{:11} tokens  {}  (e.g., in {})", type_.0.len(), type_, example);
+        }
+    }
+    log_distribution(producers, "producers (compilers) of samples", Some(options.stats_max));
+    log_distribution(languages, "source languages of samples", Some(options.stats_max));
+    log_distribution(local_counts, "number of declared locals (besides parameters) in functions of samples", Some(options.stats_max));
+    if matches!(pipeline_options.wasm_repr, options::WasmRepr::Windows(_)) {
+        log_distribution(window_counts, "number of windows per sample", Some(options.stats_max));
+    }
+
+    if let Some(primitive_counts) = primitive_counts {
+        let primitive_counts = primitive_counts.sorted();
+        log::info!("primitive types (sorted by representation, {} unique):", primitive_counts.len());
+        for ((ty, percent), count) in primitive_counts {
+            log::info!("{:11} ({:#}) {:22}  {:20}  bytes={:2}  =>  {}", count, percent, ty.source_name, ty.encoding, ty.byte_size, ty.normalized);
+        }
+    }
+
+    if options.write_vocab() {
+        wasm_vocab.write(options.create_vocab_file("wasm")?, options.vocab_min_freq)?;
+        type_vocab.write(options.create_vocab_file("type")?, options.vocab_min_freq)?;
+    }
+
+    if let (Some(writer), Some(binary_yields)) = (binary_yields_file, binary_yields) {
+        binary_yields.write_csv(writer)?;
+    }
 
-    if let Some(mut writer) = name_stats_file {
+    if let (Some(mut writer), Some(name_stats)) = (name_stats_file, name_stats) {
         writeln!(writer, "name,file,count")?;
-        for (name, binaries) in name_stats {
+        for (name, binaries) in name_stats.into_sorted_iter() {
             for (binary, count) in binaries {
                 writeln!(writer, "\"{}\",\"{}\",{}", name, binary.display(), count)?;
             }
         }
     }
 
-    // log::info!("results (on all data) for frequency-based baseline:");
-    // let baseline = baseline.build();
-    // log::info!("    overall accuracy: {}", baseline.accuracy());
-    // use wasmparser::Type::*;
-    // for wasm_type in [I32, I64, F32, F64].iter().copied() {
-    //     if let Some(prediction) = baseline.prediction(wasm_type) {
-    //         log::info!("    Wasm type {}:", type_str(wasm_type));
-    //         log::info!("        prediction: {}", prediction.hl_type);
-    //         log::info!("        accuracy:   {} ({}/{})", prediction.accuracy(), prediction.hl_type_correct, prediction.wasm_type_total);
-    //         if wasm_type == F32 || wasm_type == F64 {
-    //             log::info!("        all predictions: {}", baseline.all(wasm_type)
-    //                 .map(|pred| format!("{} {}", pred.hl_type_correct, pred.hl_type))
-    //                 .join(", "))
-    //         }
-    //     }
-    // }
+    if let Some(baseline) = baseline {
+        let model = baseline.build();
+        log::info!("frequency-based baseline (most common type on train set per Wasm type):");
+        for (input, type_) in &model.predictions {
+            log::info!("    {} {} => {}", input.0, input.1, type_);
+        }
+        for subset in [TrainDevTest::Dev, TrainDevTest::Test].iter().copied() {
+            let (per_input, overall) = model.accuracy(subset);
+            log::info!("    {} set accuracy:", subset.to_str());
+            log::info!("        overall {:#} ({}/{})", Percent::from_counts(overall.correct, overall.total), overall.correct, overall.total);
+            for (input, accuracy) in per_input {
+                log::info!("        {:7} {:#} ({}/{})", format!("{} {}", input.0, input.1), Percent::from_counts(accuracy.correct, accuracy.total), accuracy.correct, accuracy.total);
+            }
+        }
+        if let Some(writer) = options.create_baseline_file().transpose()? {
+            model.write(writer)?;
+        }
+    }
 
     // Output stats about DWARF tags encountered, extracted Wasm instructions, types etc.
 
-    // let dwarf_entries_count = AtomicU64::new(0);
-    // let dwarf_compilation_units_count = AtomicU64::new(0);
-    // let dwarf_functions_count = AtomicU64::new(0);
-    // let dwarf_functions_with_offset_count = AtomicU64::new(0);
-
-    // let dwarf_wasm_functions_mappable_count = AtomicU64::new(0);
     // let dwarf_wasm_params_matching_len = AtomicU64::new(0);
     // let dwarf_wasm_param_count = AtomicU64::new(0);
 
-    // let dwarf_entries_count = dwarf_entries_count.into_inner();
-    // aligned_human_output(dwarf_entries_count, "DWARF entries total");
-    // let dwarf_compilation_units_count = dwarf_compilation_units_count.into_inner();
-    // aligned_human_output(dwarf_compilation_units_count, "DWARF compilation units total");
-    // let dwarf_functions_count = dwarf_functions_count.into_inner();
-    // aligned_human_output(dwarf_functions_count, "DWARF functions total");
-    // let dwarf_functions_with_offset_count = dwarf_functions_with_offset_count.into_inner();
-    // aligned_human_output(dwarf_functions_with_offset_count, "DWARF functions with offset");
-
-    // let dwarf_wasm_functions_mappable_count = dwarf_wasm_functions_mappable_count.into_inner();
-    // aligned_human_output(dwarf_wasm_functions_mappable_count, "DWARF unique functions that could be mapped to Wasm offset");
     // let dwarf_wasm_params_matching_len = dwarf_wasm_params_matching_len.into_inner();
     // aligned_human_output(dwarf_wasm_params_matching_len, "DWARF-Wasm unique functions where the parameter lengths match");
     // let dwarf_wasm_param_count = dwarf_wasm_param_count.into_inner();
     // aligned_human_output(dwarf_wasm_param_count, "DWARF-Wasm unique functions parameter count");
 
-    // log::info!("primitive types (sorted by representation, {} unique):", types::parse_dwarf::PRIMITIVE_TYPE_COUNTS.len());
-    // for ((ty, percent), count) in types::parse_dwarf::PRIMITIVE_TYPE_COUNTS
-    //     .clone()
-    //     .into_iter()
-    //     .sorted_items()
-    //     .with_percent()
-    // {
-    //     log::info!("{:11} ({:#}) {:22}  {:20}  bytes={:2}  =>  {}", count, percent, ty.source_name, ty.encoding, ty.byte_size, ty.normalized);
-    // }
-
     // let samples_same_wasm_different_types: u64 = same_wasm_multiple_types.clone().into_iter().map(|(_, types)| types.len() as u64).sum();
     // log::info!(
     //     "Same Wasm, different types: {} ({})", 
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::{self, File};
 use std::io::{self, BufWriter};
@@ -7,13 +7,17 @@ use std::str::FromStr;
 
 use clap::Clap;
 use itertools::Itertools;
-use rand::prelude::StdRng;
-use rand::SeedableRng;
 use walkdir::WalkDir;
 use anyhow::bail;
+use regex::Regex;
 
+use crate::samples::extract::FunctionNameFilter;
+use crate::samples::pipeline::PipelineOptions;
+use crate::samples::split::{DatasetSplit, SplitRatios};
+use crate::samples::types::parse::ParseOptions;
+use crate::samples::wasm_repr::ReprOptions;
 use crate::util::cmultimap::CMultiMap;
-use crate::util::sample_writer::SampleWriter;
+use crate::util::sample_writer::{SampleWriter, ShardedSampleWriter};
 use crate::util::percent::Percent;
 
 #[derive(Clap, Debug)]
@@ -44,13 +48,208 @@ pub struct Options {
     #[clap(long, short)]
     pub verbose: bool,
 
+    /// Also accept WebAssembly text files (.wat) as inputs, which are assembled to binaries.
+    /// Text files are recognized by parsing them, not by their extension. Since they typically
+    /// have no DWARF debug info, they only contribute to the statistics about Wasm binaries.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub accept_wat: bool,
+
+    /// Memory-map input files instead of reading them into memory, which avoids copying large
+    /// binaries. Input files must not be modified while the extraction is running.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub mmap_input: bool,
+
+    /// How to remove duplicate input binaries before extracting samples.
+    /// "signature": binaries with the same function bodies, see --signature-mode (by default,
+    /// the same instruction names, i.e., they may differ in immediates, e.g., constants or indices).
+    /// "sha256": only byte-identical binaries.
+    /// "none": keep all binaries.
+    #[clap(long, arg_enum, default_value = "signature", value_name = "signature|sha256|none")]
+    pub dedup_mode: DedupMode,
+
+    /// What the function signatures (for --dedup-mode signature) are computed over.
+    /// "names": the names of the instructions in each body, which abstracts over immediates, e.g.,
+    /// constants or indices, and thus merges more binaries.
+    /// "bytes": the full bytes of each body, i.e., only binaries with identical code are merged,
+    /// but they may still differ in other sections, e.g., data or custom sections.
+    #[clap(long, arg_enum, default_value = "names", value_name = "names|bytes")]
+    pub signature_mode: SignatureMode,
+
+    /// Additional directories to search for separate debug files (e.g., foo.debug.wasm, or the
+    /// file named in the 'external_debug_info' custom section), for binaries without DWARF
+    /// sections. The directory of the binary itself is always searched first.
+    #[clap(long, value_name = "path")]
+    pub debug_search_path: Vec<PathBuf>,
+
+    /// Extract one return type sample per returned value for functions with multiple return
+    /// values (multi-value proposal), if the DWARF return type is a struct with a member for each.
+    /// By default, only the first returned value is used, as for WebAssembly MVP functions.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub multi_value_returns: bool,
+
+    /// Also extract samples for named local variables (DW_TAG_variable) that are stored in a
+    /// WebAssembly local, with the accesses to that local abstracted like those of a parameter.
+    /// They are written together with the parameter samples, see 'local_idx' in the info file.
+    /// Variables in the linear memory stack frame (e.g., in unoptimized code) are not included.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub include_locals: bool,
+
+    /// For relocatable object files (.o, with a 'linking' custom section), match WebAssembly and
+    /// DWARF functions by their symbol name instead of their code offset. Before linking, the
+    /// offsets in DWARF are not relocated yet, so most functions are at 0x0 and cannot be matched.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub relocatable_mode: bool,
+
+    /// Keep only functions where WebAssembly and DWARF agree on whether there is a return value.
+    /// By default, functions are kept for their parameter samples even if they do not agree, e.g.,
+    /// because the compiler returns the this pointer of methods that are void in the source.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub require_return_alignment: bool,
+
+    /// Keep only functions whose name matches the given regular expression, e.g., '^png_' to
+    /// focus on the public API of a library. Matches against the name from DWARF, or if there is
+    /// none, the WebAssembly name section. Unnamed functions are removed [default: keep all].
+    #[clap(long, value_name = "regex")]
+    function_name_include: Option<Regex>,
+
+    /// Remove functions whose name matches the given regular expression, e.g., '^__cxx_global_' to
+    /// remove compiler-generated functions. Unnamed functions are kept [default: remove none].
+    #[clap(long, value_name = "regex")]
+    function_name_exclude: Option<Regex>,
+
+    /// Demangle C++ (Itanium ABI) and Rust function names, e.g., '_ZN3foo3barEv' to 'foo::bar()'.
+    /// The demangled names are added to the sample info (next to the raw names), and are what
+    /// --function-name-include/--function-name-exclude match against.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub demangle: bool,
+
+    /// Keep only samples from compilation units in these source languages (DW_AT_language,
+    /// comma-separated), e.g., "c,cpp". A language without version also matches all its versions
+    /// (e.g., "cpp" matches "cpp14"). By default, samples from all languages are kept.
+    #[clap(long, use_delimiter = true, value_name = "languages")]
+    pub only_languages: Vec<String>,
+
     /// Number of entries to print for distributions like common types, names, etc.
     #[clap(long, default_value = "20", value_name = "N")]
     pub stats_max: usize,
 
+    /// Print the distribution of all primitive types (before simplifications), sorted by their
+    /// normalized representation, with their source name, DWARF encoding, and byte size.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub stats_primitives: bool,
+
+    /// Print how many distinct types identical WebAssembly inputs map to, both for the hash of the
+    /// raw function body and for the chosen representation (--wasm-repr). Inputs with more than one
+    /// type cannot be predicted correctly every time, so this bounds the achievable accuracy.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub stats_nondeterminism: bool,
+
+    /// Print the distribution of type lengths (in tokens) and the --stats-max longest types, each
+    /// with an example binary and function where it appears.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub stats_type_complexity: bool,
+
     /// Seed for RNG to make random operations reproducible (e.g., shuffling, subsampling data).
     #[clap(long, default_value = "0", value_name = "N")]
-    rand_seed: u64,
+    pub rand_seed: u64,
+
+    /// Also derive the random window order and selection (see --wasm-window-order and
+    /// --wasm-max-windows) from the SHA256 of each binary, not only from --rand-seed and the
+    /// function body. Then identical functions in different binaries get differently shuffled
+    /// windows, which is still reproducible across runs.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub seed_per_binary: bool,
+
+    /// Write samples in parallel to N shards per output file, which are merged at the end
+    /// [default: write sequentially from a single thread].
+    #[clap(long, value_name = "N")]
+    pub output_shards: Option<usize>,
+
+    /// Write samples sorted by their stable ID, such that two runs on the same input produce
+    /// byte-identical output files. This needs to keep all samples in memory until writing,
+    /// whereas by default, samples are streamed to the files in arbitrary order as they are
+    /// extracted. Writes sequentially, i.e., --output-shards has no effect.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub deterministic_output: bool,
+
+    /// Skip binaries whose SHA256 is listed in this manifest file, and append the samples of new
+    /// binaries to the existing output files. Afterwards, the new binaries are added to the
+    /// manifest, which is created if it does not exist yet (then, output files are overwritten).
+    /// If a run is aborted, its binaries are not recorded and processed again in the next run,
+    /// so samples that were already written in the aborted run can be duplicated.
+    #[clap(long, value_name = "manifest")]
+    pub skip_processed: Option<PathBuf>,
+
+    /// Write a CSV to this path with one line per binary: its SHA256, size, number of functions,
+    /// number of functions matched to DWARF, and number of parameter, return, and local samples
+    /// (before deduplication and subsampling), e.g., to spot binaries that contribute no samples.
+    #[clap(long, value_name = "path")]
+    binary_yields_csv: Option<PathBuf>,
+
+    /// Run the whole extraction, including all filters and type simplifications, but do not create
+    /// or write any sample, vocabulary, name statistics, or manifest files. Only the statistics are
+    /// logged (also to the log file, if requested), e.g., to estimate the yield of a corpus.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub count_only: bool,
+
+    /// Format of the sample files.
+    /// "opennmt": separate line-based files for input, output, and info, each for parameters and
+    /// return values, e.g., param/wasm.txt, param/type.txt, param/info.jsonl.
+    /// "jsonl-combined": a single samples.jsonl file with one JSON object per sample, containing
+    /// the input, output, and info fields.
+    #[clap(long, arg_enum, default_value = "opennmt", value_name = "opennmt|jsonl-combined")]
+    output_format: OutputFormat,
+
+    /// Compress all sample files with gzip, e.g., param/wasm.txt.gz instead of param/wasm.txt.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    compress_output: bool,
+
+    /// Write vocabulary files vocab/wasm.txt and vocab/type.txt with all input and output tokens
+    /// (from the train set, if the dataset is split), sorted by descending frequency.
+    /// Input tokens are separated by whitespace, output tokens are the tokens of our type language,
+    /// e.g., 'primitive int32_t' or 'name "Foo"'.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    write_vocab: bool,
+
+    /// Drop tokens that appear less than N times from the vocabulary files.
+    #[clap(long, default_value = "1", value_name = "N")]
+    pub vocab_min_freq: u64,
+
+    /// Split the dataset into train, dev, and test set with the given ratios, e.g., '0.8,0.1,0.1'.
+    /// All samples from one binary are assigned to the same subset, depending on the binary's
+    /// SHA256 and the RNG seed. Each subset is written to its own subdirectory [default: no split].
+    #[clap(long, value_name = "train,dev,test")]
+    split_ratios: Option<SplitRatios>,
+
+    /// Remove samples from the train set whose WebAssembly input and type output appear verbatim
+    /// also in the dev or test set. Requires --split-ratios.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    dedup_eval_samples: bool,
+
+    /// Build a frequency-based baseline on the train set, which predicts the most common type for
+    /// each raw WebAssembly type (separately for parameters and return values). Prints its accuracy
+    /// on the dev and test set, and writes the mapping to output_dir/baseline-model.txt.
+    /// Requires --split-ratios.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    baseline: bool,
+
+    /// Uniformly subsample the dataset (after deduplication) to at most N samples, reproducibly
+    /// depending on the RNG seed. If the dataset is split, each subset is subsampled to its share
+    /// of N according to --split-ratios [default: keep all samples].
+    #[clap(long, value_name = "N")]
+    pub max_samples: Option<usize>,
+
+    /// Write at most N samples of each type, to balance the long-tailed type distribution. Applies
+    /// to the final types, i.e., after all --type-* simplifications. Which samples of a type are
+    /// kept depends on the (parallel) processing order [default: no limit].
+    #[clap(long, value_name = "N")]
+    pub max_per_type: Option<usize>,
+
+    /// Keep at most N (randomly chosen) samples per binary, such that huge binaries do not
+    /// dominate the dataset. Applied before --max-samples and --max-per-type, reproducibly
+    /// depending on the RNG seed [default: no limit].
+    #[clap(long, value_name = "N")]
+    pub max_samples_per_binary: Option<usize>,
 
 
     // Options for WebAssembly input representation:
@@ -62,22 +261,137 @@ pub struct Options {
     pub wasm_filter_unused_param: bool,
 
     /// Add raw WebAssembly type of the parameter to predict to the input data.
+    /// Not for the 'hash' WebAssembly representation, see --hash-include-type instead.
     #[clap(long, parse(try_from_str), default_value = "true", value_name = "true|false")]
     pub wasm_add_raw_type: bool,
 
+    /// Abstract the operands of numeric constant instructions (i32.const etc.) into a few buckets,
+    /// e.g., <const:small> or <const:addr>, instead of printing the literal value.
+    /// Has no effect on the 'hash' WebAssembly representation.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub wasm_abstract_consts: bool,
+
     /// Representation of WebAssembly function bodies.
     /// "hash": hash of the body's bytes, useful for statistics on raw bodies, e.g., task-inherent non-determinism.
     /// "full": all instructions in the body.
     /// "subrange": first N instructions (for parameter types) and last N instructions (for return values).
     /// "windows": windows of size N around each parameter usage or return instruction (respectively).
+    /// "slice": only instructions that are data-flow dependent on the parameter (all instructions for return values).
+    /// "basic-blocks": all instructions, with explicit structural tokens and result types for blocks, loops, and ifs.
+    /// "bytes": the bytes of all instructions as hex tokens (with abstracted parameter accesses), for byte-level models.
     #[clap(long, arg_enum, value_name = "repr")]
     wasm_repr: WasmReprOption,
 
+    /// For the WebAssembly representation 'hash', whether to prefix the hash with the raw
+    /// WebAssembly type, independent of --wasm-add-raw-type. E.g., "never" for statistics on
+    /// identical bodies with different types, "always" for a baseline that also sees the type.
+    #[clap(long, arg_enum, default_value = "always", value_name = "never|always")]
+    pub hash_include_type: HashIncludeType,
+
     /// For the WebAssembly representations 'subrange' and 'windows', the size parameter, i.e.,
     /// the length of the subrange and the size of each window, respectively.
     #[clap(long, value_name = "N")]
     wasm_repr_size: Option<usize>,
+
+    /// For the WebAssembly representation 'windows', drop windows whose start is closer than
+    /// this fraction of the window size to the start of the previous (kept) window, e.g., to
+    /// avoid near-duplicate windows when the parameter is accessed in adjacent instructions.
+    /// 0 keeps all windows.
+    #[clap(long, default_value = "0", value_name = "fraction")]
+    pub wasm_window_overlap_threshold: f64,
+
+    /// For the WebAssembly representation 'windows', the order of windows in the input.
+    /// "shuffle": random order, deterministic per sample given the --rand-seed.
+    /// "program": order of the instructions in the function body.
+    /// "zorder": reverse Z-order, such that windows far apart in the body are next to each other.
+    #[clap(long, arg_enum, default_value = "shuffle", value_name = "shuffle|program|zorder")]
+    pub wasm_window_order: WindowOrder,
+
+    /// For the WebAssembly representation 'windows', where the parameter access is in its
+    /// window. "center": context before and after the access, "before": only the instructions
+    /// preceding the access (which often show how the value was produced), "after": only the
+    /// following instructions. Windows of return values always end with the return.
+    #[clap(long, arg_enum, default_value = "center", value_name = "center|before|after")]
+    pub wasm_window_anchor: WindowAnchor,
+
+    /// For the WebAssembly representation 'windows', keep at most this many windows per sample
+    /// (after the overlap filter), chosen randomly but deterministically given the --rand-seed.
+    /// This bounds the input length for frequently accessed parameters.
+    #[clap(long, value_name = "N")]
+    pub wasm_max_windows: Option<usize>,
         
+    /// Print the alignment of memory instructions, e.g., 'i32.load align=1', but only if it is
+    /// not the natural alignment of the instruction (which is the common case).
+    /// Has no effect on the 'hash' WebAssembly representation.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub wasm_print_align: bool,
+
+    /// Abstract the offset of memory instructions into a few buckets, e.g., 'i32.load offset=<aligned4>'
+    /// instead of 'i32.load offset=8', see `offset_bucket()`. A zero offset is still not printed.
+    /// Has no effect on the 'hash' WebAssembly representation.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub wasm_bucket_offsets: bool,
+
+    /// Prepend the WebAssembly type of the function to the instructions, e.g.,
+    /// '<sig> i32 i32 -> i32 <body> local.get <param> ; ...'. (For the 'windows' representation,
+    /// only before the first window.)
+    /// Has no effect on the 'hash' WebAssembly representation.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub wasm_prepend_signature: bool,
+
+    /// Prepend the types of all declared locals (besides parameters) of the function to the
+    /// instructions, e.g., '<locals> i32 i32 f64 <body> ...'. Comes after the signature, if both
+    /// are given. (For the 'windows' representation, only before the first window.)
+    /// Has no effect on the 'hash' WebAssembly representation.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub wasm_prepend_locals: bool,
+
+    /// Prepend the size of the function body as one of '<size:small>' (up to 32 bytes),
+    /// '<size:medium>' (up to 256 bytes), or '<size:large>', e.g., '<size:small> <body> ...'.
+    /// Comes before the signature and locals, if given. (For the 'windows' representation, only
+    /// before the first window.)
+    /// Has no effect on the 'hash' WebAssembly representation.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub wasm_prepend_size: bool,
+
+    /// Prepend where the parameter is first read (by 'local.get') in the function body, as one of
+    /// '<pos:early>', '<pos:mid>', or '<pos:late>' (by thirds of the instructions), or '<pos:none>'
+    /// if it is never read. Return values get '<pos:return>'. Comes before all other prefixes.
+    /// Only for the 'full' WebAssembly representation.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub wasm_param_position_token: bool,
+
+    /// Print the name of the called function (from the name section) after call instructions,
+    /// e.g., 'call malloc', or its index if the callee has no name.
+    /// Has no effect on the 'hash' WebAssembly representation.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub wasm_add_call_names: bool,
+
+    /// Print the type of the accessed global after global.get/global.set instructions instead of
+    /// its index, e.g., 'global.get i64', or 'global.get mut i32' for mutable globals.
+    /// Has no effect on the 'hash' WebAssembly representation.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub wasm_add_global_types: bool,
+
+    /// Print the signature of the called function type after call_indirect instructions, e.g.,
+    /// 'call_indirect (i32 i32 -> i32)', which hints at the types of the passed arguments.
+    /// Has no effect on the 'hash' WebAssembly representation.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub wasm_add_call_indirect_sig: bool,
+
+    /// Token that replaces the local index in accesses to the parameter we extract the type for,
+    /// e.g., 'local.get <param>'. Change it if your tokenizer treats angle brackets specially.
+    /// Has no effect on the 'hash' WebAssembly representation.
+    #[clap(long, default_value = "<param>", value_name = "token")]
+    pub param_token: String,
+
+    /// Truncate the WebAssembly input of each sample to at most N whitespace-separated tokens and
+    /// mark it with '<trunc>', e.g., to fit the context window of a model. Keeps the tokens around
+    /// the first access of the parameter for parameters, and the last tokens for return values.
+    /// Has no effect on the 'hash' WebAssembly representation [default: no limit].
+    #[clap(long, value_name = "N")]
+    pub wasm_max_tokens: Option<usize>,
+
     // /// Add raw WebAssembly types of calls, locals, and globals to the input data.
     // #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
     // pub wasm_add_raw_types_other: bool,
@@ -93,20 +407,65 @@ pub struct Options {
     #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
     pub type_remove_names: bool,
 
-    /// Remove const from types, i.e., equate const and non-const types.
+    /// How to handle const: Keeping it everywhere, removing it altogether (i.e., equating const
+    /// and non-const types), or keeping only a leading const of the whole type (e.g., keep it in
+    /// 'int * const', but remove it from 'const int *').
     /// Removing const retains less information about the source program, but makes prediction easier.
+    /// [default: keep]
+    #[clap(long, arg_enum, value_name = "keep|remove|outermost")]
+    type_const_mode: Option<ConstMode>,
+
+    /// Deprecated alias for --type-const-mode remove.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    type_remove_const: bool,
+
+    /// How precisely to keep fixed-width integer types: "exact" keeps them (e.g., 'int8_t' and
+    /// 'uint32_t'), "signedness" keeps only the signedness ('int' and 'uint'), and "integer"
+    /// merges all of them into 'int'. Characters and bool are not affected.
+    /// Coarser granularities show how much predicting the width contributes to the error.
+    #[clap(long, arg_enum, default_value = "exact", value_name = "exact|signedness|integer")]
+    pub type_int_granularity: IntGranularity,
+
+    /// Collapse runs of more than N consecutive pointer tokens to exactly N, e.g., 'int****' to
+    /// 'int**' for N = 2, such that deep indirection does not produce long and rare types
+    /// [default: no limit].
+    #[clap(long, value_name = "N")]
+    pub type_max_pointer_depth: Option<usize>,
+
+    /// Keep the volatile, restrict, _Atomic, and (D) immutable type qualifiers as 'volatile',
+    /// 'restrict', 'atomic', and 'immutable' tokens (like const), instead of stripping them.
     #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
-    pub type_remove_const: bool,
+    type_keep_qualifiers: bool,
+
+    /// Emit an '<anon>' token (in place of the name) for classes, structs, unions, and enums
+    /// without a name, such that anonymous and named types are distinguished symmetrically.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    type_mark_anonymous: bool,
 
     /// Map/equate class types to struct types, i.e., do NOT keep them as separate types.
     /// Mapping classes to structs retains less information about the source program, but makes 
-    /// prediction easier.
+    /// prediction easier. By default (and without --type-struct-to-class), classes and structs
+    /// are kept separate as 'class' and 'struct'.
     #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
     pub type_class_to_struct: bool,
 
-    /// How to handle typedefs: Keeping them as-is, converting them to nominal types, or removing
-    /// them altogether (essentially equating all typedefs of the same inner type).
-    #[clap(long, arg_enum, default_value = "keep", value_name = "keep|to-nominal|remove")]
+    /// Map/equate struct types to class types, i.e., the opposite direction of
+    /// --type-class-to-struct (and mutually exclusive with it). By default, classes and structs
+    /// are kept separate as 'class' and 'struct'.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub type_struct_to_class: bool,
+
+    /// Replace pointer tokens by 'pointer_to_prim', 'pointer_to_struct', 'pointer_to_func', or
+    /// 'pointer_to_void', depending on the type they point to (skipping qualifiers and names in
+    /// between). Pointers to other types, e.g., arrays or pointers, stay 'pointer'.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub type_specialize_pointers: bool,
+
+    /// How to handle typedefs: Keeping them as-is, converting them to nominal types, removing
+    /// them altogether (essentially equating all typedefs of the same inner type), or collapsing
+    /// chains of typedefs of a primitive type to the innermost one (e.g., 'typedef "my_int"
+    /// typedef "int32_t" primitive int32_t' to 'typedef "int32_t" primitive int32_t').
+    #[clap(long, arg_enum, default_value = "keep", value_name = "keep|to-nominal|remove|canonical")]
     pub type_typedefs: Typedefs,
 
     /// Save statistics about all typedef and nominal type names as a CSV [default: false].
@@ -118,10 +477,64 @@ pub struct Options {
     #[clap(long, value_name = "path")]
     type_keep_name_list: Option<String>,
 
+    /// Remove all typedef and nominal names that appear in fewer than K distinct binaries, i.e.,
+    /// keep only names that are common enough, without a manually curated name list. Requires an
+    /// additional pass over all binaries to collect the names first.
+    #[clap(long, value_name = "K")]
+    pub type_keep_names_min_binaries: Option<usize>,
+
     /// Keep at most one typedef or nominal name if there are multiple, namely the outermost one.
     #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
     pub type_name_flatten_outermost: bool,
 
+    /// Expand the members of structs, unions, and classes up to the given nesting depth, i.e.,
+    /// emit the types of all data members after the struct token, terminated by an end token.
+    /// Self-referential types are never expanded inside themselves.
+    /// 0 means members are never expanded.
+    #[clap(long, default_value = "0", value_name = "N")]
+    type_struct_expand_depth: usize,
+
+    /// Emit the (exact) length of each dimension after array tokens, e.g., 'array [3] [4] primitive char'
+    /// for 'char[3][4]', or '[]' if the length is unknown.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    type_array_length: bool,
+
+    /// Prefix the names of classes, structs, unions, and enums with their enclosing namespaces,
+    /// e.g., 'name "std::vector"' instead of 'name "vector"'. Note that this is slow for
+    /// compilation units with many DWARF entries.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    type_qualified_names: bool,
+
+    /// Emit the number of enumerators after enum tokens, e.g., 'enum <3 values> primitive uint32_t'.
+    /// Off by default, because it makes the type vocabulary larger.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    type_enum_values: bool,
+
+    /// How to map C/C++ character types. "merged": plain 'char' stays 'char', but 'signed char'
+    /// and 'unsigned char' become the integers 'int8_t' and 'uint8_t'. "three-way": keep all three
+    /// as distinct types 'char', 'schar', and 'uchar'.
+    #[clap(long, arg_enum, default_value = "merged", value_name = "merged|three-way")]
+    char_mode: CharMode,
+
+    /// Order of the type tokens. "prefix": type constructors come before their inner types, e.g.,
+    /// 'pointer const primitive int32_t' for 'const int *'. "postfix": the reverse, e.g.,
+    /// 'primitive int32_t const pointer', such that the innermost type comes first. Names come
+    /// after the type they annotate, and 'end' tokens before the members or parameters they close.
+    #[clap(long, arg_enum, default_value = "prefix", value_name = "prefix|postfix")]
+    pub type_notation: TypeNotation,
+
+    /// Terminate every type with an 'end' token, and always close structs, unions, and classes
+    /// with 'end' (also if their members are not expanded), such that a sequence of type tokens
+    /// can be split unambiguously into types.
+    #[clap(long, parse(try_from_str), default_value = "false", value_name = "true|false")]
+    pub type_explicit_end: bool,
+
+    /// Write a grammar (EBNF) of the type language to this path, i.e., of all type tokens and how
+    /// they compose, e.g., to validate model outputs. Generated from how the tokens are printed,
+    /// so it is always in sync with the extracted samples.
+    #[clap(long, value_name = "path")]
+    pub emit_type_grammar: Option<PathBuf>,
+
     // TODO use the following options
 
     // /// Remove the representation of a nominal type by truncating after the 'name' token.
@@ -137,9 +550,6 @@ pub struct Options {
     // // pub type_max_depth: Option<u32>,
     // /// Maximum number of tree-like type constructors before cut off, default: 0
     // // pub type_max_tree_depth: Option<u32>,
-
-    // /// After having split dataset into train/dev/test set, remove samples from train that appear verbatim in dev/test set
-    // // pub deduplicate_dev_test_samples: bool
 }
 
 #[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -147,6 +557,94 @@ pub enum Typedefs {
     Keep,
     ToNominal,
     Remove,
+    Canonical,
+}
+
+#[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TypeNotation {
+    Prefix,
+    Postfix,
+}
+
+#[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HashIncludeType {
+    Never,
+    Always,
+}
+
+#[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ConstMode {
+    Keep,
+    Remove,
+    Outermost,
+}
+
+#[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum IntGranularity {
+    Exact,
+    Signedness,
+    Integer,
+}
+
+#[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum CharMode {
+    #[default]
+    Merged,
+    ThreeWay,
+}
+
+#[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum DedupMode {
+    Signature,
+    Sha256,
+    None,
+}
+
+impl DedupMode {
+    /// For log output, e.g., "unique Wasm binaries (by function signatures)".
+    pub fn description(self) -> &'static str {
+        match self {
+            DedupMode::Signature => "by function signatures",
+            DedupMode::Sha256 => "by SHA256",
+            DedupMode::None => "no deduplication",
+        }
+    }
+}
+
+#[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SignatureMode {
+    Names,
+    Bytes,
+}
+
+impl SignatureMode {
+    /// For log output, e.g., "unique Wasm binaries (by function signatures of instruction names)".
+    pub fn description(self) -> &'static str {
+        match self {
+            SignatureMode::Names => "of instruction names",
+            SignatureMode::Bytes => "of body bytes",
+        }
+    }
+}
+
+#[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    Opennmt,
+    JsonlCombined,
+}
+
+#[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum WindowOrder {
+    Shuffle,
+    Program,
+    Zorder,
+}
+
+#[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum WindowAnchor {
+    Center,
+    Before,
+    After,
 }
 
 #[derive(Clap, Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -155,6 +653,9 @@ pub enum WasmReprOption {
     Full,
     Subrange,
     Windows,
+    Slice,
+    BasicBlocks,
+    Bytes,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -162,7 +663,10 @@ pub enum WasmRepr {
     Hash,
     Full,
     Subrange(usize),
-    Windows(usize)
+    Windows(usize),
+    Slice,
+    BasicBlocks,
+    Bytes,
 }
 
 impl Options {
@@ -177,14 +681,40 @@ impl Options {
             .map_ok(|entry| entry.into_path())
     }
 
-    /// Get a (deterministic) RNG with the seed given in the options.
-    pub fn rng_with_seed(&self) -> StdRng {
-        StdRng::seed_from_u64(self.rand_seed)
+    /// Create (buffered) output files, overwriting existing ones in the output directory, unless
+    /// `append` is set. None with `--count-only`.
+    pub fn sample_writer(&self, append: bool) -> Option<io::Result<SampleWriter>> {
+        if self.count_only {
+            return None;
+        }
+        Some(SampleWriter::create_files(&self.output_dir, self.split_ratios.is_some(), self.output_format, self.compress_output, append))
     }
 
-    /// Create (buffered) output files, overwriting existing ones in the output directory.
-    pub fn sample_writer(&self) -> io::Result<SampleWriter> {
-        SampleWriter::create_files(&self.output_dir)
+    /// Like `sample_writer()`, but with the given number of shards for parallel writing.
+    pub fn sample_writer_sharded(&self, n_shards: usize, append: bool) -> Option<io::Result<ShardedSampleWriter>> {
+        if self.count_only {
+            return None;
+        }
+        Some(SampleWriter::create_files_sharded(&self.output_dir, self.split_ratios.is_some(), self.output_format, self.compress_output, n_shards, append))
+    }
+
+    /// Whether to build and write the vocabulary files, see `--write-vocab`.
+    pub fn write_vocab(&self) -> bool {
+        self.write_vocab && !self.count_only
+    }
+
+    /// Assignment of binaries to train/dev/test set, if splitting the dataset was requested.
+    pub fn dataset_split(&self) -> Option<DatasetSplit> {
+        self.split_ratios.map(|ratios| DatasetSplit { ratios, seed: self.rand_seed })
+    }
+
+    /// Create a vocabulary file like output_dir/vocab/wasm.txt, see `--write-vocab`.
+    pub fn create_vocab_file(&self, name: &str) -> io::Result<BufWriter<File>> {
+        // Make sure the parent directories exist.
+        let dir = self.output_dir.join("vocab");
+        fs::create_dir_all(&dir)?;
+
+        File::create(dir.join(format!("{}.txt", name))).map(BufWriter::new)
     }
 
     /// Create a logfile in the output directory, if logging to file was requested.
@@ -205,7 +735,18 @@ impl Options {
         File::create(log_path)
     }
 
+    /// The CSV file of per-binary yields, see `--binary-yields-csv`.
+    pub fn binary_yields_file(&self) -> Option<io::Result<BufWriter<File>>> {
+        if self.count_only {
+            return None;
+        }
+        self.binary_yields_csv.as_ref().map(|path| File::create(path).map(BufWriter::new))
+    }
+
     pub fn name_stats_file(&self) -> Option<io::Result<BufWriter<File>>> {
+        if self.count_only {
+            return None;
+        }
         if let Some(filename) = &self.type_save_name_stats {
             // See the help message above for the default filename.
             let filename = filename.as_deref().unwrap_or("name-stats.csv");
@@ -217,30 +758,159 @@ impl Options {
         }
     }
 
-    pub fn keep_name_list(&self) -> Option<io::Result<Vec<Box<str>>>> {
+    pub fn keep_name_list(&self) -> Option<io::Result<HashSet<Box<str>>>> {
         if let Some(path) = &self.type_keep_name_list {
             if self.type_remove_names {
                 log::error!("conflicting options given: please only give either of --type-keep-name-list or --type-remove-names; ignoring the name list...");
             }
+            if self.type_keep_names_min_binaries.is_some() {
+                log::error!("conflicting options given: please only give either of --type-keep-name-list or --type-keep-names-min-binaries; ignoring the minimum number of binaries...");
+            }
     
             Some(std::fs::read_to_string(path).map(|s| {
-                let mut vec = Vec::new();
-                for s in s.lines() {
-                    vec.push(s.into())
-                }
-                vec
+                s.lines().map(Into::into).collect()
             }))
         } else {
             None
         }
     }
 
+    pub fn dedup_eval_samples(&self) -> anyhow::Result<bool> {
+        if self.dedup_eval_samples && self.split_ratios.is_none() {
+            bail!("option --dedup-eval-samples requires a train/dev/test split via --split-ratios");
+        }
+        Ok(self.dedup_eval_samples)
+    }
+
+    pub fn baseline(&self) -> anyhow::Result<bool> {
+        if self.baseline && self.split_ratios.is_none() {
+            bail!("option --baseline requires a train/dev/test split via --split-ratios");
+        }
+        Ok(self.baseline)
+    }
+
+    pub fn type_const_mode(&self) -> anyhow::Result<ConstMode> {
+        match (self.type_const_mode, self.type_remove_const) {
+            (Some(mode), true) if mode != ConstMode::Remove => bail!("option --type-remove-const (deprecated) conflicts with --type-const-mode {}, use only the latter", format!("{:?}", mode).to_lowercase()),
+            (_, true) => Ok(ConstMode::Remove),
+            (mode, false) => Ok(mode.unwrap_or(ConstMode::Keep)),
+        }
+    }
+
+    pub fn type_struct_to_class(&self) -> anyhow::Result<bool> {
+        if self.type_struct_to_class && self.type_class_to_struct {
+            bail!("options --type-class-to-struct and --type-struct-to-class are mutually exclusive");
+        }
+        Ok(self.type_struct_to_class)
+    }
+
+    /// Create output_dir/baseline-model.txt for the mapping of the baseline, see `--baseline`.
+    pub fn create_baseline_file(&self) -> Option<io::Result<BufWriter<File>>> {
+        if self.count_only {
+            return None;
+        }
+        Some(File::create(self.output_dir.join("baseline-model.txt")).map(BufWriter::new))
+    }
+
+    pub fn function_name_filter(&self) -> Option<FunctionNameFilter> {
+        if self.function_name_include.is_none() && self.function_name_exclude.is_none() {
+            return None;
+        }
+        Some(FunctionNameFilter {
+            include: self.function_name_include.clone(),
+            exclude: self.function_name_exclude.clone(),
+            ..FunctionNameFilter::default()
+        })
+    }
+
+    /// Options for converting DWARF types to our type language.
+    pub fn type_parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            struct_expand_depth: self.type_struct_expand_depth,
+            array_length: self.type_array_length,
+            qualified_names: self.type_qualified_names,
+            enum_values: self.type_enum_values,
+            char_mode: self.char_mode,
+            explicit_end: self.type_explicit_end,
+            keep_qualifiers: self.type_keep_qualifiers,
+            mark_anonymous: self.type_mark_anonymous,
+        }
+    }
+
+    pub fn wasm_repr_options(&self) -> ReprOptions {
+        ReprOptions {
+            with_type: self.wasm_add_raw_type,
+            abstract_consts: self.wasm_abstract_consts,
+            print_align: self.wasm_print_align,
+            bucket_offsets: self.wasm_bucket_offsets,
+            prepend_signature: self.wasm_prepend_signature,
+            prepend_locals: self.wasm_prepend_locals,
+            prepend_size: self.wasm_prepend_size,
+            prepend_param_position: self.wasm_param_position_token,
+            call_names: self.wasm_add_call_names,
+            global_types: self.wasm_add_global_types,
+            call_indirect_sigs: self.wasm_add_call_indirect_sig,
+            param_token: self.param_token.clone(),
+        }
+    }
+
+    /// Options for the per-binary part of the extraction. The `keep_name_list` is not read here,
+    /// since it may also be determined from all binaries first, see `--type-keep-names-min-binaries`.
+    pub fn pipeline_options(&self) -> anyhow::Result<PipelineOptions> {
+        Ok(PipelineOptions {
+            debug_search_path: self.debug_search_path.clone(),
+            multi_value_returns: self.multi_value_returns,
+            include_locals: self.include_locals,
+            relocatable_mode: self.relocatable_mode,
+            mmap_input: self.mmap_input,
+            require_return_alignment: self.require_return_alignment,
+            demangle: self.demangle,
+            function_name_filter: self.function_name_filter(),
+            only_languages: self.only_languages.clone(),
+
+            wasm_repr: self.wasm_repr()?,
+            wasm_repr_options: self.wasm_repr_options(),
+            wasm_filter_unused_param: self.wasm_filter_unused_param,
+            hash_include_type: self.hash_include_type,
+            wasm_window_overlap_threshold: self.wasm_window_overlap_threshold,
+            wasm_window_order: self.wasm_window_order,
+            wasm_window_anchor: self.wasm_window_anchor,
+            wasm_max_windows: self.wasm_max_windows,
+            wasm_max_tokens: self.wasm_max_tokens,
+
+            type_parse_options: self.type_parse_options(),
+            type_filter_unknown: self.type_filter_unknown,
+            type_remove_names: self.type_remove_names,
+            keep_name_list: None,
+            type_typedefs: self.type_typedefs,
+            type_name_flatten_outermost: self.type_name_flatten_outermost,
+            type_const_mode: self.type_const_mode()?,
+            type_int_granularity: self.type_int_granularity,
+            type_max_pointer_depth: self.type_max_pointer_depth,
+            type_class_to_struct: self.type_class_to_struct,
+            type_struct_to_class: self.type_struct_to_class()?,
+            type_specialize_pointers: self.type_specialize_pointers,
+            type_notation: self.type_notation,
+            type_explicit_end: self.type_explicit_end,
+
+            max_samples_per_binary: self.max_samples_per_binary,
+            rand_seed: self.rand_seed,
+            seed_per_binary: self.seed_per_binary,
+        })
+    }
+
     pub fn wasm_repr(&self) -> anyhow::Result<WasmRepr> {
         Ok(match (self.wasm_repr, self.wasm_repr_size) {
             (WasmReprOption::Hash, None) => WasmRepr::Hash,
             (WasmReprOption::Full, None) => WasmRepr::Full,
+            (WasmReprOption::Slice, None) => WasmRepr::Slice,
+            (WasmReprOption::BasicBlocks, None) => WasmRepr::BasicBlocks,
+            (WasmReprOption::Bytes, None) => WasmRepr::Bytes,
             (WasmReprOption::Hash, Some(_))
-            | (WasmReprOption::Full, Some(_)) => bail!("option --wasm-repr-size makes no sense with --wasm-repr 'hash' or 'full'"),
+            | (WasmReprOption::Full, Some(_))
+            | (WasmReprOption::Slice, Some(_))
+            | (WasmReprOption::BasicBlocks, Some(_))
+            | (WasmReprOption::Bytes, Some(_)) => bail!("option --wasm-repr-size makes no sense with --wasm-repr 'hash', 'full', 'slice', 'basic-blocks', or 'bytes'"),
             (WasmReprOption::Subrange, Some(n)) => WasmRepr::Subrange(n),
             (WasmReprOption::Windows, Some(n)) => WasmRepr::Windows(n),
             (WasmReprOption::Subrange, None)
@@ -248,3 +918,48 @@ impl Options {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::test_util::temp_dir;
+
+    #[test]
+    fn test_count_only_creates_no_files() {
+        let temp_dir = temp_dir();
+        let dir = temp_dir.path().join("count-only");
+        let options = Options::try_parse_from([
+            "extraction", "--count-only", "true", "--output-dir", dir.to_str().unwrap(),
+            "--write-vocab", "true", "--type-save-name-stats", "--split-ratios", "0.8,0.1,0.1",
+            "--wasm-repr", "full", "--binary-yields-csv", dir.join("yields.csv").to_str().unwrap(),
+            "input.wasm",
+        ]).unwrap();
+
+        assert!(options.sample_writer(false).is_none());
+        assert!(options.sample_writer_sharded(2, false).is_none());
+        assert!(options.name_stats_file().is_none());
+        assert!(options.binary_yields_file().is_none());
+        assert!(!options.write_vocab());
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_pipeline_options_default() {
+        let options = Options::try_parse_from(["extraction", "--wasm-repr", "full", "input.wasm"]).unwrap();
+        let pipeline_options = options.pipeline_options().unwrap();
+        assert_eq!(format!("{:?}", pipeline_options), format!("{:?}", PipelineOptions::default()));
+    }
+
+    #[test]
+    fn test_type_remove_const_alias() {
+        let const_mode = |args: &[&str]| {
+            let options = Options::try_parse_from(["extraction", "--wasm-repr", "full", "input.wasm"].iter().chain(args)).unwrap();
+            options.type_const_mode()
+        };
+        assert_eq!(const_mode(&[]).unwrap(), ConstMode::Keep);
+        assert_eq!(const_mode(&["--type-remove-const", "true"]).unwrap(), ConstMode::Remove);
+        assert_eq!(const_mode(&["--type-remove-const", "false", "--type-const-mode", "outermost"]).unwrap(), ConstMode::Outermost);
+        assert_eq!(const_mode(&["--type-remove-const", "true", "--type-const-mode", "remove"]).unwrap(), ConstMode::Remove);
+        assert!(const_mode(&["--type-remove-const", "true", "--type-const-mode", "keep"]).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use std::hash::Hash;
+
+use chashmap::CHashMap;
+
+use crate::util::reservoir::Reservoir;
+
+/// Concurrent cap on the number of samples per type, to counter the long-tailed type distribution
+/// (where, e.g., pointers and int32_t dominate). Should be applied to the final types, i.e., after
+/// all simplifications, such that the cap is per distinct type in the output.
+#[derive(Debug)]
+pub struct MaxPerType<T: Hash + PartialEq> {
+    max: usize,
+    counts: CHashMap<T, usize>,
+}
+
+impl<T: Hash + PartialEq + Clone> MaxPerType<T> {
+    pub fn new(max: usize) -> Self {
+        Self { max, counts: CHashMap::new() }
+    }
+
+    /// Whether a sample of this type can still be kept. If yes, it is counted towards the cap.
+    pub fn admit(&self, type_: &T) -> bool {
+        let mut admitted = false;
+        self.counts.alter(type_.clone(), |count| {
+            let count = count.unwrap_or(0);
+            admitted = count < self.max;
+            Some(if admitted { count + 1 } else { count })
+        });
+        admitted
+    }
+}
+
+/// Keep at most `max` of the samples of a single binary, such that huge binaries (e.g., a whole game
+/// engine) do not dominate the dataset. The samples are chosen uniformly at random, but
+/// reproducibly for the same seed and `key`s (see `Reservoir`), and keep their original order.
+/// Errors are always kept. Returns the kept samples and the number of dropped ones.
+pub fn cap_per_binary<T, E>(samples: Vec<Result<T, E>>, max: usize, seed: u64, key: impl Fn(&T) -> String) -> (Vec<Result<T, E>>, u64) {
+    let mut kept = Vec::with_capacity(max.min(samples.len()));
+    let mut reservoir = Reservoir::new(max, seed);
+    for (i, sample) in samples.into_iter().enumerate() {
+        match sample {
+            Ok(sample) => reservoir.add(key(&sample).as_bytes(), (i, Ok(sample))),
+            Err(err) => kept.push((i, Err(err))),
+        }
+    }
+
+    let seen = reservoir.seen();
+    let sampled = reservoir.into_vec();
+    let dropped = seen - sampled.len() as u64;
+    kept.extend(sampled);
+    kept.sort_unstable_by_key(|(i, _)| *i);
+    (kept.into_iter().map(|(_, sample)| sample).collect(), dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use rayon::prelude::*;
+
+    use crate::samples::types::{Type, TypeToken};
+
+    use super::*;
+
+    #[test]
+    fn test_max_per_type() {
+        let pointer = Type(vec![TypeToken::Pointer, TypeToken::Struct]);
+        let unknown = Type(vec![TypeToken::Unknown]);
+        let types = [pointer.clone(), pointer.clone(), unknown, pointer.clone(), pointer.clone(), pointer];
+
+        let max_per_type = MaxPerType::new(2);
+        let admitted: Vec<_> = types.iter().map(|type_| max_per_type.admit(type_)).collect();
+        assert_eq!(admitted, vec![true, true, true, false, false, false]);
+
+        // Also when checked concurrently.
+        let max_per_type = MaxPerType::new(2);
+        let admitted = (0..1000usize).into_par_iter()
+            .filter(|i| max_per_type.admit(&types[i % types.len()]))
+            .count();
+        assert_eq!(admitted, 4);
+    }
+
+    #[test]
+    fn test_cap_per_binary() {
+        let samples = || (0..10).map(Ok).chain(Some(Err("error"))).collect::<Vec<Result<u32, _>>>();
+        let key = |i: &u32| i.to_string();
+
+        let (kept, dropped) = cap_per_binary(samples(), 3, 0, key);
+        assert_eq!(dropped, 7);
+        assert_eq!(kept.iter().filter(|sample| sample.is_ok()).count(), 3);
+        // Errors are not capped, original order is kept.
+        assert_eq!(kept.last(), Some(&Err("error")));
+        assert!(kept.windows(2).all(|pair| match pair {
+            [Ok(a), Ok(b)] => a < b,
+            _ => true,
+        }));
+
+        // Deterministic for the same seed.
+        assert_eq!(cap_per_binary(samples(), 3, 0, key).0, kept);
+        assert_ne!(cap_per_binary(samples(), 3, 1, key).0, kept);
+
+        assert_eq!(cap_per_binary(samples(), 100, 0, key), (samples(), 0));
+    }
+}
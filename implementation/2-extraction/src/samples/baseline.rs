@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+use crate::samples::sample::{ParamOrReturn, WasmTypeSample};
+use crate::samples::split::TrainDevTest;
+use crate::samples::types::Type;
+use crate::util::cmultimap::CMultiMap;
+use crate::wasm::fmt::type_str;
+
+/// Input of the baseline: whether the sample is a parameter or return value, and its raw
+/// WebAssembly type, e.g., `("param", "i32")`.
+pub type BaselineInput = (&'static str, &'static str);
+
+fn baseline_input<W, U>(sample: &WasmTypeSample<W, Type, U>) -> BaselineInput {
+    let param_or_return = match sample.param_or_return {
+        ParamOrReturn::Param { .. } => "param",
+        ParamOrReturn::Return { .. } => "return",
+        ParamOrReturn::Local { .. } => "local",
+    };
+    (param_or_return, type_str(sample.wasm_type))
+}
+
+/// Concurrent builder of the frequency-based baseline: For each raw WebAssembly type (separately
+/// for parameters and return values), predict the most common type among the training samples.
+/// The dev and test samples are only counted, such that the baseline can be evaluated on them
+/// after all samples have been seen, without keeping the samples in memory.
+#[derive(Debug, Default)]
+pub struct FrequencyBaseline {
+    train: CMultiMap<BaselineInput, Type>,
+    eval: CMultiMap<(TrainDevTest, BaselineInput), Type>,
+}
+
+impl FrequencyBaseline {
+    /// Samples without a subset (i.e., if the dataset is not split) are ignored.
+    pub fn add<W>(&self, sample: &WasmTypeSample<W, Type, Option<TrainDevTest>>) {
+        let input = baseline_input(sample);
+        match sample.aux {
+            Some(TrainDevTest::Train) => self.train.insert(input, &sample.type_),
+            Some(subset) => self.eval.insert((subset, input), &sample.type_),
+            None => {}
+        }
+    }
+
+    pub fn build(self) -> BaselineModel {
+        let predictions = self.train.into_iter()
+            .filter_map(|(input, types)| {
+                // Break ties deterministically, by the (textually) smallest type.
+                let (type_, _count) = types.into_iter()
+                    .max_by(|(type1, count1), (type2, count2)| count1.cmp(count2).then_with(|| type2.to_string().cmp(&type1.to_string())))?;
+                Some((input, type_))
+            })
+            .collect();
+        BaselineModel {
+            predictions,
+            eval: self.eval.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BaselineModel {
+    /// The most common type on the training set for each input.
+    pub predictions: BTreeMap<BaselineInput, Type>,
+    eval: HashMap<(TrainDevTest, BaselineInput), Vec<(Type, usize)>>,
+}
+
+/// Number of correct predictions and of all samples.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Accuracy {
+    pub correct: u64,
+    pub total: u64,
+}
+
+impl BaselineModel {
+    /// Accuracy on the given subset, for each input (sorted), and overall. Inputs that never
+    /// appear in the training set have no prediction, so all of their samples count as wrong.
+    pub fn accuracy(&self, subset: TrainDevTest) -> (BTreeMap<BaselineInput, Accuracy>, Accuracy) {
+        let mut per_input = BTreeMap::new();
+        let mut overall = Accuracy::default();
+        for ((eval_subset, input), types) in &self.eval {
+            if *eval_subset != subset {
+                continue;
+            }
+            let prediction = self.predictions.get(input);
+            let accuracy = per_input.entry(*input).or_insert_with(Accuracy::default);
+            for (type_, count) in types {
+                let count = *count as u64;
+                if prediction == Some(type_) {
+                    accuracy.correct += count;
+                    overall.correct += count;
+                }
+                accuracy.total += count;
+                overall.total += count;
+            }
+        }
+        (per_input, overall)
+    }
+
+    /// One line per input, e.g., `param<TAB>i32<TAB>primitive int32_t`.
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        for ((param_or_return, wasm_type), type_) in &self.predictions {
+            writeln!(writer, "{}\t{}\t{}", param_or_return, wasm_type, type_)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::samples::types::TypeToken::*;
+
+    fn sample(wasm_type: wasmparser::Type, type_: &Type, subset: TrainDevTest) -> WasmTypeSample<(), Type, Option<TrainDevTest>> {
+        WasmTypeSample {
+            file: Arc::from(Path::new("test.wasm")),
+            file_sha256: Arc::from(&[0; 32][..]),
+            compilation_unit: None,
+            producer: None,
+            language: None,
+            function_idx: 0,
+            function_name_wasm: None,
+            function_name_dwarf: None,
+            function_name_wasm_demangled: None,
+            function_name_dwarf_demangled: None,
+            decl_file: None,
+            decl_line: None,
+            param_or_return: ParamOrReturn::Param { idx: 0, name: None },
+            wasm_type,
+            wasm_function_type: Arc::new(wasmparser::FuncType { params: Box::new([]), returns: Box::new([]) }),
+            wasm_body: (),
+            type_: type_.clone(),
+            aux: Some(subset),
+        }
+    }
+
+    #[test]
+    fn test_frequency_baseline() {
+        use wasmparser::Type::{F64, I32, I64};
+        use TrainDevTest::*;
+        let pointer = Type(vec![Pointer, Struct]);
+        let enum_ = Type(vec![Enum]);
+        let unknown = Type(vec![Unknown]);
+
+        let baseline = FrequencyBaseline::default();
+        // i32: pointer is the most common type on train, the enum in dev is only counted.
+        for (type_, subset) in &[(&pointer, Train), (&pointer, Train), (&enum_, Train), (&enum_, Dev), (&enum_, Dev)] {
+            baseline.add(&sample(I32, type_, *subset));
+        }
+        // i64: tie on train, broken by the textually smaller type ("enum" < "unknown").
+        for type_ in &[&unknown, &enum_] {
+            baseline.add(&sample(I64, type_, Train));
+        }
+        // Evaluation samples of i32 and i64, and f64, which never appears in train.
+        for (wasm_type, type_) in &[(I32, &pointer), (I32, &pointer), (I32, &enum_), (I64, &enum_), (F64, &unknown)] {
+            baseline.add(&sample(*wasm_type, type_, Test));
+        }
+
+        let model = baseline.build();
+        assert_eq!(model.predictions.get(&("param", "i32")), Some(&pointer));
+        assert_eq!(model.predictions.get(&("param", "i64")), Some(&enum_));
+        assert_eq!(model.predictions.get(&("param", "f64")), None);
+
+        let (per_input, overall) = model.accuracy(Test);
+        assert_eq!(per_input[&("param", "i32")], Accuracy { correct: 2, total: 3 });
+        assert_eq!(per_input[&("param", "i64")], Accuracy { correct: 1, total: 1 });
+        assert_eq!(per_input[&("param", "f64")], Accuracy { correct: 0, total: 1 });
+        assert_eq!(overall, Accuracy { correct: 3, total: 5 });
+
+        let (_per_input, overall) = model.accuracy(Dev);
+        assert_eq!(overall, Accuracy { correct: 0, total: 2 });
+
+        let mut written = Vec::new();
+        model.write(&mut written).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), "param\ti32\tpointer struct\nparam\ti64\tenum\n");
+    }
+}
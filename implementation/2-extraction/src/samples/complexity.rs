@@ -0,0 +1,110 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use chashmap::CHashMap;
+
+use crate::samples::sample::WasmTypeSample;
+use crate::samples::types::Type;
+
+/// Where a type was seen, for inspecting unusually complex types in the binaries.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Provenance {
+    pub file: Arc<Path>,
+    pub function: Option<Arc<str>>,
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.file.display(), self.function.as_deref().unwrap_or("<unnamed function>"))
+    }
+}
+
+/// Concurrently collected statistics about the (final) types of all samples, to understand the
+/// tail of the type distribution, see `--stats-type-complexity`.
+#[derive(Debug, Default)]
+pub struct TypeComplexityStats {
+    /// Number of tokens of the type -> number of samples.
+    pub lengths: CHashMap<u64, u64>,
+    /// Distinct types -> an example where they appear. If a type appears in multiple samples, the
+    /// smallest provenance is kept, such that the example does not depend on the sample order.
+    examples: CHashMap<Type, Provenance>,
+}
+
+impl TypeComplexityStats {
+    pub fn add<W, U>(&self, sample: &WasmTypeSample<W, Type, U>) {
+        self.lengths.upsert(sample.type_.0.len() as u64, || 1, |count| *count += 1);
+
+        let provenance = Provenance {
+            file: sample.file.clone(),
+            function: sample.function_name_dwarf.clone().or_else(|| sample.function_name_wasm.clone()),
+        };
+        self.examples.upsert(
+            sample.type_.clone(),
+            || provenance.clone(),
+            |example| if provenance < *example { *example = provenance.clone() },
+        );
+    }
+
+    /// The `n` types with the most tokens, longest first (ties broken textually), each with an
+    /// example of where it appears.
+    pub fn most_complex(self, n: usize) -> Vec<(Type, Provenance)> {
+        let mut types: Vec<_> = self.examples.into_iter().collect();
+        types.sort_by_cached_key(|(type_, _)| (std::cmp::Reverse(type_.0.len()), type_.to_string()));
+        types.truncate(n);
+        types
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gimli::constants::*;
+
+    use super::*;
+    use crate::dwarf::test_util::*;
+    use crate::samples::pipeline::{process_binary_bytes, PipelineOptions, PipelineStats};
+    use crate::samples::types::TypeToken;
+    use crate::wasm::test_util::*;
+
+    #[test]
+    fn test_most_complex() {
+        // void f(int x), void g(int x), void h(int*** x)
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32], &[]);
+        for _ in 0..3 {
+            // local.get 0, drop
+            module.function(ty, &[], &[0x20, 0x00, 0x1a]);
+        }
+        let low_pcs = module.code_offsets();
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let root = unit.root();
+            let mut pointer = int;
+            for _ in 0..3 {
+                pointer = add_typed(unit, root, DW_TAG_pointer_type, None, pointer);
+            }
+            for (name, low_pc, type_) in [("f", low_pcs[0], int), ("g", low_pcs[1], int), ("h", low_pcs[2], pointer)].iter().copied() {
+                let subprogram = add_subprogram(unit, Some(name), low_pc);
+                add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("x"), type_);
+            }
+        });
+        add_dwarf_sections(&mut module, &sections);
+        let bytes = module.build();
+
+        let stats = TypeComplexityStats::default();
+        for sample in process_binary_bytes(&bytes, None, &PipelineOptions::default(), &PipelineStats::default()).unwrap() {
+            stats.add(&sample.unwrap());
+        }
+
+        assert_eq!(stats.lengths.get(&1).map(|count| *count), Some(2));
+        assert_eq!(stats.lengths.get(&4).map(|count| *count), Some(1));
+
+        let most_complex = stats.most_complex(1);
+        assert_eq!(most_complex.len(), 1);
+        let (type_, provenance) = &most_complex[0];
+        assert_eq!(type_.0.len(), 4);
+        assert!(type_.0[..3].iter().all(|t| *t == TypeToken::Pointer));
+        assert_eq!(provenance.function.as_deref(), Some("h"));
+        assert_eq!(provenance.to_string(), "<memory>: h");
+    }
+}
@@ -1,48 +1,179 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use gimli::{DW_AT_name, EndianRcSlice, LittleEndian};
+use gimli::{EndianRcSlice, LittleEndian, Reader};
+use gimli::constants::*;
 use itertools::Itertools;
+use regex::Regex;
+use sha2::{Digest, Sha256};
 
-use crate::dwarf::parse::DwarfBinary;
+use crate::dwarf::parse::{DwarfBinary, DwarfStats};
 use crate::dwarf::util::DwarfEntry;
-use crate::wasm::parse::{WasmBinary, WasmBody};
+use crate::wasm::magic_bytes::read_wasm_file;
+use crate::wasm::parse::{parse_custom_sections, parse_linking_function_names, WasmBinary, WasmBody};
 use crate::samples::sample::{WasmTypeSample, ParamOrReturn};
+use crate::util::demangle::demangle;
 
-pub fn extract_samples(file: &Path) -> anyhow::Result<
-    impl Iterator<Item = 
-        gimli::Result<
-            WasmTypeSample<
-                WasmBody, 
-                DwarfEntry<EndianRcSlice<LittleEndian>>
-            >
-        >
-    >
-> {
-    let bytes = std::fs::read(file)?;
-
-    let file: Arc<Path> = Arc::from(file);
-
-    let wasm = WasmBinary::parse(&bytes)?;
+/// A sample as extracted from a binary, i.e., with the DWARF type entry not yet converted to
+/// our type language (see `Type::parse_param()` and `Type::parse_type()`).
+pub type ExtractedSample = WasmTypeSample<WasmBody, DwarfEntry<EndianRcSlice<LittleEndian>>>;
+
+/// Include or exclude functions by name, e.g., to remove compiler-generated functions such as
+/// `__cxx_global_var_init`, or to focus on the public API of a library. Matches against the DWARF
+/// name of the function if it has one, otherwise the name from the WebAssembly name section.
+#[derive(Debug, Default)]
+pub struct FunctionNameFilter {
+    /// If given, only functions whose name matches are kept (i.e., also no unnamed ones).
+    pub include: Option<Regex>,
+    /// If given, functions whose name matches are removed.
+    pub exclude: Option<Regex>,
+    pub functions_excluded: AtomicU64,
+}
+
+impl FunctionNameFilter {
+    /// Whether to keep the function with the given name. Counts the function if not.
+    pub fn keep(&self, name: Option<&str>) -> bool {
+        let keep = match (name, &self.include) {
+            (Some(name), Some(include)) => include.is_match(name),
+            (None, Some(_)) => false,
+            (_, None) => true,
+        } && !matches!((name, &self.exclude), (Some(name), Some(exclude)) if exclude.is_match(name));
+        if !keep {
+            self.functions_excluded.fetch_add(1, Ordering::Relaxed);
+        }
+        keep
+    }
+}
+
+/// Options of `extract_samples()`. The default extracts samples for parameters and (the first)
+/// return value of all functions, without collecting any statistics.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExtractOptions<'a> {
+    /// Functions returning multiple values (multi-value proposal) give one return sample per
+    /// value, if the DWARF return type can be split up, see `split_return_type()`. Otherwise,
+    /// there is only a sample for the first returned value (as in the WebAssembly MVP).
+    pub multi_value_returns: bool,
+    /// Also extract samples for local variables, see `align_locals()`.
+    pub include_locals: bool,
+    /// Match functions of relocatable object files (with a "linking" section) by their symbol
+    /// name instead of their offset, see `DwarfBinary::parse()`.
+    pub relocatable_mode: bool,
+    /// Memory-map the binary (and debug file) instead of reading it, see `read_wasm_file()`.
+    pub mmap: bool,
+    /// If given, functions where WebAssembly and DWARF disagree on whether there is a return
+    /// value are skipped, and the number of their (parameter) samples is added to this counter.
+    pub require_return_alignment: Option<&'a AtomicU64>,
+    /// If given, the DWARF entries visited and functions matched are counted here.
+    pub dwarf_stats: Option<&'a DwarfStats>,
+    /// The samples also contain the demangled function names, which are then also what
+    /// `function_name_filter` matches against.
+    pub demangle: bool,
+    /// If given, no samples are extracted for functions it does not keep.
+    pub function_name_filter: Option<&'a FunctionNameFilter>,
+}
+
+/// If the binary has no DWARF sections itself, they are read from a separate debug file, which
+/// is searched for next to the binary and in all directories of `debug_search_path`, see
+/// `find_debug_file()`.
+pub fn extract_samples<'a>(file: &Path, debug_search_path: &[PathBuf], options: &ExtractOptions<'a>) -> anyhow::Result<impl Iterator<Item = gimli::Result<ExtractedSample>> + 'a> {
+    let bytes = read_wasm_file(file, options.mmap)?;
+    extract_samples_with_file(&bytes, Arc::from(file), None, Some(debug_search_path), options)
+}
+
+/// Like `extract_samples()`, but for a binary that is already in memory, e.g., generated by a
+/// fuzzer. The `file` of all samples is the placeholder "<memory>".
+pub fn extract_samples_from_bytes(bytes: &[u8], multi_value_returns: bool) -> anyhow::Result<Vec<ExtractedSample>> {
+    let options = ExtractOptions { multi_value_returns, ..ExtractOptions::default() };
+    let samples = extract_samples_with_file(bytes, Arc::from(Path::new("<memory>")), None, None, &options)?;
+    Ok(samples.collect::<gimli::Result<_>>()?)
+}
+
+/// `file_sha256` is the SHA256 of `bytes`, if already known (e.g., from `WasmBinaryStats`), such
+/// that it is not computed again.
+/// `debug_search_path` is None if there is no file system location to search for debug files.
+pub(crate) fn extract_samples_with_file<'a>(bytes: &[u8], file: Arc<Path>, file_sha256: Option<Arc<[u8]>>, debug_search_path: Option<&[PathBuf]>, options: &ExtractOptions<'a>) -> anyhow::Result<impl Iterator<Item = gimli::Result<ExtractedSample>> + 'a> {
+    let ExtractOptions { multi_value_returns, include_locals, relocatable_mode, mmap, require_return_alignment, dwarf_stats, demangle: demangle_names, function_name_filter } = *options;
+    let wasm = WasmBinary::parse(bytes)?;
+    let file_sha256 = file_sha256.unwrap_or_else(|| Sha256::digest(bytes).as_slice().into());
     let code_section_offset = wasm.code_section_offset;
     let mut wasm_function_names = wasm.function_names;
 
-    let mut dwarf = DwarfBinary::parse(&wasm.custom_sections)?;
+    // The DWARF info in a separate debug file still refers to the code section (offsets) of the
+    // main binary, so we can use it just like the DWARF sections of the main binary.
+    let debug_file_bytes;
+    let debug_file_sections;
+    let dwarf_sections = match debug_search_path {
+        Some(debug_search_path) if !wasm.custom_sections.contains_key(".debug_info")
+            && !wasm.custom_sections.contains_key(".zdebug_info") => {
+            match find_debug_file(&file, &wasm.custom_sections, debug_search_path) {
+                Some(debug_file) => {
+                    debug_file_bytes = read_wasm_file(&debug_file, mmap)?;
+                    debug_file_sections = parse_custom_sections(&debug_file_bytes)?;
+                    &debug_file_sections
+                }
+                None => &wasm.custom_sections,
+            }
+        }
+        _ => &wasm.custom_sections,
+    };
+
+    let symbol_names = match wasm.custom_sections.get("linking") {
+        Some(linking) if relocatable_mode => Some(parse_linking_function_names(linking)?),
+        _ => None,
+    };
+    let mut dwarf = if let Some(symbol_names) = &symbol_names {
+        // Object files have no name section, so use the symbol names also as WebAssembly names.
+        for (idx, name) in symbol_names {
+            wasm_function_names.entry(*idx).or_insert_with(|| Arc::clone(name));
+        }
+        DwarfBinary::parse(dwarf_sections, None, dwarf_stats, true)?
+    } else {
+        // Only parse DWARF functions that can be matched up with a WebAssembly function below anyway.
+        let relative_offsets = wasm.functions.iter()
+            .map(|function| function.body.offset - code_section_offset)
+            .collect();
+        DwarfBinary::parse(dwarf_sections, Some(&relative_offsets), dwarf_stats, false)?
+    };
 
     let iter = 
         wasm.functions
         .into_iter()
 
-        // Match up WebAssembly functions with DWARF functions via their offsets.
+        // Match up WebAssembly functions with DWARF functions via their offsets (or symbol names).
         .filter_map(move |wasm| {
-            let relative_offset = wasm.body.offset - code_section_offset;
-            let has_dwarf = dwarf.relative_offsets_to_function_entries.remove(&relative_offset);
+            let has_dwarf = match &symbol_names {
+                Some(symbol_names) => symbol_names.get(&wasm.idx)
+                    .and_then(|name| dwarf.names_to_function_entries.remove(name)),
+                None => {
+                    let relative_offset = wasm.body.offset - code_section_offset;
+                    dwarf.relative_offsets_to_function_entries.remove(&relative_offset)
+                }
+            };
+            if let Some(stats) = dwarf_stats {
+                stats.wasm_functions.fetch_add(1, Ordering::Relaxed);
+                match has_dwarf {
+                    Some(_) => stats.functions_matched.fetch_add(1, Ordering::Relaxed),
+                    None => stats.functions_unmatched.fetch_add(1, Ordering::Relaxed),
+                };
+            }
             has_dwarf.map(|dwarf| (wasm, dwarf))
         })
         
         // Remove functions where the Wasm and DWARF types do not align.
-        .filter(|(wasm, dwarf)| {
-            let params_same_len = wasm.type_.params.len() == dwarf.params.len();
+        .filter_map(move |(wasm, mut dwarf)| {
+            let params = match align_params(wasm.type_.params.len(), std::mem::take(&mut dwarf.params)) {
+                Ok(Some(params)) => Ok(params),
+                Ok(None) => {
+                    if let Some(stats) = dwarf_stats {
+                        stats.functions_param_mismatch.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return None;
+                }
+                Err(err) => Err(err),
+            };
 
             // Originally, I wanted to remove also all samples where the return type does not align
             // (i.e., not both void xor both non-void). However, in quite many samples where the
@@ -50,68 +181,142 @@ pub fn extract_samples(file: &Path) -> anyhow::Result<
             // returns something that is neither declared in the source or debug info (example: 
             // maxtrax.o, function #13 _ZN5Audio7MaxTraxC2Eibtt). I think this is some compiler
             // optimization adding the this pointer as the return value of methods.
-            // Since we do not want to loose that many samples, we do NOT check/align returns here,
-            // (i.e., those functions are still included for their parameters), but DO check later
-            // when extracting return types (which we only do if both Wasm and DWARF have one set).
-            let return_same_len = 
-                // For now, we only support the WebAssembly MVP with a single return value.
-                (wasm.type_.returns.len() == 1 && dwarf.return_type.is_some())
-                || (wasm.type_.returns.len() == 0 && dwarf.return_type.is_none());
-            let return_same_len = true || return_same_len;
-
-            params_same_len && return_same_len
+            // Since we do not want to loose that many samples, by default we do NOT check/align
+            // returns here (i.e., those functions are still included for their parameters), but DO
+            // check later when extracting return types (which we only do if both Wasm and DWARF
+            // have one set). For experiments that want only functions where both agree, this
+            // check can be enabled with `require_return_alignment`.
+            let return_same_len =
+                // Multiple return values are aligned only later, see `split_return_type()`.
+                (!wasm.type_.returns.is_empty() && dwarf.return_type.is_some())
+                || (wasm.type_.returns.is_empty() && dwarf.return_type.is_none());
+
+            match require_return_alignment {
+                Some(samples_removed) if !return_same_len => {
+                    let param_samples = params.as_ref().map_or(0, |params| params.len());
+                    samples_removed.fetch_add(param_samples as u64, Ordering::SeqCst);
+                    None
+                }
+                _ => Some((wasm, dwarf, params)),
+            }
         })
 
-        .flat_map(move |(wasm_function, dwarf_function)| {
+        .flat_map(move |(wasm_function, dwarf_function, params)| {
             // I am not sure why I need to clone this here instead of just in the closure below?
             let file = Arc::clone(&file);
+            let file_sha256 = Arc::clone(&file_sha256);
 
             // Destructure wasm_function and dwarf_function to make borrowck happy for the closure below.
             let function_idx = wasm_function.idx;
 
             let function_name_wasm = wasm_function_names.remove(&function_idx);
             let function_name_dwarf = dwarf_function.name;
+            let demangled = |name: &Option<Arc<str>>| if demangle_names {
+                name.as_deref().map(|name| Arc::from(demangle(name)))
+            } else {
+                None
+            };
+            let function_name_wasm_demangled = demangled(&function_name_wasm);
+            let function_name_dwarf_demangled = demangled(&function_name_dwarf);
+            let excluded = match function_name_filter {
+                Some(filter) => !filter.keep(
+                    function_name_dwarf_demangled.as_deref()
+                        .or(function_name_dwarf.as_deref())
+                        .or(function_name_wasm_demangled.as_deref())
+                        .or(function_name_wasm.as_deref())
+                ),
+                None => false,
+            };
 
             let compilation_unit = dwarf_function.compilation_unit_name;
+            let producer = dwarf_function.producer;
+            let language = dwarf_function.language;
 
             let wasm_body = wasm_function.body;
+            let wasm_local_names = wasm_function.local_names;
+
+            let wasm_function_type = Arc::new(wasm_function.type_);
+            let wasm_params = &wasm_function_type.params;
+            let params_iter: Vec<_> = match params {
+                _ if excluded => Vec::new(),
+                Ok(params) => params.into_iter()
+                    .map(|(idx, dwarf)| -> gimli::Result<_> {
+                        // Prefer the parameter name from DWARF, fall back to the Wasm name section.
+                        let name = match dwarf.attr_str(DW_AT_name)? {
+                            Some(name) => Some(name),
+                            None => wasm_local_names.get(&idx).map(|name| Box::from(&**name)),
+                        };
+                        let decl_file = dwarf.decl_file()?.map(Arc::from);
+                        let decl_line = dwarf.decl_line()?;
+                        Ok((wasm_params[idx as usize], dwarf, ParamOrReturn::Param { idx, name }, (decl_file, decl_line)))
+                    })
+                    .collect(),
+                Err(err) => vec![Err(err)],
+            };
+
+            // Extract return type samples only if both WebAssembly and DWARF have a return type.
+            let function_decl = (dwarf_function.decl_file, dwarf_function.decl_line);
+            let wasm_returns = &wasm_function_type.returns;
+            let returns_iter: Vec<_> = match dwarf_function.return_type {
+                _ if excluded => Vec::new(),
+                Some(dwarf) if multi_value_returns && wasm_returns.len() > 1 => {
+                    match split_return_type(wasm_returns.len(), dwarf) {
+                        Ok(dwarf_returns) => wasm_returns.iter()
+                            .zip(dwarf_returns)
+                            .enumerate()
+                            .map(|(idx, (&wasm, dwarf))| Ok((wasm, dwarf, ParamOrReturn::Return { idx: idx as u32 }, function_decl.clone())))
+                            .collect(),
+                        Err(err) => vec![Err(err)],
+                    }
+                }
+                dwarf_return => wasm_returns.get(0).cloned()
+                    .zip(dwarf_return)
+                    .map(|(wasm, dwarf)| Ok((wasm, dwarf, ParamOrReturn::Return { idx: 0 }, function_decl)))
+                    .into_iter()
+                    .collect(),
+            };
 
-            let dwarf_params = dwarf_function.params;
-            // TODO Unfortunately, Box<[T]>::into_iter() does NOT move out of self (it only borrows 
-            // -,-), so go through a Vec instead, where into_iter() is correct and satisfies the 
-            // borrow checker (we cannot borrow wasm_function in the returned iterator).
-            let wasm_params = Vec::from(wasm_function.type_.params);
-
-            let params_iter = 
-                wasm_params.into_iter()
-                .zip_eq(dwarf_params.into_iter())
-                .enumerate()
-                .map(move |(idx, (wasm, dwarf))| -> gimli::Result<_> {
-                    let name = dwarf.attr_str(DW_AT_name)?;
-                    Ok((wasm, dwarf, ParamOrReturn::Param { idx: idx as u32, name }))
-                });
-
-            // Extract a return type sample only if both WebAssembly and DWARF have a return type.
-            let wasm_return = wasm_function.type_.returns.get(0).cloned();
-            let dwarf_return = dwarf_function.return_type;
-            let return_ = 
-                wasm_return
-                .zip(dwarf_return)
-                .map(|(wasm, dwarf)| 
-                    Ok((wasm, dwarf, ParamOrReturn::Return)));
+            // Local variables are stored in WebAssembly locals after the parameters.
+            let locals_iter: Vec<_> = match dwarf_function.variables {
+                _ if excluded || !include_locals => Vec::new(),
+                variables => {
+                    // If the locals cannot be parsed, the body is malformed, which is reported
+                    // when converting it to the WebAssembly representation anyway.
+                    let wasm_locals = wasm_body.locals().unwrap_or_default();
+                    match align_locals(wasm_params.len(), &wasm_locals, variables) {
+                        Ok(locals) => locals.into_iter()
+                            .map(|(idx, wasm, name, dwarf)| -> gimli::Result<_> {
+                                let decl_file = dwarf.decl_file()?.map(Arc::from);
+                                let decl_line = dwarf.decl_line()?;
+                                Ok((wasm, dwarf, ParamOrReturn::Local { idx, name: Some(name) }, (decl_file, decl_line)))
+                            })
+                            .collect(),
+                        Err(err) => vec![Err(err)],
+                    }
+                }
+            };
 
             let samples_iter = 
-                params_iter
-                .chain(return_);
+                params_iter.into_iter()
+                .chain(returns_iter)
+                .chain(locals_iter);
 
-            samples_iter.map_ok(move |(wasm, dwarf, param_or_return)| 
+            samples_iter.map_ok(move |(wasm, dwarf, param_or_return, (decl_file, decl_line))| 
                 WasmTypeSample {
                     file: Arc::clone(&file),
+                    file_sha256: Arc::clone(&file_sha256),
                     compilation_unit: compilation_unit.clone(),
+                    producer: producer.clone(),
+                    language,
                     function_idx,
                     function_name_wasm: function_name_wasm.clone(),
                     function_name_dwarf: function_name_dwarf.clone(),
+                    function_name_wasm_demangled: function_name_wasm_demangled.clone(),
+                    function_name_dwarf_demangled: function_name_dwarf_demangled.clone(),
+                    decl_file,
+                    decl_line,
                     wasm_type: wasm,
+                    wasm_function_type: Arc::clone(&wasm_function_type),
                     type_: dwarf,
                     wasm_body: wasm_body.clone(),
                     param_or_return,
@@ -122,3 +327,762 @@ pub fn extract_samples(file: &Path) -> anyhow::Result<
 
     Ok(iter)
 }
+
+/// Index of the WebAssembly parameter and the corresponding DWARF formal parameter.
+type AlignedParam<R> = (u32, DwarfEntry<R>);
+
+/// Map the DWARF formal parameters of a function to the indices of its WebAssembly parameters.
+/// By default, the parameters are matched by position, but this requires the same number of
+/// parameters in WebAssembly and DWARF, and breaks if the compiler reorders, removes, or splits
+/// parameters (e.g., adds a pointer for a returned struct). So if some DWARF parameters are stored
+/// directly in a Wasm parameter local (see `DwarfEntry::wasm_local_idx()`) that does not match its
+/// position, use only those parameters with their local index instead.
+/// Returns None if neither works, i.e., the function should be skipped.
+fn align_params<R: Reader>(wasm_param_count: usize, dwarf_params: Vec<DwarfEntry<R>>) -> gimli::Result<Option<Vec<AlignedParam<R>>>> {
+    let local_idxs: Vec<_> = dwarf_params.iter()
+        .map(|param| param.wasm_local_idx())
+        .try_collect()?;
+
+    let positional = local_idxs.iter()
+        .enumerate()
+        .all(|(position, local_idx)| local_idx.iter().all(|&local_idx| local_idx as usize == position));
+    if positional && wasm_param_count == dwarf_params.len() {
+        return Ok(Some(dwarf_params.into_iter()
+            .enumerate()
+            .map(|(idx, param)| (idx as u32, param))
+            .collect()));
+    }
+
+    let params: Vec<_> = dwarf_params.into_iter()
+        .zip(local_idxs)
+        .filter_map(|(param, local_idx)| Some((local_idx?, param)))
+        // Locals after the parameters are regular local variables of the function.
+        .filter(|(local_idx, _)| (*local_idx as usize) < wasm_param_count)
+        .collect();
+    let unique_locals = params.iter().map(|(local_idx, _)| local_idx).unique().count() == params.len();
+    if params.is_empty() || !unique_locals {
+        return Ok(None);
+    }
+    Ok(Some(params))
+}
+
+/// Index and WebAssembly type of a local, and the name and entry of its DWARF variable.
+type AlignedLocal<R> = (u32, wasmparser::Type, Box<str>, DwarfEntry<R>);
+
+/// Map the named DWARF local variables of a function to the WebAssembly locals they are stored
+/// in (see `DwarfEntry::wasm_local_idx()`), given the types of the declared locals (after the
+/// parameters, see `WasmBody::locals()`). Variables that are stored elsewhere (e.g., in the linear
+/// memory stack frame) or in a parameter local are skipped, as are locals that hold more than one
+/// variable (e.g., because the compiler reuses them for variables with disjoint lifetimes), since
+/// the type of their accesses is then ambiguous.
+fn align_locals<R: Reader>(wasm_param_count: usize, wasm_locals: &[(u32, wasmparser::Type)], variables: Vec<DwarfEntry<R>>) -> gimli::Result<Vec<AlignedLocal<R>>> {
+    let local_type = |local_idx: u32| {
+        let mut idx = (local_idx as usize).checked_sub(wasm_param_count)?;
+        for &(count, ty) in wasm_locals {
+            if idx < count as usize {
+                return Some(ty);
+            }
+            idx -= count as usize;
+        }
+        None
+    };
+
+    let mut locals = Vec::new();
+    for variable in variables {
+        // For concrete instances of inlined or generic functions, the name is on the abstract origin.
+        let name = match (variable.attr_str(DW_AT_name)?, variable.attr_entry(DW_AT_abstract_origin)?) {
+            (Some(name), _) => Some(name),
+            (None, Some(abstract_origin)) => abstract_origin.attr_str(DW_AT_name)?,
+            (None, None) => None,
+        };
+        if let (Some(name), Some(local_idx)) = (name, variable.wasm_local_idx()?) {
+            if let Some(ty) = local_type(local_idx) {
+                locals.push((local_idx, ty, name, variable));
+            }
+        }
+    }
+
+    let local_counts = locals.iter().map(|(local_idx, ..)| *local_idx).counts();
+    locals.retain(|(local_idx, ..)| local_counts[local_idx] == 1);
+    Ok(locals)
+}
+
+/// Split the DWARF return type of a function that returns multiple WebAssembly values into the
+/// types of the individual values. Currently, this works only for structs with exactly one member
+/// per returned value (e.g., as returned by clang with the experimental multi-value ABI).
+/// Returns an empty Vec if the return type cannot be split up that way.
+fn split_return_type<R: Reader>(wasm_return_count: usize, mut type_: DwarfEntry<R>) -> gimli::Result<Vec<DwarfEntry<R>>> {
+    // Look through typedefs and qualifiers to the actual struct type.
+    while [DW_TAG_typedef, DW_TAG_const_type, DW_TAG_volatile_type].contains(&type_.tag) {
+        type_ = match type_.attr_entry(DW_AT_type)? {
+            Some(type_) => type_,
+            None => return Ok(Vec::new()),
+        };
+    }
+    if type_.tag != DW_TAG_structure_type {
+        return Ok(Vec::new());
+    }
+
+    let mut member_types = Vec::new();
+    for member in type_.children()? {
+        let member = member?;
+        if member.tag == DW_TAG_member {
+            match member.attr_entry(DW_AT_type)? {
+                Some(member_type) => member_types.push(member_type),
+                None => return Ok(Vec::new()),
+            }
+        }
+    }
+    if member_types.len() != wasm_return_count {
+        return Ok(Vec::new());
+    }
+    Ok(member_types)
+}
+
+/// Find a separate debug file for `file`, with the DWARF sections that were stripped from it.
+/// Candidates are (in this order) the file given in the `external_debug_info` custom section
+/// (e.g., emitted by Emscripten with -gseparate-dwarf) and the conventional `foo.debug.wasm` for
+/// `foo.wasm`. Each candidate is searched for relative to the directory of `file` first, then (by
+/// its filename only) in all directories of `debug_search_path`.
+fn find_debug_file(file: &Path, custom_sections: &HashMap<&str, Rc<[u8]>>, debug_search_path: &[PathBuf]) -> Option<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Some(data) = custom_sections.get("external_debug_info") {
+        // The section contains a single string, the path or URL of the debug file.
+        if let Ok(path) = wasmparser::BinaryReader::new(data).read_string() {
+            candidates.push(PathBuf::from(path.trim_start_matches("file://")));
+        }
+    }
+    if let Some(stem) = file.file_stem() {
+        let mut name = stem.to_os_string();
+        name.push(".debug.wasm");
+        candidates.push(PathBuf::from(name));
+    }
+
+    let file_dir = file.parent().unwrap_or_else(|| Path::new(""));
+    candidates.into_iter()
+        .flat_map(|candidate| {
+            let in_search_path = candidate.file_name()
+                .map(|name| debug_search_path.iter().map(|dir| dir.join(name)).collect())
+                .unwrap_or_else(Vec::new);
+            std::iter::once(file_dir.join(&candidate)).chain(in_search_path)
+        })
+        .find(|path| path.is_file() && path != file)
+}
+
+#[cfg(test)]
+mod tests {
+    use gimli::constants::*;
+
+    use super::*;
+    use crate::dwarf::test_util::*;
+    use crate::wasm::test_util::*;
+
+    #[test]
+    fn test_param_name_fallback_to_name_section() {
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32, I32], &[]);
+        // local.get 0, local.get 1, drop, drop
+        let function = module.function(ty, &[], &[0x20, 0x00, 0x20, 0x01, 0x1a, 0x1a]);
+        let low_pc = module.code_offsets()[0];
+
+        // First parameter has a name in DWARF and the name section, second only in the name section.
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let subprogram = add_subprogram(unit, Some("f"), low_pc);
+            add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("dwarf_a"), int);
+            add_typed(unit, subprogram, DW_TAG_formal_parameter, None, int);
+        });
+        add_dwarf_sections(&mut module, &sections);
+        module.custom_section("name", &name_section(&[], &[(function, &[(0, "wasm_a"), (1, "wasm_b")])]));
+
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "param_name_fallback.wasm", &module.build());
+        let names: Vec<_> = extract_samples(&file, &[], &ExtractOptions::default()).unwrap()
+            .map(|sample| match sample.unwrap().param_or_return {
+                ParamOrReturn::Param { name, .. } => name,
+                _ => unreachable!("function has no return type and locals are not included"),
+            })
+            .collect();
+        assert_eq!(names, vec![Some("dwarf_a".into()), Some("wasm_b".into())]);
+    }
+
+    #[test]
+    fn test_param_idx_from_dwarf_location() {
+        use gimli::write::{AttributeValue, Expression};
+
+        let mut module = ModuleBuilder::new();
+        // Two Wasm parameters, e.g., because the compiler added a pointer for the returned struct.
+        let ty = module.func_type(&[I32, F64], &[]);
+        // local.get 1, drop
+        module.function(ty, &[], &[0x20, 0x01, 0x1a]);
+        let low_pc = module.code_offsets()[0];
+
+        // But only a single parameter in DWARF, which is located in Wasm local 1 (DW_OP_WASM_location 0x0 0x1).
+        let sections = dwarf_sections(|unit| {
+            let double = add_base_type(unit, "double", DW_ATE_float, 8);
+            let subprogram = add_subprogram(unit, Some("f"), low_pc);
+            let param = add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("x"), double);
+            unit.get_mut(param).set(DW_AT_location, AttributeValue::Exprloc(Expression::raw(vec![0xed, 0x00, 0x01])));
+        });
+        add_dwarf_sections(&mut module, &sections);
+
+        let samples: Vec<_> = extract_samples_from_bytes(&module.build(), false).unwrap()
+            .into_iter()
+            .map(|sample| (format!("{:?}", sample.param_or_return), sample.wasm_type))
+            .collect();
+        assert_eq!(samples, vec![(format!("{:?}", ParamOrReturn::Param { idx: 1, name: Some("x".into()) }), wasmparser::Type::F64)]);
+    }
+
+    #[test]
+    fn test_include_locals() {
+        use gimli::write::{AttributeValue, Expression};
+        use crate::samples::types::parse::ParseOptions;
+        use crate::samples::types::Type;
+
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32], &[]);
+        // (local i32 f64) local.get 0, local.set 1, local.get 1, drop
+        module.function(ty, &[(1, I32), (1, F64)], &[0x20, 0x00, 0x21, 0x01, 0x20, 0x01, 0x1a]);
+        let low_pc = module.code_offsets()[0];
+
+        // void f(int x) { { unsigned count = x; double spilled; } }
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let uint = add_base_type(unit, "unsigned int", DW_ATE_unsigned, 4);
+            let double = add_base_type(unit, "double", DW_ATE_float, 8);
+            let subprogram = add_subprogram(unit, Some("f"), low_pc);
+            add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("x"), int);
+            let block = add(unit, subprogram, DW_TAG_lexical_block, None);
+            // In Wasm local 1 (DW_OP_WASM_location 0x0 0x1).
+            let count = add_typed(unit, block, DW_TAG_variable, Some("count"), uint);
+            unit.get_mut(count).set(DW_AT_location, AttributeValue::Exprloc(Expression::raw(vec![0xed, 0x00, 0x01])));
+            // In the linear memory stack frame (DW_OP_fbreg 8), so skipped.
+            let spilled = add_typed(unit, block, DW_TAG_variable, Some("spilled"), double);
+            unit.get_mut(spilled).set(DW_AT_location, AttributeValue::Exprloc(Expression::raw(vec![0x91, 0x08])));
+        });
+        add_dwarf_sections(&mut module, &sections);
+        let bytes = module.build();
+
+        let samples = |include_locals| -> Vec<_> {
+            extract_samples_with_file(&bytes, Arc::from(Path::new("<memory>")), None, None, &ExtractOptions { include_locals, ..ExtractOptions::default() }).unwrap()
+                .map(|sample| {
+                    let sample = sample.unwrap();
+                    let type_ = Type::parse_param(&sample.type_, &ParseOptions::default()).unwrap();
+                    (format!("{:?}", sample.param_or_return), sample.wasm_type, type_.to_string())
+                })
+                .collect()
+        };
+        assert_eq!(samples(false).len(), 1);
+        assert_eq!(samples(true), vec![
+            (format!("{:?}", ParamOrReturn::Param { idx: 0, name: Some("x".into()) }), wasmparser::Type::I32, "primitive int32_t".to_string()),
+            (format!("{:?}", ParamOrReturn::Local { idx: 1, name: Some("count".into()) }), wasmparser::Type::I32, "primitive uint32_t".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_relocatable_mode() {
+        // Object file with `void f(int x)` and `void g(int a, int b)`, whose DWARF low_pc are both
+        // 0x0, since relocations are not applied yet.
+        let mut module = ModuleBuilder::new();
+        let f_ty = module.func_type(&[I32], &[]);
+        let g_ty = module.func_type(&[I32, I32], &[]);
+        // local.get 0, drop
+        module.function(f_ty, &[], &[0x20, 0x00, 0x1a]);
+        // local.get 0, local.get 1, drop, drop
+        module.function(g_ty, &[], &[0x20, 0x00, 0x20, 0x01, 0x1a, 0x1a]);
+        module.custom_section("linking", &linking_section(&[(0, "f"), (1, "g")]));
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let f = add_subprogram(unit, Some("f"), 0);
+            add_typed(unit, f, DW_TAG_formal_parameter, Some("x"), int);
+            let g = add_subprogram(unit, Some("g"), 0);
+            add_typed(unit, g, DW_TAG_formal_parameter, Some("a"), int);
+            add_typed(unit, g, DW_TAG_formal_parameter, Some("b"), int);
+        });
+        add_dwarf_sections(&mut module, &sections);
+        let bytes = module.build();
+
+        let samples = |relocatable_mode| -> Vec<_> {
+            extract_samples_with_file(&bytes, Arc::from(Path::new("<memory>")), None, None, &ExtractOptions { relocatable_mode, ..ExtractOptions::default() }).unwrap()
+                .map(|sample| {
+                    let sample = sample.unwrap();
+                    let param_name = match sample.param_or_return {
+                        ParamOrReturn::Param { name, .. } => name,
+                        _ => unreachable!("functions have only parameters"),
+                    };
+                    (sample.function_name_wasm, sample.function_name_dwarf, param_name)
+                })
+                .collect()
+        };
+        // By offset, the inconsistent DWARF functions at 0x0 are dropped, and none would match anyway.
+        assert_eq!(samples(false), vec![]);
+        assert_eq!(samples(true), vec![
+            (Some("f".into()), Some("f".into()), Some("x".into())),
+            (Some("g".into()), Some("g".into()), Some("a".into())),
+            (Some("g".into()), Some("g".into()), Some("b".into())),
+        ]);
+    }
+
+    #[test]
+    fn test_multi_value_returns() {
+        let mut module = ModuleBuilder::new();
+        // (func (result i32 i32) i32.const 1 i32.const 2)
+        let ty = module.func_type(&[], &[I32, I32]);
+        module.function(ty, &[], &[0x41, 0x01, 0x41, 0x02]);
+        let low_pc = module.code_offsets()[0];
+
+        // struct pair { int a; unsigned b; } f();
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let unsigned = add_base_type(unit, "unsigned int", DW_ATE_unsigned, 4);
+            let root = unit.root();
+            let pair = add(unit, root, DW_TAG_structure_type, Some("pair"));
+            add_typed(unit, pair, DW_TAG_member, Some("a"), int);
+            add_typed(unit, pair, DW_TAG_member, Some("b"), unsigned);
+            let subprogram = add_subprogram(unit, Some("f"), low_pc);
+            unit.get_mut(subprogram).set(DW_AT_type, gimli::write::AttributeValue::UnitRef(pair));
+        });
+        add_dwarf_sections(&mut module, &sections);
+        let bytes = module.build();
+
+        let samples = |multi_value_returns| extract_samples_from_bytes(&bytes, multi_value_returns).unwrap()
+            .into_iter()
+            .map(|sample| (format!("{:?}", sample.param_or_return), sample.type_.attr_str(DW_AT_name).unwrap()))
+            .collect::<Vec<_>>();
+
+        // By default, only the first value is a sample, with the whole struct as its type.
+        assert_eq!(samples(false), vec![("Return { idx: 0 }".to_string(), Some("pair".into()))]);
+        assert_eq!(samples(true), vec![
+            ("Return { idx: 0 }".to_string(), Some("int".into())),
+            ("Return { idx: 1 }".to_string(), Some("unsigned int".into())),
+        ]);
+    }
+
+    #[test]
+    fn test_producer_in_info_output() {
+        use crate::options::OutputFormat;
+        use crate::samples::types::parse::ParseOptions;
+        use crate::samples::types::Type;
+        use crate::samples::wasm_repr::{ReprOptions, WasmRepr};
+        use crate::util::sample_writer::SampleWriter;
+
+        let bytes = int_param_module(1, |unit| {
+            let root = unit.root();
+            unit.get_mut(root).set(DW_AT_producer, gimli::write::AttributeValue::String(b"clang version 14.0.0".to_vec()));
+        });
+
+        let dir = temp_dir();
+        let mut sample_writer = SampleWriter::create_files(dir.path(), false, OutputFormat::Opennmt, false, false).unwrap();
+        for sample in extract_samples_from_bytes(&bytes, false).unwrap() {
+            let type_ = Type::parse_param(&sample.type_, &ParseOptions::default()).unwrap();
+            let wasm = WasmRepr::new_full(&sample, &sample.wasm_body.decode().unwrap(), &ReprOptions { with_type: false, ..ReprOptions::default() }).unwrap();
+            sample_writer.write(&sample.with_type(type_).with_wasm_body(wasm).with_aux(None)).unwrap();
+        }
+        sample_writer.bytes_written().unwrap();
+
+        let info = std::fs::read_to_string(dir.path().join("param/info.jsonl")).unwrap();
+        let info: serde_json::Value = serde_json::from_str(info.trim()).unwrap();
+        assert_eq!(info["producer"], "clang version 14.0.0");
+    }
+
+    #[test]
+    fn test_only_languages() {
+        use crate::dwarf::parse::language_in;
+
+        let languages = |language| {
+            let bytes = int_param_module(1, |unit| {
+                let root = unit.root();
+                unit.get_mut(root).set(DW_AT_language, gimli::write::AttributeValue::Language(language));
+            });
+            extract_samples_from_bytes(&bytes, false).unwrap()
+                .into_iter()
+                .map(|sample| sample.language)
+                .collect::<Vec<_>>()
+        };
+        let cpp = languages(DW_LANG_C_plus_plus_14);
+        let rust = languages(DW_LANG_Rust);
+        assert_eq!(cpp, vec![Some("cpp14")]);
+        assert_eq!(rust, vec![Some("rust")]);
+
+        let only_languages = ["c".to_string(), "cpp".to_string()];
+        assert!(language_in(cpp[0], &only_languages));
+        assert!(!language_in(rust[0], &only_languages));
+        assert!(!language_in(None, &only_languages));
+        // No filter keeps all languages.
+        assert!(language_in(rust[0], &[]));
+    }
+
+    #[test]
+    fn test_gzip_compressed() {
+        use std::io::Write;
+
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        use crate::options::SignatureMode;
+        use crate::wasm::binary_stats::WasmBinaryStats;
+        use crate::wasm::magic_bytes::is_wasm_by_magic_bytes;
+
+        let (mut module, sections) = module_and_dwarf();
+        add_dwarf_sections(&mut module, &sections);
+        let bytes = module.build();
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&bytes).unwrap();
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "gzip_compressed.wasm", &bytes);
+        let file_gz = write_temp_file(&dir, "gzip_compressed.wasm.gz", &encoder.finish().unwrap());
+
+        assert!(is_wasm_by_magic_bytes(&file_gz).unwrap());
+        assert_eq!(WasmBinaryStats::from_file(&file, false, SignatureMode::Names).unwrap().file_sha256, WasmBinaryStats::from_file(&file_gz, false, SignatureMode::Names).unwrap().file_sha256);
+
+        let samples = |file| extract_samples(file, &[], &ExtractOptions::default()).unwrap()
+            .map(|sample| {
+                let sample = sample.unwrap();
+                (sample.function_idx, format!("{:?}", sample.param_or_return), sample.wasm_body.bytes, sample.type_.attr_str(DW_AT_name).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let expected = samples(&file);
+        assert_eq!(expected.len(), 2);
+        assert_eq!(samples(&file_gz), expected);
+    }
+
+    #[test]
+    fn test_zlib_compressed_dwarf_sections() {
+        use std::io::Write;
+
+        use flate2::write::ZlibEncoder;
+        use flate2::Compression;
+
+        let (module, sections) = module_and_dwarf();
+        let samples = |sections: &HashMap<&str, Rc<[u8]>>| {
+            let mut module = module.clone();
+            add_dwarf_sections(&mut module, sections);
+            extract_samples_from_bytes(&module.build(), false).unwrap()
+                .into_iter()
+                .map(|sample| (sample.function_idx, format!("{:?}", sample.param_or_return), sample.type_.attr_str(DW_AT_name).unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let expected = samples(&sections);
+        assert_eq!(expected.len(), 2);
+
+        let compress = |data: &[u8]| -> Rc<[u8]> {
+            let mut compressed = b"ZLIB".to_vec();
+            compressed.extend_from_slice(&(data.len() as u64).to_be_bytes());
+            let mut encoder = ZlibEncoder::new(compressed, Compression::default());
+            encoder.write_all(data).unwrap();
+            Rc::from(encoder.finish().unwrap())
+        };
+
+        // Compressed sections with the regular .debug_* names, ...
+        let compressed: HashMap<&str, Rc<[u8]>> = sections.iter()
+            .map(|(name, data)| (*name, compress(data)))
+            .collect();
+        assert_eq!(samples(&compressed), expected);
+
+        // ... and with the .zdebug_* names.
+        let names: HashMap<&str, String> = sections.keys()
+            .map(|name| (*name, format!(".z{}", &name[1..])))
+            .collect();
+        let compressed: HashMap<&str, Rc<[u8]>> = sections.iter()
+            .map(|(name, data)| (names[name].as_str(), compress(data)))
+            .collect();
+        assert_eq!(samples(&compressed), expected);
+    }
+
+    /// Module with a single function `int f(int x)`, and the DWARF sections for it.
+    fn module_and_dwarf() -> (ModuleBuilder, HashMap<&'static str, Rc<[u8]>>) {
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32], &[I32]);
+        // local.get 0
+        module.function(ty, &[], &[0x20, 0x00]);
+        let low_pc = module.code_offsets()[0];
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let subprogram = add_subprogram(unit, Some("f"), low_pc);
+            unit.get_mut(subprogram).set(DW_AT_type, gimli::write::AttributeValue::UnitRef(int));
+            add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("x"), int);
+        });
+        (module, sections)
+    }
+
+    fn function_names(file: &Path, debug_search_path: &[PathBuf]) -> Vec<Option<Arc<str>>> {
+        extract_samples(file, debug_search_path, &ExtractOptions::default()).unwrap()
+            .map(|sample| sample.unwrap().function_name_dwarf)
+            .collect()
+    }
+
+    #[test]
+    fn test_separate_debug_file_next_to_binary() {
+        let (module, sections) = module_and_dwarf();
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "separate_debug.wasm", &module.build());
+        assert!(function_names(&file, &[]).is_empty());
+
+        // Like Emscripten's -gseparate-dwarf, the debug file is the full binary with DWARF sections.
+        let mut debug_module = module;
+        add_dwarf_sections(&mut debug_module, &sections);
+        write_temp_file(&dir, "separate_debug.debug.wasm", &debug_module.build());
+        assert_eq!(function_names(&file, &[]), vec![Some("f".into()), Some("f".into())]);
+    }
+
+    #[test]
+    fn test_separate_debug_file_in_search_path() {
+        let (mut module, sections) = module_and_dwarf();
+
+        // Debug file with only the DWARF sections, in another directory.
+        let mut debug_module = ModuleBuilder::new();
+        add_dwarf_sections(&mut debug_module, &sections);
+        let dir = temp_dir();
+        let debug_file = write_temp_file(&dir, "search_path/external.debug", &debug_module.build());
+
+        let mut external_debug_info = Vec::new();
+        leb128_u32(&mut external_debug_info, "external.debug".len() as u32);
+        external_debug_info.extend_from_slice(b"external.debug");
+        module.custom_section("external_debug_info", &external_debug_info);
+        let file = write_temp_file(&dir, "external_debug_info.wasm", &module.build());
+
+        assert!(function_names(&file, &[]).is_empty());
+        let search_path = [debug_file.parent().unwrap().to_owned()];
+        assert_eq!(function_names(&file, &search_path), vec![Some("f".into()), Some("f".into())]);
+    }
+
+    #[test]
+    fn test_require_return_alignment() {
+        let mut module = ModuleBuilder::new();
+        // The Wasm function returns its parameter (e.g., the this pointer of a method)...
+        let ty = module.func_type(&[I32], &[I32]);
+        // local.get 0
+        module.function(ty, &[], &[0x20, 0x00]);
+        let low_pc = module.code_offsets()[0];
+
+        // ...whereas the source function is void.
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let subprogram = add_subprogram(unit, Some("f"), low_pc);
+            add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("a"), int);
+        });
+        add_dwarf_sections(&mut module, &sections);
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "require_return_alignment.wasm", &module.build());
+
+        // By default, the function is kept for its parameter.
+        let samples: Vec<_> = extract_samples(&file, &[], &ExtractOptions::default()).unwrap().try_collect().unwrap();
+        assert_eq!(samples.len(), 1);
+        assert!(matches!(samples[0].param_or_return, ParamOrReturn::Param { idx: 0, .. }));
+
+        let samples_removed = AtomicU64::new(0);
+        let samples: Vec<_> = extract_samples(&file, &[], &ExtractOptions { require_return_alignment: Some(&samples_removed), ..ExtractOptions::default() }).unwrap().try_collect().unwrap();
+        assert!(samples.is_empty());
+        assert_eq!(samples_removed.into_inner(), 1);
+    }
+
+    #[test]
+    fn test_function_name_filter() {
+        // Functions named in DWARF, only in the name section, and not at all.
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32], &[]);
+        let mut functions = Vec::new();
+        for _ in 0..4 {
+            // local.get 0, drop
+            functions.push(module.function(ty, &[], &[0x20, 0x00, 0x1a]));
+        }
+        let low_pcs = module.code_offsets();
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            for (&low_pc, name) in low_pcs.iter().zip(&[Some("lib_open"), Some("__cxx_global_var_init"), None, None]) {
+                let subprogram = add_subprogram(unit, *name, low_pc);
+                add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("x"), int);
+            }
+        });
+        add_dwarf_sections(&mut module, &sections);
+        module.custom_section("name", &name_section(&[(functions[1], "wasm_name"), (functions[2], "lib_close")], &[]));
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "function_name_filter.wasm", &module.build());
+
+        let functions = |include: Option<&str>, exclude: Option<&str>| {
+            let filter = FunctionNameFilter {
+                include: include.map(|regex| Regex::new(regex).unwrap()),
+                exclude: exclude.map(|regex| Regex::new(regex).unwrap()),
+                ..FunctionNameFilter::default()
+            };
+            let function_idxs = extract_samples(&file, &[], &ExtractOptions { function_name_filter: Some(&filter), ..ExtractOptions::default() }).unwrap()
+                .map(|sample| sample.unwrap().function_idx)
+                .collect_vec();
+            (function_idxs, filter.functions_excluded.into_inner())
+        };
+
+        assert_eq!(functions(None, None), (vec![0, 1, 2, 3], 0));
+        // The DWARF name takes precedence over the name section, unnamed functions are kept...
+        assert_eq!(functions(None, Some("^__cxx_global_")), (vec![0, 2, 3], 1));
+        assert_eq!(functions(None, Some("wasm_name")), (vec![0, 1, 2, 3], 0));
+        // ...unless there is an include pattern.
+        assert_eq!(functions(Some("^lib_"), None), (vec![0, 2], 2));
+        assert_eq!(functions(Some("^lib_"), Some("close$")), (vec![0], 3));
+    }
+
+    #[test]
+    fn test_demangle_function_names() {
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32], &[]);
+        // local.get 0, drop
+        let function = module.function(ty, &[], &[0x20, 0x00, 0x1a]);
+        let low_pc = module.code_offsets()[0];
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let subprogram = add_subprogram(unit, Some("bar"), low_pc);
+            add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("x"), int);
+        });
+        add_dwarf_sections(&mut module, &sections);
+        module.custom_section("name", &name_section(&[(function, "_ZN3foo3barEi")], &[]));
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "demangle_function_names.wasm", &module.build());
+
+        let sample = |demangle| extract_samples(&file, &[], &ExtractOptions { demangle, ..ExtractOptions::default() }).unwrap()
+            .map(|sample| sample.unwrap())
+            .next();
+
+        let raw = sample(false).unwrap();
+        assert_eq!(raw.function_name_wasm.as_deref(), Some("_ZN3foo3barEi"));
+        assert_eq!(raw.function_name_wasm_demangled, None);
+        let demangled = sample(true).unwrap();
+        assert_eq!(demangled.function_name_wasm.as_deref(), Some("_ZN3foo3barEi"));
+        assert_eq!(demangled.function_name_wasm_demangled.as_deref(), Some("foo::bar(int)"));
+        // Already unmangled in DWARF.
+        assert_eq!(demangled.function_name_dwarf_demangled.as_deref(), Some("bar"));
+    }
+
+    #[test]
+    fn test_dwarf_stats() {
+        let (mut module, sections) = module_and_dwarf();
+        add_dwarf_sections(&mut module, &sections);
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "dwarf_stats.wasm", &module.build());
+
+        let stats = DwarfStats::default();
+        let samples: Vec<_> = extract_samples(&file, &[], &ExtractOptions { dwarf_stats: Some(&stats), ..ExtractOptions::default() }).unwrap().try_collect().unwrap();
+        assert_eq!(samples.len(), 2);
+        assert!(stats.entries.load(Ordering::SeqCst) > 0);
+        assert_eq!(stats.compilation_units.into_inner(), 1);
+        assert_eq!(stats.subprograms.into_inner(), 1);
+        assert_eq!(stats.subprograms_with_location.into_inner(), 1);
+        assert_eq!(stats.functions_matched.into_inner(), 1);
+        assert_eq!(stats.functions_unmatched.into_inner(), 0);
+    }
+
+    #[test]
+    fn test_function_match_stats() {
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32], &[]);
+        for _ in 0..4 {
+            // local.get 0, drop
+            module.function(ty, &[], &[0x20, 0x00, 0x1a]);
+        }
+        let low_pcs = module.code_offsets();
+        // Function 0 matches, function 1 has no DWARF, function 2 and 3 have two DWARF parameters
+        // for a single WebAssembly parameter.
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let f = add_subprogram(unit, Some("f"), low_pcs[0]);
+            add_typed(unit, f, DW_TAG_formal_parameter, Some("a"), int);
+            for (&low_pc, name) in low_pcs[2..].iter().zip(&["g", "h"]) {
+                let function = add_subprogram(unit, Some(name), low_pc);
+                add_typed(unit, function, DW_TAG_formal_parameter, Some("a"), int);
+                add_typed(unit, function, DW_TAG_formal_parameter, Some("b"), int);
+            }
+        });
+        add_dwarf_sections(&mut module, &sections);
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "function_match_stats.wasm", &module.build());
+
+        let stats = DwarfStats::default();
+        let samples: Vec<_> = extract_samples(&file, &[], &ExtractOptions { dwarf_stats: Some(&stats), ..ExtractOptions::default() }).unwrap().try_collect().unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].function_name_dwarf.as_deref(), Some("f"));
+
+        let wasm_functions = stats.wasm_functions.into_inner();
+        let matched = stats.functions_matched.into_inner();
+        assert_eq!(wasm_functions, 4);
+        assert_eq!(matched, 3);
+        assert_eq!(stats.functions_unmatched.into_inner(), wasm_functions - matched);
+        assert_eq!(stats.functions_param_mismatch.into_inner(), 2);
+    }
+
+    #[test]
+    fn test_decl_location() {
+        use gimli::write::{AttributeValue, LineProgram, LineString};
+        use gimli::LineEncoding;
+
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32], &[I32]);
+        // local.get 0
+        module.function(ty, &[], &[0x20, 0x00]);
+        let low_pc = module.code_offsets()[0];
+
+        // src/a.c:
+        // 10 int f(
+        // 11     int a) { ... }
+        let sections = dwarf_sections(|unit| {
+            let string = |str: &str| LineString::String(str.as_bytes().to_vec());
+            unit.line_program = LineProgram::new(unit.encoding(), LineEncoding::default(), string("/work"), string("src/a.c"), None);
+            let directory = unit.line_program.add_directory(string("src"));
+            let file = unit.line_program.add_file(string("a.c"), directory, None);
+
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let subprogram = add_subprogram(unit, Some("f"), low_pc);
+            unit.get_mut(subprogram).set(DW_AT_type, AttributeValue::UnitRef(int));
+            unit.get_mut(subprogram).set(DW_AT_decl_file, AttributeValue::FileIndex(Some(file)));
+            unit.get_mut(subprogram).set(DW_AT_decl_line, AttributeValue::Udata(10));
+            let param = add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("a"), int);
+            unit.get_mut(param).set(DW_AT_decl_file, AttributeValue::FileIndex(Some(file)));
+            unit.get_mut(param).set(DW_AT_decl_line, AttributeValue::Udata(11));
+        });
+        add_dwarf_sections(&mut module, &sections);
+
+        let locations: Vec<_> = extract_samples_from_bytes(&module.build(), false).unwrap()
+            .into_iter()
+            .map(|sample| (sample.decl_file, sample.decl_line))
+            .collect();
+        assert_eq!(locations, vec![
+            // Parameter, then return type sample.
+            (Some(Arc::from("src/a.c")), Some(11)),
+            (Some(Arc::from("src/a.c")), Some(10)),
+        ]);
+    }
+
+    #[test]
+    fn test_stable_ids() {
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32, I32], &[I32]);
+        for _ in 0..2 {
+            // local.get 0, local.get 1, i32.add
+            module.function(ty, &[], &[0x20, 0x00, 0x20, 0x01, 0x6a]);
+        }
+        let low_pcs = module.code_offsets();
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            for (&low_pc, name) in low_pcs.iter().zip(&["f", "g"]) {
+                let function = add_subprogram(unit, Some(name), low_pc);
+                unit.get_mut(function).set(DW_AT_type, gimli::write::AttributeValue::UnitRef(int));
+                add_typed(unit, function, DW_TAG_formal_parameter, Some("a"), int);
+                add_typed(unit, function, DW_TAG_formal_parameter, Some("b"), int);
+            }
+        });
+        add_dwarf_sections(&mut module, &sections);
+        let bytes = module.build();
+
+        let ids = |file: &Path| -> Vec<String> {
+            extract_samples(file, &[], &ExtractOptions::default()).unwrap()
+                .map_ok(|sample| sample.id())
+                .try_collect().unwrap()
+        };
+        let dir = temp_dir();
+        let ids_first = ids(&write_temp_file(&dir, "stable_ids.wasm", &bytes));
+        // Same binary under a different path, i.e., in another run or on another machine.
+        let ids_second = ids(&write_temp_file(&dir, "stable_ids_copy.wasm", &bytes));
+        assert_eq!(ids_first.len(), 6);
+        assert_eq!(ids_first, ids_second);
+        // Different samples, also of functions with the same body, have different IDs.
+        assert_eq!(ids_first.iter().unique().count(), ids_first.len());
+    }
+}
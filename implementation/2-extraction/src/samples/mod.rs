@@ -2,3 +2,8 @@ pub mod extract;
 pub mod types;
 pub mod wasm_repr;
 pub mod sample;
+pub mod split;
+pub mod balance;
+pub mod pipeline;
+pub mod baseline;
+pub mod complexity;
@@ -0,0 +1,785 @@
+//! The per-binary part of the extraction: extract samples from a binary, filter them, convert them
+//! to the WebAssembly input representation and our type language, and simplify the types.
+//! Used by the command-line application (which adds deduplication, subsampling, statistics, and
+//! writing to files on top), but also usable directly as a library, see `render_samples_from_dir()`
+//! and `render_samples_from_bytes()`.
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chashmap::CHashMap;
+use itertools::{Either, Itertools};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+use wasmparser::Operator;
+
+use crate::dwarf::parse::{language_in, DwarfStats};
+use crate::options::{ConstMode, HashIncludeType, IntGranularity, TypeNotation, Typedefs, WasmRepr as WasmReprKind, WindowAnchor, WindowOrder};
+use crate::samples::extract::{extract_samples_with_file, ExtractOptions, ExtractedSample, FunctionNameFilter};
+use crate::samples::sample::{ParamOrReturn, WasmTypeSample};
+use crate::samples::split::TrainDevTest;
+use crate::samples::types::parse::ParseOptions;
+use crate::samples::types::{PrimitiveTypeCounts, Type, TypeToken};
+use crate::samples::wasm_repr::{ReprOptions, WasmRepr, WindowParams};
+use crate::util::cmultimap::CMultiMap;
+use crate::util::file_error::{FileError, ResultWithFile};
+use crate::util::manifest::to_hex;
+use crate::util::sample_writer::SampleInfo;
+use crate::wasm::magic_bytes::{is_wasm_by_magic_bytes, read_wasm_file};
+use crate::wasm::parse::{uses_local, WasmBody};
+
+/// A sample after the whole per-binary pipeline, i.e., as it is written to the output files.
+pub type ProcessedSample = WasmTypeSample<WasmRepr, Type, Option<TrainDevTest>>;
+
+/// Options of the per-binary pipeline, independent of the command line. The fields correspond to
+/// the command-line options of the same name (see `Options::pipeline_options()`), and the
+/// defaults are the same as on the command line (with the 'full' WebAssembly representation).
+/// Options for rendering the WebAssembly input and for parsing types are grouped, see
+/// `ReprOptions` and `ParseOptions`.
+#[derive(Debug)]
+pub struct PipelineOptions {
+    pub debug_search_path: Vec<PathBuf>,
+    pub multi_value_returns: bool,
+    pub include_locals: bool,
+    pub relocatable_mode: bool,
+    pub mmap_input: bool,
+    pub require_return_alignment: bool,
+    pub demangle: bool,
+    pub function_name_filter: Option<FunctionNameFilter>,
+    pub only_languages: Vec<String>,
+
+    pub wasm_repr: WasmReprKind,
+    pub wasm_repr_options: ReprOptions,
+    pub wasm_filter_unused_param: bool,
+    pub hash_include_type: HashIncludeType,
+    pub wasm_window_overlap_threshold: f64,
+    pub wasm_window_order: WindowOrder,
+    pub wasm_window_anchor: WindowAnchor,
+    pub wasm_max_windows: Option<usize>,
+    pub wasm_max_tokens: Option<usize>,
+
+    pub type_parse_options: ParseOptions,
+    pub type_filter_unknown: bool,
+    pub type_remove_names: bool,
+    /// Keep only these typedef and nominal names, see `--type-keep-name-list`.
+    pub keep_name_list: Option<HashSet<Box<str>>>,
+    pub type_typedefs: Typedefs,
+    pub type_name_flatten_outermost: bool,
+    pub type_const_mode: ConstMode,
+    pub type_int_granularity: IntGranularity,
+    pub type_max_pointer_depth: Option<usize>,
+    pub type_class_to_struct: bool,
+    pub type_struct_to_class: bool,
+    pub type_specialize_pointers: bool,
+    pub type_notation: TypeNotation,
+    pub type_explicit_end: bool,
+
+    pub max_samples_per_binary: Option<usize>,
+    pub rand_seed: u64,
+    pub seed_per_binary: bool,
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        Self {
+            debug_search_path: Vec::new(),
+            multi_value_returns: false,
+            include_locals: false,
+            relocatable_mode: false,
+            mmap_input: false,
+            require_return_alignment: false,
+            demangle: false,
+            function_name_filter: None,
+            only_languages: Vec::new(),
+
+            wasm_repr: WasmReprKind::Full,
+            wasm_repr_options: ReprOptions::default(),
+            wasm_filter_unused_param: true,
+            hash_include_type: HashIncludeType::Always,
+            wasm_window_overlap_threshold: 0.0,
+            wasm_window_order: WindowOrder::Shuffle,
+            wasm_window_anchor: WindowAnchor::Center,
+            wasm_max_windows: None,
+            wasm_max_tokens: None,
+
+            type_parse_options: ParseOptions::default(),
+            type_filter_unknown: true,
+            type_remove_names: false,
+            keep_name_list: None,
+            type_typedefs: Typedefs::Keep,
+            type_name_flatten_outermost: false,
+            type_const_mode: ConstMode::Keep,
+            type_int_granularity: IntGranularity::Exact,
+            type_max_pointer_depth: None,
+            type_class_to_struct: false,
+            type_struct_to_class: false,
+            type_specialize_pointers: false,
+            type_notation: TypeNotation::Prefix,
+            type_explicit_end: false,
+
+            max_samples_per_binary: None,
+            rand_seed: 0,
+            seed_per_binary: false,
+        }
+    }
+}
+
+impl PipelineOptions {
+    /// Human-readable description of the WebAssembly representation, for the log.
+    pub fn wasm_repr_description(&self) -> String {
+        match self.wasm_repr {
+            WasmReprKind::Hash => "hash of full body bytes".to_string(),
+            WasmReprKind::Full => "full body (but abstracted <param>)".to_string(),
+            WasmReprKind::Subrange(size) => format!("(single) subrange with size {}", size),
+            WasmReprKind::Windows(size) => format!("(multiple) windows with size {}, order {:?}, anchor {:?}", size, self.wasm_window_order, self.wasm_window_anchor),
+            WasmReprKind::Slice => "data-flow slice of the parameter".to_string(),
+            WasmReprKind::BasicBlocks => "full body with structural tokens for blocks".to_string(),
+            WasmReprKind::Bytes => "instruction bytes of the full body (but abstracted <param>)".to_string(),
+        }
+    }
+
+    /// Convert the sample to the WebAssembly input representation.
+    fn wasm_repr<T, U>(&self, sample: &WasmTypeSample<WasmBody, T, U>, instructions: &[Operator]) -> anyhow::Result<WasmRepr> {
+        let options = &self.wasm_repr_options;
+        match self.wasm_repr {
+            WasmReprKind::Hash => Ok(WasmRepr::new_hash(sample, self.hash_include_type)),
+            WasmReprKind::Full => WasmRepr::new_full(sample, instructions, options),
+            WasmReprKind::Subrange(size) => WasmRepr::new_subrange(sample, instructions, size, options),
+            WasmReprKind::Windows(size) => {
+                let window_params = WindowParams {
+                    size,
+                    overlap_threshold: self.wasm_window_overlap_threshold,
+                    order: self.wasm_window_order,
+                    anchor: self.wasm_window_anchor,
+                    max_windows: self.wasm_max_windows,
+                    seed: self.rand_seed,
+                    seed_per_binary: self.seed_per_binary,
+                };
+                WasmRepr::new_windows(sample, instructions, window_params, options)
+            }
+            WasmReprKind::Slice => WasmRepr::new_slice(sample, instructions, options),
+            WasmReprKind::Bytes => WasmRepr::new_bytes(sample, options),
+            WasmReprKind::BasicBlocks => WasmRepr::new_basic_blocks(sample, instructions, options),
+        }
+    }
+
+    /// Apply all --type-* simplifications (in this order) to an already parsed type.
+    fn simplify_type(&self, type_: &mut Type, stats: &PipelineStats) {
+        // Keep either no name at all, or only those in the given list.
+        if self.type_remove_names {
+            type_.0.retain(|t| !matches!(t, TypeToken::Typedef(_) | TypeToken::Nominal(_)));
+        } else if let Some(keep) = &self.keep_name_list {
+            type_.0.retain(|t| match t {
+                TypeToken::Typedef(name) | TypeToken::Nominal(name) => keep.contains(name),
+                _ => true
+            });
+        }
+
+        match self.type_typedefs {
+            Typedefs::Keep => {}
+            Typedefs::ToNominal => {
+                for t in &mut type_.0 {
+                    if let TypeToken::Typedef(name) = t {
+                        *t = TypeToken::Nominal(name.clone());
+                    }
+                }
+            }
+            Typedefs::Remove => {
+                type_.0.retain(|t| !matches!(t, TypeToken::Typedef(_)));
+            }
+            Typedefs::Canonical => type_.canonicalize_typedefs(),
+        }
+
+        if self.type_name_flatten_outermost {
+            type_.flatten_names_outermost();
+        }
+
+        type_.simplify_const(self.type_const_mode);
+        type_.coarsen_integers(self.type_int_granularity);
+
+        if let Some(max_depth) = self.type_max_pointer_depth {
+            if type_.cap_pointer_depth(max_depth) {
+                stats.types_pointer_depth_capped.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        // Counted before merging, such that the log shows how much either direction conflates.
+        for t in &mut type_.0 {
+            match t {
+                TypeToken::Class => {
+                    stats.class_tokens.fetch_add(1, Ordering::SeqCst);
+                    if self.type_class_to_struct {
+                        *t = TypeToken::Struct;
+                    }
+                }
+                TypeToken::Struct => {
+                    stats.struct_tokens.fetch_add(1, Ordering::SeqCst);
+                    if self.type_struct_to_class {
+                        *t = TypeToken::Class;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // After capping the pointer depth, which only counts plain pointer tokens.
+        if self.type_specialize_pointers {
+            type_.specialize_pointers();
+        }
+
+        // After all simplifications, which assume the (default) prefix notation.
+        if self.type_notation == TypeNotation::Postfix {
+            type_.convert_to_postfix();
+        }
+
+        // Last, such that the simplifications and filters above see the type
+        // without the terminating end token (which stays last also in postfix).
+        if self.type_explicit_end {
+            type_.0.push(TypeToken::End);
+        }
+    }
+}
+
+/// Counters and distributions collected while running the pipeline, e.g., for the log of the
+/// command-line application. Shared by all (parallel) calls of `process_binary()`.
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    pub samples_removed_return_alignment: AtomicU64,
+    pub samples_removed_language: AtomicU64,
+    pub samples_removed_unused_param: AtomicU64,
+    pub samples_removed_unknown_type: AtomicU64,
+    pub samples_removed_max_per_binary: AtomicU64,
+    pub samples_truncated: AtomicU64,
+    pub types_pointer_depth_capped: AtomicU64,
+    /// Class and struct tokens in sample types, before `--type-class-to-struct` or
+    /// `--type-struct-to-class`.
+    pub class_tokens: AtomicU64,
+    pub struct_tokens: AtomicU64,
+    pub dwarf_stats: DwarfStats,
+    /// Number of declared locals (besides parameters) -> number of samples.
+    pub local_counts: CHashMap<u64, u64>,
+    /// Number of windows (before truncation) -> number of samples, only for the representation
+    /// 'windows'.
+    pub window_counts: CHashMap<u64, u64>,
+    /// Only collected if given, see `--stats-primitives`.
+    pub primitive_counts: Option<PrimitiveTypeCounts>,
+    /// Typedef and nominal names (before any simplification) -> binaries they appear in. Only
+    /// collected if given, see `--type-save-name-stats`.
+    pub name_stats: Option<CMultiMap<Box<str>, Arc<Path>>>,
+    /// Only collected if given, see `--stats-nondeterminism`.
+    pub nondeterminism: Option<NondeterminismStats>,
+    /// Only collected if given, see `--binary-yields-csv`.
+    pub binary_yields: Option<BinaryYields>,
+}
+
+/// Identical WebAssembly inputs -> the distinct (simplified) types of their samples, i.e., the
+/// task-inherent ambiguity of the input.
+#[derive(Debug, Default)]
+pub struct NondeterminismStats {
+    /// Keyed by the hash of the raw function body (with the raw WebAssembly type), regardless of
+    /// the chosen representation.
+    pub raw_hash: CMultiMap<WasmRepr, Type>,
+    /// Keyed by the chosen representation, as written to the output files.
+    pub wasm_repr: CMultiMap<WasmRepr, Type>,
+}
+
+/// How much a single binary contributed, e.g., to spot binaries without any samples.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinaryYield {
+    pub sha256: Box<[u8]>,
+    pub size: u64,
+    /// WebAssembly functions (with a body), and those of them matched to a DWARF function.
+    pub functions: u64,
+    pub functions_matched: u64,
+    /// Samples after the per-binary pipeline, i.e., before deduplication and subsampling.
+    pub param_samples: u64,
+    pub return_samples: u64,
+    pub local_samples: u64,
+}
+
+/// Binaries (by their path) -> their yield.
+#[derive(Debug, Default)]
+pub struct BinaryYields(pub CHashMap<Arc<Path>, BinaryYield>);
+
+impl BinaryYields {
+    /// One line per binary, sorted by path.
+    pub fn write_csv(self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "file,sha256,size,functions,functions_matched,param_samples,return_samples,local_samples")?;
+        for (file, yield_) in self.0.into_iter().sorted_by(|(file1, _), (file2, _)| file1.cmp(file2)) {
+            writeln!(writer, "{},{},{},{},{},{},{},{}", csv_quote(&file.display().to_string()), to_hex(&yield_.sha256), yield_.size, yield_.functions, yield_.functions_matched, yield_.param_samples, yield_.return_samples, yield_.local_samples)?;
+        }
+        Ok(())
+    }
+}
+
+/// Quote a CSV field, doubling any quotes inside, e.g., for paths.
+fn csv_quote(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
+
+/// Extract the samples of the binary at `path` and run them through the pipeline. Samples are
+/// assigned to the given `subset` of the dataset. `file_sha256` is the SHA256 of the binary, if
+/// already known (e.g., from `WasmBinaryStats`), otherwise it is computed.
+/// The outer error is for the whole binary (e.g., it cannot be parsed), the inner ones are for
+/// individual samples, such that the other samples of a binary are still kept.
+pub fn process_binary(path: &Path, file_sha256: Option<&[u8]>, subset: Option<TrainDevTest>, options: &PipelineOptions, stats: &PipelineStats) -> anyhow::Result<Vec<Result<ProcessedSample, FileError<anyhow::Error>>>> {
+    let bytes = read_wasm_file(path, options.mmap_input)?;
+    process_binary_with_file(&bytes, Arc::from(path), file_sha256, Some(&options.debug_search_path), subset, options, stats)
+}
+
+/// Like `process_binary()`, but for a binary that is already in memory. As for
+/// `extract_samples_from_bytes()`, the `file` of all samples is the placeholder "<memory>".
+pub fn process_binary_bytes(bytes: &[u8], subset: Option<TrainDevTest>, options: &PipelineOptions, stats: &PipelineStats) -> anyhow::Result<Vec<Result<ProcessedSample, FileError<anyhow::Error>>>> {
+    process_binary_with_file(bytes, Arc::from(Path::new("<memory>")), None, None, subset, options, stats)
+}
+
+fn process_binary_with_file(bytes: &[u8], file: Arc<Path>, file_sha256: Option<&[u8]>, debug_search_path: Option<&[PathBuf]>, subset: Option<TrainDevTest>, options: &PipelineOptions, stats: &PipelineStats) -> anyhow::Result<Vec<Result<ProcessedSample, FileError<anyhow::Error>>>> {
+    // Hashed at most once, for the samples and the yield of the binary.
+    let file_sha256: Arc<[u8]> = match file_sha256 {
+        Some(file_sha256) => file_sha256.into(),
+        None => Sha256::digest(bytes).as_slice().into(),
+    };
+    // Only the hash representation does not look at the individual instructions.
+    let repr_needs_instructions = !matches!(options.wasm_repr, WasmReprKind::Hash);
+    // Count DWARF entries and functions per binary first, for its yield.
+    let binary_dwarf_stats = DwarfStats::default();
+    let extract_options = ExtractOptions {
+        multi_value_returns: options.multi_value_returns,
+        include_locals: options.include_locals,
+        relocatable_mode: options.relocatable_mode,
+        mmap: options.mmap_input,
+        require_return_alignment: options.require_return_alignment.then_some(&stats.samples_removed_return_alignment),
+        dwarf_stats: Some(&binary_dwarf_stats),
+        demangle: options.demangle,
+        function_name_filter: options.function_name_filter.as_ref(),
+    };
+
+    let samples =
+        // Parse WebAssembly binary and DWARF sections.
+        extract_samples_with_file(bytes, file.clone(), Some(file_sha256.clone()), debug_search_path, &extract_options)?
+
+        // Filter out samples from compilation units in other source languages.
+        .filter_ok(|sample| {
+            let keep = language_in(sample.language, &options.only_languages);
+            if !keep {
+                stats.samples_removed_language.fetch_add(1, Ordering::SeqCst);
+            }
+            keep
+        })
+
+        // Decode the function body only once, both for the unused parameter filter and
+        // for the WebAssembly representation. (Cloning the body is cheap, and avoids
+        // borrowing the instructions from the sample itself.)
+        .filter_map(|sample: gimli::Result<ExtractedSample>| {
+            let sample = match sample {
+                Ok(sample) => sample,
+                Err(err) => return Some(Err(err.into())),
+            };
+            let filter_unused_param = options.wasm_filter_unused_param && matches!(sample.param_or_return, ParamOrReturn::Param { .. });
+            let wasm_body = sample.wasm_body.clone();
+            let instructions = if filter_unused_param || repr_needs_instructions {
+                wasm_body.decode()
+            } else {
+                Ok(Vec::new())
+            };
+
+            // Filter out samples where the parameter is never used anywhere in the WebAssembly function.
+            if let ParamOrReturn::Param { idx, .. } = sample.param_or_return {
+                if filter_unused_param && !matches!(&instructions, Ok(instructions) if uses_local(instructions, idx)) {
+                    stats.samples_removed_unused_param.fetch_add(1, Ordering::SeqCst);
+                    return None;
+                }
+            }
+
+            // Distribution of the number of declared locals (besides parameters).
+            if let Ok(local_count) = wasm_body.local_count() {
+                stats.local_counts.upsert(local_count, || 1, |count| *count += 1);
+            }
+
+            Some((|| -> anyhow::Result<_> {
+                // Convert to own type language.
+                let ty = match sample.param_or_return {
+                    // Like parameters, local variables are DWARF entries with a type attribute.
+                    ParamOrReturn::Param { .. } | ParamOrReturn::Local { .. } => Type::parse_param(&sample.type_, &options.type_parse_options),
+                    ParamOrReturn::Return { .. } => Type::parse_type(&sample.type_, &options.type_parse_options),
+                }?;
+                if let Some(primitive_counts) = &stats.primitive_counts {
+                    primitive_counts.add(&ty);
+                }
+                let sample = sample.with_type(ty);
+                let raw_hash = stats.nondeterminism.is_some().then(|| WasmRepr::new_hash(&sample, HashIncludeType::Always));
+
+                // Convert to WebAssembly input representation.
+                let mut wasm_repr = options.wasm_repr(&sample, &instructions?)?;
+                if let WasmRepr::Windows(_, windows) = &wasm_repr {
+                    stats.window_counts.upsert(windows.len() as u64, || 1, |count| *count += 1);
+                }
+                if let Some(max_tokens) = options.wasm_max_tokens {
+                    if wasm_repr.truncate(max_tokens, &sample.param_or_return, &options.wasm_repr_options.param_token) {
+                        stats.samples_truncated.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+
+                Ok((sample.with_wasm_body(wasm_repr), raw_hash))
+            })())
+        })
+
+        // Attach file to error for better error reporting.
+        .map(|result| result.with_file(&file))
+
+        // Filter out samples where the type is just Unknown.
+        .filter_ok(|(sample, _raw_hash)| {
+            if !options.type_filter_unknown {
+                return true;
+            }
+
+            let is_unknown = sample.type_ == Type(vec![TypeToken::Unknown]);
+
+            if is_unknown {
+                stats.samples_removed_unknown_type.fetch_add(1, Ordering::SeqCst);
+            }
+            !is_unknown
+        })
+
+        // Simplify types, if options given.
+        .map_ok(|(mut sample, raw_hash)| {
+            // Collect statistics about names before removing or otherwise simplifying them.
+            if let Some(name_stats) = &stats.name_stats {
+                for t in &sample.type_.0 {
+                    if let TypeToken::Typedef(name) | TypeToken::Nominal(name) = t {
+                        name_stats.insert(name.clone(), &sample.file);
+                    }
+                }
+            }
+
+            options.simplify_type(&mut sample.type_, stats);
+
+            // Ambiguity of the input w.r.t. the final type, i.e., what a model has to predict.
+            if let (Some(nondeterminism), Some(raw_hash)) = (&stats.nondeterminism, raw_hash) {
+                nondeterminism.raw_hash.insert(raw_hash, &sample.type_);
+                nondeterminism.wasm_repr.insert(sample.wasm_body.clone(), &sample.type_);
+            }
+            sample
+        })
+
+        .map_ok(|sample| sample.with_aux(subset))
+
+        // Collect samples into Vec, for further parallel processing.
+        // (We cannot return the iterator directly here, because it contains ref-counted
+        // slices of the input files, which are not Send, which makes the iterator not Send,
+        // and can thus it cannot be processed by rayon in parallel.
+        // An alternative would be to change all Rc -> Arc and remove collect_vec() below,
+        // but I am not sure which is more expensive: every ref-count being atomic when
+        // parsing the input vs. one single allocation more per binary. I strongly suspect
+        // the Arc'ing is more expensive. So that is why collect_vec().)
+        .collect_vec();
+
+    // Cap the number of samples per binary (only after all filters above).
+    let samples = match options.max_samples_per_binary {
+        Some(max) => {
+            let (samples, dropped) = crate::samples::balance::cap_per_binary(samples, max, options.rand_seed, |sample| sample.id());
+            stats.samples_removed_max_per_binary.fetch_add(dropped, Ordering::SeqCst);
+            samples
+        }
+        None => samples,
+    };
+
+    binary_dwarf_stats.add_to(&stats.dwarf_stats);
+    if let Some(binary_yields) = &stats.binary_yields {
+        let count = |f: fn(&ParamOrReturn) -> bool| samples.iter().flatten().filter(|sample| f(&sample.param_or_return)).count() as u64;
+        let param_samples = count(|pr| matches!(pr, ParamOrReturn::Param { .. }));
+        let return_samples = count(|pr| matches!(pr, ParamOrReturn::Return { .. }));
+        let local_samples = count(|pr| matches!(pr, ParamOrReturn::Local { .. }));
+        binary_yields.0.insert(file, BinaryYield {
+            sha256: file_sha256[..].into(),
+            size: bytes.len() as u64,
+            functions: binary_dwarf_stats.wasm_functions.into_inner(),
+            functions_matched: binary_dwarf_stats.functions_matched.into_inner(),
+            param_samples,
+            return_samples,
+            local_samples,
+        });
+    }
+    Ok(samples)
+}
+
+/// A sample rendered to the strings that are otherwise written to the output files.
+#[derive(Debug)]
+pub struct RenderedSample {
+    /// The WebAssembly input representation, e.g., a line of param/wasm.txt.
+    pub wasm: String,
+    /// The type, e.g., a line of param/type.txt.
+    pub type_: String,
+    pub sample: ProcessedSample,
+}
+
+impl RenderedSample {
+    fn new(sample: ProcessedSample) -> Self {
+        Self {
+            wasm: sample.wasm_body.to_string(),
+            type_: sample.type_.to_string(),
+            sample,
+        }
+    }
+
+    /// Metadata of the sample, as in param/info.jsonl.
+    pub fn info(&self) -> SampleInfo<'_> {
+        SampleInfo::from(&self.sample)
+    }
+}
+
+/// Flatten the result of `process_binary()` into rendered samples and errors.
+fn render(samples: anyhow::Result<Vec<Result<ProcessedSample, FileError<anyhow::Error>>>>) -> impl Iterator<Item = anyhow::Result<RenderedSample>> {
+    match samples {
+        Ok(samples) => Either::Left(samples.into_iter().map(|sample| Ok(RenderedSample::new(sample?)))),
+        Err(err) => Either::Right(std::iter::once(Err(err))),
+    }
+}
+
+/// Run the pipeline on all WebAssembly binaries (by their magic bytes) in `dir` (recursively),
+/// without writing any files, e.g., for feeding the samples directly into training.
+/// Unlike the command-line application, there is no deduplication of binaries or samples and no
+/// dataset split. Samples come in arbitrary order, as they are processed in parallel.
+pub fn render_samples_from_dir<'a>(dir: impl AsRef<Path>, options: &'a PipelineOptions, stats: &'a PipelineStats) -> impl ParallelIterator<Item = anyhow::Result<RenderedSample>> + 'a {
+    let files: Vec<_> = WalkDir::new(dir).into_iter()
+        .filter_ok(|entry| entry.file_type().is_file())
+        .map_ok(|entry| entry.into_path())
+        .collect();
+    files.into_par_iter().flat_map_iter(move |file| {
+        let samples = file.map_err(anyhow::Error::from).and_then(|file| {
+            if !is_wasm_by_magic_bytes(&file)? {
+                return Ok(Vec::new());
+            }
+            process_binary(&file, None, None, options, stats).with_file(&file).map_err(anyhow::Error::from)
+        });
+        render(samples)
+    })
+}
+
+/// Like `render_samples_from_dir()`, but for binaries that are already in memory.
+///
+/// ```
+/// use rayon::prelude::*;
+/// use extraction::samples::pipeline::{render_samples_from_bytes, PipelineOptions, PipelineStats};
+/// use extraction::samples::wasm_repr::ReprOptions;
+///
+/// // A module with a single empty function `(func)`, but no DWARF debug info.
+/// let bytes = b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x03\x02\x01\0\x0a\x04\x01\x02\0\x0b";
+///
+/// let options = PipelineOptions {
+///     wasm_repr_options: ReprOptions { with_type: false, ..ReprOptions::default() },
+///     ..PipelineOptions::default()
+/// };
+/// let stats = PipelineStats::default();
+/// let pairs: Vec<(String, String)> = render_samples_from_bytes(&[&bytes[..]], &options, &stats)
+///     .map(|sample| sample.map(|sample| (sample.wasm, sample.type_)))
+///     .collect::<anyhow::Result<_>>()
+///     .unwrap();
+///
+/// // Without debug info, there are no types to extract, but the function was still looked at.
+/// assert!(pairs.is_empty());
+/// assert_eq!(stats.dwarf_stats.wasm_functions.into_inner(), 1);
+/// ```
+pub fn render_samples_from_bytes<'a>(binaries: &'a [&'a [u8]], options: &'a PipelineOptions, stats: &'a PipelineStats) -> impl ParallelIterator<Item = anyhow::Result<RenderedSample>> + 'a {
+    binaries.par_iter().flat_map_iter(move |bytes| render(process_binary_bytes(bytes, None, options, stats)))
+}
+
+#[cfg(test)]
+mod tests {
+    use gimli::constants::*;
+
+    use super::*;
+    use crate::dwarf::test_util::*;
+    use crate::wasm::test_util::*;
+
+    #[test]
+    fn test_render_samples() {
+        let options = PipelineOptions { type_explicit_end: true, ..PipelineOptions::default() };
+        let stats = PipelineStats::default();
+        let bytes = int_param_module(1, |_| {});
+        let samples: Vec<_> = render_samples_from_bytes(&[&bytes[..]], &options, &stats)
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].wasm, "i32 <begin> local.get <param> ; drop ; end");
+        assert_eq!(samples[0].type_, "primitive int32_t end");
+        assert_eq!(samples[0].info().param_name, Some("x"));
+
+        // Same samples from a directory, but skipping files that are not WebAssembly binaries.
+        let dir = temp_dir();
+        write_temp_file(&dir, "binary.wasm", &bytes);
+        write_temp_file(&dir, "other.txt", b"not a binary");
+        let from_dir: Vec<_> = render_samples_from_dir(dir.path(), &options, &stats)
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        assert_eq!(from_dir.len(), 1);
+        assert_eq!(from_dir[0].wasm, samples[0].wasm);
+        assert_eq!(from_dir[0].info().id, samples[0].info().id);
+        assert_eq!(stats.dwarf_stats.functions_matched.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_decode_body_once() {
+        // Both the unused parameter filter and the (full) WebAssembly representation need the
+        // instructions, but the body is decoded only once for them.
+        let options = PipelineOptions { wasm_filter_unused_param: true, wasm_repr: WasmReprKind::Full, ..PipelineOptions::default() };
+        let stats = PipelineStats::default();
+        let bytes = int_param_module(1, |_| {});
+        let decode_count = crate::wasm::parse::decode_count();
+        let samples = process_binary_bytes(&bytes, None, &options, &stats).unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(crate::wasm::parse::decode_count() - decode_count, 1);
+
+        // The hash representation does not need the instructions at all.
+        let options = PipelineOptions { wasm_filter_unused_param: false, wasm_repr: WasmReprKind::Hash, ..PipelineOptions::default() };
+        let decode_count = crate::wasm::parse::decode_count();
+        process_binary_bytes(&bytes, None, &options, &stats).unwrap();
+        assert_eq!(crate::wasm::parse::decode_count() - decode_count, 0);
+    }
+
+    #[test]
+    fn test_class_struct_merge() {
+        use crate::samples::types::TypeToken::*;
+
+        let simplify = |options: &PipelineOptions, stats: &PipelineStats| {
+            [vec![Nominal("C".into()), Class], vec![Class], vec![Nominal("S".into()), Struct], vec![Pointer, Struct]].iter()
+                .map(|tokens| {
+                    let mut type_ = Type(tokens.clone());
+                    options.simplify_type(&mut type_, stats);
+                    type_.to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // By default kept separate, rendered the same with and without a preceding name.
+        let stats = PipelineStats::default();
+        assert_eq!(simplify(&PipelineOptions::default(), &stats), [r#"name "C" class"#, "class", r#"name "S" struct"#, "pointer struct"]);
+        assert_eq!(stats.class_tokens.load(Ordering::SeqCst), 2);
+        assert_eq!(stats.struct_tokens.load(Ordering::SeqCst), 2);
+
+        let options = PipelineOptions { type_class_to_struct: true, ..PipelineOptions::default() };
+        assert_eq!(simplify(&options, &stats), [r#"name "C" struct"#, "struct", r#"name "S" struct"#, "pointer struct"]);
+        let options = PipelineOptions { type_struct_to_class: true, ..PipelineOptions::default() };
+        assert_eq!(simplify(&options, &stats), [r#"name "C" class"#, "class", r#"name "S" class"#, "pointer class"]);
+
+        // Counted before merging.
+        assert_eq!(stats.class_tokens.load(Ordering::SeqCst), 6);
+        assert_eq!(stats.struct_tokens.load(Ordering::SeqCst), 6);
+    }
+
+    #[test]
+    fn test_info_primitive_source_names() {
+        // void f(long x, const long *p)
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32, I32], &[]);
+        // local.get 0, local.get 1, drop, drop
+        module.function(ty, &[], &[0x20, 0x00, 0x20, 0x01, 0x1a, 0x1a]);
+        let low_pc = module.code_offsets()[0];
+        let sections = dwarf_sections(|unit| {
+            let long = add_base_type(unit, "long", DW_ATE_signed, 4);
+            let root = unit.root();
+            let const_long = add_typed(unit, root, DW_TAG_const_type, None, long);
+            let pointer = add_typed(unit, root, DW_TAG_pointer_type, None, const_long);
+            let subprogram = add_subprogram(unit, Some("f"), low_pc);
+            add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("x"), long);
+            add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("p"), pointer);
+        });
+        add_dwarf_sections(&mut module, &sections);
+        let bytes = module.build();
+
+        let samples: Vec<_> = render_samples_from_bytes(&[&bytes[..]], &PipelineOptions::default(), &PipelineStats::default())
+            .collect::<anyhow::Result<_>>()
+            .unwrap();
+        let mut infos: Vec<_> = samples.iter()
+            .map(|sample| (sample.type_.as_str(), serde_json::to_value(sample.info()).unwrap()))
+            .collect();
+        infos.sort_by_key(|(_, info)| info["param_idx"].as_u64());
+        // The type has only the normalized name, the source name is in the info, also below the pointer.
+        assert_eq!(infos[0].0, "primitive int32_t");
+        assert_eq!(infos[0].1["primitive_source_names"], serde_json::json!(["long"]));
+        assert_eq!(infos[1].0, "pointer const primitive int32_t");
+        assert_eq!(infos[1].1["primitive_source_names"], serde_json::json!(["long"]));
+    }
+
+    #[test]
+    fn test_binary_yields_csv() {
+        let options = PipelineOptions::default();
+        let stats = PipelineStats { binary_yields: Some(BinaryYields::default()), ..PipelineStats::default() };
+        let bytes = int_param_module(1, |_| {});
+        process_binary_bytes(&bytes, None, &options, &stats).unwrap();
+
+        let mut csv = Vec::new();
+        stats.binary_yields.unwrap().write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], "file,sha256,size,functions,functions_matched,param_samples,return_samples,local_samples");
+        let sha256 = format!("{:x}", Sha256::digest(&bytes));
+        // One function with a DWARF match, and one parameter (but no return) sample.
+        assert_eq!(lines[1], format!("\"<memory>\",{},{},1,1,1,0,0", sha256, bytes.len()));
+        assert_eq!(lines.len(), 2);
+        // The overall stats are still counted.
+        assert_eq!(stats.dwarf_stats.functions_matched.into_inner(), 1);
+    }
+
+    #[test]
+    fn test_known_file_sha256_is_reused() {
+        let stats = PipelineStats { binary_yields: Some(BinaryYields::default()), ..PipelineStats::default() };
+        let bytes = int_param_module(1, |_| {});
+        // Not the actual hash, to see that it is passed through instead of computed again.
+        let known_sha256 = [0xab; 32];
+        let samples = process_binary_with_file(&bytes, Arc::from(Path::new("<memory>")), Some(&known_sha256), None, None, &PipelineOptions::default(), &stats).unwrap();
+
+        assert_eq!(samples.len(), 1);
+        assert_eq!(&samples[0].as_ref().unwrap().file_sha256[..], &known_sha256[..]);
+        let binary_yields = stats.binary_yields.unwrap().0;
+        assert_eq!(&binary_yields.get(Path::new("<memory>")).unwrap().sha256[..], &known_sha256[..]);
+    }
+
+    #[test]
+    fn test_binary_yields_csv_quotes_paths() {
+        let yields = BinaryYields::default();
+        let yield_ = BinaryYield { sha256: Box::new([0xab]), size: 1, functions: 0, functions_matched: 0, param_samples: 0, return_samples: 0, local_samples: 0 };
+        yields.0.insert(Arc::from(Path::new("dir/say \"hi\",.wasm")), yield_);
+        let mut csv = Vec::new();
+        yields.write_csv(&mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap().lines().nth(1).unwrap(), "\"dir/say \"\"hi\"\",.wasm\",ab,1,0,0,0,0,0");
+    }
+
+    #[test]
+    fn test_nondeterminism_stats() {
+        // Functions f and g have identical bodies, but differently typed parameters, h is different.
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32], &[]);
+        // local.get 0, drop
+        module.function(ty, &[], &[0x20, 0x00, 0x1a]);
+        module.function(ty, &[], &[0x20, 0x00, 0x1a]);
+        // local.get 0, i32.eqz, drop
+        module.function(ty, &[], &[0x20, 0x00, 0x45, 0x1a]);
+        let low_pcs = module.code_offsets();
+        let sections = dwarf_sections(|unit| {
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let uint = add_base_type(unit, "unsigned int", DW_ATE_unsigned, 4);
+            for (name, low_pc, type_) in [("f", low_pcs[0], int), ("g", low_pcs[1], uint), ("h", low_pcs[2], int)].iter().copied() {
+                let subprogram = add_subprogram(unit, Some(name), low_pc);
+                add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("x"), type_);
+            }
+        });
+        add_dwarf_sections(&mut module, &sections);
+        let bytes = module.build();
+
+        let options = PipelineOptions::default();
+        let stats = PipelineStats { nondeterminism: Some(NondeterminismStats::default()), ..PipelineStats::default() };
+        let samples = process_binary_bytes(&bytes, None, &options, &stats).unwrap();
+        assert_eq!(samples.len(), 3);
+
+        // One input with two types (f and g), one with a single type (h).
+        let nondeterminism = stats.nondeterminism.unwrap();
+        let expected = vec![(1, 1), (2, 1)].into_iter().collect();
+        assert_eq!(nondeterminism.raw_hash.distinct_values_distribution(), expected);
+        assert_eq!(nondeterminism.wasm_repr.distinct_values_distribution(), expected);
+    }
+}
@@ -1,6 +1,8 @@
 use std::path::Path;
 use std::sync::Arc;
 
+use sha2::{Digest, Sha256};
+
 #[derive(Debug, Clone)]
 pub struct WasmTypeSample<WasmRepr, TypeRepr, Aux = ()> {
     // Metainformation, useful for debugging samples.
@@ -11,15 +13,32 @@ pub struct WasmTypeSample<WasmRepr, TypeRepr, Aux = ()> {
     // Use atomic ref-counts because those samples are processed in parallel and rayon needs the 
     // type to be Send.
     pub file: Arc<Path>,
+    // Hash of the binary's contents, see `WasmBinaryStats::file_sha256`. Unlike the path, this
+    // identifies the binary independent of where it is stored, see `id()`.
+    pub file_sha256: Arc<[u8]>,
     pub compilation_unit: Option<Arc<str>>,
+    // Compiler that produced the compilation unit (DW_AT_producer), e.g., "clang version 14.0.0".
+    pub producer: Option<Arc<str>>,
+    // Source language of the compilation unit, e.g., "cpp14", see `dwarf::parse::language_name()`.
+    pub language: Option<&'static str>,
 
     pub function_idx: u32,
     pub function_name_wasm: Option<Arc<str>>,
     pub function_name_dwarf: Option<Arc<str>>,
+    // Demangled forms of both names (or the name unchanged, if it is not mangled), only with
+    // --demangle, see `util::demangle::demangle()`.
+    pub function_name_wasm_demangled: Option<Arc<str>>,
+    pub function_name_dwarf_demangled: Option<Arc<str>>,
+    // Source file and line where the parameter (or function, for return samples) is declared
+    // (DW_AT_decl_file and DW_AT_decl_line), for debugging (mis)predictions.
+    pub decl_file: Option<Arc<str>>,
+    pub decl_line: Option<u64>,
 
     pub param_or_return: ParamOrReturn,
 
     pub wasm_type: wasmparser::Type,
+    // Type of the whole function, e.g., as additional context for the parameter or return type.
+    pub wasm_function_type: Arc<wasmparser::FuncType>,
 
     // The struct is generic over the WebAssembly and type representation: first it contains
     // the full body and the original DWARF type, later a prefix of the instructions and our own
@@ -37,20 +56,77 @@ pub enum ParamOrReturn {
         idx: u32,
         name: Option<Box<str>>,
     },
-    Return
+    /// With the multi-value proposal, functions can return more than one value, so this is the
+    /// index of the returned value (always 0 for single return values).
+    Return {
+        idx: u32,
+    },
+    /// A local variable of the function that is stored in a WebAssembly local (after the
+    /// parameters), only with `--include-locals`. Its accesses are abstracted like those of a
+    /// parameter in the WebAssembly representations.
+    Local {
+        idx: u32,
+        name: Option<Box<str>>,
+    },
+}
+
+impl ParamOrReturn {
+    /// The WebAssembly local of parameters and local variables, None for return values.
+    pub fn local_idx(&self) -> Option<u32> {
+        match self {
+            ParamOrReturn::Param { idx, .. } | ParamOrReturn::Local { idx, .. } => Some(*idx),
+            ParamOrReturn::Return { .. } => None,
+        }
+    }
 }
 
 // Generic struct update methods, since Rust's update syntax doesn't work.
 impl<T, U, V> WasmTypeSample<T, U, V> {
+    /// Stable identifier of the sample for referring to it across datasets, e.g., when comparing
+    /// model outputs, and as the key for reproducible subsampling, see `util::reservoir`. Depends
+    /// only on the contents of the binary, the function, and which parameter or return value the
+    /// sample is for, but not on the extraction order, the file path, or the options of the run.
+    pub fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.file_sha256);
+        hasher.update(self.function_idx.to_le_bytes());
+        match self.param_or_return {
+            ParamOrReturn::Param { idx, .. } => {
+                hasher.update(b"param");
+                hasher.update(idx.to_le_bytes());
+            }
+            ParamOrReturn::Return { idx } => {
+                hasher.update(b"return");
+                hasher.update(idx.to_le_bytes());
+            }
+            ParamOrReturn::Local { idx, .. } => {
+                hasher.update(b"local");
+                hasher.update(idx.to_le_bytes());
+            }
+        }
+        // 128 bits are plenty to avoid collisions, and shorter to read.
+        let mut id = format!("{:x}", hasher.finalize());
+        id.truncate(32);
+        id
+    }
+
     pub fn map_wasm_body<R>(self, f: impl FnOnce(T) -> R) -> WasmTypeSample<R, U, V> {
         WasmTypeSample {
             file: self.file,
+            file_sha256: self.file_sha256,
             compilation_unit: self.compilation_unit,
+            producer: self.producer,
+            language: self.language,
             function_idx: self.function_idx,
             function_name_wasm: self.function_name_wasm,
             function_name_dwarf: self.function_name_dwarf,
+            function_name_wasm_demangled: self.function_name_wasm_demangled,
+            function_name_dwarf_demangled: self.function_name_dwarf_demangled,
+            decl_file: self.decl_file,
+            decl_line: self.decl_line,
             param_or_return: self.param_or_return,
             wasm_type: self.wasm_type,
+            wasm_function_type: self.wasm_function_type,
             wasm_body: f(self.wasm_body),
             type_: self.type_,
             aux: self.aux,
@@ -63,12 +139,20 @@ impl<T, U, V> WasmTypeSample<T, U, V> {
     pub fn map_type<R>(self, f: impl FnOnce(U) -> R) -> WasmTypeSample<T, R, V> {
         WasmTypeSample {
             file: self.file,
+            file_sha256: self.file_sha256,
             compilation_unit: self.compilation_unit,
+            producer: self.producer,
+            language: self.language,
             function_idx: self.function_idx,
             function_name_wasm: self.function_name_wasm,
             function_name_dwarf: self.function_name_dwarf,
+            function_name_wasm_demangled: self.function_name_wasm_demangled,
+            function_name_dwarf_demangled: self.function_name_dwarf_demangled,
+            decl_file: self.decl_file,
+            decl_line: self.decl_line,
             param_or_return: self.param_or_return,
             wasm_type: self.wasm_type,
+            wasm_function_type: self.wasm_function_type,
             wasm_body: self.wasm_body,
             type_: f(self.type_),
             aux: self.aux,
@@ -77,4 +161,27 @@ impl<T, U, V> WasmTypeSample<T, U, V> {
     pub fn with_type<R>(self, type_: R) -> WasmTypeSample<T, R, V> {
         self.map_type(|_| type_)
     }
+
+    pub fn with_aux<R>(self, aux: R) -> WasmTypeSample<T, U, R> {
+        WasmTypeSample {
+            file: self.file,
+            file_sha256: self.file_sha256,
+            compilation_unit: self.compilation_unit,
+            producer: self.producer,
+            language: self.language,
+            function_idx: self.function_idx,
+            function_name_wasm: self.function_name_wasm,
+            function_name_dwarf: self.function_name_dwarf,
+            function_name_wasm_demangled: self.function_name_wasm_demangled,
+            function_name_dwarf_demangled: self.function_name_dwarf_demangled,
+            decl_file: self.decl_file,
+            decl_line: self.decl_line,
+            param_or_return: self.param_or_return,
+            wasm_type: self.wasm_type,
+            wasm_function_type: self.wasm_function_type,
+            wasm_body: self.wasm_body,
+            type_: self.type_,
+            aux,
+        }
+    }
 }
@@ -0,0 +1,291 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+
+use crate::samples::sample::WasmTypeSample;
+use crate::util::reservoir::Reservoir;
+
+/// Subset of the dataset a sample belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum TrainDevTest {
+    Train,
+    Dev,
+    Test,
+}
+
+impl TrainDevTest {
+    pub const ALL: [TrainDevTest; 3] = [TrainDevTest::Train, TrainDevTest::Dev, TrainDevTest::Test];
+
+    pub fn to_str(self) -> &'static str {
+        match self {
+            TrainDevTest::Train => "train",
+            TrainDevTest::Dev => "dev",
+            TrainDevTest::Test => "test",
+        }
+    }
+}
+
+/// Relative sizes of the train, dev, and test set, e.g., parsed from "0.8,0.1,0.1".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SplitRatios {
+    pub train: f64,
+    pub dev: f64,
+    pub test: f64,
+}
+
+impl FromStr for SplitRatios {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ratios = s.split(',')
+            .map(|ratio| ratio.trim().parse::<f64>().with_context(|| format!("invalid ratio '{}'", ratio)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let (train, dev, test) = match ratios[..] {
+            [train, dev, test] => (train, dev, test),
+            _ => bail!("expected three comma-separated ratios for train, dev, and test set, got '{}'", s),
+        };
+        if ratios.iter().any(|&ratio| !(0.0..=1.0).contains(&ratio)) {
+            bail!("ratios must be between 0 and 1, got '{}'", s);
+        }
+        if (train + dev + test - 1.0).abs() > 1e-6 {
+            bail!("ratios must sum to 1, got '{}'", s);
+        }
+        Ok(SplitRatios { train, dev, test })
+    }
+}
+
+impl SplitRatios {
+    pub fn ratio(&self, subset: TrainDevTest) -> f64 {
+        match subset {
+            TrainDevTest::Train => self.train,
+            TrainDevTest::Dev => self.dev,
+            TrainDevTest::Test => self.test,
+        }
+    }
+}
+
+/// Deterministic assignment of binaries to the train, dev, or test set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DatasetSplit {
+    pub ratios: SplitRatios,
+    pub seed: u64,
+}
+
+impl DatasetSplit {
+    /// The subset is a function of only the seed and the binary's SHA256 (and not, e.g., the
+    /// function index), such that all samples from one binary land in the same subset.
+    /// Otherwise, very similar functions from the same binary could leak from train to dev/test.
+    pub fn assign(&self, file_sha256: &[u8]) -> TrainDevTest {
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed.to_le_bytes());
+        hasher.update(file_sha256);
+        let hash = hasher.finalize();
+
+        // Map the first 8 bytes of the hash uniformly to [0, 1).
+        let hash = u64::from_le_bytes(hash[..8].try_into().unwrap());
+        let x = (hash >> 11) as f64 / (1u64 << 53) as f64;
+
+        if x < self.ratios.train {
+            TrainDevTest::Train
+        } else if x < self.ratios.train + self.ratios.dev {
+            TrainDevTest::Dev
+        } else {
+            TrainDevTest::Test
+        }
+    }
+}
+
+/// Remove train samples whose (input, output) pair also appears verbatim in the dev or test set,
+/// such that a model cannot achieve inflated accuracy on dev/test by memorizing the training data.
+/// Returns the number of removed samples.
+pub fn dedup_eval_samples<T: Display, U: Display>(samples: &mut Vec<WasmTypeSample<T, U, Option<TrainDevTest>>>) -> u64 {
+    let eval_samples: HashSet<(String, String)> = samples
+        .iter()
+        .filter(|sample| matches!(sample.aux, Some(TrainDevTest::Dev) | Some(TrainDevTest::Test)))
+        .map(|sample| (sample.wasm_body.to_string(), sample.type_.to_string()))
+        .collect();
+
+    let len_before = samples.len();
+    samples.retain(|sample| {
+        sample.aux != Some(TrainDevTest::Train)
+            || !eval_samples.contains(&(sample.wasm_body.to_string(), sample.type_.to_string()))
+    });
+    (len_before - samples.len()) as u64
+}
+
+/// Uniformly subsample to at most `max_samples` samples, reproducibly for the same seed, see
+/// `Reservoir`. If the dataset is split, each subset is subsampled separately to its share of
+/// `max_samples` (according to `ratios`), such that the split ratios are kept.
+/// Returns the kept samples and the number of dropped ones.
+pub fn subsample<T, U>(
+    samples: impl IntoIterator<Item = WasmTypeSample<T, U, Option<TrainDevTest>>>,
+    max_samples: usize,
+    ratios: Option<SplitRatios>,
+    seed: u64,
+) -> (Vec<WasmTypeSample<T, U, Option<TrainDevTest>>>, u64) {
+    let capacity = |subset: Option<TrainDevTest>| match (subset, ratios) {
+        (Some(subset), Some(ratios)) => (max_samples as f64 * ratios.ratio(subset)).round() as usize,
+        _ => max_samples,
+    };
+
+    let mut reservoirs = HashMap::new();
+    for sample in samples {
+        let key = sample.id();
+        reservoirs.entry(sample.aux)
+            .or_insert_with(|| Reservoir::new(capacity(sample.aux), seed))
+            .add(key.as_bytes(), sample);
+    }
+
+    // Sort subsets for a deterministic output order.
+    let mut kept = Vec::new();
+    let mut dropped = 0;
+    for (_, reservoir) in reservoirs.into_iter().sorted_by_key(|(subset, _)| *subset) {
+        let seen = reservoir.seen();
+        let samples = reservoir.into_vec();
+        dropped += seen - samples.len() as u64;
+        kept.extend(samples);
+    }
+    (kept, dropped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use crate::samples::sample::ParamOrReturn;
+
+    use super::*;
+
+    fn split(seed: u64) -> DatasetSplit {
+        DatasetSplit { ratios: "0.8,0.1,0.1".parse().unwrap(), seed }
+    }
+
+    fn fake_sha256s() -> impl DoubleEndedIterator<Item = Vec<u8>> {
+        (0..1000u32).map(|i| Sha256::digest(&i.to_le_bytes()).to_vec())
+    }
+
+    #[test]
+    fn test_parse_ratios() {
+        assert_eq!("0.8, 0.1, 0.1".parse::<SplitRatios>().unwrap(), SplitRatios { train: 0.8, dev: 0.1, test: 0.1 });
+        assert!("0.8,0.1".parse::<SplitRatios>().is_err());
+        assert!("0.8,0.1,0.2".parse::<SplitRatios>().is_err());
+        assert!("1.5,-0.5,0".parse::<SplitRatios>().is_err());
+        assert!("a,b,c".parse::<SplitRatios>().is_err());
+    }
+
+    #[test]
+    fn test_same_seed_same_split() {
+        let first_run = fake_sha256s().map(|sha256| split(42).assign(&sha256)).collect::<Vec<_>>();
+        let second_run = fake_sha256s().map(|sha256| split(42).assign(&sha256)).collect::<Vec<_>>();
+        assert_eq!(first_run, second_run);
+
+        let other_seed = fake_sha256s().map(|sha256| split(43).assign(&sha256)).collect::<Vec<_>>();
+        assert_ne!(first_run, other_seed);
+    }
+
+    #[test]
+    fn test_no_binary_split_across_sets() {
+        // Simulate samples from all functions of 1000 binaries, where each binary appears multiple
+        // times (e.g., once per function), in different order.
+        let samples = fake_sha256s().chain(fake_sha256s().rev()).chain(fake_sha256s());
+        let mut subset_per_binary = HashMap::new();
+        for sha256 in samples {
+            let subset = split(0).assign(&sha256);
+            assert_eq!(*subset_per_binary.entry(sha256).or_insert(subset), subset);
+        }
+
+        // Roughly respects the ratios.
+        let train_count = subset_per_binary.values().filter(|&&subset| subset == TrainDevTest::Train).count();
+        assert!((700..900).contains(&train_count), "{}", train_count);
+    }
+
+    fn sample(wasm: &str, type_: &str, subset: TrainDevTest) -> WasmTypeSample<String, String, Option<TrainDevTest>> {
+        WasmTypeSample {
+            file: Arc::from(Path::new("test.wasm")),
+            file_sha256: Arc::from(&[0; 32][..]),
+            compilation_unit: None,
+            producer: None,
+            language: None,
+            function_idx: 0,
+            function_name_wasm: None,
+            function_name_dwarf: None,
+            function_name_wasm_demangled: None,
+            function_name_dwarf_demangled: None,
+            decl_file: None,
+            decl_line: None,
+            param_or_return: ParamOrReturn::Return { idx: 0 },
+            wasm_type: wasmparser::Type::I32,
+            wasm_function_type: Arc::new(wasmparser::FuncType { params: Box::new([]), returns: Box::new([]) }),
+            wasm_body: wasm.to_string(),
+            type_: type_.to_string(),
+            aux: Some(subset),
+        }
+    }
+
+    #[test]
+    fn test_dedup_eval_samples() {
+        use TrainDevTest::*;
+        let mut samples = vec![
+            sample("local.get <param>", "primitive int", Train),
+            sample("local.get <param>", "primitive int", Train),
+            // Same input, but different output: not a duplicate.
+            sample("local.get <param>", "pointer primitive char", Train),
+            sample("i32.load", "primitive int", Train),
+            sample("local.get <param>", "primitive int", Dev),
+            sample("i32.load", "primitive int", Test),
+            sample("f64.add", "primitive float64_t", Test),
+        ];
+        assert_eq!(dedup_eval_samples(&mut samples), 3);
+
+        let remaining = samples.iter().map(|s| (s.wasm_body.as_str(), s.type_.as_str(), s.aux.unwrap())).collect::<Vec<_>>();
+        assert_eq!(remaining, vec![
+            ("local.get <param>", "pointer primitive char", Train),
+            ("local.get <param>", "primitive int", Dev),
+            ("i32.load", "primitive int", Test),
+            ("f64.add", "primitive float64_t", Test),
+        ]);
+    }
+
+    #[test]
+    fn test_subsample_deterministic() {
+        use TrainDevTest::*;
+        let samples = || (0..1000).map(|i| {
+            let subset = [Train, Train, Train, Train, Train, Train, Train, Train, Dev, Test][i % 10];
+            let mut sample = sample(&format!("i32.const {}", i), "primitive int", subset);
+            sample.function_idx = i as u32;
+            sample
+        });
+        let ratios = Some("0.8,0.1,0.1".parse().unwrap());
+        let bodies = |(samples, dropped): (Vec<WasmTypeSample<String, String, Option<TrainDevTest>>>, u64)| {
+            (samples.into_iter().map(|s| (s.wasm_body, s.aux.unwrap())).collect::<Vec<_>>(), dropped)
+        };
+
+        let (first_run, dropped) = bodies(subsample(samples(), 100, ratios, 42));
+        assert_eq!(first_run.len(), 100);
+        assert_eq!(dropped, 900);
+        assert_eq!(first_run.iter().filter(|(_, subset)| *subset == Dev).count(), 10);
+
+        // Same seed, same subsample, even if the samples arrive in a different order.
+        let second_run = bodies(subsample(samples().rev(), 100, ratios, 42)).0;
+        assert_eq!(first_run, second_run);
+        let other_seed = bodies(subsample(samples(), 100, ratios, 43)).0;
+        assert_ne!(first_run, other_seed);
+        // Nor does it depend on the path of the binary, only on its contents.
+        let moved = samples().map(|mut sample| {
+            sample.file = Arc::from(Path::new("moved/test.wasm"));
+            sample
+        });
+        assert_eq!(first_run, bodies(subsample(moved, 100, ratios, 42)).0);
+
+        // Without a split, all samples are subsampled together.
+        let (unsplit, _) = subsample(samples().map(|s| s.with_aux(None)), 100, None, 42);
+        assert_eq!(unsplit.len(), 100);
+    }
+}
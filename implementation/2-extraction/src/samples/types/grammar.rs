@@ -0,0 +1,151 @@
+//! Machine-readable description of the type language (see `--emit-type-grammar`), e.g., to
+//! validate the output of a model. The terminals are derived from how tokens are printed.
+use gimli::constants::DW_ATE_signed;
+use itertools::Itertools;
+
+use crate::samples::types::{PointerTarget, PrimitiveType, TypeToken};
+use crate::samples::types::TypeToken::*;
+
+/// EBNF (ISO 14977 style) of all types in prefix notation. Optional tokens are listed together
+/// with the option that enables them, such that the grammar is a superset of the types of any
+/// single configuration.
+pub fn type_grammar_ebnf() -> String {
+    let alternatives = |tokens: &[TypeToken]| tokens.iter().map(terminal).join(" | ");
+    let int32_t = Primitive(PrimitiveType {
+        normalized: "int32_t",
+        source_name: "int".into(),
+        encoding: DW_ATE_signed,
+        byte_size: 4,
+    });
+
+    format!(
+r#"(* Type language of the extracted samples, in prefix notation (the default). Terminals are the
+   printed tokens, which are separated by single spaces. With --type-notation postfix, the tokens
+   of each type are in reverse order. *)
+
+(* The final "end" only with --type-explicit-end. *)
+sample_type = type , [ {end} ] ;
+
+type = {unknown}
+     | {primitive}
+     | pointer , type
+     | {array} , {{ array_length }} , type
+     | qualifier , type
+     | name , type
+     | {enum} , [ enum_values ] , type  (* the underlying integer type *)
+     | compound , [ members ]
+     | function ;
+
+(* Specialized pointers only with --type-specialize-pointers. *)
+pointer = {pointers} ;
+
+(* All but "const" only with --type-keep-qualifiers. *)
+qualifier = {qualifiers} ;
+
+(* Typedefs can name any type. Nominal names and "<anon>" (only with --type-mark-anonymous)
+   name classes, structs, unions, and enums, or any type with --type-typedefs to-nominal. *)
+name = {names} ;
+
+compound = {compounds} ;
+
+(* Members are expanded with --type-struct-expand-depth, otherwise (or beyond that depth, or
+   for self-referential types) there are none, and "end" only with --type-explicit-end. *)
+members = {{ type }} , {end} ;
+
+(* The return type ("unknown" for void), then the parameter types. *)
+function = {function} , type , {{ type }} , {end} ;
+
+(* Only with --type-array-length, one per dimension. *)
+array_length = {array_lengths} ;
+
+(* Only with --type-enum-values. *)
+enum_values = {enum_values} ;
+
+(* The normalized name, e.g., int32_t, float64_t, char, or bool. *)
+primitive_name = letter , {{ letter | digit | "_" }} ;
+
+(* Quoted and escaped like a Rust string literal. *)
+string = '"' , {{ character }} , '"' ;
+
+digits = digit , {{ digit }} ;
+"#,
+        end = terminal(&End),
+        unknown = terminal(&Unknown),
+        primitive = terminal(&int32_t),
+        array = terminal(&Array),
+        enum = terminal(&Enum),
+        pointers = alternatives(&[Pointer, PointerTo(PointerTarget::Primitive), PointerTo(PointerTarget::Struct), PointerTo(PointerTarget::Function), PointerTo(PointerTarget::Void)]),
+        qualifiers = alternatives(&[Const, Volatile, Restrict, Atomic, Immutable]),
+        names = alternatives(&[Typedef("size_t".into()), Nominal("S".into()), Anonymous]),
+        compounds = alternatives(&[Struct, Class, Union]),
+        function = terminal(&Function),
+        array_lengths = alternatives(&[ArrayLength(Some(3)), ArrayLength(None)]),
+        enum_values = terminal(&EnumValues(3)),
+    )
+}
+
+/// EBNF for a single token: how it is printed, as a terminal, but with its variable part (a name
+/// or number) replaced by the corresponding nonterminal, e.g., `"<" , digits , " values>"`.
+fn terminal(token: &TypeToken) -> String {
+    // Exhaustive, such that new tokens must be added here (and then to the grammar above).
+    let variable = match token {
+        Primitive(primitive) => Some((primitive.normalized.to_string(), "primitive_name")),
+        ArrayLength(Some(count))
+        | EnumValues(count) => Some((count.to_string(), "digits")),
+        Nominal(name)
+        | Typedef(name) => Some((format!("{:?}", name), "string")),
+        Unknown | Pointer | PointerTo(_) | Array | ArrayLength(None) | Const | Volatile | Restrict | Atomic | Immutable
+        | Struct | Class | Union | Enum | Function | Anonymous | End => None,
+    };
+
+    let printed = token.to_string();
+    match variable {
+        Some((value, nonterminal)) => {
+            let start = printed.find(&value).expect("variable part must be printed");
+            let (before, after) = (&printed[..start], &printed[start + value.len()..]);
+            [format!("{:?}", before), nonterminal.to_string(), format!("{:?}", after)].iter()
+                .filter(|part| part.as_str() != r#""""#)
+                .join(" , ")
+        }
+        None => format!("{:?}", printed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grammar_lists_all_tokens() {
+        let int32_t = Primitive(PrimitiveType {
+            normalized: "int32_t",
+            source_name: "int".into(),
+            encoding: DW_ATE_signed,
+            byte_size: 4,
+        });
+        let tokens = vec![
+            Unknown, int32_t, Pointer, PointerTo(PointerTarget::Primitive), PointerTo(PointerTarget::Struct),
+            PointerTo(PointerTarget::Function), PointerTo(PointerTarget::Void), Array, ArrayLength(Some(3)),
+            ArrayLength(None), Const, Volatile, Restrict, Atomic, Immutable, Struct, Class, Union, Enum,
+            EnumValues(3), Function, Nominal("S".into()), Typedef("size_t".into()), Anonymous, End,
+        ];
+        // Compile error as a reminder to extend the list above when adding a token.
+        for token in &tokens {
+            match token {
+                Unknown | Primitive(_) | Pointer | PointerTo(_) | Array | ArrayLength(_) | Const | Volatile | Restrict
+                | Atomic | Immutable | Struct | Class | Union | Enum | EnumValues(_) | Function | Nominal(_)
+                | Typedef(_) | Anonymous | End => {}
+            }
+        }
+
+        let grammar = type_grammar_ebnf();
+        for token in &tokens {
+            assert!(grammar.contains(&terminal(token)), "{:?} missing in grammar:\n{}", token, grammar);
+        }
+        assert!(grammar.contains(r#"pointer = "pointer" | "pointer_to_prim" | "pointer_to_struct" | "pointer_to_func" | "pointer_to_void" ;"#));
+        assert!(grammar.contains(r#"name = "typedef " , string | "name " , string | "<anon>" ;"#));
+        assert!(grammar.contains(r#"array_length = "[" , digits , "]" | "[]" ;"#));
+        assert!(grammar.contains(r#"enum_values = "<" , digits , " values>" ;"#));
+        assert!(grammar.contains(r#"| "primitive " , primitive_name"#));
+    }
+}
@@ -1,13 +1,157 @@
 //! Our own "type language", abstracted away from the DWARF format.
 use std::fmt;
 
+use chashmap::CHashMap;
 use gimli::DwAte;
 
+use crate::options::{ConstMode, IntGranularity};
+use crate::util::frequencies::Frequencies;
+use crate::util::percent::Percent;
+
+pub mod grammar;
 pub mod parse;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Type(pub Vec<TypeToken>);
 
+fn is_name(token: &TypeToken) -> bool {
+    matches!(token, TypeToken::Typedef(_) | TypeToken::Nominal(_))
+}
+
+// Simplifications of already parsed types (see `PipelineOptions::simplify_type()`).
+impl Type {
+    /// Collapse a chain of names directly wrapping a primitive type to the innermost one, e.g.,
+    /// `typedef "my_int2" typedef "my_int" typedef "int32_t" primitive int32_t` (from
+    /// `typedef int32_t my_int; typedef my_int my_int2;`) to `typedef "int32_t" primitive int32_t`,
+    /// such that all aliases of the same primitive type have the same representation.
+    pub fn canonicalize_typedefs(&mut self) {
+        let mut new_type = Vec::with_capacity(self.0.len());
+        let mut tokens = std::mem::take(&mut self.0).into_iter().peekable();
+        while let Some(token) = tokens.next() {
+            // Drop all but the last name of a chain, if the chain ends in a primitive type.
+            let chain_continues = matches!(tokens.peek(), Some(t) if is_name(t));
+            if is_name(&token) && chain_continues {
+                let rest_of_chain = new_type.len();
+                new_type.push(token);
+                while matches!(tokens.peek(), Some(t) if is_name(t)) {
+                    new_type.push(tokens.next().unwrap());
+                }
+                if let Some(TypeToken::Primitive(_)) = tokens.peek() {
+                    new_type.drain(rest_of_chain..new_type.len() - 1);
+                }
+            } else {
+                new_type.push(token);
+            }
+        }
+        self.0 = new_type;
+    }
+
+    /// Keep at most one typedef or nominal name if there are multiple, namely the outermost one.
+    pub fn flatten_names_outermost(&mut self) {
+        let mut outermost_name = false;
+        self.0.retain(|t| {
+            if is_name(t) {
+                let keep = !outermost_name;
+                outermost_name = true;
+                keep
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Reverse the order of the tokens, i.e., from the (default) prefix notation, where type
+    /// constructors come before their inner types, to postfix notation, e.g., from
+    /// `pointer const primitive int32_t` to `primitive int32_t const pointer`. The conversion is its
+    /// own inverse. Each token stays intact (e.g., `primitive int32_t` or `name "S"`), and names
+    /// then come after the type they annotate. An `End` token that closes function parameters or
+    /// struct members comes before them, so non-linear types remain unambiguous.
+    pub fn convert_to_postfix(&mut self) {
+        self.0.reverse();
+    }
+
+    /// Collapse runs of more than `max_depth` consecutive pointer tokens to exactly `max_depth`,
+    /// keeping the pointee type. Returns whether the type was changed.
+    pub fn cap_pointer_depth(&mut self, max_depth: usize) -> bool {
+        let len_before = self.0.len();
+        let mut depth = 0;
+        self.0.retain(|t| {
+            if t == &TypeToken::Pointer {
+                depth += 1;
+                depth <= max_depth
+            } else {
+                depth = 0;
+                true
+            }
+        });
+        self.0.len() != len_before
+    }
+
+    /// Keep, remove, or only keep a leading const token (i.e., the const of the outermost type,
+    /// e.g., of the pointer in `const pointer primitive int32_t` for `int * const`).
+    pub fn simplify_const(&mut self, mode: ConstMode) {
+        match mode {
+            ConstMode::Keep => {}
+            ConstMode::Remove => self.0.retain(|t| t != &TypeToken::Const),
+            ConstMode::Outermost => {
+                let mut is_leading = true;
+                self.0.retain(|t| {
+                    let keep = is_leading || t != &TypeToken::Const;
+                    is_leading = false;
+                    keep
+                });
+            }
+        }
+    }
+
+    /// Replace the normalized name of fixed-width integer types by only their signedness (`int` or
+    /// `uint`), or by `int` for all of them, depending on the `granularity`. The source name and
+    /// other properties of the primitive type are kept.
+    pub fn coarsen_integers(&mut self, granularity: IntGranularity) {
+        if granularity == IntGranularity::Exact {
+            return;
+        }
+        for t in &mut self.0 {
+            if let TypeToken::Primitive(prim) = t {
+                let is_signed = match prim.normalized {
+                    "int8_t" | "int16_t" | "int32_t" | "int64_t" | "int128_t" => true,
+                    "uint8_t" | "uint16_t" | "uint32_t" | "uint64_t" | "uint128_t" => false,
+                    _ => continue,
+                };
+                prim.normalized = match (granularity, is_signed) {
+                    (IntGranularity::Signedness, false) => "uint",
+                    _ => "int",
+                };
+            }
+        }
+    }
+
+    /// Replace each pointer token by one that also encodes the kind of its target, e.g.,
+    /// `pointer const primitive int32_t` by `pointer_to_prim const primitive int32_t`. Qualifiers,
+    /// names, and `<anon>` tokens between the pointer and its target are skipped. Pointers to
+    /// other targets (e.g., to arrays, unions, or other pointers) are kept as-is.
+    pub fn specialize_pointers(&mut self) {
+        for i in 0..self.0.len() {
+            if self.0[i] != TypeToken::Pointer {
+                continue;
+            }
+            let target = self.0[i + 1..].iter()
+                .find(|t| !is_name(t) && !matches!(t, TypeToken::Const | TypeToken::Volatile | TypeToken::Restrict | TypeToken::Atomic | TypeToken::Immutable | TypeToken::Anonymous));
+            let target = match target {
+                Some(TypeToken::Primitive(_)) => PointerTarget::Primitive,
+                Some(TypeToken::Struct) | Some(TypeToken::Class) => PointerTarget::Struct,
+                Some(TypeToken::Function) => PointerTarget::Function,
+                // A pointer without DW_AT_type, i.e., void* (or decltype(nullptr)).
+                Some(TypeToken::Unknown) => PointerTarget::Void,
+                _ => continue,
+            };
+            self.0[i] = TypeToken::PointerTo(target);
+        }
+    }
+}
+
+/// Tokens separated by single spaces. Types are not delimited by themselves, except if they end
+/// with an `End` token (see `ParseOptions::explicit_end`).
 impl fmt::Display for Type {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some((last_token, tokens)) = self.0.split_last() {
@@ -28,45 +172,83 @@ pub enum TypeToken {
     Primitive(PrimitiveType),
 
     Pointer,
+    // Only emitted with --type-specialize-pointers, instead of Pointer.
+    PointerTo(PointerTarget),
     Array,
+    // Number of elements of one array dimension, None if unknown.
+    ArrayLength(Option<u64>),
 
     Const,
+    // Only emitted with --type-keep-qualifiers, otherwise stripped like in the source type.
+    Volatile,
+    Restrict,
+    // C11 _Atomic and D immutable.
+    Atomic,
+    Immutable,
 
     Struct,
     Class,
 
     Union,
     Enum,
+    // Number of enumerators of an enum.
+    EnumValues(u64),
 
     Function,
 
     Nominal(Box<str>),
     Typedef(Box<str>),
+    // Only emitted with --type-mark-anonymous, instead of the name of an unnamed class, struct,
+    // union, or enum.
+    Anonymous,
 
-    // Artificial token to make non-linear types unambiguous.
+    // Artificial token to make non-linear types unambiguous. Closes function types and expanded
+    // structs, unions, and classes, and with --type-explicit-end also unexpanded ones and every
+    // type as a whole.
     End
 }
 
+/// Kind of the type a pointer points to, see `Type::specialize_pointers()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PointerTarget {
+    Primitive,
+    Struct,
+    Function,
+    Void,
+}
+
 impl fmt::Display for TypeToken {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use TypeToken::*;
         match self {
             Unknown => f.write_str("unknown"),
             Pointer => f.write_str("pointer"),
+            PointerTo(PointerTarget::Primitive) => f.write_str("pointer_to_prim"),
+            PointerTo(PointerTarget::Struct) => f.write_str("pointer_to_struct"),
+            PointerTo(PointerTarget::Function) => f.write_str("pointer_to_func"),
+            PointerTo(PointerTarget::Void) => f.write_str("pointer_to_void"),
             Array => f.write_str("array"),
+            ArrayLength(Some(length)) => write!(f, "[{}]", length),
+            ArrayLength(None) => f.write_str("[]"),
             // TODO Print primitive prefix, or just the inner name?
             Primitive(prim) => write!(f, "primitive {}", prim.normalized),
             // Primitive(prim) => write!(f, "{}", prim.normalized),
             Const => f.write_str("const"),
+            Volatile => f.write_str("volatile"),
+            Restrict => f.write_str("restrict"),
+            Atomic => f.write_str("atomic"),
+            Immutable => f.write_str("immutable"),
             Struct => f.write_str("struct"),
             Class => f.write_str("class"),
             Union => f.write_str("union"),
             Enum => f.write_str("enum"),
+            EnumValues(count) => write!(f, "<{} values>", count),
             Function => f.write_str("function"),
             // TODO Print nominal prefix?
             Nominal(name) => write!(f, "name {:?}", name),
             // Nominal(name) => write!(f, "{:?}", name),
             Typedef(name) => write!(f, "typedef {:?}", name),
+            Anonymous => f.write_str("<anon>"),
             End => write!(f, "end"),
         }
     }
@@ -82,3 +264,220 @@ pub struct PrimitiveType {
     pub encoding: DwAte,
     pub byte_size: u64,
 }
+
+/// Concurrent counter of the primitive types in parsed types, e.g., to spot unusual base types
+/// and check how their source names were normalized.
+#[derive(Debug, Default)]
+pub struct PrimitiveTypeCounts(CHashMap<PrimitiveType, u64>);
+
+impl PrimitiveTypeCounts {
+    pub fn add(&self, type_: &Type) {
+        for token in &type_.0 {
+            if let TypeToken::Primitive(primitive) = token {
+                self.0.upsert(primitive.clone(), || 1, |count| *count += 1);
+            }
+        }
+    }
+
+    /// Primitive types with their count (and percentage of all primitive types), sorted by
+    /// normalized representation first, then by source name etc.
+    pub fn sorted(self) -> Vec<((PrimitiveType, Percent), u64)> {
+        self.0.into_iter()
+            .sorted_items()
+            .with_percent()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::TypeToken::*;
+
+    fn int32_t() -> TypeToken {
+        Primitive(PrimitiveType {
+            normalized: "int32_t",
+            source_name: "int".into(),
+            encoding: gimli::DW_ATE_signed,
+            byte_size: 4,
+        })
+    }
+
+    fn typedef(name: &str) -> TypeToken {
+        Typedef(name.into())
+    }
+
+    fn primitive(normalized: &'static str, source_name: &str, encoding: DwAte, byte_size: u64) -> TypeToken {
+        Primitive(PrimitiveType { normalized, source_name: source_name.into(), encoding, byte_size })
+    }
+
+    #[test]
+    fn test_primitive_type_counts() {
+        let uint = primitive("uint32_t", "unsigned int", gimli::DW_ATE_unsigned, 4);
+        let ulong = primitive("uint32_t", "unsigned long", gimli::DW_ATE_unsigned, 4);
+        let long_double = primitive("float128_t", "long double", gimli::DW_ATE_float, 16);
+
+        let counts = PrimitiveTypeCounts::default();
+        counts.add(&Type(vec![Pointer, long_double.clone()]));
+        counts.add(&Type(vec![typedef("size_t"), ulong.clone()]));
+        counts.add(&Type(vec![int32_t()]));
+        counts.add(&Type(vec![uint.clone()]));
+        counts.add(&Type(vec![uint]));
+        // Non-primitive types are not counted.
+        counts.add(&Type(vec![Pointer, Struct]));
+
+        let counts: Vec<_> = counts.sorted().into_iter()
+            .map(|((ty, percent), count)| (ty.normalized, ty.source_name, percent.to_string(), count))
+            .collect();
+        assert_eq!(counts, vec![
+            ("float128_t", "long double".into(), "20.0%".to_string(), 1),
+            ("int32_t", "int".into(), "20.0%".to_string(), 1),
+            ("uint32_t", "unsigned int".into(), "40.0%".to_string(), 2),
+            ("uint32_t", "unsigned long".into(), "20.0%".to_string(), 1),
+        ]);
+    }
+
+    #[test]
+    fn test_canonicalize_typedefs() {
+        // typedef int32_t my_int; typedef my_int my_int2;
+        let mut type_ = Type(vec![typedef("my_int2"), typedef("my_int"), typedef("int32_t"), int32_t()]);
+        type_.canonicalize_typedefs();
+        assert_eq!(type_, Type(vec![typedef("int32_t"), int32_t()]));
+
+        // Only the chain directly wrapping the primitive is collapsed, not the pointer's name.
+        let mut type_ = Type(vec![typedef("my_ptr"), Pointer, typedef("my_int"), typedef("int32_t"), int32_t()]);
+        type_.canonicalize_typedefs();
+        assert_eq!(type_, Type(vec![typedef("my_ptr"), Pointer, typedef("int32_t"), int32_t()]));
+
+        // Names of non-primitive types are kept.
+        let mut type_ = Type(vec![typedef("S_t"), Nominal("S".into()), Struct]);
+        type_.canonicalize_typedefs();
+        assert_eq!(type_, Type(vec![typedef("S_t"), Nominal("S".into()), Struct]));
+    }
+
+    #[test]
+    fn test_canonicalize_typedefs_and_flatten_outermost() {
+        let type_ = Type(vec![typedef("my_ptr"), Pointer, typedef("my_int2"), typedef("my_int"), int32_t()]);
+
+        let mut flattened = type_.clone();
+        flattened.flatten_names_outermost();
+        assert_eq!(flattened, Type(vec![typedef("my_ptr"), Pointer, int32_t()]));
+
+        // Canonicalization first, as in `PipelineOptions::simplify_type()`, then flattening keeps
+        // the outermost name.
+        let mut canonical = type_;
+        canonical.canonicalize_typedefs();
+        assert_eq!(canonical, Type(vec![typedef("my_ptr"), Pointer, typedef("my_int"), int32_t()]));
+        canonical.flatten_names_outermost();
+        assert_eq!(canonical, Type(vec![typedef("my_ptr"), Pointer, int32_t()]));
+
+        // Without an outer name, the canonical typedef is the outermost one.
+        let mut canonical = Type(vec![typedef("my_int2"), typedef("my_int"), int32_t()]);
+        canonical.canonicalize_typedefs();
+        canonical.flatten_names_outermost();
+        assert_eq!(canonical, Type(vec![typedef("my_int"), int32_t()]));
+    }
+
+    #[test]
+    fn test_simplify_const() {
+        let const_int = Type(vec![Const, int32_t()]);
+        let pointer_to_const_int = Type(vec![Pointer, Const, int32_t()]);
+        let const_pointer_to_int = Type(vec![Const, Pointer, int32_t()]);
+
+        let simplify = |type_: &Type, mode| {
+            let mut type_ = type_.clone();
+            type_.simplify_const(mode);
+            type_
+        };
+
+        for type_ in &[&const_int, &pointer_to_const_int, &const_pointer_to_int] {
+            assert_eq!(&&simplify(type_, ConstMode::Keep), type_);
+        }
+
+        assert_eq!(simplify(&const_int, ConstMode::Remove), Type(vec![int32_t()]));
+        assert_eq!(simplify(&pointer_to_const_int, ConstMode::Remove), Type(vec![Pointer, int32_t()]));
+        assert_eq!(simplify(&const_pointer_to_int, ConstMode::Remove), Type(vec![Pointer, int32_t()]));
+
+        assert_eq!(simplify(&const_int, ConstMode::Outermost), const_int);
+        assert_eq!(simplify(&pointer_to_const_int, ConstMode::Outermost), Type(vec![Pointer, int32_t()]));
+        assert_eq!(simplify(&const_pointer_to_int, ConstMode::Outermost), const_pointer_to_int);
+
+        // Only the leading const is kept, e.g., for 'const int * const'.
+        let type_ = Type(vec![Const, Pointer, Const, int32_t()]);
+        assert_eq!(simplify(&type_, ConstMode::Outermost), const_pointer_to_int);
+    }
+
+    #[test]
+    fn test_coarsen_integers() {
+        let int8 = primitive("int8_t", "signed char", gimli::DW_ATE_signed_char, 1);
+        let uint32 = primitive("uint32_t", "unsigned int", gimli::DW_ATE_unsigned, 4);
+        let int64 = primitive("int64_t", "long long", gimli::DW_ATE_signed, 8);
+        let float = primitive("float32_t", "float", gimli::DW_ATE_float, 4);
+        let type_ = Type(vec![Function, int8, Pointer, uint32, int64, float, End]);
+
+        let coarsen = |granularity| {
+            let mut type_ = type_.clone();
+            type_.coarsen_integers(granularity);
+            type_.to_string()
+        };
+        assert_eq!(coarsen(IntGranularity::Exact), "function primitive int8_t pointer primitive uint32_t primitive int64_t primitive float32_t end");
+        assert_eq!(coarsen(IntGranularity::Signedness), "function primitive int pointer primitive uint primitive int primitive float32_t end");
+        assert_eq!(coarsen(IntGranularity::Integer), "function primitive int pointer primitive int primitive int primitive float32_t end");
+    }
+
+    #[test]
+    fn test_cap_pointer_depth() {
+        let mut type_ = Type(vec![Pointer, Pointer, Pointer, Pointer, int32_t()]);
+        assert!(type_.cap_pointer_depth(2));
+        assert_eq!(type_, Type(vec![Pointer, Pointer, int32_t()]));
+
+        // Already short enough.
+        assert!(!type_.cap_pointer_depth(2));
+        assert_eq!(type_, Type(vec![Pointer, Pointer, int32_t()]));
+
+        // Each run of pointers is capped separately.
+        let mut type_ = Type(vec![Pointer, Pointer, Pointer, Const, Pointer, Pointer, Pointer, int32_t()]);
+        assert!(type_.cap_pointer_depth(2));
+        assert_eq!(type_, Type(vec![Pointer, Pointer, Const, Pointer, Pointer, int32_t()]));
+    }
+
+    #[test]
+    fn test_convert_to_postfix() {
+        let pointer_to_const_int = Type(vec![Pointer, Const, int32_t()]);
+        assert_eq!(pointer_to_const_int.to_string(), "pointer const primitive int32_t");
+
+        let mut postfix = pointer_to_const_int.clone();
+        postfix.convert_to_postfix();
+        assert_eq!(postfix.to_string(), "primitive int32_t const pointer");
+        postfix.convert_to_postfix();
+        assert_eq!(postfix, pointer_to_const_int);
+
+        // Names stay attached to their type, and 'end' opens the parameters.
+        let mut type_ = Type(vec![Pointer, Function, int32_t(), Pointer, Nominal("S".into()), Struct, End]);
+        type_.convert_to_postfix();
+        assert_eq!(type_.to_string(), r#"end struct name "S" pointer primitive int32_t function pointer"#);
+    }
+
+    #[test]
+    fn test_specialize_pointers() {
+        let specialize = |tokens: Vec<TypeToken>| {
+            let mut type_ = Type(tokens);
+            type_.specialize_pointers();
+            type_.to_string()
+        };
+
+        // int*
+        assert_eq!(specialize(vec![Pointer, int32_t()]), "pointer_to_prim primitive int32_t");
+        // struct Foo*
+        assert_eq!(specialize(vec![Pointer, Nominal("Foo".into()), Struct]), r#"pointer_to_struct name "Foo" struct"#);
+        // void(*)()
+        assert_eq!(specialize(vec![Pointer, Function, Unknown, End]), "pointer_to_func function unknown end");
+        // void*
+        assert_eq!(specialize(vec![Pointer, Unknown]), "pointer_to_void unknown");
+
+        // const int*, the const between the pointer and its target is skipped (and kept).
+        assert_eq!(specialize(vec![Pointer, Const, int32_t()]), "pointer_to_prim const primitive int32_t");
+        // int**, only the inner pointer has a classified target.
+        assert_eq!(specialize(vec![Pointer, Pointer, int32_t()]), "pointer pointer_to_prim primitive int32_t");
+    }
+}
@@ -1,16 +1,61 @@
 //! Parse the DWARF type format to our own, which abstracts over some language-specifcs and 
 //! simplifies the type language a lot.
+use std::collections::HashSet;
+
 use anyhow::Context;
-use gimli::{AttributeValue, Reader, constants::*};
+use gimli::{AttributeValue, Reader, UnitOffset, constants::*};
 
 use crate::dwarf::util::DwarfEntry;
+use crate::options::CharMode;
 use crate::samples::types::{Type, TypeToken, PrimitiveType};
 use crate::samples::types::TypeToken::*;
 
+/// Options that influence how DWARF types are converted to our type language (as opposed to the
+/// simplifications in `PipelineOptions::simplify_type()`, which are applied on the already
+/// converted types).
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Up to which nesting depth the members of structs, unions, and classes are expanded.
+    /// 0 means members are never expanded, i.e., those types are just a single token.
+    pub struct_expand_depth: usize,
+    /// Emit the length of each dimension after array tokens, e.g., `array [3] [4] primitive char`
+    /// for `char[3][4]`.
+    pub array_length: bool,
+    /// Prefix the names of classes, structs, unions, and enums with their enclosing namespaces (and
+    /// types), e.g., `name "std::vector"` instead of `name "vector"`.
+    pub qualified_names: bool,
+    /// Emit the number of enumerators after enum tokens, e.g., `enum <3 values> primitive uint32_t`.
+    pub enum_values: bool,
+    /// Whether `signed char` and `unsigned char` are merged with the 1-byte integers, or kept
+    /// distinct from them and from plain `char`, see `parse_primitive_type()`.
+    pub char_mode: CharMode,
+    /// Always close structs, unions, and classes with an `End` token, also if their members are not
+    /// expanded, such that every non-linear type constructor is balanced by an `End`. (The `End`
+    /// after the whole type is appended in `PipelineOptions::simplify_type()`, after all
+    /// simplifications.)
+    pub explicit_end: bool,
+    /// Emit `volatile`, `restrict`, `atomic`, and `immutable` tokens for the respective type
+    /// qualifiers (like for `const`), instead of stripping them.
+    pub keep_qualifiers: bool,
+    /// Emit an `<anon>` token (where the name would be) for classes, structs, unions, and enums
+    /// without a name, e.g., `<anon> struct` for `struct { int x; }`.
+    pub mark_anonymous: bool,
+}
+
+/// State while converting a single (top-level) DWARF type.
+struct ParseState<'a, O> {
+    options: &'a ParseOptions,
+
+    // Structs, unions, and classes whose members are currently being expanded. Used both to
+    // determine the current nesting depth and to avoid infinite recursion for self-referential
+    // types (e.g., a linked-list node that contains a pointer to the same node type).
+    expanding: HashSet<UnitOffset<O>>,
+}
+
 impl Type {
-    pub fn parse_param<R: Reader>(param_entry: &DwarfEntry<R>) -> anyhow::Result<Self> {
+    pub fn parse_param<R: Reader>(param_entry: &DwarfEntry<R>, options: &ParseOptions) -> anyhow::Result<Self> {
         if let Some(type_entry) = param_entry.attr_entry(DW_AT_type)? {
-            Self::parse_type(&type_entry)
+            Self::parse_type(&type_entry, options)
         } else {
             // For some parameters (about 1.9% of all samples), the DW_AT_type attribute is absent.
             // This seems to be the case for generic or inlined functions (not exactly sure) 
@@ -19,28 +64,29 @@ impl Type {
             // of the parameters) is only available behind the abstract origin of the parameters 
             // also. So we try to resolve that here and otherwise say unknown type.
             if let Some(abstract_origin) = param_entry.attr_entry(DW_AT_abstract_origin)? {
-                Self::parse_param(&abstract_origin)
+                Self::parse_param(&abstract_origin, options)
             } else {
                 Ok(Type(vec!(Unknown)))
             }
         }
     }
 
-    pub fn parse_type<R: Reader>(type_entry: &DwarfEntry<R>) -> anyhow::Result<Self> {
+    pub fn parse_type<R: Reader>(type_entry: &DwarfEntry<R>, options: &ParseOptions) -> anyhow::Result<Self> {
         // Pre-allocate such that most types never need to grow (average: ~2.5 tokens per type).
         let mut tokens = Vec::with_capacity(4);
 
-        Self::parse_type_to_tokens(&mut tokens, type_entry)?;
+        let mut state = ParseState { options, expanding: HashSet::new() };
+        Self::parse_type_to_tokens(&mut tokens, type_entry, &mut state)?;
 
         Ok(Type(tokens))
     }
 
-    fn parse_type_to_tokens<R: Reader>(tokens: &mut Vec<TypeToken>, entry: &DwarfEntry<R>) -> anyhow::Result<()> {
+    fn parse_type_to_tokens<R: Reader>(tokens: &mut Vec<TypeToken>, entry: &DwarfEntry<R>, state: &mut ParseState<R::Offset>) -> anyhow::Result<()> {
         #[allow(non_upper_case_globals)]
         match entry.tag {
 
             DW_TAG_base_type => {
-                let prim = Self::parse_primitive_type(entry)?;
+                let prim = Self::parse_primitive_type(entry, state.options.char_mode)?;
                 tokens.push(Primitive(prim));
             },
 
@@ -51,17 +97,26 @@ impl Type {
             | DW_TAG_ptr_to_member_type
             | DW_TAG_pointer_type => {
                 tokens.push(Pointer);
-                Self::parse_inner_type_to_tokens(tokens, entry)?;
+                Self::parse_inner_type_to_tokens(tokens, entry, state)?;
             }
 
             DW_TAG_const_type => {
                 tokens.push(Const);
-                Self::parse_inner_type_to_tokens(tokens, entry)?;
+                Self::parse_inner_type_to_tokens(tokens, entry, state)?;
             }
 
             DW_TAG_array_type => {
                 tokens.push(Array);
-                Self::parse_inner_type_to_tokens(tokens, entry)?;
+                if state.options.array_length {
+                    // Multi-dimensional arrays are a single array type with one subrange per dimension.
+                    for subrange in entry.children()? {
+                        let subrange = subrange?;
+                        if subrange.tag == DW_TAG_subrange_type {
+                            tokens.push(ArrayLength(Self::parse_array_length(&subrange)?));
+                        }
+                    }
+                }
+                Self::parse_inner_type_to_tokens(tokens, entry, state)?;
             }
 
             DW_TAG_typedef => {
@@ -76,55 +131,84 @@ impl Type {
                 // nominal types).
                 let name = entry.attr_str(DW_AT_name)?.context("typedef must have DW_AT_name attribute")?;
                 tokens.push(Typedef(name));
-                Self::parse_inner_type_to_tokens(tokens, entry)?;
+                Self::parse_inner_type_to_tokens(tokens, entry, state)?;
             },
 
             DW_TAG_enumeration_type => {
-                if let Some(name) = entry.attr_str(DW_AT_name)? {
-                    tokens.push(Nominal(name));
-                }
+                Self::parse_nominal_name_to_tokens(tokens, entry, state)?;
                 tokens.push(Enum);
+                if state.options.enum_values {
+                    let mut count = 0;
+                    for enumerator in entry.children()? {
+                        if enumerator?.tag == DW_TAG_enumerator {
+                            count += 1;
+                        }
+                    }
+                    tokens.push(EnumValues(count));
+                }
                 // The inner type of an enum is its primitive base type, I believe.
-                Self::parse_inner_type_to_tokens(tokens, entry)?;
+                Self::parse_inner_type_to_tokens(tokens, entry, state)?;
             }
 
-            // TODO keep class vs. struct spearate? -> ablation study how well the model can handle this
-            // // Map C++ classes to C structs (forgetting about all contained methods, virtuality, etc.)
+            // Kept separate here, see --type-class-to-struct and --type-struct-to-class for the
+            // ablation that merges them (in either direction) in the pipeline.
             DW_TAG_class_type => {
-                if let Some(name) = entry.attr_str(DW_AT_name)? {
-                    tokens.push(Nominal(name));
-                }
+                Self::parse_nominal_name_to_tokens(tokens, entry, state)?;
                 tokens.push(Class);
-
-                // FIXME currently not recursing into "types with multiple children"
+                Self::parse_members_to_tokens(tokens, entry, state)?;
             } 
             DW_TAG_structure_type => {
-                if let Some(name) = entry.attr_str(DW_AT_name)? {
-                    tokens.push(Nominal(name));
-                }
+                Self::parse_nominal_name_to_tokens(tokens, entry, state)?;
                 tokens.push(Struct);
-
-                // FIXME currently not recursing into "types with multiple children"
+                Self::parse_members_to_tokens(tokens, entry, state)?;
             }
 
             DW_TAG_union_type => {
-                if let Some(name) = entry.attr_str(DW_AT_name)? {
-                    tokens.push(Nominal(name));
-                }
+                Self::parse_nominal_name_to_tokens(tokens, entry, state)?;
                 tokens.push(Union);
-
-                // FIXME currently not recursing into "types with multiple children"
+                Self::parse_members_to_tokens(tokens, entry, state)?;
             }
 
+            // Function types are followed by the return type, then all parameter types, terminated by
+            // an End token (since the number of parameters varies).
             DW_TAG_subroutine_type => {
-                tokens.push(Function)
+                tokens.push(Function);
+
+                // If there is no DW_AT_type, the function returns void, for which we have no token.
+                Self::parse_inner_type_to_tokens(tokens, entry, state)?;
 
-                // FIXME currently not printing type of arguments and returns.
+                // Variadic arguments (DW_TAG_unspecified_parameters) are ignored.
+                for param in entry.children()? {
+                    let param = param?;
+                    if param.tag == DW_TAG_formal_parameter {
+                        Self::parse_inner_type_to_tokens(tokens, &param, state)?;
+                    }
+                }
+                tokens.push(End);
             },
 
-            // Strip some type modifiers by just returning the inner type without wrapping
+            DW_TAG_volatile_type if state.options.keep_qualifiers => {
+                tokens.push(Volatile);
+                Self::parse_inner_type_to_tokens(tokens, entry, state)?;
+            }
+            DW_TAG_restrict_type if state.options.keep_qualifiers => {
+                tokens.push(Restrict);
+                Self::parse_inner_type_to_tokens(tokens, entry, state)?;
+            }
+            // Newer (DWARF 5) qualifiers: C11 _Atomic, and immutable from D.
+            DW_TAG_atomic_type if state.options.keep_qualifiers => {
+                tokens.push(Atomic);
+                Self::parse_inner_type_to_tokens(tokens, entry, state)?;
+            }
+            DW_TAG_immutable_type if state.options.keep_qualifiers => {
+                tokens.push(Immutable);
+                Self::parse_inner_type_to_tokens(tokens, entry, state)?;
+            }
+            // Otherwise, strip these type modifiers by just returning the inner type without wrapping
             DW_TAG_volatile_type
-            | DW_TAG_restrict_type => Self::parse_inner_type_to_tokens(tokens, entry)?,
+            | DW_TAG_restrict_type
+            | DW_TAG_atomic_type
+            | DW_TAG_immutable_type => Self::parse_inner_type_to_tokens(tokens, entry, state)?,
 
             // In general, the unspecified type can be a lot of things, the DWARF 5 standard, section 
             // 5.2, "Unspecified Type Entries" says:
@@ -163,16 +247,109 @@ impl Type {
         Ok(())
     }
 
-    fn parse_inner_type_to_tokens<R: Reader>(tokens: &mut Vec<TypeToken>, entry_with_type_attr: &DwarfEntry<R>) -> anyhow::Result<()> {
+    /// Push the name of a class, struct, union, or enum, or with `ParseOptions::mark_anonymous`,
+    /// an `Anonymous` token if it has none.
+    fn parse_nominal_name_to_tokens<R: Reader>(tokens: &mut Vec<TypeToken>, entry: &DwarfEntry<R>, state: &ParseState<R::Offset>) -> anyhow::Result<()> {
+        match Self::parse_nominal_name(entry, state)? {
+            Some(name) => tokens.push(Nominal(name)),
+            None if state.options.mark_anonymous => tokens.push(Anonymous),
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Name of a class, struct, union, or enum, optionally qualified with the enclosing namespaces
+    /// and types (see `ParseOptions::qualified_names`).
+    fn parse_nominal_name<R: Reader>(entry: &DwarfEntry<R>, state: &ParseState<R::Offset>) -> anyhow::Result<Option<Box<str>>> {
+        let name = match entry.attr_str(DW_AT_name)? {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        if !state.options.qualified_names {
+            return Ok(Some(name));
+        }
+
+        let mut qualified_name = String::new();
+        for ancestor in entry.ancestors()? {
+            #[allow(non_upper_case_globals)]
+            let ancestor_name = match ancestor.tag {
+                DW_TAG_namespace => ancestor.attr_str(DW_AT_name)?
+                    .unwrap_or_else(|| "(anonymous namespace)".into()),
+                // Nested types, e.g., `std::vector<int>::iterator`.
+                DW_TAG_class_type
+                | DW_TAG_structure_type
+                | DW_TAG_union_type => match ancestor.attr_str(DW_AT_name)? {
+                    Some(name) => name,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            qualified_name.push_str(&ancestor_name);
+            qualified_name.push_str("::");
+        }
+        qualified_name.push_str(&name);
+        Ok(Some(qualified_name.into()))
+    }
+
+    fn parse_inner_type_to_tokens<R: Reader>(tokens: &mut Vec<TypeToken>, entry_with_type_attr: &DwarfEntry<R>, state: &mut ParseState<R::Offset>) -> anyhow::Result<()> {
         if let Some(type_entry) = entry_with_type_attr.attr_entry(DW_AT_type)? {
-            Self::parse_type_to_tokens(tokens, &type_entry)
+            Self::parse_type_to_tokens(tokens, &type_entry, state)
         } else {
             tokens.push(Unknown);
             Ok(())
         }
     }
 
-    fn parse_primitive_type<R: Reader>(entry: &DwarfEntry<R>) -> anyhow::Result<PrimitiveType> {
+    /// Number of elements of a single array dimension, or None if unknown (e.g., for `int[]`).
+    fn parse_array_length<R: Reader>(subrange: &DwarfEntry<R>) -> gimli::Result<Option<u64>> {
+        // Not via attr_uint(), since the bounds can also be negative (Clang uses a count of -1 for
+        // unknown lengths) or non-constant (for variable-length arrays), which we treat as unknown.
+        let constant = |name| -> gimli::Result<Option<u64>> {
+            Ok(subrange.attr(name)?.and_then(|value| value.udata_value()))
+        };
+        if let Some(count) = constant(DW_AT_count)? {
+            return Ok(Some(count));
+        }
+        // The lower bound defaults to 0 for C/C++.
+        let lower_bound = constant(DW_AT_lower_bound)?.unwrap_or(0);
+        Ok(constant(DW_AT_upper_bound)?.map(|upper_bound| upper_bound.saturating_sub(lower_bound) + 1))
+    }
+
+    /// Expand the members of a struct, union, or class (if enabled via the options), i.e., the
+    /// types of all data members follow in declaration order, terminated by an `End` token.
+    /// Methods, base classes, nested type declarations etc. are not included.
+    fn parse_members_to_tokens<R: Reader>(tokens: &mut Vec<TypeToken>, entry: &DwarfEntry<R>, state: &mut ParseState<R::Offset>) -> anyhow::Result<()> {
+        if state.options.struct_expand_depth == 0 {
+            if state.options.explicit_end {
+                tokens.push(End);
+            }
+            return Ok(());
+        }
+
+        // Do not expand beyond the maximum depth or if we are already inside this very type.
+        // However, still emit the End token, such that it is unambiguous to which type the
+        // following members belong.
+        let offset = entry.offset();
+        let expand = state.expanding.len() < state.options.struct_expand_depth 
+            && !state.expanding.contains(&offset);
+        if expand {
+            state.expanding.insert(offset);
+            for member in entry.children()? {
+                let member = member?;
+                // Static data members are not part of the layout (in DWARF 4, they are members
+                // with DW_AT_declaration, in DWARF 5 they are DW_TAG_variable children instead).
+                if member.tag == DW_TAG_member && member.attr(DW_AT_declaration)?.is_none() {
+                    Self::parse_inner_type_to_tokens(tokens, &member, state)?;
+                }
+            }
+            state.expanding.remove(&offset);
+        }
+        tokens.push(End);
+
+        Ok(())
+    }
+
+    fn parse_primitive_type<R: Reader>(entry: &DwarfEntry<R>, char_mode: CharMode) -> anyhow::Result<PrimitiveType> {
         let source_name = entry.attr_str(DW_AT_name)?
             .context("base (=primitive) type must have DW_AT_name attribute")?;
         
@@ -218,7 +395,10 @@ impl Type {
             ("char16_t", DW_ATE_UTF, 2) => "char16_t",
             ("char32_t", DW_ATE_UTF, 4) => "char32_t",
 
-            // Treat chars with explicit signed/unsigned annotation as integers of 1 byte.
+            // By default, treat chars with explicit signed/unsigned annotation as integers of 1 byte.
+            // For C faithfulness, they can also be kept as two more distinct char types.
+            (_, DW_ATE_signed_char, 1) if char_mode == CharMode::ThreeWay => "schar",
+            (_, DW_ATE_unsigned_char, 1) if char_mode == CharMode::ThreeWay => "uchar",
             (_, DW_ATE_signed_char, 1) => "int8_t",
             (_, DW_ATE_unsigned_char, 1) => "uint8_t",
 
@@ -228,6 +408,11 @@ impl Type {
             (_, DW_ATE_unsigned, 2) => "uint16_t",
             (_, DW_ATE_unsigned, 4) => "uint32_t",
             (_, DW_ATE_unsigned, 8) => "uint64_t",
+            // 128-bit integers are a GCC/Clang extension (__int128 and unsigned __int128), not in the
+            // C/C++ standards, but we name them consistently with the fixed-width types above.
+            // https://gcc.gnu.org/onlinedocs/gcc/_005f_005fint128.html
+            (_, DW_ATE_signed, 16) => "int128_t",
+            (_, DW_ATE_unsigned, 16) => "uint128_t",
 
             // Exact-width floating point types do not exist in the C/C++ standards, but we believe the
             // following names are self-explanatory enough, and there is prior work for fixed-size
@@ -235,9 +420,16 @@ impl Type {
             // https://www.boost.org/doc/libs/master/libs/math/doc/html/math_toolkit/exact_typdefs.html
             // Typical float types are "float" and "double" (32/64-bit, standard IEEE754), "long double"
             // (can be 80-bit extended precision floats of the x86 x87 FPU, but could also be 128-bit
-            // IEEE 754, then also typedef'd to "__float128")
+            // IEEE 754, then also typedef'd to "__float128"). Half-precision floats (16-bit) are
+            // "_Float16" (C, ISO/IEC TS 18661-3) or "__fp16" (ARM), e.g., in machine learning code.
+            (_, DW_ATE_float, 2) => "float16_t",
             (_, DW_ATE_float, 4) => "float32_t",
             (_, DW_ATE_float, 8) => "float64_t",
+            // The 80-bit x87 extended precision "long double" has a byte_size of 10, or 12 when padded
+            // for 4-byte alignment (e.g., i386). When padded to 16 bytes (e.g., x86-64), it is
+            // indistinguishable from a 128-bit IEEE 754 float by the DWARF attributes alone, so that
+            // case still maps to "float128_t" below. The original name is kept in source_name.
+            (_, DW_ATE_float, 10) | (_, DW_ATE_float, 12) => "float80_t",
             (_, DW_ATE_float, 16) => "float128_t",
 
             // C99 has a keyword for complex numbers (guaranteed to have the same layout as a 2-element
@@ -264,4 +456,398 @@ impl Type {
 
         Ok(PrimitiveType { normalized, source_name, encoding, byte_size })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use gimli::write;
+
+    use super::*;
+    use crate::dwarf::test_util::*;
+
+    fn parse_param_str(sections: &HashMap<&str, Rc<[u8]>>, param_name: &str, options: &ParseOptions) -> String {
+        let param = entry_by_name(sections, param_name);
+        Type::parse_param(&param, options).unwrap().to_string()
+    }
+
+    fn struct_expand_depth(depth: usize) -> ParseOptions {
+        ParseOptions { struct_expand_depth: depth, ..ParseOptions::default() }
+    }
+
+    /// `struct Inner { float f; }; struct Outer { int i; struct Inner* p; struct Inner in; };`
+    /// and a parameter `struct Outer x`.
+    fn nested_struct(unit: &mut write::Unit) {
+        let root = unit.root();
+        let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+        let float = add_base_type(unit, "float", DW_ATE_float, 4);
+
+        let inner = add(unit, root, DW_TAG_structure_type, Some("Inner"));
+        add_typed(unit, inner, DW_TAG_member, Some("f"), float);
+        let inner_ptr = add_typed(unit, root, DW_TAG_pointer_type, None, inner);
+
+        let outer = add(unit, root, DW_TAG_structure_type, Some("Outer"));
+        add_typed(unit, outer, DW_TAG_member, Some("i"), int);
+        add_typed(unit, outer, DW_TAG_member, Some("p"), inner_ptr);
+        add_typed(unit, outer, DW_TAG_member, Some("in"), inner);
+
+        add_typed(unit, root, DW_TAG_formal_parameter, Some("x"), outer);
+    }
+
+    #[test]
+    fn test_struct_not_expanded_by_default() {
+        let sections = dwarf_sections(nested_struct);
+        assert_eq!(parse_param_str(&sections, "x", &ParseOptions::default()), r#"name "Outer" struct"#);
+    }
+
+    #[test]
+    fn test_struct_nested() {
+        let sections = dwarf_sections(nested_struct);
+        assert_eq!(
+            parse_param_str(&sections, "x", &struct_expand_depth(2)),
+            r#"name "Outer" struct primitive int32_t pointer name "Inner" struct primitive float32_t end name "Inner" struct primitive float32_t end end"#
+        );
+    }
+
+    #[test]
+    fn test_struct_depth_cutoff() {
+        let sections = dwarf_sections(nested_struct);
+        assert_eq!(
+            parse_param_str(&sections, "x", &struct_expand_depth(1)),
+            r#"name "Outer" struct primitive int32_t pointer name "Inner" struct end name "Inner" struct end end"#
+        );
+    }
+
+    #[test]
+    fn test_function_pointer() {
+        // int (*f)(char, double)
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let char = add_base_type(unit, "char", DW_ATE_signed_char, 1);
+            let double = add_base_type(unit, "double", DW_ATE_float, 8);
+            let function = add_typed(unit, root, DW_TAG_subroutine_type, None, int);
+            add_typed(unit, function, DW_TAG_formal_parameter, None, char);
+            add_typed(unit, function, DW_TAG_formal_parameter, None, double);
+            let function_ptr = add_typed(unit, root, DW_TAG_pointer_type, None, function);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("f"), function_ptr);
+        });
+        assert_eq!(
+            parse_param_str(&sections, "f", &ParseOptions::default()),
+            "pointer function primitive int32_t primitive char primitive float64_t end"
+        );
+    }
+
+    #[test]
+    fn test_function_void_no_params() {
+        // void (*f)(void)
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let function = add(unit, root, DW_TAG_subroutine_type, None);
+            let function_ptr = add_typed(unit, root, DW_TAG_pointer_type, None, function);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("f"), function_ptr);
+        });
+        assert_eq!(parse_param_str(&sections, "f", &ParseOptions::default()), "pointer function unknown end");
+    }
+
+    #[test]
+    fn test_struct_recursive() {
+        // struct Node { int value; struct Node* next; };
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let node = add(unit, root, DW_TAG_structure_type, Some("Node"));
+            let node_ptr = add_typed(unit, root, DW_TAG_pointer_type, None, node);
+            add_typed(unit, node, DW_TAG_member, Some("value"), int);
+            add_typed(unit, node, DW_TAG_member, Some("next"), node_ptr);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("list"), node_ptr);
+        });
+        assert_eq!(
+            parse_param_str(&sections, "list", &struct_expand_depth(10)),
+            r#"pointer name "Node" struct primitive int32_t pointer name "Node" struct end end"#
+        );
+    }
+
+    /// Whether each struct, class, union, and function token is closed by an end token, and the
+    /// type as a whole by one more.
+    fn is_balanced(type_: &Type) -> bool {
+        let mut open = 1;
+        for token in &type_.0 {
+            match token {
+                Struct | Class | Union | Function => open += 1,
+                End if open == 0 => return false,
+                End => open -= 1,
+                _ => {}
+            }
+        }
+        open == 0
+    }
+
+    #[test]
+    fn test_explicit_end() {
+        // struct S { int i; }; and parameters `struct S* p`, `int (*f)(struct S)`.
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let s = add(unit, root, DW_TAG_structure_type, Some("S"));
+            add_typed(unit, s, DW_TAG_member, Some("i"), int);
+            let s_ptr = add_typed(unit, root, DW_TAG_pointer_type, None, s);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("p"), s_ptr);
+
+            let function = add_typed(unit, root, DW_TAG_subroutine_type, None, int);
+            add_typed(unit, function, DW_TAG_formal_parameter, None, s);
+            let function_ptr = add_typed(unit, root, DW_TAG_pointer_type, None, function);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("f"), function_ptr);
+        });
+        let parse = |param, options: &ParseOptions| {
+            let mut type_ = Type::parse_param(&entry_by_name(&sections, param), options).unwrap();
+            // As in `PipelineOptions::simplify_type()`.
+            if options.explicit_end {
+                type_.0.push(End);
+            }
+            type_
+        };
+
+        for struct_expand_depth in 0..2 {
+            let options = ParseOptions { struct_expand_depth, explicit_end: true, ..ParseOptions::default() };
+            assert!(is_balanced(&parse("p", &options)));
+            assert!(is_balanced(&parse("f", &options)));
+        }
+        let options = ParseOptions { explicit_end: true, ..ParseOptions::default() };
+        assert_eq!(parse("p", &options).to_string(), r#"pointer name "S" struct end end"#);
+        assert_eq!(parse("f", &options).to_string(), r#"pointer function primitive int32_t name "S" struct end end end"#);
+        let options = ParseOptions { struct_expand_depth: 1, explicit_end: true, ..ParseOptions::default() };
+        assert_eq!(parse("p", &options).to_string(), r#"pointer name "S" struct primitive int32_t end end"#);
+
+        // Without the option, unexpanded structs are not closed and types are not terminated.
+        let type_ = parse("p", &ParseOptions::default());
+        assert_eq!(type_.to_string(), r#"pointer name "S" struct"#);
+        assert!(!is_balanced(&type_));
+    }
+
+    #[test]
+    fn test_mark_anonymous() {
+        // struct { int x; } a, struct Foo { int x; } n, and union { int x; } u
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let anonymous = add(unit, root, DW_TAG_structure_type, None);
+            add_typed(unit, anonymous, DW_TAG_member, Some("x"), int);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("a"), anonymous);
+            let named = add(unit, root, DW_TAG_structure_type, Some("Foo"));
+            add_typed(unit, named, DW_TAG_member, Some("x"), int);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("n"), named);
+            let union = add(unit, root, DW_TAG_union_type, None);
+            add_typed(unit, union, DW_TAG_member, Some("x"), int);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("u"), union);
+        });
+
+        // Without the option, anonymous types are just missing the name.
+        assert_eq!(parse_param_str(&sections, "a", &ParseOptions::default()), "struct");
+        assert_eq!(parse_param_str(&sections, "n", &ParseOptions::default()), r#"name "Foo" struct"#);
+
+        let options = ParseOptions { mark_anonymous: true, ..ParseOptions::default() };
+        assert_eq!(parse_param_str(&sections, "a", &options), "<anon> struct");
+        assert_eq!(parse_param_str(&sections, "n", &options), r#"name "Foo" struct"#);
+        assert_eq!(parse_param_str(&sections, "u", &options), "<anon> union");
+        let options = ParseOptions { mark_anonymous: true, struct_expand_depth: 1, ..ParseOptions::default() };
+        assert_eq!(parse_param_str(&sections, "a", &options), "<anon> struct primitive int32_t end");
+    }
+
+    #[test]
+    fn test_qualifiers() {
+        // volatile int v, and int* restrict r
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let volatile_int = add_typed(unit, root, DW_TAG_volatile_type, None, int);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("v"), volatile_int);
+            let int_ptr = add_typed(unit, root, DW_TAG_pointer_type, None, int);
+            let restrict_int_ptr = add_typed(unit, root, DW_TAG_restrict_type, None, int_ptr);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("r"), restrict_int_ptr);
+        });
+
+        // Stripped by default.
+        assert_eq!(parse_param_str(&sections, "v", &ParseOptions::default()), "primitive int32_t");
+        assert_eq!(parse_param_str(&sections, "r", &ParseOptions::default()), "pointer primitive int32_t");
+
+        let options = ParseOptions { keep_qualifiers: true, ..ParseOptions::default() };
+        assert_eq!(parse_param_str(&sections, "v", &options), "volatile primitive int32_t");
+        assert_eq!(parse_param_str(&sections, "r", &options), "restrict pointer primitive int32_t");
+    }
+
+    #[test]
+    fn test_atomic() {
+        // _Atomic int a, and _Atomic int* p
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let atomic_int = add_typed(unit, root, DW_TAG_atomic_type, None, int);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("a"), atomic_int);
+            let atomic_int_ptr = add_typed(unit, root, DW_TAG_pointer_type, None, atomic_int);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("p"), atomic_int_ptr);
+        });
+
+        // Stripped by default, instead of failing on the unknown tag.
+        assert_eq!(parse_param_str(&sections, "a", &ParseOptions::default()), "primitive int32_t");
+        assert_eq!(parse_param_str(&sections, "p", &ParseOptions::default()), "pointer primitive int32_t");
+
+        let options = ParseOptions { keep_qualifiers: true, ..ParseOptions::default() };
+        assert_eq!(parse_param_str(&sections, "a", &options), "atomic primitive int32_t");
+        assert_eq!(parse_param_str(&sections, "p", &options), "pointer atomic primitive int32_t");
+    }
+
+    #[test]
+    fn test_int128() {
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let int128 = add_base_type(unit, "__int128", DW_ATE_signed, 16);
+            let uint128 = add_base_type(unit, "unsigned __int128", DW_ATE_unsigned, 16);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("i"), int128);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("u"), uint128);
+        });
+        let options = ParseOptions::default();
+        assert_eq!(parse_param_str(&sections, "i", &options), "primitive int128_t");
+        assert_eq!(parse_param_str(&sections, "u", &options), "primitive uint128_t");
+
+        // Each is a single primitive token.
+        let type_ = Type::parse_param(&entry_by_name(&sections, "u"), &options).unwrap();
+        match &type_.0[..] {
+            [TypeToken::Primitive(primitive)] => assert_eq!(primitive.source_name.as_ref(), "unsigned __int128"),
+            tokens => panic!("expected single primitive token, got {:?}", tokens),
+        }
+    }
+
+    #[test]
+    fn test_float16() {
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let float16 = add_base_type(unit, "_Float16", DW_ATE_float, 2);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("h"), float16);
+        });
+        assert_eq!(parse_param_str(&sections, "h", &ParseOptions::default()), "primitive float16_t");
+    }
+
+    #[test]
+    fn test_float80() {
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            let float80 = add_base_type(unit, "long double", DW_ATE_float, 10);
+            let float80_padded = add_base_type(unit, "long double", DW_ATE_float, 12);
+            let float128 = add_base_type(unit, "long double", DW_ATE_float, 16);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("a"), float80);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("b"), float80_padded);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("c"), float128);
+        });
+        let options = ParseOptions::default();
+        assert_eq!(parse_param_str(&sections, "a", &options), "primitive float80_t");
+        assert_eq!(parse_param_str(&sections, "b", &options), "primitive float80_t");
+        assert_eq!(parse_param_str(&sections, "c", &options), "primitive float128_t");
+    }
+
+    #[test]
+    fn test_array_length() {
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            // int a[10] (with DW_AT_count, as emitted by Clang)
+            let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+            let int_array = add_typed(unit, root, DW_TAG_array_type, None, int);
+            let subrange = add(unit, int_array, DW_TAG_subrange_type, None);
+            unit.get_mut(subrange).set(DW_AT_count, write::AttributeValue::Udata(10));
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("a"), int_array);
+
+            // char b[3][4] (with DW_AT_upper_bound, as emitted by GCC)
+            let char = add_base_type(unit, "char", DW_ATE_signed_char, 1);
+            let char_array = add_typed(unit, root, DW_TAG_array_type, None, char);
+            for upper_bound in &[2, 3] {
+                let subrange = add(unit, char_array, DW_TAG_subrange_type, None);
+                unit.get_mut(subrange).set(DW_AT_upper_bound, write::AttributeValue::Udata(*upper_bound));
+            }
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("b"), char_array);
+
+            // int c[] (unknown length)
+            let unknown_array = add_typed(unit, root, DW_TAG_array_type, None, int);
+            add(unit, unknown_array, DW_TAG_subrange_type, None);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("c"), unknown_array);
+        });
+        let options = ParseOptions { array_length: true, ..ParseOptions::default() };
+        assert_eq!(parse_param_str(&sections, "a", &options), "array [10] primitive int32_t");
+        assert_eq!(parse_param_str(&sections, "b", &options), "array [3] [4] primitive char");
+        assert_eq!(parse_param_str(&sections, "c", &options), "array [] primitive int32_t");
+        assert_eq!(parse_param_str(&sections, "b", &ParseOptions::default()), "array primitive char");
+    }
+
+    #[test]
+    fn test_qualified_names() {
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            // namespace std { class vector; } namespace { struct S; } class Top;
+            let std = add(unit, root, DW_TAG_namespace, Some("std"));
+            let vector = add(unit, std, DW_TAG_class_type, Some("vector"));
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("a"), vector);
+
+            let anonymous = add(unit, root, DW_TAG_namespace, None);
+            let s = add(unit, anonymous, DW_TAG_structure_type, Some("S"));
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("b"), s);
+
+            let top = add(unit, root, DW_TAG_class_type, Some("Top"));
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("c"), top);
+        });
+        let options = ParseOptions { qualified_names: true, ..ParseOptions::default() };
+        assert_eq!(parse_param_str(&sections, "a", &options), r#"name "std::vector" class"#);
+        assert_eq!(parse_param_str(&sections, "b", &options), r#"name "(anonymous namespace)::S" struct"#);
+        assert_eq!(parse_param_str(&sections, "c", &options), r#"name "Top" class"#);
+        assert_eq!(parse_param_str(&sections, "a", &ParseOptions::default()), r#"name "vector" class"#);
+    }
+
+    #[test]
+    fn test_enum_values() {
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            // enum Color { RED, GREEN, BLUE };
+            let uint = add_base_type(unit, "unsigned int", DW_ATE_unsigned, 4);
+            let color = add_typed(unit, root, DW_TAG_enumeration_type, Some("Color"), uint);
+            for name in &["RED", "GREEN", "BLUE"] {
+                add(unit, color, DW_TAG_enumerator, Some(name));
+            }
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("a"), color);
+
+            // enum class Small : unsigned char { A, B };
+            let uchar = add_base_type(unit, "unsigned char", DW_ATE_unsigned_char, 1);
+            let small = add_typed(unit, root, DW_TAG_enumeration_type, Some("Small"), uchar);
+            for name in &["A", "B"] {
+                add(unit, small, DW_TAG_enumerator, Some(name));
+            }
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("b"), small);
+        });
+        let options = ParseOptions { enum_values: true, ..ParseOptions::default() };
+        assert_eq!(parse_param_str(&sections, "a", &options), r#"name "Color" enum <3 values> primitive uint32_t"#);
+        assert_eq!(parse_param_str(&sections, "b", &options), r#"name "Small" enum <2 values> primitive uint8_t"#);
+        assert_eq!(parse_param_str(&sections, "a", &ParseOptions::default()), r#"name "Color" enum primitive uint32_t"#);
+    }
+
+    #[test]
+    fn test_char_mode() {
+        let sections = dwarf_sections(|unit| {
+            let root = unit.root();
+            for (name, encoding) in [("char", DW_ATE_signed_char), ("signed char", DW_ATE_signed_char), ("unsigned char", DW_ATE_unsigned_char)] {
+                let ty = add_base_type(unit, name, encoding, 1);
+                add_typed(unit, root, DW_TAG_formal_parameter, Some(&format!("p_{}", name)), ty);
+            }
+            // Plain char of a target/compiler configuration where it is unsigned.
+            let ty = add_base_type(unit, "char", DW_ATE_unsigned_char, 1);
+            add_typed(unit, root, DW_TAG_formal_parameter, Some("p_char_unsigned"), ty);
+        });
+        let types = |char_mode| {
+            let options = ParseOptions { char_mode, ..ParseOptions::default() };
+            ["p_char", "p_char_unsigned", "p_signed char", "p_unsigned char"].iter()
+                .map(|param| parse_param_str(&sections, param, &options))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(types(CharMode::Merged), ["primitive char", "primitive char", "primitive int8_t", "primitive uint8_t"]);
+        assert_eq!(types(CharMode::ThreeWay), ["primitive char", "primitive char", "primitive schar", "primitive uchar"]);
+        assert_eq!(CharMode::default(), CharMode::Merged);
+    }
+}
@@ -1,164 +1,617 @@
+use std::collections::HashSet;
 use std::fmt;
+use std::fmt::Write;
 
+use anyhow::Context;
 use itertools::Itertools;
 use sha2::{Digest, Sha256};
 use wasmparser::Operator;
 use rand::prelude::{SliceRandom, StdRng};
+use rand::SeedableRng;
 
+use crate::options::{HashIncludeType, WindowAnchor, WindowOrder};
 use crate::samples::sample::{WasmTypeSample, ParamOrReturn};
-use crate::wasm::fmt::{type_str, fmt_instr};
+use crate::wasm::fmt::{type_str, fmt_instr, fmt_structural_instr, InstrFormat};
 use crate::wasm::parse::WasmBody;
+use crate::wasm::stack_effect::{is_unconditional_branch, stack_effect};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum WasmRepr {
     Hash(Option<wasmparser::Type>, Box<str>),
     Full(Option<wasmparser::Type>, Box<str>),
     Subrange(Option<wasmparser::Type>, Box<str>),
-    Windows(Option<wasmparser::Type>, Vec<Box<str>>)
+    Windows(Option<wasmparser::Type>, Vec<Box<str>>),
+    Slice(Option<wasmparser::Type>, Box<str>),
+    BasicBlocks(Option<wasmparser::Type>, Box<str>),
+    Bytes(Option<wasmparser::Type>, Box<str>),
+}
+
+// Special tokens of the WebAssembly representations, in one place for users whose tokenizer needs
+// to treat them specially.
+
+/// Default token for accesses to the local of the parameter we extract the type for, see
+/// `ReprOptions::param_token`.
+pub const PARAM_TOKEN: &str = "<param>";
+/// Separates the raw WebAssembly type of the sample (if `ReprOptions::with_type` is set) from the instructions.
+pub const BEGIN_TOKEN: &str = "<begin>";
+/// Separates the windows of the 'windows' representation.
+pub const WINDOW_TOKEN: &str = "<window>";
+/// Separates instructions.
+pub const INSTRUCTION_SEPARATOR: &str = ";";
+/// Start the function signature, locals, and instructions, see `with_prefix()`.
+pub const SIGNATURE_TOKEN: &str = "<sig>";
+pub const LOCALS_TOKEN: &str = "<locals>";
+pub const BODY_TOKEN: &str = "<body>";
+/// Marks where tokens were cut off by `WasmRepr::truncate()`.
+pub const TRUNCATION_TOKEN: &str = "<trunc>";
+
+/// Options shared by the WebAssembly representations (except 'hash', see `WasmRepr::new_hash()`).
+/// The fields correspond to the `--wasm-*` command-line options (and `--param-token`), and the
+/// defaults are the same as on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReprOptions {
+    /// Prefix the raw WebAssembly type of the sample and `BEGIN_TOKEN`.
+    pub with_type: bool,
+    pub abstract_consts: bool,
+    pub print_align: bool,
+    pub bucket_offsets: bool,
+    /// Prefixes of the instructions, see `WasmRepr::with_prefix()`.
+    pub prepend_signature: bool,
+    pub prepend_locals: bool,
+    pub prepend_size: bool,
+    /// Only for the 'full' representation, see `param_position()`.
+    pub prepend_param_position: bool,
+    pub call_names: bool,
+    pub global_types: bool,
+    pub call_indirect_sigs: bool,
+    pub param_token: String,
+}
+
+impl Default for ReprOptions {
+    fn default() -> Self {
+        Self {
+            with_type: true,
+            abstract_consts: false,
+            print_align: false,
+            bucket_offsets: false,
+            prepend_signature: false,
+            prepend_locals: false,
+            prepend_size: false,
+            prepend_param_position: false,
+            call_names: false,
+            global_types: false,
+            call_indirect_sigs: false,
+            param_token: PARAM_TOKEN.to_string(),
+        }
+    }
+}
+
+impl ReprOptions {
+    /// How to print the instructions of `sample`, with the lookup tables of its binary (for
+    /// names and types) if they are enabled.
+    fn instr_format<'a, T, U>(&'a self, sample: &'a WasmTypeSample<WasmBody, T, U>) -> InstrFormat<'a> {
+        InstrFormat {
+            param_local_idx: sample.param_or_return.local_idx(),
+            param_token: &self.param_token,
+            abstract_consts: self.abstract_consts,
+            print_align: self.print_align,
+            bucket_offsets: self.bucket_offsets,
+            call_names: self.call_names.then_some(&*sample.wasm_body.function_names),
+            global_types: self.global_types.then_some(&sample.wasm_body.global_types[..]),
+            call_indirect_types: self.call_indirect_sigs.then_some(&sample.wasm_body.func_types[..]),
+        }
+    }
+}
+
+/// Parameters of the 'windows' representation, see `WasmRepr::new_windows()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WindowParams {
+    pub size: usize,
+    /// Fraction of the window size, see `--wasm-window-overlap-threshold`.
+    pub overlap_threshold: f64,
+    pub order: WindowOrder,
+    /// Position of the parameter access in its window, only for parameter samples.
+    pub anchor: WindowAnchor,
+    /// Keep at most this many windows (after the overlap filter), see `--wasm-max-windows`.
+    pub max_windows: Option<usize>,
+    /// Only used for `WindowOrder::Shuffle` and for choosing among more than `max_windows`.
+    pub seed: u64,
+    /// Also derive the randomness from the binary (by its hash), see `--seed-per-binary`.
+    pub seed_per_binary: bool,
 }
 
 impl WasmRepr {
-    pub fn new_hash<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, with_type: bool) -> Self {
+    /// Unlike the other representations, whether the raw type is included is not controlled by
+    /// `--wasm-add-raw-type`, but separately by `--hash-include-type`.
+    pub fn new_hash<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, include_type: HashIncludeType) -> Self {
         let hash = format!("{:x}", Sha256::digest(&sample.wasm_body.bytes[..])).into();
         
-        let with_type = with_type.then(|| sample.wasm_type);
+        let with_type = (include_type == HashIncludeType::Always).then(|| sample.wasm_type);
         Self::Hash(with_type, hash)
     }
 
-    pub fn new_full<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, with_type: bool) -> anyhow::Result<Self> {
-        let instructions: Vec<_> = sample.wasm_body.instructions()?.try_collect()?;
-        
-        let str = Self::instructions_to_string(&instructions, &sample.param_or_return)?;
+    /// The encoding of all instructions (opcodes and immediates) as hex tokens, one per byte, e.g.,
+    /// for byte-level models. Like for the other representations, accesses to the local of the
+    /// parameter are abstracted, by replacing the bytes of their local index with the param token,
+    /// e.g., "20 <param> 41 01 6a 0b". The declared locals are not included, and only `with_type`
+    /// and `param_token` of the options apply.
+    pub fn new_bytes<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, options: &ReprOptions) -> anyhow::Result<Self> {
+        let param_local_idx = sample.param_or_return.local_idx();
+        let mut str = String::with_capacity(sample.wasm_body.bytes.len() * 3);
+        for (op, bytes) in sample.wasm_body.instructions_with_bytes()? {
+            let bytes = match op {
+                Operator::LocalGet { local_index }
+                | Operator::LocalSet { local_index }
+                | Operator::LocalTee { local_index } if Some(local_index) == param_local_idx => {
+                    // The opcode is a single byte, the rest is the local index.
+                    write!(str, "{:02x} {} ", bytes[0], options.param_token)?;
+                    continue;
+                }
+                _ => bytes,
+            };
+            for byte in bytes {
+                write!(str, "{:02x} ", byte)?;
+            }
+        }
+        str.pop();
 
-        let with_type = with_type.then(|| sample.wasm_type);
+        let with_type = options.with_type.then_some(sample.wasm_type);
+        Ok(Self::Bytes(with_type, str.into()))
+    }
+
+    // All constructors below take the already decoded instructions of `sample.wasm_body` (see
+    // `WasmBody::decode()`), such that the body is not decoded again for each use.
+
+    pub fn new_full<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, instructions: &[Operator], options: &ReprOptions) -> anyhow::Result<Self> {
+        let str = Self::instructions_to_string(instructions, &options.instr_format(sample))?;
+
+        let mut str = Self::with_prefix(sample, options, str)?;
+        if options.prepend_param_position {
+            str = format!("{} {}", param_position(instructions, &sample.param_or_return), str).into();
+        }
+        let with_type = options.with_type.then(|| sample.wasm_type);
         Ok(Self::Full(with_type, str))
     }
 
-    pub fn new_subrange<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, n_instructions: usize, with_type: bool) -> anyhow::Result<Self> {
+    pub fn new_subrange<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, instructions: &[Operator], n_instructions: usize, options: &ReprOptions) -> anyhow::Result<Self> {
         // Take the first n instructions for parameters, and the last n for returns.
+        let n_instructions = n_instructions.min(instructions.len());
         let instructions = match sample.param_or_return {
-            ParamOrReturn::Param { .. } => {
-                sample.wasm_body.instructions()?.take(n_instructions).try_collect()?
-            },
-            ParamOrReturn::Return => {
-                // Put the instructions into a vector first, such that we can get the last N.
-                let instructions: Vec<_> = sample.wasm_body.instructions()?.try_collect()?;
-                instructions.into_iter().rev().take(n_instructions).rev().collect_vec()
-            }
+            ParamOrReturn::Param { .. } | ParamOrReturn::Local { .. } => &instructions[..n_instructions],
+            ParamOrReturn::Return { .. } => &instructions[instructions.len() - n_instructions..],
         };
 
-        let str = Self::instructions_to_string(&instructions, &sample.param_or_return)?;
+        let str = Self::instructions_to_string(instructions, &options.instr_format(sample))?;
 
-        let with_type = with_type.then(|| sample.wasm_type);
+        let str = Self::with_prefix(sample, options, str)?;
+        let with_type = options.with_type.then(|| sample.wasm_type);
         Ok(Self::Subrange(with_type, str))
     }
 
-    pub fn new_windows<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, window_size: usize, with_type: bool, rng: &mut StdRng) -> anyhow::Result<Self> {
+    pub fn new_windows<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, instructions: &[Operator], params: WindowParams, options: &ReprOptions) -> anyhow::Result<Self> {
+        let WindowParams { size: window_size, overlap_threshold, order, anchor, max_windows, seed, seed_per_binary } = params;
+        let instr_format = options.instr_format(sample);
+        let anchor_idx = match anchor {
+            WindowAnchor::Center => window_size / 2,
+            WindowAnchor::Before => window_size - 1,
+            WindowAnchor::After => 0,
+        };
         // Left and right pad instructions, for functions that are shorter than the window size.
         let mut padded_instructions = vec![None; window_size];
-        let mut instruction_count = 0;
-        for op in sample.wasm_body.instructions()? {
-            padded_instructions.push(Some(op?));
-            instruction_count += 1;
-        }
+        padded_instructions.extend(instructions.iter().map(Some));
+        let instruction_count = instructions.len();
         padded_instructions.extend(std::iter::repeat(None).take(window_size));
 
+        // Window start index (in padded_instructions) and the formatted window.
         let mut windows = Vec::new();
         for (i, window) in padded_instructions.windows(window_size).enumerate() {
             use wasmparser::Operator::*;
             let extract = match &sample.param_or_return {
-                ParamOrReturn::Param { idx, .. } => {
-                    // Window _around_ a parameter local access for parameter samples.
-                    match &window[window_size/2] {
+                ParamOrReturn::Param { idx, .. } | ParamOrReturn::Local { idx, .. } => {
+                    // Window _around_ (or before/after) a parameter local access for parameter samples.
+                    match &window[anchor_idx] {
                         Some(LocalGet { local_index })
                         | Some(LocalSet { local_index })
                         | Some(LocalTee { local_index }) => local_index == idx,
                         _ => false
                     }
                 }
-                ParamOrReturn::Return => {
+                ParamOrReturn::Return { .. } => {
                     // Window _before_ (ending with) return instructions.
                     let return_window = match window.last() {
                         Some(Some(Return)) => true,
                         _ => false,
                     };
 
-                    // If there is no explicit return, just use the last window (before padding).
-                    // -1 because we don't want the end instruction in the window (which is always at the end of the function) and also don't want to replicate the window twice, if there WAS a return
-                    let is_last_window_before_end = i == instruction_count - 1; 
+                    // If there is no explicit return, just use the window ending right before the
+                    // final end instruction (which is always at the end of the function, so we
+                    // don't want it in the window). If that instruction IS a return, this is the
+                    // same window as above, so it is not replicated.
+                    // Index of the window's last instruction in the real (unpadded) instructions:
+                    let last_instruction_idx = (i + window_size - 1).checked_sub(window_size);
+                    let is_last_window_before_end = last_instruction_idx.is_some()
+                        && last_instruction_idx == instruction_count.checked_sub(2);
 
                     return_window || is_last_window_before_end
                 }
             };
 
             // Filter out padding, because that only uses up "token space":
-            let window = window.into_iter().filter_map(|option| option.as_ref());
+            let window = window.iter().filter_map(|option| *option);
             
             if extract {
-                windows.push(Self::instructions_to_string(window, &sample.param_or_return)?);
+                windows.push((i, Self::instructions_to_string(window, &instr_format)?));
             }
         }
 
-        // TODO Filter out windows that are overlapping by more than x% of the window size.
-        // Idea/steps: 1. save window start index with each window, 2. iterate over subsequent pairs
-        // of windows, 3. take the first element of the pair only, if delta(index, index_next) < threshold.
+        // Filter out windows that are overlapping too much with the previous window, e.g., when
+        // the parameter is accessed in adjacent instructions. Must happen before shuffling, such
+        // that the result does not depend on the order of windows.
+        let min_distance = overlap_threshold * window_size as f64;
+        let mut last_kept_start = None;
+        let mut windows: Vec<_> = windows.into_iter()
+            .filter(|&(start, _)| {
+                let keep = match last_kept_start {
+                    Some(last_start) => (start - last_start) as f64 >= min_distance,
+                    None => true,
+                };
+                if keep {
+                    last_kept_start = Some(start);
+                }
+                keep
+            })
+            .map(|(_, window)| window)
+            .collect();
+
+        // Derive the RNG from the seed and the sample (instead of sharing one RNG), such that the
+        // selection and order are reproducible regardless of the (parallel) processing order, but
+        // still differ between samples. By default, samples with the same body (and parameter) get
+        // the same windows, even when they come from different binaries.
+        let rng = || {
+            let mut hasher = Sha256::new();
+            hasher.update(seed.to_le_bytes());
+            if seed_per_binary {
+                hasher.update(&sample.file_sha256[..]);
+            }
+            hasher.update(&sample.wasm_body.bytes[..]);
+            if let Some(idx) = sample.param_or_return.local_idx() {
+                hasher.update(idx.to_le_bytes());
+            }
+            StdRng::from_seed(hasher.finalize().into())
+        };
+
+        // Bound the length of the input for frequently accessed parameters. The kept windows stay
+        // in program order, for the reordering below.
+        if let Some(max_windows) = max_windows {
+            if windows.len() > max_windows {
+                let mut keep = rand::seq::index::sample(&mut rng(), windows.len(), max_windows).into_vec();
+                keep.sort_unstable();
+                let mut keep = keep.into_iter().peekable();
+                windows = windows.into_iter()
+                    .enumerate()
+                    .filter(|(i, _)| keep.next_if_eq(i).is_some())
+                    .map(|(_, window)| window)
+                    .collect();
+            }
+        }
 
         // Reorder windows such that closeby windows do not end up next to each other.
-        // TODO Alternative to random shuffling: reverse Z-order curve, which maps far away items
-        // close to each other, with decreasing "frequency".
-        windows.shuffle(rng);
+        match order {
+            WindowOrder::Shuffle => windows.shuffle(&mut rng()),
+            WindowOrder::Program => {}
+            WindowOrder::Zorder => {
+                // Reverse Z-order curve (bit-reversed index), which maps far away items close to
+                // each other, with decreasing "frequency", e.g., 0, 4, 2, 6, 1, 5, 3, 7.
+                // Sorting by the reversal of all bits of the index gives the same order as
+                // reversing only the lowest log2(len) bits.
+                windows = windows.into_iter()
+                    .enumerate()
+                    .sorted_by_key(|(i, _)| i.reverse_bits())
+                    .map(|(_, window)| window)
+                    .collect();
+            }
+        }
 
-        let with_type = with_type.then(|| sample.wasm_type);
+        // Only once before the first window, not before each of them.
+        if let Some(first_window) = windows.first_mut() {
+            *first_window = Self::with_prefix(sample, options, std::mem::take(first_window))?;
+        }
+
+        let with_type = options.with_type.then(|| sample.wasm_type);
         Ok(Self::Windows(with_type, windows))
     }
 
-    fn instructions_to_string<'a, 'b : 'a>(instructions: impl IntoIterator<Item=&'a Operator<'b>>, abstract_param: &ParamOrReturn) -> anyhow::Result<Box<str>> {
+    /// All instructions, but with explicit structural tokens (including the block result types)
+    /// for block, loop, if, else, and end instructions, see `fmt_structural_instr()`.
+    pub fn new_basic_blocks<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, instructions: &[Operator], options: &ReprOptions) -> anyhow::Result<Self> {
+        // Remove the end of the function body itself, such that the structural tokens are balanced.
+        let instructions = match instructions.split_last() {
+            Some((Operator::End, rest)) => rest,
+            _ => instructions,
+        };
+
+        let instr_format = options.instr_format(sample);
+
+        let mut str = String::with_capacity(instructions.len() * 6);
+        for op in instructions {
+            let handled = fmt_structural_instr(&mut str, op)
+                .with_context(|| format!("could not format instruction {:?}", op))?;
+            if !handled {
+                fmt_instr(&mut str, op, &instr_format)
+                    .with_context(|| format!("could not format instruction {:?}", op))?;
+            }
+            str.push(' ');
+            str.push_str(INSTRUCTION_SEPARATOR);
+            str.push(' ');
+        }
+        // Remove last trailing seperator.
+        str.truncate(str.len().saturating_sub(INSTRUCTION_SEPARATOR.len() + 2));
+
+        let str = Self::with_prefix(sample, options, str.into())?;
+        let with_type = options.with_type.then(|| sample.wasm_type);
+        Ok(Self::BasicBlocks(with_type, str))
+    }
+
+    /// Only those instructions that are data-flow dependent on the parameter, see `param_slice()`.
+    /// For return values, there is no single value to start the slice from, so this keeps all
+    /// instructions (i.e., is the same as `new_full()`).
+    pub fn new_slice<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, instructions: &[Operator], options: &ReprOptions) -> anyhow::Result<Self> {
+        let instructions = match sample.param_or_return.local_idx() {
+            Some(idx) => Self::param_slice(instructions, idx),
+            None => instructions.iter().collect(),
+        };
+
+        let str = Self::instructions_to_string(instructions, &options.instr_format(sample))?;
+
+        let str = Self::with_prefix(sample, options, str)?;
+        let with_type = options.with_type.then(|| sample.wasm_type);
+        Ok(Self::Slice(with_type, str))
+    }
+
+    /// Forward data-flow slice, starting from the parameter: Walks over the instructions with an
+    /// abstract operand stack, where each stack slot is "tainted" if its value (transitively)
+    /// depends on the parameter, including via other locals. Returns the instructions that consume
+    /// or produce tainted values, in program order.
+    ///
+    /// This is deliberately simple: it ignores control-flow (locals stay tainted once assigned a
+    /// tainted value) and instructions with unknown stack effect (e.g., calls) are assumed to
+    /// consume all values on the stack and produce a single one.
+    fn param_slice<'a, 'b>(instructions: &'a [Operator<'b>], param_idx: u32) -> Vec<&'a Operator<'b>> {
+        let mut stack: Vec<bool> = Vec::new();
+        let mut tainted_locals = HashSet::new();
+        tainted_locals.insert(param_idx);
+
+        let mut slice = Vec::new();
+        for op in instructions {
+            use wasmparser::Operator::*;
+            let tainted = match op {
+                LocalGet { local_index } => {
+                    let tainted = tainted_locals.contains(local_index);
+                    stack.push(tainted);
+                    tainted
+                }
+                LocalSet { local_index }
+                | LocalTee { local_index } => {
+                    // Missing operands, e.g., after an instruction with unknown stack effect, are untainted.
+                    let tainted = stack.pop().unwrap_or(false);
+                    if tainted {
+                        tainted_locals.insert(*local_index);
+                    }
+                    if let LocalTee { .. } = op {
+                        stack.push(tainted);
+                    }
+                    tainted
+                }
+                _ => {
+                    let (pops, pushes) = stack_effect(op).unwrap_or((stack.len(), 1));
+                    let operands_start = stack.len().saturating_sub(pops);
+                    let tainted = stack.drain(operands_start..).any(|tainted| tainted);
+                    stack.resize(stack.len() + pushes, tainted);
+                    tainted
+                }
+            };
+
+            if is_unconditional_branch(op) {
+                stack.clear();
+            }
+            if tainted {
+                slice.push(op);
+            }
+        }
+        slice
+    }
+
+    fn instructions_to_string<'a, 'b : 'a>(instructions: impl IntoIterator<Item=&'a Operator<'b>>, instr_format: &InstrFormat) -> anyhow::Result<Box<str>> {
         let instructions = instructions.into_iter();
 
         // Pre-allocate string: one instruction is about 6 (?) characters.
         let mut str = String::with_capacity(instructions.size_hint().1.unwrap_or(0) * 6);
 
-        // Abstract parameter index in local.* instructions to <param>, no abstraction for returns.
-        let param_local_idx = match abstract_param {
-            ParamOrReturn::Param { idx, .. } => Some(*idx),
-            ParamOrReturn::Return => None
-        };
-
+        // The parameter index in local.* instructions is abstracted to the param token (see
+        // `ReprOptions::instr_format()`), no abstraction for returns.
         for op in instructions {
-            fmt_instr(&mut str, &op, param_local_idx)?;
-            str.push_str(" ; ");
+            fmt_instr(&mut str, op, instr_format)
+                .with_context(|| format!("could not format instruction {:?}", op))?;
+            str.push(' ');
+            str.push_str(INSTRUCTION_SEPARATOR);
+            str.push(' ');
         }
         // Remove last trailing seperator.
-        str.pop(); str.pop(); str.pop();
+        str.truncate(str.len().saturating_sub(INSTRUCTION_SEPARATOR.len() + 2));
 
         // Compact the string to a Box<str>, because we will never append anything to it from here on.
         Ok(str.into())
     }
 
+    /// Prepend the size of the sample's function (if `prepend_size` is set, see `size_bucket()`),
+    /// its WebAssembly type (if `prepend_signature` is set), and the types of its declared locals
+    /// (if `prepend_locals` is set), e.g.,
+    /// "<size:small> <sig> i32 i32 -> i32 <locals> i32 f64 <body> local.get <param> ; ...".
+    fn with_prefix<T, U>(sample: &WasmTypeSample<WasmBody, T, U>, options: &ReprOptions, body: Box<str>) -> anyhow::Result<Box<str>> {
+        let ReprOptions { prepend_signature, prepend_locals, prepend_size, .. } = *options;
+        if !prepend_signature && !prepend_locals && !prepend_size {
+            return Ok(body);
+        }
+
+        let mut str = String::new();
+        if prepend_size {
+            str.push_str(size_bucket(sample.wasm_body.bytes.len()));
+            str.push(' ');
+        }
+        if prepend_signature {
+            let function_type = &sample.wasm_function_type;
+            str.push_str(SIGNATURE_TOKEN);
+            for &ty in function_type.params.iter() {
+                str.push(' ');
+                str.push_str(type_str(ty));
+            }
+            str.push_str(" ->");
+            for &ty in function_type.returns.iter() {
+                str.push(' ');
+                str.push_str(type_str(ty));
+            }
+            str.push(' ');
+        }
+        if prepend_locals {
+            str.push_str(LOCALS_TOKEN);
+            for (count, ty) in sample.wasm_body.locals()? {
+                for _ in 0..count {
+                    str.push(' ');
+                    str.push_str(type_str(ty));
+                }
+            }
+            str.push(' ');
+        }
+        str.push_str(BODY_TOKEN);
+        str.push(' ');
+        str.push_str(&body);
+        Ok(str.into())
+    }
+
+    /// Truncate the representation to at most `max_tokens` whitespace-separated tokens (not
+    /// counting the raw type and `<begin>`, if `with_type` was set), plus a `<trunc>` marker.
+    /// For parameters, keeps the tokens around the first access to the parameter (i.e.,
+    /// `param_token`), for return values the last tokens (i.e., close to the returns). The marker
+    /// is put at the front, if leading tokens were dropped, otherwise at the end.
+    /// Returns whether the representation was truncated. Hashes are never truncated.
+    pub fn truncate(&mut self, max_tokens: usize, param_or_return: &ParamOrReturn, param_token: &str) -> bool {
+        match self {
+            WasmRepr::Hash(_, _) => false,
+            WasmRepr::Full(_, str)
+            | WasmRepr::Subrange(_, str)
+            | WasmRepr::Slice(_, str)
+            | WasmRepr::BasicBlocks(_, str)
+            | WasmRepr::Bytes(_, str) => match truncate_tokens(str, max_tokens, param_or_return, param_token) {
+                Some(truncated) => {
+                    *str = truncated;
+                    true
+                }
+                None => false,
+            },
+            WasmRepr::Windows(_, windows) => {
+                // Truncate the windows as they are printed, i.e., the result is a single "window".
+                let joined = windows.iter().join(&format!(" {} ", WINDOW_TOKEN));
+                match truncate_tokens(&joined, max_tokens, param_or_return, param_token) {
+                    Some(truncated) => {
+                        *windows = vec![truncated];
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+
     fn type_(&self) -> Option<wasmparser::Type> {
         match self {
             WasmRepr::Hash(ty, _) 
             | WasmRepr::Full(ty, _)
             | WasmRepr::Subrange(ty, _)
-            | WasmRepr::Windows(ty, _) => ty.clone()
+            | WasmRepr::Windows(ty, _)
+            | WasmRepr::Slice(ty, _)
+            | WasmRepr::BasicBlocks(ty, _)
+            | WasmRepr::Bytes(ty, _) => ty.clone()
         }
     }
 }
 
+/// Abstract the size of a function body (in bytes, including the declaration of locals) into one of
+/// a few buckets, as a cheap signal for the complexity of the function:
+/// - `<size:small>`: up to 32 bytes, e.g., getters, setters, and wrappers that forward a call.
+/// - `<size:medium>`: up to 256 bytes.
+/// - `<size:large>`: everything else, e.g., constructors of large structs.
+pub fn size_bucket(body_bytes: usize) -> &'static str {
+    match body_bytes {
+        0..=32 => "<size:small>",
+        33..=256 => "<size:medium>",
+        _ => "<size:large>",
+    }
+}
+
+/// Abstract where a parameter is first read (by `local.get`), relative to the number of
+/// instructions in the function body, into one of a few buckets:
+/// - `<pos:early>`: in the first third of the body, e.g., when the parameter is checked first.
+/// - `<pos:mid>`: in the second third.
+/// - `<pos:late>`: in the last third, e.g., when it is only passed on to a final call.
+/// - `<pos:none>`: the parameter is never read with `local.get`.
+/// - `<pos:return>`: for return values, which have no such position.
+pub fn param_position(instructions: &[Operator], param_or_return: &ParamOrReturn) -> &'static str {
+    let param_idx = match param_or_return {
+        ParamOrReturn::Param { idx, .. } | ParamOrReturn::Local { idx, .. } => *idx,
+        ParamOrReturn::Return { .. } => return "<pos:return>",
+    };
+    let first_get = instructions.iter()
+        .position(|instr| matches!(instr, Operator::LocalGet { local_index } if *local_index == param_idx));
+    match first_get {
+        None => "<pos:none>",
+        Some(i) if i * 3 < instructions.len() => "<pos:early>",
+        Some(i) if i * 3 < instructions.len() * 2 => "<pos:mid>",
+        Some(_) => "<pos:late>",
+    }
+}
+
+/// See `WasmRepr::truncate()`, returns `None` if `str` has at most `max_tokens` tokens.
+fn truncate_tokens(str: &str, max_tokens: usize, param_or_return: &ParamOrReturn, param_token: &str) -> Option<Box<str>> {
+    let tokens = str.split_whitespace().collect_vec();
+    if tokens.len() <= max_tokens {
+        return None;
+    }
+
+    let start = match param_or_return {
+        ParamOrReturn::Param { .. } | ParamOrReturn::Local { .. } => {
+            // Center the kept tokens around the parameter, but do not go past the end. If the
+            // parameter is not accessed at all, keep the beginning.
+            let param_pos = tokens.iter().position(|&token| token == param_token).unwrap_or(0);
+            param_pos.saturating_sub(max_tokens / 2).min(tokens.len() - max_tokens)
+        }
+        ParamOrReturn::Return { .. } => tokens.len() - max_tokens,
+    };
+    let kept = tokens[start..start + max_tokens].iter().join(" ");
+
+    Some(if start > 0 {
+        format!("{} {}", TRUNCATION_TOKEN, kept)
+    } else {
+        format!("{} {}", kept, TRUNCATION_TOKEN)
+    }.trim().into())
+}
+
 impl fmt::Display for WasmRepr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some(ty) = self.type_() {
-            write!(f, "{} <begin> ", type_str(ty))?;
+            write!(f, "{} {} ", type_str(ty), BEGIN_TOKEN)?;
         }
         match self {
             WasmRepr::Hash(_, hash) => f.write_str(hash),
             WasmRepr::Full(_, str) => f.write_str(str),
             WasmRepr::Subrange(_, str) => f.write_str(str),
+            WasmRepr::Slice(_, str) => f.write_str(str),
+            WasmRepr::BasicBlocks(_, str) => f.write_str(str),
+            WasmRepr::Bytes(_, str) => f.write_str(str),
             WasmRepr::Windows(_, windows) => {
                 if let Some((last_window, windows)) = windows.split_last() {
                     for window in windows {
                         f.write_str(window)?;
-                        f.write_str(" <window> ")?;
+                        write!(f, " {} ", WINDOW_TOKEN)?;
                     }
                     f.write_str(last_window)?;
                 }
@@ -166,4 +619,482 @@ impl fmt::Display for WasmRepr {
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use wasmparser::FuncType;
+
+    use super::*;
+    use crate::wasm::parse::WasmBinary;
+    use crate::wasm::test_util::*;
+
+    fn sample(param_or_return: ParamOrReturn, body: &[u8]) -> WasmTypeSample<WasmBody, (), ()> {
+        WasmTypeSample {
+            file: Arc::from(Path::new("test.wasm")),
+            file_sha256: Arc::from(&[0; 32][..]),
+            compilation_unit: None,
+            producer: None,
+            language: None,
+            function_idx: 0,
+            function_name_wasm: None,
+            function_name_dwarf: None,
+            function_name_wasm_demangled: None,
+            function_name_dwarf_demangled: None,
+            decl_file: None,
+            decl_line: None,
+            param_or_return,
+            wasm_type: wasmparser::Type::I32,
+            wasm_function_type: Arc::new(FuncType { params: Box::new([]), returns: Box::new([]) }),
+            wasm_body: WasmBody { offset: 0, bytes: Rc::from(body), function_names: Rc::default(), global_types: Rc::default(), func_types: Rc::default() },
+            type_: (),
+            aux: (),
+        }
+    }
+
+    fn param(idx: u32) -> ParamOrReturn {
+        ParamOrReturn::Param { idx, name: None }
+    }
+
+    /// Without the raw type, which most tests do not look at.
+    fn plain_options() -> ReprOptions {
+        ReprOptions { with_type: false, ..ReprOptions::default() }
+    }
+
+    #[test]
+    fn test_slice() {
+        // Parameter 0 is an int that is stored to the pointer in local 1 (after adding 4 to it).
+        #[rustfmt::skip]
+        let body = [
+            0x01, 0x01, 0x7f, // 1 local i32
+            0x20, 0x01, // local.get 1
+            0x20, 0x00, // local.get 0
+            0x41, 0x04, // i32.const 4
+            0x6a, // i32.add
+            0x36, 0x02, 0x00, // i32.store
+            0x20, 0x01, // local.get 1
+            0x41, 0x01, // i32.const 1
+            0x6a, // i32.add
+            0x1a, // drop
+            0x0b, // end
+        ];
+        let sample = sample(param(0), &body);
+        let repr = WasmRepr::new_slice(&sample, &sample.wasm_body.decode().unwrap(), &plain_options()).unwrap();
+        assert_eq!(repr.to_string(), "local.get <param> ; i32.add ; i32.store");
+    }
+
+    #[test]
+    fn test_slice_through_locals() {
+        #[rustfmt::skip]
+        let body = [
+            0x01, 0x01, 0x7f, // 1 local i32
+            0x20, 0x00, // local.get 0
+            0x21, 0x01, // local.set 1
+            0x41, 0x00, // i32.const 0
+            0x1a, // drop
+            0x20, 0x01, // local.get 1
+            0x45, // i32.eqz
+            0x1a, // drop
+            0x0b, // end
+        ];
+        let sample = sample(param(0), &body);
+        let repr = WasmRepr::new_slice(&sample, &sample.wasm_body.decode().unwrap(), &ReprOptions::default()).unwrap();
+        assert_eq!(repr.to_string(), "i32 <begin> local.get <param> ; local.set 1 ; local.get 1 ; i32.eqz ; drop");
+    }
+
+    #[test]
+    fn test_basic_blocks_nested_if_else() {
+        #[rustfmt::skip]
+        let body = [
+            0x00, // no locals
+            0x20, 0x00, // local.get 0
+            0x04, 0x7f, // if (result i32)
+                0x20, 0x01, // local.get 1
+                0x04, 0x40, // if
+                    0x01, // nop
+                0x05, // else
+                    0x01, // nop
+                0x0b, // end
+                0x41, 0x01, // i32.const 1
+            0x05, // else
+                0x41, 0x00, // i32.const 0
+            0x0b, // end
+            0x0f, // return
+            0x0b, // end
+        ];
+        let sample = sample(param(1), &body);
+        let repr = WasmRepr::new_basic_blocks(&sample, &sample.wasm_body.decode().unwrap(), &plain_options()).unwrap();
+        assert_eq!(repr.to_string(), "local.get 0 ; <if> i32 ; local.get <param> ; <if> ; nop ; <else> ; nop ; <end> ; i32.const 1 ; <else> ; i32.const 0 ; <end> ; return");
+
+        let tokens = repr.to_string();
+        let opening = tokens.matches("<if>").count() + tokens.matches("<block>").count() + tokens.matches("<loop>").count();
+        assert_eq!(opening, tokens.matches("<end>").count());
+    }
+
+    #[test]
+    fn test_call_names() {
+        let mut module = ModuleBuilder::new();
+        let malloc_type = module.func_type(&[I32], &[I32]);
+        let malloc = module.import_function("env", "malloc", malloc_type);
+        let ty = module.func_type(&[I32], &[]);
+        // local.get 0, call malloc, drop, call 2
+        let function = module.function(ty, &[], &[0x20, 0x00, 0x10, malloc as u8, 0x1a, 0x10, 0x02]);
+        // Unnamed function.
+        module.function(ty, &[], &[]);
+        module.custom_section("name", &name_section(&[(malloc, "malloc"), (function, "f")], &[]));
+        let bytes = module.build();
+
+        let wasm = WasmBinary::parse(&bytes).unwrap();
+        let mut sample = sample(param(0), &[]);
+        sample.wasm_body = wasm.functions[0].body.clone();
+
+        let repr = WasmRepr::new_full(&sample, &sample.wasm_body.decode().unwrap(), &ReprOptions { call_names: true, ..plain_options() }).unwrap();
+        assert_eq!(repr.to_string(), "local.get <param> ; call malloc ; drop ; call 2 ; end");
+        let repr = WasmRepr::new_full(&sample, &sample.wasm_body.decode().unwrap(), &plain_options()).unwrap();
+        assert_eq!(repr.to_string(), "local.get <param> ; call ; drop ; call ; end");
+    }
+
+    #[test]
+    fn test_global_types() {
+        let mut module = ModuleBuilder::new();
+        // i64.const 42
+        let counter = module.global(I64, false, &[0x42, 0x2a]);
+        // i32.const 0
+        let stack_pointer = module.global(I32, true, &[0x41, 0x00]);
+        let ty = module.func_type(&[I64], &[]);
+        // global.get counter, local.get 0, i64.add, drop, global.get stack_pointer, global.set stack_pointer
+        module.function(ty, &[], &[0x23, counter as u8, 0x20, 0x00, 0x7c, 0x1a, 0x23, stack_pointer as u8, 0x24, stack_pointer as u8]);
+        let bytes = module.build();
+
+        let wasm = WasmBinary::parse(&bytes).unwrap();
+        let mut sample = sample(param(0), &[]);
+        sample.wasm_body = wasm.functions[0].body.clone();
+        let instructions = sample.wasm_body.decode().unwrap();
+
+        let repr = WasmRepr::new_full(&sample, &instructions, &ReprOptions { global_types: true, ..plain_options() }).unwrap();
+        assert_eq!(repr.to_string(), "global.get i64 ; local.get <param> ; i64.add ; drop ; global.get mut i32 ; global.set mut i32 ; end");
+        let repr = WasmRepr::new_full(&sample, &instructions, &plain_options()).unwrap();
+        assert_eq!(repr.to_string(), "global.get 0 ; local.get <param> ; i64.add ; drop ; global.get 1 ; global.set 1 ; end");
+    }
+
+    #[test]
+    fn test_call_indirect_sig() {
+        let mut module = ModuleBuilder::new();
+        let callee_ty = module.func_type(&[I32, I32], &[I32]);
+        let ty = module.func_type(&[I32], &[]);
+        // local.get 0, local.get 0, i32.const 0, call_indirect callee_ty (table 0), drop
+        module.function(ty, &[], &[0x20, 0x00, 0x20, 0x00, 0x41, 0x00, 0x11, callee_ty as u8, 0x00, 0x1a]);
+        let bytes = module.build();
+
+        let wasm = WasmBinary::parse(&bytes).unwrap();
+        let mut sample = sample(param(0), &[]);
+        sample.wasm_body = wasm.functions[0].body.clone();
+        let instructions = sample.wasm_body.decode().unwrap();
+
+        let repr = WasmRepr::new_full(&sample, &instructions, &ReprOptions { call_indirect_sigs: true, ..plain_options() }).unwrap();
+        assert_eq!(repr.to_string(), "local.get <param> ; local.get <param> ; i32.const 0 ; call_indirect (i32 i32 -> i32) ; drop ; end");
+        let repr = WasmRepr::new_full(&sample, &instructions, &plain_options()).unwrap();
+        assert_eq!(repr.to_string(), "local.get <param> ; local.get <param> ; i32.const 0 ; call_indirect ; drop ; end");
+    }
+
+    #[test]
+    fn test_truncate() {
+        // 100 times (local.get 1, drop), local.get 0, drop, (end)
+        let mut body = vec![0x00];
+        for _ in 0..100 {
+            body.extend_from_slice(&[0x20, 0x01, 0x1a]);
+        }
+        body.extend_from_slice(&[0x20, 0x00, 0x1a, 0x0b]);
+        let sample = sample(param(0), &body);
+        let instructions = sample.wasm_body.decode().unwrap();
+
+        let mut repr = WasmRepr::new_full(&sample, &instructions, &ReprOptions::default()).unwrap();
+        assert!(repr.truncate(10, &sample.param_or_return, PARAM_TOKEN));
+        // Raw type and <begin>, 10 tokens around the parameter, and the marker.
+        assert_eq!(repr.to_string(), "i32 <begin> <trunc> 1 ; drop ; local.get <param> ; drop ; end");
+        let tokens = repr.to_string().split_whitespace().count();
+        assert_eq!(tokens, 2 + 10 + 1);
+
+        // Short enough already.
+        assert!(!repr.truncate(100, &sample.param_or_return, PARAM_TOKEN));
+
+        // Returns keep the tail, parameters without any access the beginning.
+        let mut repr = WasmRepr::new_full(&sample, &instructions, &plain_options()).unwrap();
+        assert!(repr.truncate(4, &ParamOrReturn::Return { idx: 0 }, PARAM_TOKEN));
+        assert_eq!(repr.to_string(), "<trunc> ; drop ; end");
+        let mut repr = WasmRepr::new_full(&sample, &instructions, &plain_options()).unwrap();
+        assert!(repr.truncate(4, &param(2), "<other>"));
+        assert_eq!(repr.to_string(), "local.get 1 ; drop <trunc>");
+    }
+
+    #[test]
+    fn test_param_token() {
+        // local.get 0, local.get 1, i32.add, (end)
+        let body = [0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b];
+        let sample = sample(param(1), &body);
+        let instructions = sample.wasm_body.decode().unwrap();
+
+        let repr = WasmRepr::new_full(&sample, &instructions, &ReprOptions { param_token: "PARAM".to_string(), ..ReprOptions::default() }).unwrap();
+        assert_eq!(repr.to_string(), "i32 <begin> local.get 0 ; local.get PARAM ; i32.add ; end");
+        let repr = WasmRepr::new_windows(&sample, &instructions, WindowParams { size: 2, overlap_threshold: 0.0, order: WindowOrder::Program, anchor: WindowAnchor::Center, max_windows: None, seed: 0, seed_per_binary: false }, &ReprOptions { param_token: "PARAM".to_string(), ..plain_options() }).unwrap();
+        assert_eq!(repr.to_string(), "local.get 0 ; local.get PARAM");
+    }
+
+    #[test]
+    fn test_prepend_size() {
+        assert_eq!(size_bucket(0), "<size:small>");
+        assert_eq!(size_bucket(32), "<size:small>");
+        assert_eq!(size_bucket(33), "<size:medium>");
+        assert_eq!(size_bucket(256), "<size:medium>");
+        assert_eq!(size_bucket(257), "<size:large>");
+
+        // (no locals) local.get 0, end
+        let sample = sample(param(0), &[0x00, 0x20, 0x00, 0x0b]);
+        let instructions = sample.wasm_body.decode().unwrap();
+        let repr = WasmRepr::new_full(&sample, &instructions, &ReprOptions { prepend_signature: true, prepend_size: true, ..ReprOptions::default() }).unwrap();
+        assert_eq!(repr.to_string(), "i32 <begin> <size:small> <sig> -> <body> local.get <param> ; end");
+        let repr = WasmRepr::new_full(&sample, &instructions, &ReprOptions { prepend_size: true, ..plain_options() }).unwrap();
+        assert_eq!(repr.to_string(), "<size:small> <body> local.get <param> ; end");
+    }
+
+    #[test]
+    fn test_param_position() {
+        // local.get 0, i32.const 1, i32.const 2, drop, drop, nop, local.get 1, drop, (end)
+        let body = [0x00, 0x20, 0x00, 0x41, 0x01, 0x41, 0x02, 0x1a, 0x1a, 0x01, 0x20, 0x01, 0x1a, 0x0b];
+        let position = |param_or_return| {
+            let sample = sample(param_or_return, &body);
+            let instructions = sample.wasm_body.decode().unwrap();
+            let repr = WasmRepr::new_full(&sample, &instructions, &ReprOptions { prepend_param_position: true, ..plain_options() }).unwrap();
+            repr.to_string().split_whitespace().next().unwrap().to_string()
+        };
+
+        // Parameter 0 is read by the first instruction, parameter 1 only by one of the last.
+        assert_eq!(position(param(0)), "<pos:early>");
+        assert_eq!(position(param(1)), "<pos:late>");
+        assert_eq!(position(param(2)), "<pos:none>");
+        assert_eq!(position(ParamOrReturn::Return { idx: 0 }), "<pos:return>");
+
+        let instructions = [Operator::Nop, Operator::LocalGet { local_index: 0 }, Operator::Nop];
+        assert_eq!(param_position(&instructions, &param(0)), "<pos:mid>");
+    }
+
+    #[test]
+    fn test_prepend_signature() {
+        // local.get 0, local.get 1, i32.add, (end)
+        let body = [0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b];
+        let mut sample = sample(param(1), &body);
+        sample.wasm_function_type = Arc::new(FuncType {
+            params: Box::new([wasmparser::Type::I32, wasmparser::Type::I32]),
+            returns: Box::new([wasmparser::Type::I32]),
+        });
+        let instructions = sample.wasm_body.decode().unwrap();
+
+        let repr = WasmRepr::new_full(&sample, &instructions, &ReprOptions { prepend_signature: true, ..ReprOptions::default() }).unwrap();
+        assert_eq!(repr.to_string(), "i32 <begin> <sig> i32 i32 -> i32 <body> local.get 0 ; local.get <param> ; i32.add ; end");
+        let repr = WasmRepr::new_full(&sample, &instructions, &plain_options()).unwrap();
+        assert_eq!(repr.to_string(), "local.get 0 ; local.get <param> ; i32.add ; end");
+    }
+
+    #[test]
+    fn test_prepend_locals() {
+        // 2 locals i32, 1 local f64, local.get 0, local.set 2, (end)
+        let body = [0x02, 0x02, 0x7f, 0x01, 0x7c, 0x20, 0x00, 0x21, 0x02, 0x0b];
+        let sample = sample(param(0), &body);
+        assert_eq!(sample.wasm_body.locals().unwrap(), vec![(2, wasmparser::Type::I32), (1, wasmparser::Type::F64)]);
+
+        let instructions = sample.wasm_body.decode().unwrap();
+        let repr = WasmRepr::new_full(&sample, &instructions, &ReprOptions { prepend_locals: true, ..plain_options() }).unwrap();
+        assert_eq!(repr.to_string(), "<locals> i32 i32 f64 <body> local.get <param> ; local.set 2 ; end");
+        let repr = WasmRepr::new_full(&sample, &instructions, &ReprOptions { prepend_signature: true, prepend_locals: true, ..plain_options() }).unwrap();
+        assert_eq!(repr.to_string(), "<sig> -> <locals> i32 i32 f64 <body> local.get <param> ; local.set 2 ; end");
+    }
+
+    #[test]
+    fn test_windows_overlap_threshold() {
+        // local.get 0, local.get 0, i32.add, drop
+        let body = [0x00, 0x20, 0x00, 0x20, 0x00, 0x6a, 0x1a, 0x0b];
+        let windows = |overlap_threshold| {
+            let sample = sample(param(0), &body);
+            let repr = WasmRepr::new_windows(&sample, &sample.wasm_body.decode().unwrap(), WindowParams { size: 3, overlap_threshold, order: WindowOrder::Shuffle, anchor: WindowAnchor::Center, max_windows: None, seed: 0, seed_per_binary: false }, &plain_options()).unwrap();
+            match repr {
+                WasmRepr::Windows(_, windows) => windows,
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(windows(0.0).len(), 2);
+        assert_eq!(windows(0.8), vec![Box::from("local.get <param> ; local.get <param>")]);
+    }
+
+    #[test]
+    fn test_windows_order() {
+        // local.get 0, i32.const 1, local.get 0, i32.const 2, local.get 0, i32.const 3, local.get 0, i32.const 4
+        let body = [0x00, 0x20, 0x00, 0x41, 0x01, 0x20, 0x00, 0x41, 0x02, 0x20, 0x00, 0x41, 0x03, 0x20, 0x00, 0x41, 0x04, 0x0b];
+        let windows = |order| {
+            let sample = sample(param(0), &body);
+            match WasmRepr::new_windows(&sample, &sample.wasm_body.decode().unwrap(), WindowParams { size: 2, overlap_threshold: 0.0, order, anchor: WindowAnchor::Center, max_windows: None, seed: 0, seed_per_binary: false }, &plain_options()).unwrap() {
+                WasmRepr::Windows(_, windows) => windows.into_iter().map(|window| window.to_string()).collect::<Vec<_>>(),
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(windows(WindowOrder::Program), vec![
+            "local.get <param>",
+            "i32.const 1 ; local.get <param>",
+            "i32.const 2 ; local.get <param>",
+            "i32.const 3 ; local.get <param>",
+        ]);
+        assert_eq!(windows(WindowOrder::Zorder), vec![
+            "local.get <param>",
+            "i32.const 2 ; local.get <param>",
+            "i32.const 1 ; local.get <param>",
+            "i32.const 3 ; local.get <param>",
+        ]);
+
+        // Shuffling is deterministic per sample.
+        assert_eq!(windows(WindowOrder::Shuffle), windows(WindowOrder::Shuffle));
+        let mut shuffled = windows(WindowOrder::Shuffle);
+        shuffled.sort();
+        let mut program = windows(WindowOrder::Program);
+        program.sort();
+        assert_eq!(shuffled, program);
+    }
+
+    #[test]
+    fn test_windows_seed_per_binary() {
+        // 10x (i32.const i, local.get 0), i.e., distinguishable windows.
+        let mut body = vec![0x00];
+        for i in 0..10 {
+            body.extend_from_slice(&[0x41, i, 0x20, 0x00]);
+        }
+        body.push(0x0b);
+        let windows = |file_sha256: [u8; 32], seed_per_binary| {
+            let mut sample = sample(param(0), &body);
+            sample.file_sha256 = Arc::from(&file_sha256[..]);
+            let params = WindowParams { size: 2, overlap_threshold: 0.0, order: WindowOrder::Shuffle, anchor: WindowAnchor::Center, max_windows: None, seed: 0, seed_per_binary };
+            match WasmRepr::new_windows(&sample, &sample.wasm_body.decode().unwrap(), params, &plain_options()).unwrap() {
+                WasmRepr::Windows(_, windows) => windows,
+                _ => unreachable!(),
+            }
+        };
+
+        // Without, the same body is shuffled the same regardless of the binary.
+        assert_eq!(windows([1; 32], false), windows([2; 32], false));
+        // With, different binaries get different shuffles, but each binary is stable across runs.
+        assert_ne!(windows([1; 32], true), windows([2; 32], true));
+        assert_eq!(windows([1; 32], true), windows([1; 32], true));
+    }
+
+    #[test]
+    fn test_windows_max_windows() {
+        // 10x (local.get 0, drop)
+        let mut body = vec![0x00];
+        for _ in 0..10 {
+            body.extend_from_slice(&[0x20, 0x00, 0x1a]);
+        }
+        body.push(0x0b);
+        let sample = sample(param(0), &body);
+        let windows = |max_windows| {
+            let params = WindowParams { size: 2, overlap_threshold: 0.0, order: WindowOrder::Program, anchor: WindowAnchor::Center, max_windows, seed: 0, seed_per_binary: false };
+            match WasmRepr::new_windows(&sample, &sample.wasm_body.decode().unwrap(), params, &plain_options()).unwrap() {
+                WasmRepr::Windows(_, windows) => windows,
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(windows(None).len(), 10);
+        assert_eq!(windows(Some(3)).len(), 3);
+        assert_eq!(windows(Some(3)), windows(Some(3)));
+        // Fewer windows than the maximum are all kept.
+        assert_eq!(windows(Some(20)).len(), 10);
+    }
+
+    #[test]
+    fn test_windows_anchor() {
+        // i32.const 1, i32.const 2, local.get 0, i32.const 3, i32.const 4
+        let body = [0x00, 0x41, 0x01, 0x41, 0x02, 0x20, 0x00, 0x41, 0x03, 0x41, 0x04, 0x0b];
+        let sample = sample(param(0), &body);
+        let windows = |anchor| {
+            let params = WindowParams { size: 3, overlap_threshold: 0.0, order: WindowOrder::Program, anchor, max_windows: None, seed: 0, seed_per_binary: false };
+            match WasmRepr::new_windows(&sample, &sample.wasm_body.decode().unwrap(), params, &plain_options()).unwrap() {
+                WasmRepr::Windows(_, windows) => windows,
+                _ => unreachable!(),
+            }
+        };
+
+        assert_eq!(windows(WindowAnchor::Center), vec![Box::from("i32.const 2 ; local.get <param> ; i32.const 3")]);
+        assert_eq!(windows(WindowAnchor::Before), vec![Box::from("i32.const 1 ; i32.const 2 ; local.get <param>")]);
+        assert_eq!(windows(WindowAnchor::After), vec![Box::from("local.get <param> ; i32.const 3 ; i32.const 4")]);
+    }
+
+    #[test]
+    fn test_windows_return_without_explicit_return() {
+        // i32.const 1, i32.const 2, i32.add, (end)
+        let body = [0x00, 0x41, 0x01, 0x41, 0x02, 0x6a, 0x0b];
+        let params = WindowParams { size: 2, overlap_threshold: 0.0, order: WindowOrder::Program, anchor: WindowAnchor::Center, max_windows: None, seed: 0, seed_per_binary: false };
+        let implicit_return = sample(ParamOrReturn::Return { idx: 0 }, &body);
+        match WasmRepr::new_windows(&implicit_return, &implicit_return.wasm_body.decode().unwrap(), params, &plain_options()).unwrap() {
+            WasmRepr::Windows(_, windows) => assert_eq!(windows, vec![Box::from("i32.const 2 ; i32.add")]),
+            _ => unreachable!(),
+        }
+
+        // Ends with an explicit return, which must not produce a second window.
+        // i32.const 1, return, (end)
+        let body = [0x00, 0x41, 0x01, 0x0f, 0x0b];
+        let explicit_return = sample(ParamOrReturn::Return { idx: 0 }, &body);
+        match WasmRepr::new_windows(&explicit_return, &explicit_return.wasm_body.decode().unwrap(), params, &plain_options()).unwrap() {
+            WasmRepr::Windows(_, windows) => assert_eq!(windows, vec![Box::from("i32.const 1 ; return")]),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_malformed_br_table() {
+        // The br_table target is a too large LEB128 number, which is only detected when iterating
+        // over the targets (i.e., while formatting), not when decoding the instruction.
+        // i32.const 0, br_table [0xffffffffff] 0, end
+        let body = [0x00, 0x41, 0x00, 0x0e, 0x01, 0xff, 0xff, 0xff, 0xff, 0x7f, 0x00, 0x0b];
+        let malformed = sample(param(0), &body);
+        let instructions = malformed.wasm_body.decode().unwrap();
+        assert!(WasmRepr::new_full(&malformed, &instructions, &plain_options()).is_err());
+        assert!(WasmRepr::new_basic_blocks(&malformed, &instructions, &plain_options()).is_err());
+
+        // Truncating a valid br_table anywhere must give an error (or a shorter, valid body), but
+        // never panic.
+        // block, i32.const 0, br_table [0 0] 0, end, end
+        let body = [0x00, 0x02, 0x40, 0x41, 0x00, 0x0e, 0x02, 0x00, 0x00, 0x00, 0x0b, 0x0b];
+        for len in 1..=body.len() {
+            let sample = sample(param(0), &body[..len]);
+            if let Ok(instructions) = sample.wasm_body.decode() {
+                let _ = WasmRepr::new_full(&sample, &instructions, &plain_options());
+                let _ = WasmRepr::new_basic_blocks(&sample, &instructions, &plain_options());
+            }
+        }
+    }
+
+    #[test]
+    fn test_bytes() {
+        // (no locals) local.get 0, local.get 1, i32.const 200, i32.add, i32.add, local.set 1, (end)
+        let sample = sample(param(1), &[0x00, 0x20, 0x00, 0x20, 0x01, 0x41, 0xc8, 0x01, 0x6a, 0x6a, 0x21, 0x01, 0x0b]);
+        let repr = WasmRepr::new_bytes(&sample, &ReprOptions::default()).unwrap();
+        assert_eq!(repr.to_string(), "i32 <begin> 20 00 20 <param> 41 c8 01 6a 6a 21 <param> 0b");
+
+        // Returns have no local to abstract.
+        let sample = WasmTypeSample { param_or_return: ParamOrReturn::Return { idx: 0 }, ..sample };
+        let repr = WasmRepr::new_bytes(&sample, &plain_options()).unwrap();
+        assert_eq!(repr.to_string(), "20 00 20 01 41 c8 01 6a 6a 21 01 0b");
+    }
+
+    #[test]
+    fn test_hash_include_type() {
+        let sample = sample(param(0), &[0x00, 0x20, 0x00, 0x1a, 0x0b]);
+        let without_type = WasmRepr::new_hash(&sample, HashIncludeType::Never).to_string();
+        let with_type = WasmRepr::new_hash(&sample, HashIncludeType::Always).to_string();
+        assert_eq!(without_type.len(), 64);
+        assert!(without_type.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(with_type, format!("i32 {} {}", BEGIN_TOKEN, without_type));
+    }
+}
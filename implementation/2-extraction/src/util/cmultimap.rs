@@ -1,5 +1,5 @@
 use std::cmp::Reverse;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
 
 use chashmap::CHashMap;
@@ -28,6 +28,75 @@ impl<K: Hash + PartialEq, V: Hash + Eq + Clone> CMultiMap<K, V> {
             }
         );
     }
+
+    /// Number of values associated with `key`, counting each occurrence (i.e., the size of the
+    /// bag), or 0 if the key was never inserted.
+    pub fn get_total(&self, key: &K) -> usize {
+        self.0.get(key)
+            .map(|values| values.values().sum())
+            .unwrap_or(0)
+    }
+
+    /// Distinct values associated with `key` and how often each was inserted, most common first
+    /// (like `into_iter()`), without consuming the map.
+    pub fn value_counts(&self, key: &K) -> Option<Vec<(V, usize)>> {
+        self.0.get(key)
+            .map(|values| sorted_by_count(values.iter().map(|(value, count)| (value.clone(), *count))))
+    }
+
+    /// Add all values of `other` to this map, i.e., the counts of values under the same key are
+    /// summed up (bag union), e.g., to combine the results of separate passes.
+    pub fn merge(&self, other: Self) {
+        for (key, other_values) in other.0 {
+            self.0.alter(key, |values| match values {
+                Some(mut values) => {
+                    for (value, count) in other_values {
+                        *values.entry(value).or_insert(0) += count;
+                    }
+                    Some(values)
+                }
+                None => Some(other_values),
+            });
+        }
+    }
+
+    /// Like `into_iter()`, but deterministic: keys in sorted order, and values with the same count
+    /// also sorted, e.g., for reports that should not change from run to run.
+    pub fn into_sorted_iter(self) -> impl Iterator<Item = (K, Vec<(V, usize)>)> where K: Ord, V: Ord {
+        self.0.into_iter()
+            .sorted_by(|(key1, _), (key2, _)| key1.cmp(key2))
+            .map(|(key, values)| {
+                let values = values.into_iter()
+                    .sorted_by(|(value1, count1), (value2, count2)| count2.cmp(count1).then_with(|| value1.cmp(value2)))
+                    .collect();
+                (key, values)
+            })
+    }
+
+    /// Keys that are associated with at least `min_values` distinct values, e.g., the names that
+    /// appear in at least so many binaries.
+    pub fn keys_with_min_distinct_values(self, min_values: usize) -> HashSet<K> where K: Eq {
+        self.0.into_iter()
+            .filter(|(_key, values)| values.len() >= min_values)
+            .map(|(key, _values)| key)
+            .collect()
+    }
+
+    /// Number of distinct values -> number of keys with that many distinct values, e.g., how many
+    /// WebAssembly inputs map to one, two, or more different types.
+    pub fn distinct_values_distribution(self) -> HashMap<usize, u64> {
+        let mut distribution = HashMap::new();
+        for (_key, values) in self.0 {
+            *distribution.entry(values.len()).or_insert(0) += 1;
+        }
+        distribution
+    }
+}
+
+impl<K: Hash + PartialEq, V: Hash + Eq + Clone> Default for CMultiMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub struct IntoIter<K, V>(chashmap::IntoIter<K, HashMap<V, usize>>);
@@ -36,14 +105,16 @@ impl<K, V> Iterator for IntoIter<K, V> {
     type Item = (K, Vec<(V, usize)>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.next().map(|(k, vs)| {
-            let mut vs = vs.into_iter().collect_vec();
-            vs.sort_by_key(|(_v, count)| Reverse(*count));
-            (k, vs)
-        })
+        self.0.next().map(|(k, vs)| (k, sorted_by_count(vs)))
     }
 }
 
+fn sorted_by_count<V>(values: impl IntoIterator<Item = (V, usize)>) -> Vec<(V, usize)> {
+    let mut values = values.into_iter().collect_vec();
+    values.sort_by_key(|(_v, count)| Reverse(*count));
+    values
+}
+
 impl<K, V> IntoIterator for CMultiMap<K, V> {
     type Item = (K, Vec<(V, usize)>);
     type IntoIter = IntoIter<K, V>;
@@ -51,4 +122,61 @@ impl<K, V> IntoIterator for CMultiMap<K, V> {
     fn into_iter(self) -> Self::IntoIter {
         IntoIter(self.0.into_iter())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_with_min_distinct_values() {
+        let name_binaries = CMultiMap::new();
+        name_binaries.insert("rare", &"a.wasm");
+        // Multiple occurrences in the same binary count only once.
+        name_binaries.insert("rare", &"a.wasm");
+        for binary in &["a.wasm", "b.wasm", "c.wasm"] {
+            name_binaries.insert("common", binary);
+        }
+        assert_eq!(name_binaries.keys_with_min_distinct_values(2), ["common"].iter().copied().collect());
+    }
+
+    #[test]
+    fn test_counts_and_merge() {
+        let first = CMultiMap::new();
+        first.insert("int", &"a.wasm");
+        first.insert("int", &"a.wasm");
+        first.insert("int", &"b.wasm");
+        let second = CMultiMap::new();
+        second.insert("int", &"b.wasm");
+        second.insert("int", &"b.wasm");
+        second.insert("int", &"c.wasm");
+        second.insert("float", &"c.wasm");
+
+        // Counts are of the bag, i.e., with repeated values, and queries do not consume the map.
+        assert_eq!(first.get_total(&"int"), 3);
+        assert_eq!(first.get_total(&"float"), 0);
+        assert_eq!(first.value_counts(&"int"), Some(vec![("a.wasm", 2), ("b.wasm", 1)]));
+        assert_eq!(first.value_counts(&"float"), None);
+
+        // Counts of the same key and value are summed, new keys and values are added.
+        first.merge(second);
+        assert_eq!(first.get_total(&"int"), 6);
+        assert_eq!(first.value_counts(&"int"), Some(vec![("b.wasm", 3), ("a.wasm", 2), ("c.wasm", 1)]));
+        assert_eq!(first.value_counts(&"float"), Some(vec![("c.wasm", 1)]));
+        assert_eq!(first.into_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_into_sorted_iter() {
+        let name_binaries = CMultiMap::new();
+        for (name, binary) in &[("size_t", "b.wasm"), ("FILE", "c.wasm"), ("size_t", "a.wasm"), ("FILE", "a.wasm"), ("size_t", "c.wasm"), ("size_t", "c.wasm")] {
+            name_binaries.insert(*name, binary);
+        }
+        let expected = vec![
+            ("FILE", vec![("a.wasm", 1), ("c.wasm", 1)]),
+            ("size_t", vec![("c.wasm", 2), ("a.wasm", 1), ("b.wasm", 1)]),
+        ];
+        assert_eq!(name_binaries.clone().into_sorted_iter().collect_vec(), expected);
+        assert_eq!(name_binaries.into_sorted_iter().collect_vec(), expected);
+    }
+}
@@ -0,0 +1,35 @@
+use cpp_demangle::{DemangleOptions, Symbol};
+
+/// Demangle a Rust (legacy or v0 scheme) or Itanium C++ symbol name, e.g., `_ZN3foo3barEv` to
+/// `foo::bar()`. Names that are not mangled (or cannot be demangled) are returned unchanged.
+/// The hash suffix of Rust legacy symbols is omitted, since it is unique per crate (version).
+pub fn demangle(name: &str) -> String {
+    if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+        return format!("{:#}", demangled);
+    }
+    if name.starts_with("_Z") {
+        if let Some(demangled) = Symbol::new(name).ok()
+            .and_then(|symbol| symbol.demangle(&DemangleOptions::default()).ok()) {
+            return demangled;
+        }
+    }
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demangle() {
+        assert_eq!(demangle("_ZN3foo3barEv"), "foo::bar()");
+        assert_eq!(demangle("_ZNSt6vectorIiSaIiEE9push_backERKi"), "std::vector<int, std::allocator<int> >::push_back(int const&)");
+        assert_eq!(demangle("_ZN4core3fmt5write17h4a5c8a7a3f0b1e2dE"), "core::fmt::write");
+        assert_eq!(demangle("_RNvCs1234_7mycrate3foo"), "mycrate::foo");
+
+        // Not mangled, or not valid.
+        assert_eq!(demangle("main"), "main");
+        assert_eq!(demangle("__cxx_global_var_init"), "__cxx_global_var_init");
+        assert_eq!(demangle("_Zinvalid"), "_Zinvalid");
+    }
+}
@@ -1,33 +1,51 @@
 /// Print a number in short human format, similar to GNU tools, e.g., 10M for 10 million.
 /// The number including the suffix will be at most 4 characters long, e.g., 1.3M or 400K.
 pub fn format_integer(uint: u64) -> String {
-    format(uint, 1000.0, &["k", "M", "B", "T", "P"], "")
+    format_with(uint, 1000.0, &["k", "M", "B", "T", "P"], "", 3)
 }
 
 /// Similar to format_integer, except that it uses binary suffixes (Kibibyte etc.) and B as a unit.
 pub fn format_file_size_binary(uint: u64) -> String {
-    format(uint, 1024.0, &["Ki", "Mi", "Gi", "Ti", "Pi"], "B")
+    format_with(uint, 1024.0, &["Ki", "Mi", "Gi", "Ti", "Pi"], "B", 3)
 }
 
-fn format(uint: u64, base: f64, suffixes: &[&str], unit: &str) -> String {
-    // Scale down number, iteratively going through the suffixes.
+/// Similar to format_file_size_binary, except that it uses decimal (SI) suffixes (Kilobyte etc.).
+pub fn format_file_size_si(uint: u64) -> String {
+    format_with(uint, 1000.0, &["k", "M", "G", "T", "P"], "B", 3)
+}
+
+/// Format the number with at most `precision` characters (digits and decimal point, without
+/// suffix and unit), scaling it down by `base` for each suffix as long as it does not fit.
+/// Only if the number is too large even for the last suffix, the output is wider.
+pub fn format_with(uint: u64, base: f64, suffixes: &[&str], unit: &str, precision: usize) -> String {
+    // Scale down number, iteratively going through the suffixes. Note that a number may only fit
+    // after scaling, even though it is smaller than the base, e.g., 1000 bytes = 0.98 KiB.
+    let max_integral = 10f64.powi(precision as i32);
     let mut scaled_value = uint as f64;
     let mut suffix = None;
     for s in suffixes {
-        if scaled_value < base {
+        if scaled_value.round() < max_integral {
             break;
         }
-        scaled_value = scaled_value / base;
+        scaled_value /= base;
         suffix = Some(s);
     }
 
-    // Format the number such that it takes at most 4 characters (e.g., "0", "10K", "100K", or "1.5K").
     match suffix {
         // Whole number fits, no fractional part, no suffix.
         None => format!("{}{}", uint, unit),
-        // Integral part has only a single digit, use additional space for fractional part.
-        Some(suffix) if scaled_value < 10.0 => format!("{:.1}{}{}", scaled_value, suffix, unit),
-        Some(suffix) => format!("{:.0}{}{}", scaled_value, suffix, unit),
+        // Use the remaining space for the fractional part, e.g., "1.5K", but "10K" and "100K".
+        Some(suffix) => {
+            // At least one integral digit and the decimal point.
+            let mut decimals = precision.saturating_sub(2);
+            loop {
+                let number = format!("{:.*}", decimals, scaled_value);
+                if number.len() <= precision || decimals == 0 {
+                    return format!("{}{}{}", number, suffix, unit);
+                }
+                decimals -= 1;
+            }
+        }
     }
 }
 
@@ -59,8 +77,36 @@ mod tests {
         assert_eq!(format_file_size_binary(1), "1B");
         assert_eq!(format_file_size_binary(10), "10B");
         assert_eq!(format_file_size_binary(100), "100B");
-        // FIXME Output is wider than 3 digits, because 1000<1024 and thus output is 1000B.
+        assert_eq!(format_file_size_binary(999), "999B");
+        // Smaller than 1 KiB, but would be wider than 3 digits.
         assert_eq!(format_file_size_binary(1000), "1.0KiB");
+        assert_eq!(format_file_size_binary(1023), "1.0KiB");
+        assert_eq!(format_file_size_binary(1024), "1.0KiB");
         assert_eq!(format_file_size_binary(1100), "1.1KiB");
+        assert_eq!(format_file_size_binary(10 * 1024), "10KiB");
+        assert_eq!(format_file_size_binary(1000 * 1024), "1.0MiB");
+    }
+
+    #[test]
+    fn test_file_size_si() {
+        assert_eq!(format_file_size_si(0), "0B");
+        assert_eq!(format_file_size_si(999), "999B");
+        assert_eq!(format_file_size_si(1000), "1.0kB");
+        assert_eq!(format_file_size_si(1024), "1.0kB");
+        assert_eq!(format_file_size_si(999_999), "1.0MB");
+        assert_eq!(format_file_size_si(1_500_000), "1.5MB");
+        assert_eq!(format_file_size_si(250_000_000_000), "250GB");
+    }
+
+    #[test]
+    fn test_precision() {
+        let suffixes = &["k", "M"];
+        assert_eq!(format_with(1500, 1000.0, suffixes, "", 4), "1500");
+        assert_eq!(format_with(15_000, 1000.0, suffixes, "", 4), "15.0k");
+        assert_eq!(format_with(1_234_567, 1000.0, suffixes, "", 4), "1235k");
+        assert_eq!(format_with(1500, 1000.0, suffixes, "", 2), "2k");
+        assert_eq!(format_with(15_000, 1000.0, suffixes, "", 2), "15k");
+        // Too large even for the last suffix.
+        assert_eq!(format_with(15_000_000_000, 1000.0, suffixes, "", 3), "15000M");
     }
 }
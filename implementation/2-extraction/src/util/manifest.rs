@@ -0,0 +1,145 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use itertools::Itertools;
+
+/// Manifest of already processed binaries, one hex-encoded SHA256 per line, such that incremental
+/// runs over a growing corpus can skip them (see `--skip-processed`).
+#[derive(Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    existed: bool,
+    processed: HashSet<Box<[u8]>>,
+}
+
+impl Manifest {
+    /// Read the manifest at `path`, or start an empty one if the file does not exist yet.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self { path, existed: false, processed: HashSet::new() }),
+            Err(err) => return Err(err).with_context(|| format!("could not open manifest {}", path.display())),
+        };
+
+        let mut processed = HashSet::new();
+        for (line_idx, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let sha256 = from_hex(line)
+                .with_context(|| format!("{}:{}: invalid SHA256 '{}'", path.display(), line_idx + 1, line))?;
+            processed.insert(sha256);
+        }
+        Ok(Self { path, existed: true, processed })
+    }
+
+    /// Whether there was a manifest from a previous run, i.e., whether outputs should be appended to.
+    pub fn existed(&self) -> bool {
+        self.existed
+    }
+
+    pub fn contains(&self, sha256: &[u8]) -> bool {
+        self.processed.contains(sha256)
+    }
+
+    pub fn len(&self) -> usize {
+        self.processed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processed.is_empty()
+    }
+
+    /// Add binaries to the manifest and append them to the file (creating it, if necessary).
+    /// Call this only after all samples of these binaries were written and flushed: If the run is
+    /// aborted before, the binaries are not in the manifest and will be processed again next time,
+    /// which can duplicate samples that were already (partially) written in this run. Recording the
+    /// binaries before writing would instead risk losing their samples for good.
+    pub fn append<'a>(&mut self, sha256s: impl IntoIterator<Item = &'a [u8]>) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let mut writer = BufWriter::new(file);
+        for sha256 in sha256s {
+            if self.processed.insert(sha256.into()) {
+                writeln!(writer, "{}", to_hex(sha256))?;
+            }
+        }
+        writer.flush()?;
+        self.existed = true;
+        Ok(())
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).join("")
+}
+
+fn from_hex(str: &str) -> anyhow::Result<Box<[u8]>> {
+    if str.len() & 1 == 1 || !str.is_ascii() {
+        anyhow::bail!("odd number of hex digits or non-ASCII characters");
+    }
+    (0..str.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&str[i..i + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::SignatureMode;
+    use crate::wasm::binary_stats::WasmBinaryStats;
+    use crate::wasm::test_util::{temp_dir, write_temp_file, ModuleBuilder, I32};
+
+    use super::*;
+
+    #[test]
+    fn test_second_run_skips_all() {
+        let dir = temp_dir();
+        let binaries: Vec<_> = (0..3)
+            .map(|i| {
+                let mut module = ModuleBuilder::new();
+                let ty = module.func_type(&[I32], &[]);
+                // i32.const i, drop
+                module.function(ty, &[], &[0x41, i, 0x1a]);
+                write_temp_file(&dir, &format!("{}.wasm", i), &module.build())
+            })
+            .collect();
+        let manifest_path = dir.path().join("manifest.txt");
+
+        // Like main.rs: process all binaries not in the manifest, then record them.
+        let run = || -> usize {
+            let mut manifest = Manifest::load(&manifest_path).unwrap();
+            let new_binaries: Vec<_> = binaries.iter()
+                .map(|file| WasmBinaryStats::from_file(file, false, SignatureMode::Names).unwrap().file_sha256)
+                .filter(|sha256| !manifest.contains(sha256))
+                .collect();
+            manifest.append(new_binaries.iter().map(|sha256| &sha256[..])).unwrap();
+            new_binaries.len()
+        };
+
+        assert!(!Manifest::load(&manifest_path).unwrap().existed());
+        assert_eq!(run(), 3);
+        let manifest = Manifest::load(&manifest_path).unwrap();
+        assert!(manifest.existed());
+        assert_eq!(manifest.len(), 3);
+        assert_eq!(run(), 0);
+    }
+
+    #[test]
+    fn test_hex_roundtrip() {
+        let bytes = [0x00, 0x0f, 0xab, 0xff];
+        assert_eq!(to_hex(&bytes), "000fabff");
+        assert_eq!(&*from_hex("000fabff").unwrap(), &bytes);
+        assert_eq!(&*from_hex("000FABFF").unwrap(), &bytes);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+}
@@ -9,15 +9,20 @@ pub mod sample_writer;
 pub mod percent;
 pub mod file_error;
 pub mod cmultimap;
-// pub mod progress_bar_log;
+pub mod vocab;
+pub mod reservoir;
+pub mod manifest;
+pub mod demangle;
+pub mod progress_bar_log;
 
 // WORKAROUND IndexedParallelIterator know their length, so we can use that instead of 0 by default.
 // See https://github.com/mitsuhiko/indicatif/issues/242
 // Use progress_bar() instead of progress() to avoid ambiguous method name vs. upstream.
+// The bar is registered with progress_bar_log, such that log lines do not clobber it.
 pub trait ParallelProgressBar: IndexedParallelIterator {
     fn progress_bar(self) -> ParProgressBarIter<Self> {
         let total = self.len() as u64;
-        self.progress_count(total)
+        self.progress_with(progress_bar_log::progress_bar(total))
     }
 }
 
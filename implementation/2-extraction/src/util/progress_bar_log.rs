@@ -1,63 +1,74 @@
+use std::io::{self, Write};
+use std::sync::Mutex;
+
 use atty::Stream;
 use chrono::Local;
-use indicatif::ProgressBar;
-use termcolor::{BufferWriter, Color, ColorChoice, ColorSpec, WriteColor};
-use std::io::{self, Write};
+use indicatif::{ProgressBar, ProgressDrawTarget};
+use log::LevelFilter;
+use once_cell::sync::Lazy;
+use simplelog::{Config, SharedLogger};
+use termcolor::{Buffer, Color, ColorSpec, WriteColor};
 
-// FIXME not working yet
-// It is quite difficult to have:
-// - a progress bar at the bottom of the output (sometimes, indicatif crate)
-// - colored output for log levels (termcolor crate is the only one that supports Window 7)
-// - implement the standard logging facade of the log crate
+// It is quite difficult to have all of:
+// - a progress bar at the bottom of the output (indicatif crate),
+// - colored output for log levels (termcolor crate is the only one that supports Windows 7),
+// - implement the standard logging facade of the log crate.
+// So: the logger knows about the currently active progress bar (there is at most one at a time,
+// see `progress_bar()`), and prints log lines above it with `ProgressBar::println()`, which
+// redraws the bar afterwards. Coloring is done by termcolor into a buffer with ANSI codes.
 
-// TODO add logging function to print an empty line (without level, without time etc.)
+/// The progress bar that log lines are printed above, while it is active (see `is_active()`).
+static ACTIVE_PROGRESS_BAR: Lazy<Mutex<Option<ProgressBar>>> = Lazy::new(|| Mutex::new(None));
 
-pub struct ProgressBarLog {
-    progress_bar: Option<ProgressBar>,
-    max_level: log::Level,
-    message_output: termcolor::BufferWriter,
+/// Create a new progress bar on stdout, which `ProgressBarLog` prints log lines above.
+/// (Hidden if stdout is not a terminal, then log lines are printed as usual.)
+pub fn progress_bar(total: u64) -> ProgressBar {
+    set_active(ProgressBar::with_draw_target(total, ProgressDrawTarget::stdout()))
 }
 
-impl ProgressBarLog {
-    pub fn stdout() -> Self {
-        let colors = if atty::is(Stream::Stdout) {
-            ColorChoice::Auto
-        } else {
-            ColorChoice::Never
-        };
-        let message_output = BufferWriter::stdout(colors);
-        Self {
-            progress_bar: None,
-            max_level: log::Level::Info,
-            message_output
-        }
-    }
+fn set_active(progress_bar: ProgressBar) -> ProgressBar {
+    *ACTIVE_PROGRESS_BAR.lock().unwrap() = Some(progress_bar.clone());
+    progress_bar
+}
 
-    pub fn progress_bar(&mut self, total: u64) -> ProgressBar {
-        let pb = self.progress_bar.get_or_insert(ProgressBar::new(total));
-        pb.clone()
-    }
+/// Progress bars of iterators are not explicitly finished, but stop at their length.
+fn is_active(progress_bar: &ProgressBar) -> bool {
+    !progress_bar.is_hidden() && !progress_bar.is_finished() && progress_bar.position() < progress_bar.length()
 }
 
-impl log::Log for ProgressBarLog {
-    fn enabled(&self, metadata: &log::Metadata) -> bool {
-        metadata.level() <= self.max_level
+/// Logger with colored level prefixes, that does not clobber the active progress bar.
+pub struct ProgressBarLog {
+    max_level: LevelFilter,
+    colors: bool,
+    /// Where lines are written while no progress bar is drawn (otherwise, they are printed with
+    /// the bar, i.e., to stdout).
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl ProgressBarLog {
+    pub fn new(max_level: LevelFilter, colors: bool, writer: impl Write + Send + 'static) -> Box<Self> {
+        Box::new(Self {
+            max_level,
+            colors,
+            writer: Mutex::new(Box::new(writer)),
+        })
     }
 
-    fn log(&self, record: &log::Record) {
-        if !self.enabled(record.metadata()) {
-            return;
-        }
+    pub fn stdout(max_level: LevelFilter) -> Box<Self> {
+        Self::new(max_level, atty::is(Stream::Stdout), io::stdout())
+    }
 
-        let mut buffer = self.message_output.buffer();
+    /// Same format as simplelog's `TermLogger` with our config in main.rs, e.g.,
+    /// `2021-03-01 12:34:56 INFO  message`.
+    fn format(&self, record: &log::Record) -> io::Result<String> {
+        let mut buffer = if self.colors { Buffer::ansi() } else { Buffer::no_color() };
 
-        let time = Local::now().to_rfc3339();
-        write!(buffer, "{} ", time).unwrap();
+        write!(buffer, "{} ", Local::now().format("%F %T"))?;
 
         let mut level_style = ColorSpec::new();
         let (level_str, level_color) = match record.level() {
             log::Level::Error => {
-                level_style.set_bold(true); 
+                level_style.set_bold(true);
                 ("ERROR", Color::Red)
             },
             log::Level::Warn => ("WARN ", Color::Yellow),
@@ -66,14 +77,166 @@ impl log::Log for ProgressBarLog {
             log::Level::Trace => ("TRACE", Color::Magenta),
         };
         level_style.set_fg(Some(level_color));
-        buffer.set_color(&level_style).unwrap();
-        buffer.write(level_str.as_bytes()).unwrap();
-        buffer.reset().unwrap();
-        
-        write!(buffer, " {}", record.args()).unwrap();
+        buffer.set_color(&level_style)?;
+        buffer.write_all(level_str.as_bytes())?;
+        buffer.reset()?;
+
+        write!(buffer, " {}", record.args())?;
 
-        self.message_output.print(&buffer).unwrap();
+        Ok(String::from_utf8_lossy(buffer.as_slice()).into_owned())
     }
+}
 
-    fn flush(&self) {}
-}
\ No newline at end of file
+impl log::Log for ProgressBarLog {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.max_level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Logging must not panic or fail the program, so just drop lines that cannot be written.
+        let mut line = match self.format(record) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        // Hold the lock while printing, such that the bar is not replaced in between.
+        let active_progress_bar = ACTIVE_PROGRESS_BAR.lock().unwrap();
+        match &*active_progress_bar {
+            Some(progress_bar) if is_active(progress_bar) => {
+                // println() drops a trailing empty line, which is used to separate sections of the log.
+                if line.ends_with('\n') {
+                    line.push(' ');
+                }
+                progress_bar.println(line);
+            }
+            _ => {
+                writeln!(self.writer.lock().unwrap(), "{}", line).ok();
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.writer.lock().unwrap().flush().ok();
+    }
+}
+
+/// Such that it can be combined with simplelog's `WriteLogger` for the log file.
+impl SharedLogger for ProgressBarLog {
+    fn level(&self) -> LevelFilter {
+        self.max_level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn log::Log> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    use indicatif::MultiProgress;
+    use log::Log;
+    use rayon::prelude::*;
+    use regex::Regex;
+
+    /// The active progress bar is global, so tests that set it must not run concurrently.
+    static ACTIVE_PROGRESS_BAR_TEST: Mutex<()> = Mutex::new(());
+
+    /// Collects everything written to it, and can be read from while the logger owns a clone.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_log_during_progress_bar() {
+        let _lock = ACTIVE_PROGRESS_BAR_TEST.lock().unwrap();
+        let output = SharedBuffer::default();
+        let logger = ProgressBarLog::new(LevelFilter::Info, false, output.clone());
+
+        // Not drawn, such that lines go to the writer also while the bar is active.
+        let level = |i: i32| if i % 10 == 0 { log::Level::Error } else { log::Level::Info };
+        let progress_bar = set_active(ProgressBar::hidden());
+        progress_bar.set_length(100);
+        (0..100).into_par_iter().for_each(|i| {
+            logger.log(&log::Record::builder()
+                .level(level(i))
+                .args(format_args!("message {}", i))
+                .build());
+            // Below the maximum level, so not written.
+            logger.log(&log::Record::builder().level(log::Level::Debug).args(format_args!("debug {}", i)).build());
+            progress_bar.inc(1);
+        });
+        progress_bar.finish();
+        logger.log(&log::Record::builder().level(log::Level::Warn).args(format_args!("after bar")).build());
+        logger.flush();
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 101);
+
+        // Each line is complete, i.e., lines from different threads are not interleaved.
+        let line_regex = Regex::new(r"^\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2} (ERROR|INFO ) message (\d+)$").unwrap();
+        let mut messages: Vec<_> = lines[..100].iter()
+            .map(|line| {
+                let captures = line_regex.captures(line).unwrap_or_else(|| panic!("unexpected line '{}'", line));
+                let i: i32 = captures[2].parse().unwrap();
+                assert_eq!(captures[1].trim(), level(i).to_string(), "{}", line);
+                i
+            })
+            .collect();
+        messages.sort_unstable();
+        assert_eq!(messages, (0..100).collect::<Vec<_>>());
+        assert!(lines[100].ends_with(" WARN  after bar"), "{}", lines[100]);
+    }
+
+    #[test]
+    fn test_log_above_visible_progress_bar() {
+        let _lock = ACTIVE_PROGRESS_BAR_TEST.lock().unwrap();
+        let output = SharedBuffer::default();
+        let logger = ProgressBarLog::new(LevelFilter::Info, false, output.clone());
+        let info = |message| logger.log(&log::Record::builder().level(log::Level::Info).args(format_args!("{}", message)).build());
+
+        // Bars of a MultiProgress draw to it instead of to a terminal, so they are visible also
+        // when the tests do not run in one.
+        let multi_progress = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let progress_bar = set_active(multi_progress.add(ProgressBar::new(2)));
+        assert!(is_active(&progress_bar));
+
+        // Printed above the bar, not to the writer.
+        info("first");
+        progress_bar.inc(1);
+        // With a trailing empty line.
+        info("second\n");
+        progress_bar.inc(1);
+        assert!(!is_active(&progress_bar));
+        progress_bar.finish();
+        // Receives the bar's draws (including the printed lines) until it is finished.
+        multi_progress.join().unwrap();
+
+        info("after bar");
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 1, "{}", output);
+        assert!(lines[0].ends_with(" INFO  after bar"), "{}", lines[0]);
+    }
+}
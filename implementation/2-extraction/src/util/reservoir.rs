@@ -0,0 +1,115 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::convert::TryInto;
+
+use sha2::{Digest, Sha256};
+
+/// Uniform random subsample of at most `capacity` items from a stream of unknown length, which
+/// only ever holds `capacity` items in memory (reservoir sampling).
+///
+/// Instead of drawing random numbers in stream order (as in the classic "Algorithm R"), each item
+/// gets a pseudo-random priority by hashing its key together with the seed, and the items with the
+/// lowest priorities are kept. That way, the subsample is reproducible for the same seed, even if
+/// the items arrive in arbitrary order (e.g., from a parallel iterator, see `into_seq_iter()`).
+#[derive(Debug)]
+pub struct Reservoir<T> {
+    capacity: usize,
+    seed: u64,
+    // Max-heap, such that the item with the highest priority can be replaced quickly.
+    items: BinaryHeap<Prioritized<T>>,
+    seen: u64,
+}
+
+impl<T> Reservoir<T> {
+    pub fn new(capacity: usize, seed: u64) -> Self {
+        Self { capacity, seed, items: BinaryHeap::with_capacity(capacity), seen: 0 }
+    }
+
+    /// The `key` should uniquely identify the item, independent of the order of the stream.
+    pub fn add(&mut self, key: &[u8], item: T) {
+        self.seen += 1;
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed.to_le_bytes());
+        hasher.update(key);
+        let priority = u64::from_le_bytes(hasher.finalize()[..8].try_into().unwrap());
+
+        if self.items.len() < self.capacity {
+            self.items.push(Prioritized { priority, item });
+        } else if let Some(mut highest) = self.items.peek_mut() {
+            if priority < highest.priority {
+                *highest = Prioritized { priority, item };
+            }
+        }
+    }
+
+    /// Number of items added so far, including those that were not kept.
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    /// The kept items, ordered by priority (i.e., in a deterministic but random order).
+    pub fn into_vec(self) -> Vec<T> {
+        self.items.into_sorted_vec().into_iter().map(|prioritized| prioritized.item).collect()
+    }
+}
+
+#[derive(Debug)]
+struct Prioritized<T> {
+    priority: u64,
+    item: T,
+}
+
+// Compare only by priority, such that the items themselves need not be comparable.
+impl<T> PartialEq for Prioritized<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority
+    }
+}
+
+impl<T> Eq for Prioritized<T> {}
+
+impl<T> PartialOrd for Prioritized<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Prioritized<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subsample(items: impl IntoIterator<Item = u32>, capacity: usize, seed: u64) -> Vec<u32> {
+        let mut reservoir = Reservoir::new(capacity, seed);
+        for item in items {
+            reservoir.add(&item.to_le_bytes(), item);
+        }
+        reservoir.into_vec()
+    }
+
+    #[test]
+    fn test_reservoir() {
+        let kept = subsample(0..1000, 100, 0);
+        assert_eq!(kept.len(), 100);
+
+        // Independent of the order of the stream, but not of the seed.
+        assert_eq!(subsample((0..1000).rev(), 100, 0), kept);
+        assert_ne!(subsample(0..1000, 100, 1), kept);
+
+        // Roughly uniform, i.e., not only from the beginning or end of the stream.
+        let first_half = kept.iter().filter(|&&item| item < 500).count();
+        assert!((30..70).contains(&first_half), "{}", first_half);
+
+        assert_eq!(subsample(0..10, 100, 0).len(), 10);
+        assert!(subsample(0..10, 0, 0).is_empty());
+    }
+}
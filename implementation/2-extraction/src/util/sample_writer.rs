@@ -1,46 +1,97 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::{fs, io};
 
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use itertools::Itertools;
 use rand::prelude::{SliceRandom, StdRng};
+use rayon::iter::ParallelIterator;
 use rayon::slice::ParallelSliceMut;
 
 use serde::Serialize;
 
+use crate::options::OutputFormat;
 use crate::samples::sample::{ParamOrReturn, WasmTypeSample};
-use crate::samples::types::Type;
+use crate::samples::split::TrainDevTest;
+use crate::samples::types::{Type, TypeToken};
 use crate::samples::wasm_repr::WasmRepr;
 
-// Struct for quick implementation of serialization to JSON with serde.
+/// Metadata of a sample, as written to info.jsonl (serialized to JSON with serde).
 #[derive(Debug, Serialize)]
-struct SampleInfo<'a> {
-    file: &'a str,
-    compilation_unit: Option<&'a str>,
-    function_idx: u32,
-    function_name_wasm: Option<&'a str>,
-    function_name_dwarf: Option<&'a str>,
+pub struct SampleInfo<'a> {
+    // Stable across runs, see `WasmTypeSample::id()`.
+    pub id: String,
+    // Paths are not necessarily valid UTF-8, so invalid parts are replaced (with U+FFFD).
+    pub file: Cow<'a, str>,
+    pub compilation_unit: Option<&'a str>,
+    pub producer: Option<&'a str>,
+    pub function_idx: u32,
+    pub function_name_wasm: Option<&'a str>,
+    pub function_name_dwarf: Option<&'a str>,
+    // Only with --demangle.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_name_wasm_demangled: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_name_dwarf_demangled: Option<&'a str>,
     // None/null (JSON) if this is a return type sample.
-    param_idx: Option<u32>,
-    param_name: Option<&'a str>,
+    pub param_idx: Option<u32>,
+    pub param_name: Option<&'a str>,
+    // None/null (JSON) if this is a parameter sample.
+    pub return_idx: Option<u32>,
+    // Only for local variable samples (with --include-locals), which are written together with
+    // the parameter samples.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_idx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_name: Option<&'a str>,
+    pub decl_file: Option<&'a str>,
+    pub decl_line: Option<u64>,
+    // Source names (e.g., "long unsigned int") of all primitive types in the final type, in token
+    // order, i.e., one for `primitive uint32_t` as well as for `pointer const primitive uint32_t`,
+    // and one per primitive member or parameter of expanded structs and function types.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub primitive_source_names: Vec<&'a str>,
 }
 
 impl<'a> SampleInfo<'a> {
-    pub fn from<T, U, R>(sample: &'a WasmTypeSample<T, U, R>) -> Self {
-        let (param_idx, param_name) = match &sample.param_or_return {
-            ParamOrReturn::Param { idx, name } => (Some(*idx), name.as_deref()),
-            ParamOrReturn::Return => (None, None)
+    pub fn from<T, R>(sample: &'a WasmTypeSample<T, Type, R>) -> Self {
+        let (param_idx, param_name, return_idx) = match &sample.param_or_return {
+            ParamOrReturn::Param { idx, name } => (Some(*idx), name.as_deref(), None),
+            ParamOrReturn::Return { idx } => (None, None, Some(*idx)),
+            ParamOrReturn::Local { .. } => (None, None, None),
+        };
+        let (local_idx, local_name) = match &sample.param_or_return {
+            ParamOrReturn::Local { idx, name } => (Some(*idx), name.as_deref()),
+            _ => (None, None),
         };
         Self {
-            file: sample.file.to_str().unwrap(),
+            id: sample.id(),
+            file: sample.file.to_string_lossy(),
             compilation_unit: sample.compilation_unit.as_deref(),
+            producer: sample.producer.as_deref(),
             function_idx: sample.function_idx,
             function_name_wasm: sample.function_name_wasm.as_deref(),
             function_name_dwarf: sample.function_name_dwarf.as_deref(),
+            function_name_wasm_demangled: sample.function_name_wasm_demangled.as_deref(),
+            function_name_dwarf_demangled: sample.function_name_dwarf_demangled.as_deref(),
             param_idx,
-            param_name
+            param_name,
+            return_idx,
+            local_idx,
+            local_name,
+            decl_file: sample.decl_file.as_deref(),
+            decl_line: sample.decl_line,
+            primitive_source_names: sample.type_.0.iter()
+                .filter_map(|token| match token {
+                    TypeToken::Primitive(primitive) => Some(primitive.source_name.as_ref()),
+                    _ => None,
+                })
+                .collect(),
         }
     }
 }
@@ -75,75 +126,500 @@ impl ParamReturn {
     }
 }
 
-/// Convenience wrapper around output files: 3 wasm/dwarf/info * 2 param/return.
-pub struct SampleWriter {
-    writers: HashMap<(WasmTypeInfo, ParamReturn), BufWriter<File>>,
+// Struct for serializing a whole sample as a single JSON object, see OutputFormat::JsonlCombined.
+#[derive(Debug, Serialize)]
+struct CombinedSample<'a> {
+    wasm: String,
+    #[serde(rename = "type")]
+    type_: String,
+    param_or_return: &'static str,
+    #[serde(flatten)]
+    info: SampleInfo<'a>,
 }
 
-impl SampleWriter {
-    pub fn create_files(directory: impl AsRef<Path>) -> io::Result<Self> {
-        let mut writers = HashMap::new();
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum FileKind {
+    Separate(WasmTypeInfo, ParamReturn),
+    Combined,
+}
 
-        use WasmTypeInfo::*;
-        use ParamReturn::*;
-        for &wti in &[Wasm, Type, Info] {
-            for &pr in &[Param, Return] {
-                let writer = Self::create_file(&directory, wti, pr)?;
-                writers.insert((wti, pr), writer);
+// Optional dataset subset (if the dataset is split) and which part of the samples is in the file.
+type FileKey = (Option<TrainDevTest>, FileKind);
+
+fn all_files(split: bool, format: OutputFormat) -> Vec<FileKey> {
+    let subsets = if split {
+        TrainDevTest::ALL.iter().copied().map(Some).collect()
+    } else {
+        vec![None]
+    };
+
+    use WasmTypeInfo::*;
+    use ParamReturn::*;
+    let mut files = Vec::new();
+    for subset in subsets {
+        match format {
+            OutputFormat::Opennmt => {
+                for &pr in &[Param, Return] {
+                    for &wti in &[Wasm, Type, Info] {
+                        files.push((subset, FileKind::Separate(wti, pr)));
+                    }
+                }
             }
+            OutputFormat::JsonlCombined => files.push((subset, FileKind::Combined)),
         }
+    }
+    files
+}
 
-        Ok(SampleWriter { writers })
+/// Path of a file like output_dir/param/wasm.txt or output_dir/samples.jsonl, prefixed by the subset,
+/// e.g., output_dir/train/param/wasm.txt if the dataset is split, or output_dir/param/wasm.<shard>.txt
+/// for shards. With `compress`, the filename ends with .gz, e.g., output_dir/param/wasm.txt.gz.
+fn file_path(output_dir: impl AsRef<Path>, (subset, kind): FileKey, shard: Option<usize>, compress: bool) -> PathBuf {
+    let mut path = output_dir.as_ref().to_path_buf();
+    if let Some(subset) = subset {
+        path.push(subset.to_str());
     }
 
-    /// Create a file like output_dir/param/wasm.txt
-    fn create_file(output_dir: impl AsRef<Path>, wti: WasmTypeInfo, pr: ParamReturn) -> io::Result<BufWriter<File>> {
-        // Make sure the parent directories exist.
-        let dir = output_dir.as_ref().join(pr.to_str());
-        fs::create_dir_all(&dir)?;
+    use WasmTypeInfo::*;
+    let (name, extension) = match kind {
+        FileKind::Separate(wti, pr) => {
+            path.push(pr.to_str());
+            match wti {
+                Wasm | Type => (wti.to_str(), "txt"),
+                Info => (wti.to_str(), "jsonl"),
+            }
+        }
+        FileKind::Combined => ("samples", "jsonl"),
+    };
+    let mut filename = match shard {
+        None => format!("{}.{}", name, extension),
+        Some(shard) => format!("{}.{}.{}", name, shard, extension),
+    };
+    if compress {
+        filename.push_str(".gz");
+    }
+    path.join(filename)
+}
 
-        use WasmTypeInfo::*;
-        let extension = match wti {
-            Wasm | Type => "txt",
-            Info => "jsonl"
+/// Counts the bytes written to the inner writer, since `Seek` is not available through a
+/// compressing writer (and would not count the compressed size anyway).
+struct CountingWriter<W> {
+    inner: W,
+    bytes_written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes = self.inner.write(buf)?;
+        self.bytes_written += bytes as u64;
+        Ok(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A single (buffered) output file, optionally gzip compressed.
+enum OutputFile {
+    Plain(CountingWriter<BufWriter<File>>),
+    // Appending to a gzip file adds another gzip member, which decompresses just like a single
+    // stream (e.g., with gzip -d or zcat). This also allows to merge shards by concatenation.
+    Gzip(GzEncoder<CountingWriter<BufWriter<File>>>),
+}
+
+impl OutputFile {
+    /// Flushes all data to the file (and for gzip, finishes the compressed stream, so no more
+    /// data must be written afterwards) and returns the number of bytes written to the file.
+    fn finish(&mut self) -> io::Result<u64> {
+        let file = match self {
+            OutputFile::Plain(file) => file,
+            OutputFile::Gzip(encoder) => {
+                encoder.try_finish()?;
+                encoder.get_mut()
+            }
         };
-        let filename = format!("{}.{}", wti.to_str(), extension);
-        let path = dir.join(filename);
+        file.flush()?;
+        Ok(file.bytes_written)
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputFile::Plain(file) => file.write(buf),
+            OutputFile::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(file) => file.flush(),
+            OutputFile::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Convenience wrapper around output files: 3 wasm/dwarf/info * 2 param/return (or a single
+/// combined JSONL file, see `OutputFormat`), and optionally all of that for each of the
+/// train/dev/test subsets.
+///
+/// Note that the order of samples in the output files is non-deterministic, since samples are
+/// extracted in parallel (and then fed to the writer via `into_seq_iter()`), so the sharded mode
+/// (see `create_files_sharded()`) does not weaken any guarantees. For a deterministic order, see
+/// `write_sorted()`.
+pub struct SampleWriter {
+    format: OutputFormat,
+    writers: HashMap<FileKey, OutputFile>,
+}
+
+impl SampleWriter {
+    /// If `split` is true, create separate files for each of the train/dev/test subsets, and all
+    /// written samples must then have their subset attached.
+    /// If `compress` is true, all files are gzip compressed.
+    /// If `append` is true, samples are appended to existing files (e.g., from a previous run,
+    /// see `--skip-processed`) instead of overwriting them.
+    pub fn create_files(directory: impl AsRef<Path>, split: bool, format: OutputFormat, compress: bool, append: bool) -> io::Result<Self> {
+        Self::create_files_(directory, split, format, compress, None, append)
+    }
+
+    /// Create `n_shards` sets of output files, such that multiple (rayon worker) threads can write
+    /// samples in parallel. Call `ShardedSampleWriter::merge()` at the end to obtain the regular
+    /// output files.
+    /// With `append`, the shards are still written from scratch, but appended to the regular output
+    /// files when merging.
+    pub fn create_files_sharded(directory: impl AsRef<Path>, split: bool, format: OutputFormat, compress: bool, n_shards: usize, append: bool) -> io::Result<ShardedSampleWriter> {
+        assert!(n_shards > 0, "need at least one shard");
+        let shards = (0..n_shards)
+            .map(|shard| Self::create_files_(&directory, split, format, compress, Some(shard), false).map(Mutex::new))
+            .collect::<io::Result<_>>()?;
+        Ok(ShardedSampleWriter { directory: directory.as_ref().to_path_buf(), files: all_files(split, format), compress, shards, append })
+    }
+
+    fn create_files_(directory: impl AsRef<Path>, split: bool, format: OutputFormat, compress: bool, shard: Option<usize>, append: bool) -> io::Result<Self> {
+        let mut writers = HashMap::new();
+        for key in all_files(split, format) {
+            let file = CountingWriter { inner: Self::create_file(file_path(&directory, key, shard, compress), append)?, bytes_written: 0 };
+            let writer = if compress {
+                OutputFile::Gzip(GzEncoder::new(file, Compression::default()))
+            } else {
+                OutputFile::Plain(file)
+            };
+            writers.insert(key, writer);
+        }
+        Ok(SampleWriter { format, writers })
+    }
 
-        let file = File::create(path)?;
+    fn create_file(path: PathBuf, append: bool) -> io::Result<BufWriter<File>> {
+        // Make sure the parent directories exist.
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = if append {
+            OpenOptions::new().create(true).append(true).open(path)?
+        } else {
+            File::create(path)?
+        };
         let writer = BufWriter::new(file);
         Ok(writer)
     }
 
-    pub fn write(&mut self, sample: &WasmTypeSample<WasmRepr, Type, ()>) -> io::Result<()> {
+    pub fn write(&mut self, sample: &WasmTypeSample<WasmRepr, Type, Option<TrainDevTest>>) -> io::Result<()> {
         use ParamReturn::*;
         let pr = match sample.param_or_return {
-            ParamOrReturn::Param { .. } => Param,
-            ParamOrReturn::Return => Return
+            ParamOrReturn::Param { .. } | ParamOrReturn::Local { .. } => Param,
+            ParamOrReturn::Return { .. } => Return
         };
+        let subset = sample.aux;
+        let missing_file = "dataset subset of sample does not match whether the sample writer splits the dataset";
+
+        if let OutputFormat::JsonlCombined = self.format {
+            let combined = CombinedSample {
+                wasm: sample.wasm_body.to_string(),
+                type_: sample.type_.to_string(),
+                param_or_return: match sample.param_or_return {
+                    ParamOrReturn::Local { .. } => "local",
+                    _ => pr.to_str(),
+                },
+                info: SampleInfo::from(sample),
+            };
+            let mut writer = self.writers.get_mut(&(subset, FileKind::Combined)).expect(missing_file);
+            serde_json::to_writer(&mut writer, &combined)?;
+            writeln!(writer)?;
+            return Ok(());
+        }
 
         // Write WebAssembly input, type output, and sample info for debugging.
         use WasmTypeInfo::*;
-        writeln!(self.writers.get_mut(&(Wasm, pr)).unwrap(), "{}", sample.wasm_body)?;
-        writeln!(self.writers.get_mut(&(Type, pr)).unwrap(), "{}", sample.type_)?;
+        writeln!(self.writers.get_mut(&(subset, FileKind::Separate(Wasm, pr))).expect(missing_file), "{}", sample.wasm_body)?;
+        writeln!(self.writers.get_mut(&(subset, FileKind::Separate(Type, pr))).expect(missing_file), "{}", sample.type_)?;
         
         let info = SampleInfo::from(sample);
-        let mut info_writer = self.writers.get_mut(&(Info, pr)).unwrap();
+        let mut info_writer = self.writers.get_mut(&(subset, FileKind::Separate(Info, pr))).expect(missing_file);
         serde_json::to_writer(&mut info_writer, &info)?;
         writeln!(info_writer)?;
 
         Ok(())
     }
 
-    /// Flushes all underlying writers and reports the number of bytes written to all files combined.
+    /// Writes all samples sorted by their stable ID (see `WasmTypeSample::id()`) instead of in the
+    /// (arbitrary) order they come out of the parallel extraction, such that two runs on the same
+    /// input produce byte-identical files. Unlike streaming the samples to `write()`, this keeps
+    /// all samples in memory at once.
+    pub fn write_sorted(&mut self, samples: impl ParallelIterator<Item = WasmTypeSample<WasmRepr, Type, Option<TrainDevTest>>>) -> io::Result<()> {
+        let mut samples: Vec<_> = samples.collect();
+        samples.par_sort_unstable_by_key(|sample| sample.id());
+        for sample in &samples {
+            self.write(sample)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes all underlying writers and reports the number of bytes written to all files combined
+    /// (not counting what was in the files before, when appending). When compressing, this finishes
+    /// the compressed streams, so no more samples must be written afterwards.
     pub fn bytes_written(&mut self) -> io::Result<u64> {
         let mut bytes_written = 0;
         for writer in self.writers.values_mut() {
+            bytes_written += writer.finish()?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+/// Output files split into multiple shards, one per (rayon worker) thread, such that samples can
+/// be written in parallel. Each thread only writes to its own shard, so the locks are never
+/// contended if there are at least as many shards as threads.
+pub struct ShardedSampleWriter {
+    directory: PathBuf,
+    files: Vec<FileKey>,
+    compress: bool,
+    shards: Vec<Mutex<SampleWriter>>,
+    append: bool,
+}
+
+impl ShardedSampleWriter {
+    pub fn write(&self, sample: &WasmTypeSample<WasmRepr, Type, Option<TrainDevTest>>) -> io::Result<()> {
+        // Outside of the rayon thread pool, e.g., on the main thread, just write to the first shard.
+        let shard = rayon::current_thread_index().unwrap_or(0) % self.shards.len();
+        self.shards[shard].lock().unwrap().write(sample)
+    }
+
+    /// Concatenate all shards into the regular output files (see `SampleWriter::create_files()`)
+    /// and remove the shard files.
+    /// Returns the number of bytes written to all files combined.
+    pub fn merge(self) -> io::Result<u64> {
+        let n_shards = self.shards.len();
+        let mut bytes_written = 0;
+        for shard in self.shards {
+            // Flushes the shard files, which are closed at the end of this iteration.
+            bytes_written += shard.into_inner().unwrap().bytes_written()?;
+        }
+
+        for &key in &self.files {
+            // Compressed shards can be concatenated as well, see `OutputFile::Gzip`.
+            let mut writer = SampleWriter::create_file(file_path(&self.directory, key, None, self.compress), self.append)?;
+            for shard in 0..n_shards {
+                let shard_path = file_path(&self.directory, key, Some(shard), self.compress);
+                io::copy(&mut File::open(&shard_path)?, &mut writer)?;
+                fs::remove_file(shard_path)?;
+            }
             writer.flush()?;
-            // BufWriter and File implement Seek, from which we can get the current position:
-            // https://stackoverflow.com/questions/42187591/how-to-keep-track-of-how-many-bytes-written-when-using-stdiowrite
-            bytes_written += writer.seek(SeekFrom::Current(0))?;
         }
+
         Ok(bytes_written)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::SeedableRng;
+    use rayon::prelude::*;
+
+    use super::*;
+    use crate::samples::types::TypeToken;
+    use crate::wasm::test_util::temp_dir;
+
+    fn sample(function_idx: u32) -> WasmTypeSample<WasmRepr, Type, Option<TrainDevTest>> {
+        WasmTypeSample {
+            file: Arc::from(Path::new("test.wasm")),
+            file_sha256: Arc::from(&[0; 32][..]),
+            compilation_unit: None,
+            producer: None,
+            language: None,
+            function_idx,
+            function_name_wasm: None,
+            function_name_dwarf: None,
+            function_name_wasm_demangled: None,
+            function_name_dwarf_demangled: None,
+            decl_file: None,
+            decl_line: None,
+            param_or_return: ParamOrReturn::Param { idx: 0, name: None },
+            wasm_type: wasmparser::Type::I32,
+            wasm_function_type: Arc::new(wasmparser::FuncType { params: Box::new([]), returns: Box::new([]) }),
+            wasm_body: WasmRepr::Full(None, format!("call {}", function_idx).into()),
+            type_: Type(vec![TypeToken::Unknown]),
+            aux: None,
+        }
+    }
+
+    #[test]
+    fn test_sharded_merge() {
+        let temp_dir = temp_dir();
+        let dir = temp_dir.path();
+        let sample_writer = SampleWriter::create_files_sharded(dir, false, OutputFormat::Opennmt, false, 4, false).unwrap();
+        (0..1000u32).into_par_iter().try_for_each(|i| sample_writer.write(&sample(i))).unwrap();
+        let bytes_written = sample_writer.merge().unwrap();
+
+        let wasm = fs::read_to_string(dir.join("param/wasm.txt")).unwrap();
+        let mut lines = wasm.lines().collect_vec();
+        lines.sort_unstable();
+        let mut expected = (0..1000).map(|i| format!("call {}", i)).collect_vec();
+        expected.sort_unstable();
+        assert_eq!(lines, expected);
+
+        let bytes_on_disk: u64 = all_files(false, OutputFormat::Opennmt).into_iter()
+            .map(|key| fs::metadata(file_path(dir, key, None, false)).unwrap().len())
+            .sum();
+        assert_eq!(bytes_written, bytes_on_disk);
+        assert!(!dir.join("param/wasm.0.txt").exists());
+    }
+
+    #[test]
+    fn test_append() {
+        let temp_dir = temp_dir();
+        let dir = temp_dir.path();
+        let mut sample_writer = SampleWriter::create_files(dir, false, OutputFormat::Opennmt, false, false).unwrap();
+        sample_writer.write(&sample(0)).unwrap();
+        let bytes_first_run = sample_writer.bytes_written().unwrap();
+
+        let mut sample_writer = SampleWriter::create_files(dir, false, OutputFormat::Opennmt, false, true).unwrap();
+        sample_writer.write(&sample(1)).unwrap();
+        assert_eq!(sample_writer.bytes_written().unwrap(), bytes_first_run);
+
+        let sample_writer = SampleWriter::create_files_sharded(dir, false, OutputFormat::Opennmt, false, 2, true).unwrap();
+        sample_writer.write(&sample(2)).unwrap();
+        sample_writer.merge().unwrap();
+
+        let wasm = fs::read_to_string(dir.join("param/wasm.txt")).unwrap();
+        assert_eq!(wasm.lines().collect_vec(), vec!["call 0", "call 1", "call 2"]);
+    }
+
+    #[test]
+    fn test_jsonl_combined() {
+        let temp_dir = temp_dir();
+        let dir = temp_dir.path();
+        let mut sample_writer = SampleWriter::create_files(dir, false, OutputFormat::JsonlCombined, false, false).unwrap();
+        let mut return_sample = sample(1);
+        return_sample.param_or_return = ParamOrReturn::Return { idx: 0 };
+        sample_writer.write(&sample(0)).unwrap();
+        sample_writer.write(&return_sample).unwrap();
+        sample_writer.bytes_written().unwrap();
+
+        let lines = fs::read_to_string(dir.join("samples.jsonl")).unwrap();
+        let lines = lines.lines().map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()).collect_vec();
+        assert_eq!(lines[0], serde_json::json!({
+            "wasm": "call 0",
+            "type": "unknown",
+            "param_or_return": "param",
+            "id": sample(0).id(),
+            "file": "test.wasm",
+            "compilation_unit": null,
+            "producer": null,
+            "function_idx": 0,
+            "function_name_wasm": null,
+            "function_name_dwarf": null,
+            "param_idx": 0,
+            "param_name": null,
+            "return_idx": null,
+            "decl_file": null,
+            "decl_line": null,
+        }));
+        assert_eq!(lines[1]["param_or_return"], "return");
+        assert_eq!(lines[1]["param_idx"], serde_json::Value::Null);
+        assert_eq!(lines[1]["return_idx"], 0);
+        assert!(!dir.join("param").exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = temp_dir();
+        let dir = temp_dir.path();
+        let mut sample_writer = SampleWriter::create_files(dir, false, OutputFormat::JsonlCombined, false, false).unwrap();
+        let mut sample = sample(0);
+        sample.file = Arc::from(Path::new(OsStr::from_bytes(b"dir/caf\xe9.wasm")));
+        sample_writer.write(&sample).unwrap();
+        sample_writer.bytes_written().unwrap();
+
+        let lines = fs::read_to_string(dir.join("samples.jsonl")).unwrap();
+        let line: serde_json::Value = serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+        assert_eq!(line["file"], "dir/caf\u{fffd}.wasm");
+    }
+
+    #[test]
+    fn test_compress() {
+        use flate2::read::MultiGzDecoder;
+        use std::io::Read;
+
+        let read_gz = |path: PathBuf| {
+            let mut decompressed = String::new();
+            MultiGzDecoder::new(File::open(path).unwrap()).read_to_string(&mut decompressed).unwrap();
+            decompressed
+        };
+
+        let temp_dir = temp_dir();
+        let dir = temp_dir.path();
+        let mut sample_writer = SampleWriter::create_files(dir.join("plain"), false, OutputFormat::Opennmt, false, false).unwrap();
+        let mut compressed_writer = SampleWriter::create_files(dir.join("gzip"), false, OutputFormat::Opennmt, true, false).unwrap();
+        for i in 0..100 {
+            sample_writer.write(&sample(i)).unwrap();
+            compressed_writer.write(&sample(i)).unwrap();
+        }
+        sample_writer.bytes_written().unwrap();
+        let bytes_written = compressed_writer.bytes_written().unwrap();
+
+        for file in &["param/wasm.txt", "param/type.txt", "param/info.jsonl"] {
+            let plain = fs::read_to_string(dir.join("plain").join(file)).unwrap();
+            assert_eq!(read_gz(dir.join("gzip").join(format!("{}.gz", file))), plain);
+        }
+        let bytes_on_disk: u64 = all_files(false, OutputFormat::Opennmt).into_iter()
+            .map(|key| fs::metadata(file_path(dir.join("gzip"), key, None, true)).unwrap().len())
+            .sum();
+        assert_eq!(bytes_written, bytes_on_disk);
+
+        // Appending and merging shards add gzip members, which decompress as a single file.
+        let sample_writer = SampleWriter::create_files_sharded(dir.join("gzip"), false, OutputFormat::Opennmt, true, 2, true).unwrap();
+        sample_writer.write(&sample(100)).unwrap();
+        sample_writer.merge().unwrap();
+        let wasm = read_gz(dir.join("gzip/param/wasm.txt.gz"));
+        assert_eq!(wasm.lines().count(), 101);
+        assert_eq!(wasm.lines().last(), Some("call 100"));
+    }
+
+    #[test]
+    fn test_write_sorted_deterministic() {
+        let temp_dir = temp_dir();
+        let dir = temp_dir.path();
+        let mut shuffled = (0..1000).collect_vec();
+        shuffled.shuffle(&mut StdRng::seed_from_u64(0));
+        for (run, order) in [(0..1000).collect_vec(), shuffled].iter().enumerate() {
+            let mut sample_writer = SampleWriter::create_files(dir.join(run.to_string()), false, OutputFormat::Opennmt, false, false).unwrap();
+            sample_writer.write_sorted(order.par_iter().map(|&i| sample(i))).unwrap();
+            sample_writer.bytes_written().unwrap();
+        }
+        for file in &["param/wasm.txt", "param/type.txt", "param/info.jsonl"] {
+            let run0 = fs::read(dir.join("0").join(file)).unwrap();
+            let run1 = fs::read(dir.join("1").join(file)).unwrap();
+            assert_eq!(run0, run1);
+        }
+        let wasm = fs::read_to_string(dir.join("0/param/wasm.txt")).unwrap();
+        assert_eq!(wasm.lines().count(), 1000);
+    }
+}
@@ -0,0 +1,76 @@
+use std::io::{self, Write};
+
+use chashmap::CHashMap;
+
+/// Concurrent token counter, e.g., for building fixed vocabularies for seq2seq models.
+#[derive(Debug, Clone, Default)]
+pub struct Vocabulary(CHashMap<Box<str>, u64>);
+
+impl Vocabulary {
+    pub fn new() -> Self {
+        Self(CHashMap::new())
+    }
+
+    pub fn add(&self, token: &str) {
+        self.0.upsert(token.into(), || 1, |count| *count += 1);
+    }
+
+    /// Tokens with at least `min_freq` occurrences, sorted by descending frequency (and then by
+    /// token, to make the output deterministic).
+    pub fn sorted(self, min_freq: u64) -> Vec<(Box<str>, u64)> {
+        let mut tokens: Vec<_> = self.0.into_iter()
+            .filter(|(_, count)| *count >= min_freq)
+            .collect();
+        tokens.sort_unstable_by(|(token_a, count_a), (token_b, count_b)| count_b.cmp(count_a).then(token_a.cmp(token_b)));
+        tokens
+    }
+
+    /// Write one token and its count (separated by a tab) per line, see `sorted()`.
+    pub fn write(self, mut writer: impl Write, min_freq: u64) -> io::Result<()> {
+        for (token, count) in self.sorted(min_freq) {
+            writeln!(writer, "{}\t{}", token, count)?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vocab(tokens: &str) -> Vocabulary {
+        let vocab = Vocabulary::new();
+        for token in tokens.split_whitespace() {
+            vocab.add(token);
+        }
+        vocab
+    }
+
+    #[test]
+    fn test_sorted_by_frequency() {
+        let vocab = vocab("local.get i32.add local.get call local.get i32.add");
+        assert_eq!(vocab.sorted(1), vec![
+            ("local.get".into(), 3),
+            ("i32.add".into(), 2),
+            ("call".into(), 1),
+        ]);
+    }
+
+    #[test]
+    fn test_min_freq() {
+        let vocab = vocab("b a b a c d d d");
+        assert_eq!(vocab.sorted(2), vec![
+            ("d".into(), 3),
+            // Ties are sorted by token.
+            ("a".into(), 2),
+            ("b".into(), 2),
+        ]);
+    }
+
+    #[test]
+    fn test_write() {
+        let mut output = Vec::new();
+        vocab("pointer const pointer").write(&mut output, 1).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "pointer\t2\nconst\t1\n");
+    }
+}
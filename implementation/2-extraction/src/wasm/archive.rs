@@ -0,0 +1,146 @@
+//! Static archives (`.a`, created by `ar`) of WebAssembly object files. Members are referred to
+//! by a "virtual" path of the archive, the position of the member in the archive, and its name,
+//! e.g., `libfoo.a!3!bar.o`, which is understood by `read_wasm_file()`, such that members can be
+//! processed like any other binary. (The position is necessary, since archives can contain
+//! several members with the same name, e.g., `util.o` from different directories.)
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::wasm::magic_bytes::MAGIC_BYTES_VERSION_1;
+
+pub static AR_MAGIC_BYTES: [u8; 8] = *b"!<arch>\n";
+
+/// Separator between the archive, the member position, and the member name in member paths.
+pub const MEMBER_SEPARATOR: char = '!';
+
+/// Whether the file is an `ar` archive, by its magic bytes.
+pub fn is_archive(file: impl AsRef<Path>) -> io::Result<bool> {
+    let mut f = BufReader::new(File::open(file)?);
+    Ok(f.fill_buf()?.starts_with(&AR_MAGIC_BYTES))
+}
+
+/// Paths of all members of `archive` that are WebAssembly binaries (by their magic bytes), e.g.,
+/// `libfoo.a!3!bar.o`. Other members, e.g., the symbol table, are skipped.
+pub fn wasm_members(archive: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut members = Vec::new();
+    let mut reader = ar::Archive::new(File::open(archive)?);
+    let mut position = 0;
+    while let Some(entry) = reader.next_entry() {
+        let mut entry = entry?;
+        let name = String::from_utf8_lossy(entry.header().identifier()).into_owned();
+        let mut magic_bytes = [0u8; 8];
+        if entry.read_exact(&mut magic_bytes).is_ok() && magic_bytes == MAGIC_BYTES_VERSION_1 {
+            members.push(member_path(archive, position, &name));
+        }
+        position += 1;
+    }
+    Ok(members)
+}
+
+fn member_path(archive: &Path, position: usize, member: &str) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(format!("{}{}{}", MEMBER_SEPARATOR, position, MEMBER_SEPARATOR));
+    path.push(member);
+    path.into()
+}
+
+/// Split a member path into the archive, the position and the name of the member, if it refers to
+/// a member of an existing archive (and not to a regular file that just contains the separator in
+/// its name).
+pub fn split_member_path(path: &Path) -> Option<(&Path, usize, &str)> {
+    if path.exists() {
+        return None;
+    }
+    let (archive_and_position, member) = path.to_str()?.rsplit_once(MEMBER_SEPARATOR)?;
+    let (archive, position) = archive_and_position.rsplit_once(MEMBER_SEPARATOR)?;
+    let position = position.parse().ok()?;
+    let archive = Path::new(archive);
+    archive.is_file().then_some((archive, position, member))
+}
+
+/// Read the bytes of the member at the given position, which must have the given name.
+pub fn read_member(archive: &Path, position: usize, member: &str) -> io::Result<Vec<u8>> {
+    let mut reader = ar::Archive::new(File::open(archive)?);
+    let mut current_position = 0;
+    while let Some(entry) = reader.next_entry() {
+        let mut entry = entry?;
+        if current_position == position && entry.header().identifier() == member.as_bytes() {
+            let mut bytes = Vec::with_capacity(entry.header().size() as usize);
+            entry.read_to_end(&mut bytes)?;
+            return Ok(bytes);
+        }
+        current_position += 1;
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, format!("no member '{}' at position {} in archive {}", member, position, archive.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::SignatureMode;
+    use crate::samples::extract::{extract_samples, ExtractOptions};
+    use crate::wasm::binary_stats::WasmBinaryStats;
+    use crate::wasm::magic_bytes::read_wasm_file;
+    use crate::wasm::test_util::*;
+
+    #[test]
+    fn test_two_member_archive() {
+        let first = int_param_module(1, |_| {});
+        let second = int_param_module(2, |_| {});
+        let mut archive = Vec::new();
+        {
+            let mut builder = ar::Builder::new(&mut archive);
+            builder.append(&ar::Header::new(b"first.o".to_vec(), first.len() as u64), &first[..]).unwrap();
+            builder.append(&ar::Header::new(b"notes.txt".to_vec(), 5), &b"hello"[..]).unwrap();
+            builder.append(&ar::Header::new(b"second.o".to_vec(), second.len() as u64), &second[..]).unwrap();
+        }
+        let dir = temp_dir();
+        let archive = write_temp_file(&dir, "libtest.a", &archive);
+        assert!(is_archive(&archive).unwrap());
+        assert!(!is_archive(write_temp_file(&dir, "first.wasm", &first)).unwrap());
+
+        let members = wasm_members(&archive).unwrap();
+        let expected: Vec<PathBuf> = vec![
+            format!("{}!0!first.o", archive.display()).into(),
+            format!("{}!2!second.o", archive.display()).into(),
+        ];
+        assert_eq!(members, expected);
+
+        // Members can be read, counted, and extracted from like regular binaries.
+        assert_eq!(&read_wasm_file(&members[1], true).unwrap()[..], &second[..]);
+        assert_eq!(WasmBinaryStats::from_file(&members[0], false, SignatureMode::Names).unwrap().function_bodies_count, 1);
+        assert_eq!(WasmBinaryStats::from_file(&members[1], false, SignatureMode::Names).unwrap().function_bodies_count, 2);
+
+        // The member path is the provenance of its samples, e.g., in the info file.
+        let samples: Vec<_> = extract_samples(&members[1], &[], &ExtractOptions::default()).unwrap().collect::<gimli::Result<_>>().unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(&*samples[0].file, members[1].as_path());
+
+        assert!(split_member_path(&archive).is_none());
+        assert!(read_wasm_file(format!("{}!3!missing.o", archive.display()), false).is_err());
+        assert!(read_wasm_file(format!("{}!0!second.o", archive.display()), false).is_err());
+    }
+
+    #[test]
+    fn test_duplicate_member_names() {
+        // E.g., from `ar` on `a/util.o` and `b/util.o`.
+        let first = int_param_module(1, |_| {});
+        let second = int_param_module(2, |_| {});
+        let mut archive = Vec::new();
+        {
+            let mut builder = ar::Builder::new(&mut archive);
+            builder.append(&ar::Header::new(b"util.o".to_vec(), first.len() as u64), &first[..]).unwrap();
+            builder.append(&ar::Header::new(b"util.o".to_vec(), second.len() as u64), &second[..]).unwrap();
+        }
+        let dir = temp_dir();
+        let archive = write_temp_file(&dir, "libdup.a", &archive);
+
+        let members = wasm_members(&archive).unwrap();
+        assert_eq!(members.len(), 2);
+        assert_ne!(members[0], members[1]);
+        assert_eq!(&read_wasm_file(&members[0], false).unwrap()[..], &first[..]);
+        assert_eq!(&read_wasm_file(&members[1], false).unwrap()[..], &second[..]);
+    }
+}
@@ -1,12 +1,16 @@
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use itertools::Itertools;
+use rayon::slice::ParallelSliceMut;
 use sha2::{Digest, Sha256};
 use wasmparser::{Parser, Payload};
 
+use crate::options::{DedupMode, SignatureMode};
 use crate::util::file_error::{FileError, ResultWithFile};
+use crate::wasm::magic_bytes::read_wasm_file;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct WasmBinaryStats {
     pub file_size: u64,
     pub file_sha256: Box<[u8]>,
@@ -21,12 +25,13 @@ pub struct WasmBinaryStats {
 
 impl WasmBinaryStats {
     // Wrap inner function to attach filename to error.
-    pub fn from_file(file: impl AsRef<Path>) -> Result<Self, FileError<anyhow::Error>> {
-        Self::from_file_inner(file.as_ref()).with_file(file)
+    /// See `read_wasm_file()` for `mmap`, and `SignatureMode` for how `binary_signature` is computed.
+    pub fn from_file(file: impl AsRef<Path>, mmap: bool, signature_mode: SignatureMode) -> Result<Self, FileError<anyhow::Error>> {
+        Self::from_file_inner(file.as_ref(), mmap, signature_mode).with_file(file)
     }
 
-    fn from_file_inner(file: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let bytes = std::fs::read(file)?;
+    fn from_file_inner(file: impl AsRef<Path>, mmap: bool, signature_mode: SignatureMode) -> anyhow::Result<Self> {
+        let bytes = read_wasm_file(file, mmap)?;
         
         let file_sha256 = Sha256::digest(&bytes).as_slice().into();
 
@@ -46,27 +51,26 @@ impl WasmBinaryStats {
                 function_bodies_bytes += function_body_bytes.len() as u64;
 
                 // Compute hash over names of instructions, this abstracts away differences in function indices etc.
+                // Count the instructions in the same pass, to decode each body only once.
                 let mut hasher = Sha256::new();
                 let mut reader = function_body.get_operators_reader()?;
                 while !reader.eof() {
                     let op = reader.read()?;
-                    let instruction_name_only = crate::wasm::fmt::instr_name(&op);
-                    hasher.write(instruction_name_only.as_bytes())?;
+                    if signature_mode == SignatureMode::Names {
+                        let instruction_name_only = crate::wasm::fmt::instr_name(&op);
+                        hasher.write(instruction_name_only.as_bytes())?;
+                    }
+                    instruction_count += 1;
                 }
-                let function_instruction_names_sha256 = hasher.finalize();
-
-                // TODO Alternative that is more strict, i.e., produces less duplicates:
-                // Take the full bytes of the body for producing the hash. This does not abstract
-                // over (function/local/global) indices or instruction immediates (constants).
-                let _function_body_sha256 = Sha256::digest(function_body_bytes);
-                
-                function_bodies_hashes.extend(function_instruction_names_sha256);
 
-                let mut reader = function_body.get_operators_reader()?;
-                while !reader.eof() {
-                    let _op = reader.read()?;
-                    instruction_count += 1;
+                // More strict, i.e., produces less duplicates: Take the full bytes of the body for
+                // producing the hash. This does not abstract over (function/local/global) indices
+                // or instruction immediates (constants).
+                if signature_mode == SignatureMode::Bytes {
+                    hasher.update(function_body_bytes);
                 }
+
+                function_bodies_hashes.extend(hasher.finalize());
             }
         }
 
@@ -82,3 +86,134 @@ impl WasmBinaryStats {
         })
     }
 }
+
+/// Remove duplicate binaries according to `mode`, keeping the first one (by path) of each group of
+/// duplicates, together with the size of the group.
+/// Sorts the binaries first (in parallel), including by path to make the file order deterministic.
+pub fn dedup_binaries(binaries: &mut [(PathBuf, WasmBinaryStats)], mode: DedupMode) -> Vec<(usize, &(PathBuf, WasmBinaryStats))> {
+    match mode {
+        DedupMode::Signature => binaries.par_sort_unstable_by(|(path_a, a), (path_b, b)| (&a.binary_signature, path_a).cmp(&(&b.binary_signature, path_b))),
+        DedupMode::Sha256 => binaries.par_sort_unstable_by(|(path_a, a), (path_b, b)| (&a.file_sha256, path_a).cmp(&(&b.file_sha256, path_b))),
+        DedupMode::None => binaries.par_sort_unstable_by(|(path_a, _), (path_b, _)| path_a.cmp(path_b)),
+    }
+
+    binaries.iter()
+        .dedup_by_with_count(|(_, a), (_, b)| match mode {
+            DedupMode::Signature => a.binary_signature == b.binary_signature,
+            DedupMode::Sha256 => a.file_sha256 == b.file_sha256,
+            DedupMode::None => false,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::test_util::*;
+
+    #[test]
+    fn test_mmap_same_stats() {
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32], &[I32]);
+        // local.get 0
+        module.function(ty, &[], &[0x20, 0x00]);
+        // local.get 0, i32.const 1, i32.add
+        module.function(ty, &[], &[0x20, 0x00, 0x41, 0x01, 0x6a]);
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "mmap_same_stats.wasm", &module.build());
+
+        let stats = WasmBinaryStats::from_file(&file, false, SignatureMode::Names).unwrap();
+        assert_eq!(stats.function_bodies_count, 2);
+        assert_eq!(WasmBinaryStats::from_file(&file, true, SignatureMode::Names).unwrap(), stats);
+    }
+
+    #[test]
+    fn test_single_pass_same_stats() {
+        let mut module = ModuleBuilder::new();
+        let ty = module.func_type(&[I32], &[I32]);
+        // local.get 0
+        module.function(ty, &[], &[0x20, 0x00]);
+        // local.get 0, i32.const 1, i32.add
+        module.function(ty, &[], &[0x20, 0x00, 0x41, 0x01, 0x6a]);
+        let bytes = module.build();
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "single_pass_same_stats.wasm", &bytes);
+        let stats = WasmBinaryStats::from_file(&file, false, SignatureMode::Names).unwrap();
+
+        // As computed before, with separate passes over each body for hashing and counting.
+        let mut instruction_count = 0;
+        let mut function_bodies_hashes = Vec::new();
+        for payload in Parser::new(0).parse_all(&bytes) {
+            if let Payload::CodeSectionEntry(function_body) = payload.unwrap() {
+                let mut hasher = Sha256::new();
+                let mut reader = function_body.get_operators_reader().unwrap();
+                while !reader.eof() {
+                    hasher.update(crate::wasm::fmt::instr_name(&reader.read().unwrap()).as_bytes());
+                }
+                function_bodies_hashes.extend(hasher.finalize());
+
+                let mut reader = function_body.get_operators_reader().unwrap();
+                while !reader.eof() {
+                    reader.read().unwrap();
+                    instruction_count += 1;
+                }
+            }
+        }
+
+        // Including the implicit end of each body.
+        assert_eq!(stats.instruction_count, 6);
+        assert_eq!(stats.instruction_count, instruction_count);
+        assert_eq!(stats.binary_signature, Sha256::digest(&function_bodies_hashes).as_slice().into());
+    }
+
+    #[test]
+    fn test_dedup_modes() {
+        let dir = temp_dir();
+        let binary = |name, value| {
+            let mut module = ModuleBuilder::new();
+            let ty = module.func_type(&[], &[I32]);
+            // i32.const value
+            module.function(ty, &[], &[0x41, value]);
+            write_temp_file(&dir, name, &module.build())
+        };
+        // Near-duplicates (differ only in a constant), and an exact copy.
+        let files = [binary("dedup_a.wasm", 1), binary("dedup_b.wasm", 2), binary("dedup_c.wasm", 1)];
+        let binaries = files.iter()
+            .map(|file| (file.clone(), WasmBinaryStats::from_file(file, false, SignatureMode::Names).unwrap()))
+            .collect_vec();
+
+        let unique = |mode| {
+            let mut binaries = binaries.clone();
+            dedup_binaries(&mut binaries, mode).into_iter()
+                .map(|(count, (path, _))| (count, path.file_name().unwrap().to_str().unwrap().to_string()))
+                .sorted()
+                .collect_vec()
+        };
+        assert_eq!(unique(DedupMode::Signature), vec![(3, "dedup_a.wasm".to_string())]);
+        assert_eq!(unique(DedupMode::Sha256), vec![(1, "dedup_b.wasm".to_string()), (2, "dedup_a.wasm".to_string())]);
+        assert_eq!(unique(DedupMode::None).len(), 3);
+    }
+
+    #[test]
+    fn test_signature_modes() {
+        let dir = temp_dir();
+        let binary = |name, value| {
+            let mut module = ModuleBuilder::new();
+            let ty = module.func_type(&[], &[I32]);
+            // i32.const value
+            module.function(ty, &[], &[0x41, value]);
+            write_temp_file(&dir, name, &module.build())
+        };
+        // Differ only in a constant.
+        let files = [binary("signature_a.wasm", 1), binary("signature_b.wasm", 2)];
+
+        let unique_count = |signature_mode| {
+            let mut binaries = files.iter()
+                .map(|file| (file.clone(), WasmBinaryStats::from_file(file, false, signature_mode).unwrap()))
+                .collect_vec();
+            dedup_binaries(&mut binaries, DedupMode::Signature).len()
+        };
+        assert_eq!(unique_count(SignatureMode::Names), 1);
+        assert_eq!(unique_count(SignatureMode::Bytes), 2);
+    }
+}
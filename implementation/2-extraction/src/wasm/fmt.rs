@@ -1,6 +1,10 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::Arc;
 
-use wasmparser::{MemoryImmediate, Operator, Type, V128};
+use wasmparser::{FuncType, GlobalType, MemoryImmediate, Operator, Type, TypeOrFuncType, V128};
+
+use crate::samples::wasm_repr::PARAM_TOKEN;
 
 pub fn type_str(ty: Type) -> &'static str {
     use wasmparser::Type::*;
@@ -18,27 +22,99 @@ pub fn type_str(ty: Type) -> &'static str {
     }
 }
 
-pub fn fmt_instr(fmt: &mut impl fmt::Write, op: &Operator, param_local_idx: Option<u32>) -> fmt::Result {
+/// How `fmt_instr()` prints the arguments of instructions. The default prints them as they are
+/// (besides `param_token`, which is only used if `param_local_idx` is given).
+#[derive(Debug, Clone, Copy)]
+pub struct InstrFormat<'a> {
+    /// Accesses to this local are printed with `param_token` (e.g., `<param>`) instead of its index.
+    pub param_local_idx: Option<u32>,
+    pub param_token: &'a str,
+    /// Abstract integer constants into buckets (see `const_bucket()`) and floats to `<const>`.
+    pub abstract_consts: bool,
+    /// Print the alignment of memory instructions, if it is not the natural one.
+    pub print_align: bool,
+    /// Abstract the offset of memory instructions, see `offset_bucket()`.
+    pub bucket_offsets: bool,
+    /// If given, print the name (or index, if it has no name) of called functions.
+    pub call_names: Option<&'a HashMap<u32, Arc<str>>>,
+    /// If given, print the type of accessed globals instead of their index, prefixed with `mut`
+    /// for mutable globals (e.g., the stack pointer).
+    pub global_types: Option<&'a [GlobalType]>,
+    /// If given (type idx -> function type), print the signature of indirectly called functions,
+    /// e.g., `call_indirect (i32 i32 -> i32)`.
+    pub call_indirect_types: Option<&'a [Option<FuncType>]>,
+}
+
+impl Default for InstrFormat<'_> {
+    fn default() -> Self {
+        Self {
+            param_local_idx: None,
+            param_token: PARAM_TOKEN,
+            abstract_consts: false,
+            print_align: false,
+            bucket_offsets: false,
+            call_names: None,
+            global_types: None,
+            call_indirect_types: None,
+        }
+    }
+}
+
+pub fn fmt_instr(fmt: &mut impl fmt::Write, op: &Operator, format: &InstrFormat) -> fmt::Result {
+    let InstrFormat { param_local_idx, param_token, call_names, global_types, call_indirect_types, .. } = *format;
+
     // Print instruction mnemonic.
     fmt.write_str(instr_name(op))?;
 
-    // Print instruction arguments, or <param> for the local we currently extract the type for.
+    // Print instruction arguments, or the param token for the local we currently extract the type for.
     use wasmparser::Operator::*;
     #[rustfmt::skip]
     match op {
         LocalGet { local_index } 
         | LocalSet { local_index } 
-        | LocalTee { local_index } if Some(*local_index) == param_local_idx => fmt.write_str(" <param>")?,
-        _ => fmt_instr_args(fmt, op)?
+        | LocalTee { local_index } if Some(*local_index) == param_local_idx => write!(fmt, " {}", param_token)?,
+        Call { function_index }
+        | ReturnCall { function_index } if call_names.is_some() => {
+            match call_names.and_then(|names| names.get(function_index)) {
+                Some(name) => write!(fmt, " {}", name)?,
+                None => write!(fmt, " {}", function_index)?,
+            }
+        }
+        GlobalGet { global_index }
+        | GlobalSet { global_index } if global_types.is_some() => {
+            match global_types.and_then(|types| types.get(*global_index as usize)) {
+                Some(GlobalType { content_type, mutable: true }) => write!(fmt, " mut {}", type_str(*content_type))?,
+                Some(GlobalType { content_type, mutable: false }) => write!(fmt, " {}", type_str(*content_type))?,
+                None => write!(fmt, " {}", global_index)?,
+            }
+        }
+        CallIndirect { index, .. }
+        | ReturnCallIndirect { index, .. } if call_indirect_types.is_some() => {
+            match call_indirect_types.and_then(|types| types.get(*index as usize)) {
+                Some(Some(FuncType { params, returns })) => {
+                    fmt.write_str(" (")?;
+                    for ty in params.iter() {
+                        write!(fmt, "{} ", type_str(*ty))?;
+                    }
+                    fmt.write_str("->")?;
+                    for ty in returns.iter() {
+                        write!(fmt, " {}", type_str(*ty))?;
+                    }
+                    fmt.write_str(")")?;
+                }
+                _ => write!(fmt, " {}", index)?,
+            }
+        }
+        _ => fmt_instr_args(fmt, op, format.abstract_consts, format.print_align, format.bucket_offsets)?
     };
 
-    // TODO print types of globals, calls, etc.
+    // TODO print types of direct calls, etc.
 
     Ok(())
 }
 
 #[rustfmt::skip]
-fn fmt_instr_args(fmt: &mut impl fmt::Write, op: &Operator) -> fmt::Result {
+fn fmt_instr_args(fmt: &mut impl fmt::Write, op: &Operator, abstract_consts: bool, print_align: bool, bucket_offsets: bool) -> fmt::Result {
     // Contains all instructions with arguments below, 
     // but those which we do not print are commented out.
     use wasmparser::Operator::*;
@@ -57,7 +133,9 @@ fn fmt_instr_args(fmt: &mut impl fmt::Write, op: &Operator) -> fmt::Result {
         
         BrTable { table } => {
             for result in table.targets() {
-                let (relative_depth, _default_label) = result.unwrap();
+                // The targets are only decoded here, so a malformed table is a formatting error
+                // (that callers report for the sample), not a reason to abort.
+                let (relative_depth, _default_label) = result.map_err(|_| fmt::Error)?;
                 write!(fmt, " {}", relative_depth)?;
             }
             Ok(())
@@ -89,6 +167,11 @@ fn fmt_instr_args(fmt: &mut impl fmt::Write, op: &Operator) -> fmt::Result {
         MemorySize { mem, .. }
         | MemoryGrow { mem, .. } => write!(fmt, " {}", mem),
 
+        I32Const { value } if abstract_consts => write!(fmt, " {}", const_bucket(*value as i64, true)),
+        I64Const { value } if abstract_consts => write!(fmt, " {}", const_bucket(*value, false)),
+        F32Const { .. }
+        | F64Const { .. } if abstract_consts => fmt.write_str(" <const>"),
+
         I32Const { value } => write!(fmt, " {}", value),
         I64Const { value } => write!(fmt, " {}", value),
         F32Const { value } => write!(fmt, " {}", f32::from_bits(value.bits())),
@@ -225,7 +308,7 @@ fn fmt_instr_args(fmt: &mut impl fmt::Write, op: &Operator) -> fmt::Result {
         | V128Load16x4S { memarg }
         | V128Load16x4U { memarg }
         | V128Load32x2S { memarg }
-        | V128Load32x2U { memarg } => fmt_memarg(fmt, memarg),
+        | V128Load32x2U { memarg } => fmt_memarg(fmt, op, memarg, print_align, bucket_offsets),
 
         V128Const { value } => fmt_v128(fmt, value),
 
@@ -260,7 +343,7 @@ fn fmt_instr_args(fmt: &mut impl fmt::Write, op: &Operator) -> fmt::Result {
         | V128Store16Lane { memarg, lane } 
         | V128Store32Lane { memarg, lane } 
         | V128Store64Lane { memarg, lane } => {
-            fmt_memarg(fmt, memarg)?;
+            fmt_memarg(fmt, op, memarg, print_align, bucket_offsets)?;
             write!(fmt, " {}", lane)
         }
 
@@ -268,19 +351,154 @@ fn fmt_instr_args(fmt: &mut impl fmt::Write, op: &Operator) -> fmt::Result {
     }
 }
 
+/// Print block-structured control instructions as explicit structural tokens, e.g., `<if> i32`,
+/// including the block result type (if any), which `fmt_instr()` does not print.
+/// Returns false and prints nothing for all other instructions.
+pub fn fmt_structural_instr(fmt: &mut impl fmt::Write, op: &Operator) -> Result<bool, fmt::Error> {
+    use wasmparser::Operator::*;
+    match op {
+        Block { ty } => {
+            fmt.write_str("<block>")?;
+            fmt_block_type(fmt, ty)?;
+        }
+        Loop { ty } => {
+            fmt.write_str("<loop>")?;
+            fmt_block_type(fmt, ty)?;
+        }
+        If { ty } => {
+            fmt.write_str("<if>")?;
+            fmt_block_type(fmt, ty)?;
+        }
+        Else => fmt.write_str("<else>")?,
+        End => fmt.write_str("<end>")?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn fmt_block_type(fmt: &mut impl fmt::Write, ty: &TypeOrFuncType) -> fmt::Result {
+    match ty {
+        TypeOrFuncType::Type(Type::EmptyBlockType) => Ok(()),
+        TypeOrFuncType::Type(ty) => write!(fmt, " {}", type_str(*ty)),
+        // Multi-value blocks: print only the type index, since we have no access to the type section here.
+        TypeOrFuncType::FuncType(type_idx) => write!(fmt, " (type {})", type_idx),
+    }
+}
+
+/// Abstract an integer constant into one of a few buckets, instead of printing the literal value,
+/// which otherwise blows up the vocabulary with rarely repeated tokens.
+/// The buckets are checked in this order:
+/// - `<const:small>`: values in -256..=256, e.g., loop bounds, shift amounts, field offsets.
+/// - `<const:pow2>`: larger positive powers of two, e.g., buffer sizes, alignments, bit flags.
+/// - `<const:addr>`: other (positive) i32 values from 1024 upwards, which are likely pointers to
+///   static data (Emscripten places static data at GLOBAL_BASE = 1024 by default).
+/// - `<const>`: everything else, e.g., large negative values or large non-power-of-two i64s.
+///
+/// (Floating-point constants are always abstracted to `<const>`, see `fmt_instr_args()`.)
+pub fn const_bucket(value: i64, is_i32: bool) -> &'static str {
+    match value {
+        -256..=256 => "<const:small>",
+        v if v > 0 && (v as u64).is_power_of_two() => "<const:pow2>",
+        v if is_i32 && v >= 1024 => "<const:addr>",
+        _ => "<const>",
+    }
+}
+
+/// Abstract a (non-zero) offset of a memory instruction into one of a few buckets, similar to
+/// `const_bucket()`. This keeps the signal that there is an offset (i.e., the access is likely to
+/// a field of a struct or an element of an array) without a token for every distinct offset.
+/// - `<aligned4>`: multiples of 4 up to 256, e.g., fields of structs with 4-byte members.
+/// - `<small>`: other offsets up to 256, e.g., 1- or 2-byte fields.
+/// - `<large>`: everything else, e.g., into large structs or to static data.
+pub fn offset_bucket(offset: u32) -> &'static str {
+    match offset {
+        0..=256 if offset & 0b11 == 0 => "<aligned4>",
+        0..=256 => "<small>",
+        _ => "<large>",
+    }
+}
+
 // see https://github.com/bytecodealliance/wasm-tools/blob/main/crates/wasmprinter/src/lib.rs mem_instr
-fn fmt_memarg(fmt: &mut impl fmt::Write, memarg: &MemoryImmediate) -> fmt::Result {
+fn fmt_memarg(fmt: &mut impl fmt::Write, op: &Operator, memarg: &MemoryImmediate, print_align: bool, bucket_offsets: bool) -> fmt::Result {
     if memarg.memory != 0 {
         write!(fmt, " (memory {})", memarg.memory)?;
     }
     if memarg.offset != 0 {
-        write!(fmt, " offset={}", memarg.offset)?;
+        if bucket_offsets {
+            write!(fmt, " offset={}", offset_bucket(memarg.offset))?;
+        } else {
+            write!(fmt, " offset={}", memarg.offset)?;
+        }
+    }
+    // Print only non-natural alignment, since the natural one is the common case and would only
+    // be noise in the input tokens.
+    if print_align && natural_alignment(op) != Some(memarg.align) {
+        write!(fmt, " align={}", 1u32 << memarg.align)?;
     }
-    // TODO Print alignment as well?
-    // For that we would need the default_alignment, which depends on the instruction.
     Ok(())
 }
 
+/// Natural alignment (i.e., the access size) of load and store instructions, as exponent of 2
+/// (like `MemoryImmediate::align`). None for all other instructions, including atomics, whose
+/// alignment must always be the natural one anyway.
+#[rustfmt::skip]
+fn natural_alignment(op: &Operator) -> Option<u8> {
+    use wasmparser::Operator::*;
+    Some(match op {
+        I32Load8S { .. }
+        | I32Load8U { .. }
+        | I64Load8S { .. }
+        | I64Load8U { .. }
+        | I32Store8 { .. }
+        | I64Store8 { .. }
+        | V128Load8Splat { .. }
+        | V128Load8Lane { .. }
+        | V128Store8Lane { .. } => 0,
+
+        I32Load16S { .. }
+        | I32Load16U { .. }
+        | I64Load16S { .. }
+        | I64Load16U { .. }
+        | I32Store16 { .. }
+        | I64Store16 { .. }
+        | V128Load16Splat { .. }
+        | V128Load16Lane { .. }
+        | V128Store16Lane { .. } => 1,
+
+        I32Load { .. }
+        | F32Load { .. }
+        | I64Load32S { .. }
+        | I64Load32U { .. }
+        | I32Store { .. }
+        | F32Store { .. }
+        | I64Store32 { .. }
+        | V128Load32Splat { .. }
+        | V128Load32Zero { .. }
+        | V128Load32Lane { .. }
+        | V128Store32Lane { .. } => 2,
+
+        I64Load { .. }
+        | F64Load { .. }
+        | I64Store { .. }
+        | F64Store { .. }
+        | V128Load64Splat { .. }
+        | V128Load64Zero { .. }
+        | V128Load8x8S { .. }
+        | V128Load8x8U { .. }
+        | V128Load16x4S { .. }
+        | V128Load16x4U { .. }
+        | V128Load32x2S { .. }
+        | V128Load32x2U { .. }
+        | V128Load64Lane { .. }
+        | V128Store64Lane { .. } => 3,
+
+        V128Load { .. }
+        | V128Store { .. } => 4,
+
+        _ => return None,
+    })
+}
+
 // see https://github.com/bytecodealliance/wasm-tools/blob/main/crates/wasmprinter/src/lib.rs print_operator
 fn fmt_v128(fmt: &mut impl fmt::Write, value: &V128) -> fmt::Result {
     for chunk in value.bytes().chunks(4) {
@@ -884,3 +1102,121 @@ pub fn instr_name(op: &Operator) -> &'static str {
         I32x4ExtAddPairwiseI16x8U => "i32x4.extadd_pairwise_i16x8_u",
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use wasmparser::BinaryReader;
+
+    use super::*;
+
+    /// Decode a single instruction from its binary encoding.
+    fn op(bytes: &[u8]) -> Operator<'_> {
+        BinaryReader::new(bytes).read_operator().unwrap()
+    }
+
+    fn fmt_to_string(op: &Operator<'_>, format: &InstrFormat) -> String {
+        let mut str = String::new();
+        fmt_instr(&mut str, op, format).unwrap();
+        str
+    }
+
+    fn abstract_consts(abstract_consts: bool) -> InstrFormat<'static> {
+        InstrFormat { abstract_consts, ..InstrFormat::default() }
+    }
+
+    #[test]
+    fn test_abstract_consts() {
+        // i32.const 4096
+        assert_eq!(fmt_to_string(&op(&[0x41, 0x80, 0x20]), &abstract_consts(false)), "i32.const 4096");
+        assert_eq!(fmt_to_string(&op(&[0x41, 0x80, 0x20]), &abstract_consts(true)), "i32.const <const:pow2>");
+        // i32.const -1
+        assert_eq!(fmt_to_string(&op(&[0x41, 0x7f]), &abstract_consts(true)), "i32.const <const:small>");
+        // i32.const 5000
+        assert_eq!(fmt_to_string(&op(&[0x41, 0x88, 0x27]), &abstract_consts(true)), "i32.const <const:addr>");
+        // i64.const 5000
+        assert_eq!(fmt_to_string(&op(&[0x42, 0x88, 0x27]), &abstract_consts(true)), "i64.const <const>");
+        // f64.const 2.5
+        let f64_const = [&[0x44][..], &2.5f64.to_le_bytes()].concat();
+        assert_eq!(fmt_to_string(&op(&f64_const), &abstract_consts(false)), "f64.const 2.5");
+        assert_eq!(fmt_to_string(&op(&f64_const), &abstract_consts(true)), "f64.const <const>");
+    }
+
+    #[test]
+    fn test_abstract_consts_param_precedence() {
+        // local.get 1
+        let format = |param_local_idx| InstrFormat { param_local_idx, ..abstract_consts(true) };
+        assert_eq!(fmt_to_string(&op(&[0x20, 0x01]), &format(Some(1))), "local.get <param>");
+        assert_eq!(fmt_to_string(&op(&[0x20, 0x01]), &format(Some(0))), "local.get 1");
+    }
+
+    #[test]
+    fn test_bucket_offsets() {
+        let fmt_offset = |bytes: &[u8]| fmt_to_string(&op(bytes), &InstrFormat { bucket_offsets: true, ..InstrFormat::default() });
+        // i32.load (offset 0)
+        assert_eq!(fmt_offset(&[0x28, 0x02, 0x00]), "i32.load");
+        // i32.load offset=8
+        assert_eq!(fmt_offset(&[0x28, 0x02, 0x08]), "i32.load offset=<aligned4>");
+        // i32.store8 offset=3
+        assert_eq!(fmt_offset(&[0x3a, 0x00, 0x03]), "i32.store8 offset=<small>");
+        // i64.load offset=1024
+        assert_eq!(fmt_offset(&[0x29, 0x03, 0x80, 0x08]), "i64.load offset=<large>");
+
+        assert_eq!(offset_bucket(256), "<aligned4>");
+        assert_eq!(offset_bucket(258), "<large>");
+    }
+
+    #[test]
+    fn test_print_align() {
+        let fmt_align = |bytes: &[u8], print_align| fmt_to_string(&op(bytes), &InstrFormat { print_align, ..InstrFormat::default() });
+        // i32.load align=4 (natural)
+        assert_eq!(fmt_align(&[0x28, 0x02, 0x00], true), "i32.load");
+        // i32.load offset=8 align=1
+        assert_eq!(fmt_align(&[0x28, 0x00, 0x08], true), "i32.load offset=8 align=1");
+        assert_eq!(fmt_align(&[0x28, 0x00, 0x08], false), "i32.load offset=8");
+        // i64.load16_u align=2 (natural)
+        assert_eq!(fmt_align(&[0x33, 0x01, 0x00], true), "i64.load16_u");
+    }
+
+    #[test]
+    fn test_call_names() {
+        let mut names = HashMap::new();
+        names.insert(0, Arc::from("malloc"));
+        let fmt_call = |bytes: &[u8], call_names| fmt_to_string(&op(bytes), &InstrFormat { call_names, ..InstrFormat::default() });
+        // call 0
+        assert_eq!(fmt_call(&[0x10, 0x00], None), "call");
+        assert_eq!(fmt_call(&[0x10, 0x00], Some(&names)), "call malloc");
+        // call 1
+        assert_eq!(fmt_call(&[0x10, 0x01], Some(&names)), "call 1");
+    }
+
+    #[test]
+    fn test_structural_instr() {
+        let fmt_structural = |bytes: &[u8]| {
+            let mut str = String::new();
+            let handled = fmt_structural_instr(&mut str, &op(bytes)).unwrap();
+            (handled, str)
+        };
+        // block (result i32)
+        assert_eq!(fmt_structural(&[0x02, 0x7f]), (true, "<block> i32".to_string()));
+        // loop (no result)
+        assert_eq!(fmt_structural(&[0x03, 0x40]), (true, "<loop>".to_string()));
+        // if (type 2)
+        assert_eq!(fmt_structural(&[0x04, 0x02]), (true, "<if> (type 2)".to_string()));
+        assert_eq!(fmt_structural(&[0x05]), (true, "<else>".to_string()));
+        assert_eq!(fmt_structural(&[0x0b]), (true, "<end>".to_string()));
+        // i32.add
+        assert_eq!(fmt_structural(&[0x6a]), (false, String::new()));
+    }
+
+    #[test]
+    fn test_const_bucket() {
+        assert_eq!(const_bucket(0, true), "<const:small>");
+        assert_eq!(const_bucket(-256, true), "<const:small>");
+        assert_eq!(const_bucket(512, false), "<const:pow2>");
+        assert_eq!(const_bucket(1000, true), "<const>");
+        assert_eq!(const_bucket(1024, true), "<const:pow2>");
+        assert_eq!(const_bucket(1025, true), "<const:addr>");
+        assert_eq!(const_bucket(-1025, true), "<const>");
+    }
+}
@@ -1,28 +1,209 @@
-use std::fs::File;
-use std::io::{self, Read};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::Deref;
 use std::path::Path;
 
+use flate2::read::GzDecoder;
+use memmap2::Mmap;
+
 use crate::util::file_error::{FileError, ResultWithFile};
+use crate::wasm::archive::{read_member, split_member_path};
 
 pub static MAGIC_BYTES_VERSION_1: [u8; 8] = *b"\0asm\x01\0\0\0";
 
+/// Component model binaries have the same magic bytes, but a different version (here, the one of
+/// the current proposal) and layer 1 (core modules have layer 0, i.e., the upper half of their
+/// 32-bit version field).
+pub static MAGIC_BYTES_COMPONENT: [u8; 8] = *b"\0asm\x0d\0\x01\0";
+
+pub static GZIP_MAGIC_BYTES: [u8; 2] = [0x1f, 0x8b];
+
+/// Kind of a file by its magic bytes (and for text files, whether it can be assembled).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WasmFileKind {
+    /// A core WebAssembly module, either binary or text (if accepted).
+    Module,
+    /// A component model binary, which we cannot extract samples from (yet).
+    Component,
+    Other,
+}
+
+/// Whether the bytes start like a component, ignoring the exact version, since it changed
+/// between pre-releases of the proposal.
+fn is_component(bytes: &[u8]) -> bool {
+    bytes.len() >= 8 && bytes[..4] == MAGIC_BYTES_COMPONENT[..4] && bytes[6..8] == MAGIC_BYTES_COMPONENT[6..]
+}
+
 /// Quick check if a file could be a Wasm binary by its magic bytes and version.
+/// Gzip-compressed files (e.g., .wasm.gz) are transparently decompressed.
 pub fn is_wasm_by_magic_bytes(file: impl AsRef<Path>) -> Result<bool, FileError<io::Error>> {
+    Ok(read_magic_bytes(file)? == Some(MAGIC_BYTES_VERSION_1))
+}
+
+/// The first 8 bytes of the (decompressed) file, or None if it is shorter.
+fn read_magic_bytes(file: impl AsRef<Path>) -> Result<Option<[u8; 8]>, FileError<io::Error>> {
+    let mut f = BufReader::new(File::open(&file).with_file(&file)?);
+    let is_gzip = f.fill_buf().with_file(&file)?.starts_with(&GZIP_MAGIC_BYTES);
+    let mut f: Box<dyn Read> = if is_gzip {
+        Box::new(GzDecoder::new(f))
+    } else {
+        Box::new(f)
+    };
+
     // Read the first 8 bytes of the (supposed) Wasm binary.
-    let mut f = File::open(&file).with_file(&file)?;
     let mut buf = [0u8; 8];
     let result = f.read_exact(&mut buf);
 
     // Cannot be a valid wasm file because it has less than 8 bytes.
     if let Err(e) = &result {
         if let io::ErrorKind::UnexpectedEof = e.kind() {
-            return Ok(false);
+            return Ok(None);
         }
     }
 
     // Propagate other IO errors.
     result.with_file(&file)?;
 
-    // Check magic bytes and version.
-    Ok(buf == MAGIC_BYTES_VERSION_1)
+    Ok(Some(buf))
+}
+
+/// Like `is_wasm_by_magic_bytes()`, but if `accept_wat` is set, also accepts WebAssembly text
+/// files, i.e., files that can be assembled to a binary (see `read_wasm_file()`).
+pub fn is_wasm_or_wat(file: impl AsRef<Path>, accept_wat: bool) -> Result<bool, FileError<io::Error>> {
+    Ok(wasm_file_kind(file, accept_wat)? == WasmFileKind::Module)
+}
+
+/// Like `is_wasm_or_wat()`, but also recognizes component model binaries, e.g., to report them
+/// instead of silently ignoring them (they are not valid text files either).
+pub fn wasm_file_kind(file: impl AsRef<Path>, accept_wat: bool) -> Result<WasmFileKind, FileError<io::Error>> {
+    match read_magic_bytes(&file)? {
+        Some(magic_bytes) if magic_bytes == MAGIC_BYTES_VERSION_1 => return Ok(WasmFileKind::Module),
+        Some(magic_bytes) if is_component(&magic_bytes) => return Ok(WasmFileKind::Component),
+        _ => {}
+    }
+    if !accept_wat {
+        return Ok(WasmFileKind::Other);
+    }
+    let bytes = read_decompressed(&file).with_file(&file)?;
+    Ok(if wat::parse_bytes(&bytes).is_ok() { WasmFileKind::Module } else { WasmFileKind::Other })
+}
+
+/// Read the whole file, decompressing it if it is gzip-compressed (by its magic bytes).
+fn read_decompressed(file: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+    let bytes = fs::read(file)?;
+    if bytes.starts_with(&GZIP_MAGIC_BYTES) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&bytes[..]).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    } else {
+        Ok(bytes)
+    }
+}
+
+/// Bytes of a WebAssembly binary, either read into memory or memory-mapped, see `read_wasm_file()`.
+pub enum WasmBytes {
+    Read(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Deref for WasmBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            WasmBytes::Read(bytes) => bytes,
+            WasmBytes::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+/// Read a WebAssembly binary from `file`. Gzip-compressed files are decompressed first, such that
+/// everything downstream (e.g., the SHA256 for deduplication) sees the same bytes for foo.wasm and
+/// foo.wasm.gz. Files that are not a binary (by their magic bytes) are assumed to be in the text
+/// format and are assembled to a binary first.
+/// Note that text files typically have no DWARF debug info, i.e., they contribute only to the
+/// statistics about WebAssembly binaries, but no samples (unless they contain custom sections).
+///
+/// With `mmap`, binaries are memory-mapped instead of copied into memory. This saves the
+/// allocation and copy for large binaries (the parsed function bodies and custom sections are
+/// copied out anyway, so samples may outlive the mapping). Compressed and text files are still
+/// read, since they must be converted in memory.
+/// E.g., for a 400 MB binary (already in the page cache), computing its statistics and extracting
+/// its samples took about 0.6s instead of 1.1s with mmap.
+///
+/// Members of static archives are given by their member path, e.g., `libfoo.a!3!bar.o`, see
+/// `wasm::archive`, and are always read.
+pub fn read_wasm_file(file: impl AsRef<Path>, mmap: bool) -> anyhow::Result<WasmBytes> {
+    if let Some((archive, position, member)) = split_member_path(file.as_ref()) {
+        return Ok(WasmBytes::Read(read_member(archive, position, member)?));
+    }
+
+    if mmap {
+        let f = File::open(&file)?;
+        // Safety: Modifying (in particular, truncating) the file while it is mapped is undefined
+        // behavior. We assume the input files are not modified during the extraction.
+        let mapped = unsafe { Mmap::map(&f)? };
+        if mapped.starts_with(&MAGIC_BYTES_VERSION_1) {
+            return Ok(WasmBytes::Mapped(mapped));
+        }
+    }
+
+    let bytes = read_decompressed(file)?;
+    if bytes.starts_with(&MAGIC_BYTES_VERSION_1) {
+        Ok(WasmBytes::Read(bytes))
+    } else if is_component(&bytes) {
+        // Otherwise, the error would be that it is not a valid text file.
+        anyhow::bail!("component model not supported, only core WebAssembly modules")
+    } else {
+        Ok(WasmBytes::Read(wat::parse_bytes(&bytes)?.into_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::SignatureMode;
+    use crate::wasm::binary_stats::WasmBinaryStats;
+    use crate::wasm::test_util::{temp_dir, write_temp_file};
+
+    const WAT: &str = "(module (func (param i32) (result i32) local.get 0))";
+
+    #[test]
+    fn test_accept_wat() {
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "accept_wat.wat", WAT.as_bytes());
+        assert!(!is_wasm_by_magic_bytes(&file).unwrap());
+        assert!(!is_wasm_or_wat(&file, false).unwrap());
+        assert!(is_wasm_or_wat(&file, true).unwrap());
+
+        let stats = WasmBinaryStats::from_file(&file, false, SignatureMode::Names).unwrap();
+        assert_eq!(stats.function_bodies_count, 1);
+        // local.get, end
+        assert_eq!(stats.instruction_count, 2);
+
+        let not_wat = write_temp_file(&dir, "not_wat.txt", b"not a WebAssembly module");
+        assert!(!is_wasm_or_wat(&not_wat, true).unwrap());
+    }
+
+    #[test]
+    fn test_component() {
+        // Component header, followed by an empty core module section.
+        let mut bytes = MAGIC_BYTES_COMPONENT.to_vec();
+        bytes.extend_from_slice(&[0x01, 0x00]);
+        let dir = temp_dir();
+        let file = write_temp_file(&dir, "component.wasm", &bytes);
+        assert!(!is_wasm_by_magic_bytes(&file).unwrap());
+        assert_eq!(wasm_file_kind(&file, false).unwrap(), WasmFileKind::Component);
+        assert_eq!(wasm_file_kind(&file, true).unwrap(), WasmFileKind::Component);
+        assert!(!is_wasm_or_wat(&file, true).unwrap());
+        let err = read_wasm_file(&file, false).err().unwrap();
+        assert!(err.to_string().contains("component model not supported"));
+
+        // Pre-release version of the component model.
+        let file = write_temp_file(&dir, "component_pre_release.wasm", b"\0asm\x0a\0\x01\0");
+        assert_eq!(wasm_file_kind(&file, false).unwrap(), WasmFileKind::Component);
+
+        let file = write_temp_file(&dir, "component_module.wasm", &MAGIC_BYTES_VERSION_1);
+        assert_eq!(wasm_file_kind(&file, false).unwrap(), WasmFileKind::Module);
+    }
 }
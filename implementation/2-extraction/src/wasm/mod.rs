@@ -1,4 +1,8 @@
+pub mod archive;
 pub mod binary_stats;
 pub mod fmt;
 pub mod magic_bytes;
 pub mod parse;
+pub mod stack_effect;
+#[cfg(test)]
+pub mod test_util;
@@ -4,7 +4,8 @@ use std::rc::Rc;
 use std::sync::Arc;
 
 use anyhow::anyhow;
-use wasmparser::{FunctionBody, ImportSectionEntryType, NameSectionReader, Operator, Parser, TypeDef};
+use itertools::Itertools;
+use wasmparser::{BinaryReader, BinaryReaderError, FuncType, FunctionBody, GlobalType, ImportSectionEntryType, Name, NameSectionReader, Operator, Parser, TypeDef};
 
 /// Extracted information about a WebAssembly binary. Borrows from some underlying data.
 #[derive(Debug, Clone)]
@@ -21,6 +22,8 @@ pub struct WasmBinary<'a> {
 pub struct WasmFunction {
     pub idx: u32,
     pub type_: wasmparser::FuncType,
+    // Names of locals (including parameters) from the name section, if present.
+    pub local_names: HashMap<u32, Arc<str>>,
     // Unfortunately, wasmparser::FunctionBody<'a> and even individual wasmparser::Operator<'a>
     // borrow from the underlying bytes, which adds a lifetime to this struct, which would make
     // it impossible to return it from a function where the bytes are a local variable.
@@ -30,14 +33,22 @@ pub struct WasmFunction {
 }
 
 /// Not-yet parsed representation of WebAssembly function bodies.
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct WasmBody {
     // We use Rc instead of Box/Vec for the bytes, such that we can share the same function body 
     // across multiple function parameters/return type samples later, without copying.
     pub offset: usize,
     pub bytes: Rc<[u8]>,
 
-    // TODO Add lookup tables global, local, function types.
+    // Lookup table for printing names of called functions, shared by all bodies of the binary.
+    pub function_names: Rc<HashMap<u32, Arc<str>>>,
+
+    // Lookup table global idx -> type (including imported globals), also shared by all bodies.
+    pub global_types: Rc<Vec<GlobalType>>,
+
+    // Lookup table type idx -> function type (None for other types), for printing the signature
+    // of call_indirect instructions, also shared. (Locals are parsed on demand, see `locals()`.)
+    pub func_types: Rc<Vec<Option<FuncType>>>,
 }
 
 // Do not write the raw body bytes to debug output, since (1) I cannot read WebAssembly byte code
@@ -56,10 +67,16 @@ impl WasmBody {
     pub fn from(body: FunctionBody, bytes: &[u8]) -> Self {
         let offset = body.range().start;
         let bytes = Rc::from(body.range().slice(bytes));
-        Self { offset, bytes }
+        // Filled in after parsing the whole binary, since the name section comes after the code section.
+        let function_names = Rc::default();
+        let global_types = Rc::default();
+        let func_types = Rc::default();
+        Self { offset, bytes, function_names, global_types, func_types }
     }
 
     pub fn instructions(&self) -> wasmparser::Result<impl Iterator<Item = wasmparser::Result<Operator>>> {
+        #[cfg(test)]
+        DECODE_COUNT.with(|count| count.set(count.get() + 1));
         let body = FunctionBody::new(self.offset, &self.bytes);
         let iter = body.get_operators_reader()?
             .into_iter_with_offsets()
@@ -67,6 +84,64 @@ impl WasmBody {
                 result.map(|(op, _offset)| op));
         Ok(iter)
     }
+
+    /// Like `instructions()`, but each with its encoding in the body, i.e., its opcode and immediates.
+    pub fn instructions_with_bytes(&self) -> wasmparser::Result<Vec<(Operator<'_>, &[u8])>> {
+        let body = FunctionBody::new(self.offset, &self.bytes);
+        let instructions: Vec<_> = body.get_operators_reader()?
+            .into_iter_with_offsets()
+            .collect::<wasmparser::Result<_>>()?;
+        // Each instruction ends where the next one starts, the last one at the end of the body.
+        let ends = instructions.iter()
+            .skip(1)
+            .map(|(_op, offset)| *offset)
+            .chain(std::iter::once(self.offset + self.bytes.len()))
+            .collect_vec();
+        Ok(instructions.into_iter()
+            .zip(ends)
+            .map(|((op, start), end)| (op, &self.bytes[start - self.offset..end - self.offset]))
+            .collect())
+    }
+
+    /// Declared locals of the function (besides its parameters), in the same compressed form as in
+    /// the binary, i.e., (count, type) pairs, e.g., [(2, i32), (1, f64)].
+    pub fn locals(&self) -> wasmparser::Result<Vec<(u32, wasmparser::Type)>> {
+        let body = FunctionBody::new(self.offset, &self.bytes);
+        body.get_locals_reader()?.into_iter().collect()
+    }
+
+    /// Number of declared locals of the function (besides its parameters), see `locals()`.
+    pub fn local_count(&self) -> wasmparser::Result<u64> {
+        Ok(self.locals()?.iter().map(|&(count, _)| count as u64).sum())
+    }
+
+    /// All instructions at once, e.g., to decode the body only once if the instructions are used
+    /// multiple times (such as for filtering samples and for their `WasmRepr`).
+    pub fn decode(&self) -> wasmparser::Result<Vec<Operator<'_>>> {
+        self.instructions()?.collect()
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static DECODE_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// How often a function body was decoded on the current thread (via `WasmBody::instructions()` or
+/// `WasmBody::decode()`), to check in tests that bodies are not decoded more often than necessary.
+#[cfg(test)]
+pub fn decode_count() -> usize {
+    DECODE_COUNT.with(|count| count.get())
+}
+
+/// Whether any of the instructions reads or writes the given local (e.g., a parameter).
+pub fn uses_local(instructions: &[Operator], local_idx: u32) -> bool {
+    instructions.iter().any(|op| match op {
+        Operator::LocalGet { local_index }
+        | Operator::LocalSet { local_index }
+        | Operator::LocalTee { local_index } => *local_index == local_idx,
+        _ => false
+    })
 }
 
 impl<'a> WasmBinary<'a> {
@@ -77,11 +152,16 @@ impl<'a> WasmBinary<'a> {
         let mut function_names = HashMap::new();
 
         // Global state during parsing.
+        // The name section comes after the code section, so attach local names only in the end.
+        let mut local_names = HashMap::new();
         let mut code_section_offset = None;
 
         let mut imported_function_count = 0;
         let mut local_function_count = 0;
 
+        // Imported globals come first in the global index space, so this is dense as well.
+        let mut global_types = Vec::new();
+
         // Since those two maps will be dense, use a Vec as representation.
         let mut local_function_idx_to_type_idx = Vec::new();
         let mut type_idx_to_type = Vec::new();
@@ -101,15 +181,24 @@ impl<'a> WasmBinary<'a> {
                         type_idx_to_type.push(func_ty);
                     }
                 }
-                // Keep number of imported functions for offsetting function indices.
+                // Keep number of imported functions for offsetting function indices,
+                // and the types of imported globals.
                 ImportSection(mut reader) => {
                     for _ in 0..reader.get_count() {
                         let import = reader.read()?;
-                        if let ImportSectionEntryType::Function(..) = import.ty {
-                            imported_function_count += 1;
+                        match import.ty {
+                            ImportSectionEntryType::Function(..) => imported_function_count += 1,
+                            ImportSectionEntryType::Global(ty) => global_types.push(ty),
+                            _ => {}
                         }
                     }
                 }
+                // Keep a map of global idx -> type for printing global.get/set instructions.
+                GlobalSection(mut reader) => {
+                    for _ in 0..reader.get_count() {
+                        global_types.push(reader.read()?.ty);
+                    }
+                }
                 // Keep a map of func idx (without the imported functions!) -> ty idx for resolving function types.
                 FunctionSection(mut reader) => {
                     for _local_function_idx in 0..reader.get_count() {
@@ -141,6 +230,7 @@ impl<'a> WasmBinary<'a> {
                     functions.push(WasmFunction {
                         idx: function_idx,
                         type_,
+                        local_names: HashMap::new(),
                         body: WasmBody::from(body, bytes),
                     });
 
@@ -150,7 +240,7 @@ impl<'a> WasmBinary<'a> {
                 CustomSection { name, data, data_offset } => {
                     custom_sections.insert(name, Rc::from(data));
                     if name == "name" {
-                        parse_name_section(data, data_offset, &mut function_names)?;
+                        parse_name_section(data, data_offset, &mut function_names, &mut local_names)?;
                     }
                 }
                 _ => {}
@@ -159,44 +249,210 @@ impl<'a> WasmBinary<'a> {
 
         let code_section_offset = code_section_offset.ok_or_else(|| anyhow!("missing code section"))?;
 
+        let function_names_shared = Rc::new(function_names.clone());
+        let global_types = Rc::new(global_types);
+        let func_types = Rc::new(type_idx_to_type);
+        for function in &mut functions {
+            if let Some(names) = local_names.remove(&function.idx) {
+                function.local_names = names;
+            }
+            function.body.function_names = Rc::clone(&function_names_shared);
+            function.body.global_types = Rc::clone(&global_types);
+            function.body.func_types = Rc::clone(&func_types);
+        }
+
         Ok(WasmBinary { code_section_offset, custom_sections, functions, function_names })
     }
 }
 
+/// Only the custom sections of a binary, e.g., of a separate debug file, which might not have
+/// the other sections (in particular, no code section) that `WasmBinary::parse()` requires.
+pub fn parse_custom_sections(bytes: &[u8]) -> anyhow::Result<HashMap<&str, Rc<[u8]>>> {
+    let mut custom_sections = HashMap::new();
+    for payload in Parser::new(0).parse_all(bytes) {
+        if let wasmparser::Payload::CustomSection { name, data, .. } = payload? {
+            custom_sections.insert(name, Rc::from(data));
+        }
+    }
+    Ok(custom_sections)
+}
+
+/// Names of the function symbols in the symbol table of the "linking" custom section of relocatable
+/// object files, by function index (which includes imported functions), see
+/// https://github.com/WebAssembly/tool-conventions/blob/main/Linking.md#symbol-table-subsection
+/// If multiple symbols refer to the same function (aliases), the first one is kept.
+pub fn parse_linking_function_names(data: &[u8]) -> anyhow::Result<HashMap<u32, Arc<str>>> {
+    const SYMBOL_TABLE: u32 = 8;
+    const UNDEFINED: u32 = 0x10;
+    const EXPLICIT_NAME: u32 = 0x40;
+
+    let mut function_names = HashMap::new();
+    let mut reader = BinaryReader::new(data);
+    let version = reader.read_var_u32()?;
+    if version != 2 {
+        anyhow::bail!("unsupported linking metadata version {}", version);
+    }
+    while !reader.eof() {
+        let id = reader.read_u8()?;
+        let size = reader.read_var_u32()?;
+        let subsection = reader.read_bytes(size as usize)?;
+        if id != SYMBOL_TABLE {
+            continue;
+        }
+
+        let mut reader = BinaryReader::new(subsection);
+        for _ in 0..reader.read_var_u32()? {
+            let kind = reader.read_u8()?;
+            let flags = reader.read_var_u32()?;
+            let has_name = flags & UNDEFINED == 0 || flags & EXPLICIT_NAME != 0;
+            match kind {
+                // Function, global, event (tag), and table symbols.
+                0 | 2 | 4 | 5 => {
+                    let idx = reader.read_var_u32()?;
+                    if has_name {
+                        let name = reader.read_string()?;
+                        if kind == 0 {
+                            function_names.entry(idx).or_insert_with(|| Arc::from(name));
+                        }
+                    }
+                }
+                // Data symbols.
+                1 => {
+                    reader.read_string()?;
+                    if flags & UNDEFINED == 0 {
+                        // Segment index, offset, and size.
+                        for _ in 0..3 {
+                            reader.read_var_u32()?;
+                        }
+                    }
+                }
+                // Section symbols.
+                3 => {
+                    reader.read_var_u32()?;
+                }
+                kind => anyhow::bail!("unknown symbol kind {} in linking section", kind),
+            }
+        }
+    }
+    Ok(function_names)
+}
+
 fn parse_name_section(
     data: &[u8], 
     section_offset: usize, 
-    function_names: &mut HashMap<u32, Arc<str>>
+    function_names: &mut HashMap<u32, Arc<str>>,
+    local_names: &mut HashMap<u32, HashMap<u32, Arc<str>>>,
 ) -> anyhow::Result<()> {
-    let mut reader = NameSectionReader::new(data, section_offset)?;
+    // Errors when reading the name section can come from the (still non-standard) "extended name
+    // section", e.g., unknown subsections or different encodings of the known ones:
+    // https://github.com/WebAssembly/extended-name-section/blob/master/proposals/extended-name-section/Overview.md
+    // Every subsection starts with its id and size, so split the section into subsections first,
+    // and skip the ones that cannot be read, while still parsing the ones after it.
+    let skip_subsection = |reader: &mut BinaryReader| -> Result<(), BinaryReaderError> {
+        let _id = reader.read_u8()?;
+        let size = reader.read_var_u32()?;
+        reader.read_bytes(size as usize)?;
+        Ok(())
+    };
+    let mut reader = BinaryReader::new_with_offset(data, section_offset);
     while !reader.eof() {
-        use wasmparser::Name::*;
-        match reader.read() {
-            Ok(Module(_)) => {}
-            Ok(Function(function_names_subsection)) => {
-                let mut reader = function_names_subsection.get_map()?;
+        let subsection_start = reader.original_position() - section_offset;
+        if skip_subsection(&mut reader).is_err() {
+            // Then we cannot know where the next subsection begins. Stop parsing, otherwise
+            // it might hypnotize weird function names from garbage.
+            break;
+        }
+        let subsection_end = reader.original_position() - section_offset;
+        // Always advances past the subsection, even if its contents cannot be read, so no infinite loop.
+        let subsection = &data[subsection_start..subsection_end];
+        let result = NameSectionReader::new(subsection, section_offset + subsection_start)
+            .and_then(|mut reader| reader.read())
+            .map_err(anyhow::Error::from)
+            .and_then(|name| parse_name_subsection(name, function_names, local_names));
+        match result {
+            Ok(()) => {}
+            Err(err) if err.is::<BinaryReaderError>() => {}
+            // Duplicate names are not a read error, so do not ignore those.
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn parse_name_subsection(
+    name: Name,
+    function_names: &mut HashMap<u32, Arc<str>>,
+    local_names: &mut HashMap<u32, HashMap<u32, Arc<str>>>,
+) -> anyhow::Result<()> {
+    match name {
+        Name::Function(function_names_subsection) => {
+            let mut reader = function_names_subsection.get_map()?;
+            for _ in 0..reader.get_count() {
+                let naming = reader.read()?;
+                let duplicate_name = function_names.insert(naming.index, Arc::from(naming.name));
+                if let Some(duplicate_name) = duplicate_name {
+                    anyhow::bail!("duplicate name for function {}: '{}' and '{}'", naming.index, duplicate_name, naming.name);
+                }
+            }
+        }
+        Name::Local(local_names_subsection) => {
+            // Parameter names come primarily from the DWARF info, these are only a fallback.
+            let mut reader = local_names_subsection.get_function_local_reader()?;
+            for _ in 0..reader.get_count() {
+                let function = reader.read()?;
+                let names = local_names.entry(function.func_index).or_default();
+                let mut reader = function.get_map()?;
                 for _ in 0..reader.get_count() {
                     let naming = reader.read()?;
-                    let duplicate_name = function_names.insert(naming.index, Arc::from(naming.name));
+                    let duplicate_name = names.insert(naming.index, Arc::from(naming.name));
                     if let Some(duplicate_name) = duplicate_name {
-                        anyhow::bail!("duplicate name for function {}: '{}' and '{}'", naming.index, duplicate_name, naming.name);
+                        anyhow::bail!("duplicate name for local {} in function {}: '{}' and '{}'", naming.index, function.func_index, duplicate_name, naming.name);
                     }
                 }
             }
-            Ok(Local(_)) => {
-                // TODO Also extract parameter names from name section?
-                // For now, parameter names come from the DWARF info only, which makes sense for
-                // debugging information, which also comes from DWARF.
-            }
-            // Ignore errors when reading the name section, bacuse those could be from the (still 
-            // non-standard) "extended name section".
-            // https://github.com/WebAssembly/extended-name-section/blob/master/proposals/extended-name-section/Overview.md
-            _ => {
-                // But stop parsing after the first error, otherwise it might  
-                // hypnotize weird function names.
-                break;
-            }, 
         }
+        // Module name and unknown subsections from the extended name section (e.g., label names).
+        _ => {}
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::test_util::*;
+
+    #[test]
+    fn test_name_section_skip_subsections() {
+        let module = |names: &[u8]| {
+            let mut module = ModuleBuilder::new();
+            let ty = module.func_type(&[I32], &[]);
+            module.function(ty, &[], &[]);
+            module.function(ty, &[], &[]);
+            module.custom_section("name", names);
+            module.build()
+        };
+        let function_name = |wasm: &WasmBinary, idx| wasm.function_names.get(&idx).map(|name| name.to_string());
+
+        let mut names = Vec::new();
+        // Unknown subsection from the extended name section (type names).
+        subsection(&mut names, 4, vec![0x01, 0x00, 0x03, b'f', b'o', b'o']);
+        // Local names subsection, whose content is truncated.
+        subsection(&mut names, 2, vec![0x01, 0x00, 0x05]);
+        // Valid names afterwards.
+        names.extend(name_section(&[(0, "first"), (1, "second")], &[(1, &[(0, "param")])]));
+        let bytes = module(&names);
+        let wasm = WasmBinary::parse(&bytes).unwrap();
+        assert_eq!(function_name(&wasm, 0).as_deref(), Some("first"));
+        assert_eq!(function_name(&wasm, 1).as_deref(), Some("second"));
+        assert_eq!(wasm.functions[1].local_names.get(&0).map(|name| &**name), Some("param"));
+
+        // Subsection size past the end: stop there, but keep the names before.
+        let mut names = name_section(&[(0, "first")], &[]);
+        names.extend(&[0x01, 0x7f, 0x00]);
+        let bytes = module(&names);
+        let wasm = WasmBinary::parse(&bytes).unwrap();
+        assert_eq!(function_name(&wasm, 0).as_deref(), Some("first"));
+        assert_eq!(function_name(&wasm, 1), None);
+    }
+}
@@ -0,0 +1,113 @@
+use wasmparser::Operator;
+
+/// Number of operands an instruction pops from and pushes onto the operand stack, or None if that
+/// depends on information not available from the instruction alone (e.g., the function type of
+/// calls or block types) or the instruction is not (yet) supported here (e.g., SIMD).
+/// Values that are popped by unconditional branches (br, return etc.) are not counted.
+#[rustfmt::skip]
+pub fn stack_effect(op: &Operator) -> Option<(usize, usize)> {
+    use wasmparser::Operator::*;
+    Some(match op {
+        Nop
+        | Unreachable
+        | Block { .. }
+        | Loop { .. }
+        | Else
+        | End
+        | Br { .. }
+        | Return => (0, 0),
+
+        If { .. }
+        | BrIf { .. }
+        | BrTable { .. }
+        | Drop => (1, 0),
+
+        Select
+        | TypedSelect { .. } => (3, 1),
+
+        LocalGet { .. }
+        | GlobalGet { .. } => (0, 1),
+        LocalSet { .. }
+        | GlobalSet { .. } => (1, 0),
+        LocalTee { .. } => (1, 1),
+
+        I32Load { .. }
+        | I64Load { .. }
+        | F32Load { .. }
+        | F64Load { .. }
+        | I32Load8S { .. }
+        | I32Load8U { .. }
+        | I32Load16S { .. }
+        | I32Load16U { .. }
+        | I64Load8S { .. }
+        | I64Load8U { .. }
+        | I64Load16S { .. }
+        | I64Load16U { .. }
+        | I64Load32S { .. }
+        | I64Load32U { .. } => (1, 1),
+
+        I32Store { .. }
+        | I64Store { .. }
+        | F32Store { .. }
+        | F64Store { .. }
+        | I32Store8 { .. }
+        | I32Store16 { .. }
+        | I64Store8 { .. }
+        | I64Store16 { .. }
+        | I64Store32 { .. } => (2, 0),
+
+        MemorySize { .. } => (0, 1),
+        MemoryGrow { .. } => (1, 1),
+
+        I32Const { .. }
+        | I64Const { .. }
+        | F32Const { .. }
+        | F64Const { .. } => (0, 1),
+
+        // Unary operations, tests, and conversions.
+        I32Eqz | I64Eqz
+        | I32Clz | I32Ctz | I32Popcnt
+        | I64Clz | I64Ctz | I64Popcnt
+        | F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt
+        | F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt
+        | I32WrapI64
+        | I32TruncF32S | I32TruncF32U | I32TruncF64S | I32TruncF64U
+        | I64ExtendI32S | I64ExtendI32U
+        | I64TruncF32S | I64TruncF32U | I64TruncF64S | I64TruncF64U
+        | F32ConvertI32S | F32ConvertI32U | F32ConvertI64S | F32ConvertI64U
+        | F32DemoteF64
+        | F64ConvertI32S | F64ConvertI32U | F64ConvertI64S | F64ConvertI64U
+        | F64PromoteF32
+        | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64
+        | I32Extend8S | I32Extend16S
+        | I64Extend8S | I64Extend16S | I64Extend32S
+        | I32TruncSatF32S | I32TruncSatF32U | I32TruncSatF64S | I32TruncSatF64U
+        | I64TruncSatF32S | I64TruncSatF32U | I64TruncSatF64S | I64TruncSatF64U => (1, 1),
+
+        // Binary operations and comparisons.
+        I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS | I32GeU
+        | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU
+        | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge
+        | F64Eq | F64Ne | F64Lt | F64Gt | F64Le | F64Ge
+        | I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU
+        | I32And | I32Or | I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr
+        | I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU
+        | I64And | I64Or | I64Xor | I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr
+        | F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign
+        | F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign => (2, 1),
+
+        MemoryCopy { .. }
+        | MemoryFill { .. }
+        | MemoryInit { .. } => (3, 0),
+        DataDrop { .. } => (0, 0),
+
+        _ => return None,
+    })
+}
+
+/// Whether the instruction unconditionally transfers control, such that the operand stack after
+/// it is unconstrained (i.e., "polymorphic" in the terms of the WebAssembly spec).
+pub fn is_unconditional_branch(op: &Operator) -> bool {
+    use wasmparser::Operator::*;
+    matches!(op, Unreachable | Br { .. } | BrTable { .. } | Return | ReturnCall { .. } | ReturnCallIndirect { .. })
+}
@@ -0,0 +1,243 @@
+//! Minimal encoder for small WebAssembly binaries in unit tests, since we cannot easily compile
+//! C/C++ test programs to WebAssembly as part of the tests.
+//! Sections are always emitted in the standard order, custom sections last.
+
+use std::path::PathBuf;
+
+use gimli::constants::*;
+use tempfile::TempDir;
+
+use crate::dwarf::test_util::{add_base_type, add_dwarf_sections, add_subprogram, add_typed, dwarf_sections};
+
+// Binary encoding of value types.
+pub const I32: u8 = 0x7f;
+#[allow(dead_code)]
+pub const I64: u8 = 0x7e;
+#[allow(dead_code)]
+pub const F64: u8 = 0x7c;
+
+#[derive(Debug, Clone, Default)]
+pub struct ModuleBuilder {
+    types: Vec<(Vec<u8>, Vec<u8>)>,
+    imported_functions: Vec<(String, String, u32)>,
+    functions: Vec<(u32, Vec<u8>)>,
+    globals: Vec<(u8, bool, Vec<u8>)>,
+    custom_sections: Vec<(String, Vec<u8>)>,
+}
+
+impl ModuleBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a function type, returns its type index.
+    pub fn func_type(&mut self, params: &[u8], results: &[u8]) -> u32 {
+        self.types.push((params.to_vec(), results.to_vec()));
+        self.types.len() as u32 - 1
+    }
+
+    /// Add a function import. Must be called before adding any local function, since imported
+    /// functions come first in the function index space.
+    #[allow(dead_code)]
+    pub fn import_function(&mut self, module: &str, name: &str, type_idx: u32) -> u32 {
+        assert!(self.functions.is_empty(), "function imports must be added before local functions");
+        self.imported_functions.push((module.to_string(), name.to_string(), type_idx));
+        self.imported_functions.len() as u32 - 1
+    }
+
+    /// Add a function with the given local declarations (count, type) and instructions (without
+    /// the final `end`), returns its function index.
+    pub fn function(&mut self, type_idx: u32, locals: &[(u32, u8)], instructions: &[u8]) -> u32 {
+        let mut body = Vec::new();
+        leb128_u32(&mut body, locals.len() as u32);
+        for &(count, ty) in locals {
+            leb128_u32(&mut body, count);
+            body.push(ty);
+        }
+        body.extend_from_slice(instructions);
+        body.push(0x0b);
+
+        self.functions.push((type_idx, body));
+        (self.imported_functions.len() + self.functions.len()) as u32 - 1
+    }
+
+    /// Add a global with the given constant initializer expression (without the final `end`).
+    #[allow(dead_code)]
+    pub fn global(&mut self, ty: u8, mutable: bool, init: &[u8]) -> u32 {
+        self.globals.push((ty, mutable, init.to_vec()));
+        self.globals.len() as u32 - 1
+    }
+
+    pub fn custom_section(&mut self, name: &str, data: &[u8]) {
+        self.custom_sections.push((name.to_string(), data.to_vec()));
+    }
+
+    /// Offsets of all local function bodies relative to the start of the code section, i.e., the
+    /// values that DWARF uses for DW_AT_low_pc of the respective functions.
+    pub fn code_offsets(&self) -> Vec<u64> {
+        let mut offset = leb128_u32_len(self.functions.len() as u32);
+        let mut offsets = Vec::new();
+        for (_, body) in &self.functions {
+            offset += leb128_u32_len(body.len() as u32);
+            offsets.push(offset as u64);
+            offset += body.len();
+        }
+        offsets
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+
+        if !self.types.is_empty() {
+            section(&mut bytes, 1, vec(&self.types, |buf, (params, results)| {
+                buf.push(0x60);
+                vec_bytes(buf, params);
+                vec_bytes(buf, results);
+            }));
+        }
+        if !self.imported_functions.is_empty() {
+            section(&mut bytes, 2, vec(&self.imported_functions, |buf, (module, name, type_idx)| {
+                vec_bytes(buf, module.as_bytes());
+                vec_bytes(buf, name.as_bytes());
+                buf.push(0x00);
+                leb128_u32(buf, *type_idx);
+            }));
+        }
+        if !self.functions.is_empty() {
+            section(&mut bytes, 3, vec(&self.functions, |buf, (type_idx, _)| leb128_u32(buf, *type_idx)));
+        }
+        if !self.globals.is_empty() {
+            section(&mut bytes, 6, vec(&self.globals, |buf, (ty, mutable, init)| {
+                buf.push(*ty);
+                buf.push(*mutable as u8);
+                buf.extend_from_slice(init);
+                buf.push(0x0b);
+            }));
+        }
+        if !self.functions.is_empty() {
+            section(&mut bytes, 10, vec(&self.functions, |buf, (_, body)| vec_bytes(buf, body)));
+        }
+        for (name, data) in &self.custom_sections {
+            let mut content = Vec::new();
+            vec_bytes(&mut content, name.as_bytes());
+            content.extend_from_slice(data);
+            section(&mut bytes, 0, content);
+        }
+
+        bytes
+    }
+}
+
+/// Content of a "name" custom section with the given function names and local names per function.
+pub fn name_section(function_names: &[(u32, &str)], local_names: &[(u32, &[(u32, &str)])]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    if !function_names.is_empty() {
+        subsection(&mut bytes, 1, name_map(function_names));
+    }
+    if !local_names.is_empty() {
+        subsection(&mut bytes, 2, vec(local_names, |buf, (function_idx, names)| {
+            leb128_u32(buf, *function_idx);
+            buf.extend(name_map(names));
+        }));
+    }
+    bytes
+}
+
+/// Contents of a "linking" custom section (as in relocatable object files) with a symbol table of
+/// defined functions, given by their index and name.
+pub fn linking_section(function_symbols: &[(u32, &str)]) -> Vec<u8> {
+    // Version 2 of the linking metadata.
+    let mut bytes = vec![2];
+    subsection(&mut bytes, 8, vec(function_symbols, |buf, (idx, name)| {
+        // Kind function, no flags.
+        buf.extend_from_slice(&[0, 0]);
+        leb128_u32(buf, *idx);
+        vec_bytes(buf, name.as_bytes());
+    }));
+    bytes
+}
+
+fn name_map(names: &[(u32, &str)]) -> Vec<u8> {
+    vec(names, |buf, (idx, name)| {
+        leb128_u32(buf, *idx);
+        vec_bytes(buf, name.as_bytes());
+    })
+}
+
+/// Append a (name section) subsection, which has the same encoding as a section.
+pub fn subsection(bytes: &mut Vec<u8>, id: u8, content: Vec<u8>) {
+    section(bytes, id, content)
+}
+
+fn section(bytes: &mut Vec<u8>, id: u8, content: Vec<u8>) {
+    bytes.push(id);
+    vec_bytes(bytes, &content);
+}
+
+fn vec<T>(items: &[T], mut encode: impl FnMut(&mut Vec<u8>, &T)) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    leb128_u32(&mut bytes, items.len() as u32);
+    for item in items {
+        encode(&mut bytes, item);
+    }
+    bytes
+}
+
+fn vec_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    leb128_u32(bytes, data.len() as u32);
+    bytes.extend_from_slice(data);
+}
+
+pub fn leb128_u32(bytes: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn leb128_u32_len(value: u32) -> usize {
+    let mut bytes = Vec::new();
+    leb128_u32(&mut bytes, value);
+    bytes.len()
+}
+
+/// Module with `n_functions` functions `void f(int x)`, whose body only uses its parameter, with
+/// DWARF info for the first one. `build_unit` can set further attributes on the compilation unit,
+/// e.g., its producer or language.
+pub fn int_param_module(n_functions: usize, build_unit: impl FnOnce(&mut gimli::write::Unit)) -> Vec<u8> {
+    let mut module = ModuleBuilder::new();
+    let ty = module.func_type(&[I32], &[]);
+    for _ in 0..n_functions {
+        // local.get 0, drop
+        module.function(ty, &[], &[0x20, 0x00, 0x1a]);
+    }
+    let low_pc = module.code_offsets()[0];
+    let sections = dwarf_sections(|unit| {
+        build_unit(unit);
+        let int = add_base_type(unit, "int", DW_ATE_signed, 4);
+        let subprogram = add_subprogram(unit, Some("f"), low_pc);
+        add_typed(unit, subprogram, DW_TAG_formal_parameter, Some("x"), int);
+    });
+    add_dwarf_sections(&mut module, &sections);
+    module.build()
+}
+
+/// Fresh temporary directory for the files of a single test, deleted with all its files when the
+/// returned value is dropped.
+pub fn temp_dir() -> TempDir {
+    tempfile::tempdir().unwrap()
+}
+
+/// Write `bytes` to a file in `dir`, e.g., for functions that read from a path. `name` may contain
+/// (sub-)directories.
+pub fn write_temp_file(dir: &TempDir, name: &str, bytes: &[u8]) -> PathBuf {
+    let path = dir.path().join(name);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, bytes).unwrap();
+    path
+}